@@ -0,0 +1,215 @@
+use bevy::{
+    app::App,
+    log::{self, LogPlugin},
+    math::Vec2,
+    tasks::{AsyncComputeTaskPool, ComputeTaskPool, IoTaskPool},
+};
+use bevy_networking_turbulence::NetworkResource;
+use mr_client_lib::{
+    bots::{Bot, BotBehaviour, BotStats, ReplayedInput},
+    MuddleHeadlessClientPlugin,
+};
+use std::time::{Duration, Instant};
+
+const DEFAULT_BOTS_COUNT: usize = 10;
+const STATS_INTERVAL_SECS: u64 = 5;
+
+fn main() {
+    let bots_count = bots_count();
+    let behaviour = bots_behaviour();
+
+    let mut apps: Vec<App> = Vec::with_capacity(bots_count);
+    for i in 0..bots_count {
+        let mut builder = App::build();
+        match apps.first() {
+            // All the bots share the same task pools, so that we don't spawn threads per bot.
+            Some(first_app) => {
+                builder
+                    .insert_resource(
+                        first_app
+                            .world
+                            .get_resource::<IoTaskPool>()
+                            .unwrap()
+                            .clone(),
+                    )
+                    .insert_resource(
+                        first_app
+                            .world
+                            .get_resource::<ComputeTaskPool>()
+                            .unwrap()
+                            .clone(),
+                    )
+                    .insert_resource(
+                        first_app
+                            .world
+                            .get_resource::<AsyncComputeTaskPool>()
+                            .unwrap()
+                            .clone(),
+                    );
+            }
+            None => {
+                builder.add_plugin(LogPlugin::default());
+            }
+        }
+        builder
+            .insert_resource(Bot::new(behaviour.clone(), i as u64))
+            .add_plugin(MuddleHeadlessClientPlugin::<NetworkResource>::default());
+        apps.push(builder.app);
+    }
+    log::info!("Started {} bots ({:?})", bots_count, behaviour);
+
+    let mut stats_printed_at = Instant::now();
+    loop {
+        for app in &mut apps {
+            app.update();
+        }
+        if stats_printed_at.elapsed() >= Duration::from_secs(STATS_INTERVAL_SECS) {
+            stats_printed_at = Instant::now();
+            print_stats(&apps);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn print_stats(apps: &[App]) {
+    let stats = apps
+        .iter()
+        .map(|app| BotStats::from_world(&app.world))
+        .collect::<Vec<_>>();
+    let connected = stats
+        .iter()
+        .filter(|stats| stats.is_connected)
+        .collect::<Vec<_>>();
+    let desyncs: u32 = stats.iter().map(|stats| stats.desyncs).sum();
+
+    if connected.is_empty() {
+        println!("Connected: 0/{}, desyncs: {}", stats.len(), desyncs);
+        return;
+    }
+    let count = connected.len() as f32;
+    let avg_rtt = connected.iter().map(|stats| stats.rtt_millis).sum::<f32>() / count;
+    let max_rtt = connected
+        .iter()
+        .map(|stats| stats.rtt_millis)
+        .fold(0.0, f32::max);
+    let avg_loss = connected.iter().map(|stats| stats.packet_loss).sum::<f32>() / count;
+    let avg_jitter = connected
+        .iter()
+        .map(|stats| stats.jitter_millis)
+        .sum::<f32>()
+        / count;
+    println!(
+        "Connected: {}/{}, RTT: {:.0}ms (max: {:.0}ms), packet loss: {:.2}%, jitter: {:.0}ms, desyncs: {}",
+        connected.len(),
+        stats.len(),
+        avg_rtt,
+        max_rtt,
+        avg_loss * 100.0,
+        avg_jitter,
+        desyncs
+    );
+}
+
+fn bots_count() -> usize {
+    std::env::var("MUDDLE_BOTS_COUNT")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_BOTS_COUNT").map(str::to_owned))
+        .map_or(DEFAULT_BOTS_COUNT, |count| {
+            count
+                .parse::<usize>()
+                .expect("Expected MUDDLE_BOTS_COUNT to be a number")
+        })
+}
+
+fn bots_behaviour() -> BotBehaviour {
+    let behaviour = std::env::var("MUDDLE_BOTS_BEHAVIOUR")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_BOTS_BEHAVIOUR").map(str::to_owned));
+    match behaviour.as_deref() {
+        None | Some("random_walk") => BotBehaviour::RandomWalk,
+        Some("circle") => BotBehaviour::Circle,
+        Some("replay") => BotBehaviour::Replay(load_replay()),
+        Some(behaviour) => panic!(
+            "Unknown MUDDLE_BOTS_BEHAVIOUR: {} (expected random_walk, circle or replay)",
+            behaviour
+        ),
+    }
+}
+
+/// Replay files have an input per line: `<frames> <x> <y> [jump]`, where the direction `(x, y)`
+/// is held for the number of frames. Empty lines and lines starting with `#` are skipped.
+fn load_replay() -> Vec<ReplayedInput> {
+    let path = std::env::var("MUDDLE_BOTS_REPLAY_PATH")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_BOTS_REPLAY_PATH").map(str::to_owned))
+        .expect("Expected MUDDLE_BOTS_REPLAY_PATH to be set for the replay behaviour");
+    let replay = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Failed to read the replay file {}: {}", path, err));
+
+    replay
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| {
+            parse_replayed_input(line)
+                .unwrap_or_else(|| panic!("Invalid input at {}:{}: {}", path, i + 1, line))
+        })
+        .collect()
+}
+
+fn parse_replayed_input(line: &str) -> Option<ReplayedInput> {
+    let mut parts = line.split_whitespace();
+    let frames = parts.next()?.parse::<u16>().ok()?;
+    let x = parts.next()?.parse::<f32>().ok()?;
+    let y = parts.next()?.parse::<f32>().ok()?;
+    let jump = match parts.next() {
+        None => false,
+        Some("jump") => true,
+        Some(_) => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(ReplayedInput {
+        frames,
+        direction: Vec2::new(x, y),
+        jump,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse_replayed_input;
+    use bevy::math::Vec2;
+    use mr_client_lib::bots::ReplayedInput;
+
+    #[test]
+    fn test_parse_replayed_input() {
+        assert_eq!(
+            parse_replayed_input("60 1 -0.5"),
+            Some(ReplayedInput {
+                frames: 60,
+                direction: Vec2::new(1.0, -0.5),
+                jump: false,
+            })
+        );
+        assert_eq!(
+            parse_replayed_input("  1\t0 0   jump "),
+            Some(ReplayedInput {
+                frames: 1,
+                direction: Vec2::ZERO,
+                jump: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_replayed_input() {
+        assert_eq!(parse_replayed_input(""), None);
+        assert_eq!(parse_replayed_input("60 1"), None);
+        assert_eq!(parse_replayed_input("-1 1 0"), None);
+        assert_eq!(parse_replayed_input("60 x 0"), None);
+        assert_eq!(parse_replayed_input("60 1 0 run"), None);
+        assert_eq!(parse_replayed_input("60 1 0 jump jump"), None);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{desync::DesyncDetection, CurrentPlayerNetId};
+use bevy::{
+    ecs::{
+        system::{Res, ResMut},
+        world::World,
+    },
+    math::Vec2,
+};
+use mr_shared_lib::{
+    codec::quantize_direction,
+    framebuffer::FrameNumber,
+    net::{ConnectionState, ConnectionStatus},
+    player::{PlayerDirectionUpdate, PlayerUpdates},
+    GameTime, COMPONENT_FRAMEBUFFER_LIMIT, SIMULATIONS_PER_SECOND,
+};
+
+/// A random walk keeps a direction for a second before picking a new one.
+const RANDOM_WALK_SEGMENT_FRAMES: u16 = SIMULATIONS_PER_SECOND;
+/// One in this many random walk segments starts with a jump.
+const RANDOM_WALK_JUMP_ODDS: u64 = 4;
+/// Bots walking in circles make a full turn in 4 seconds.
+const CIRCLE_RADIANS_PER_FRAME: f32 = std::f32::consts::TAU / (SIMULATIONS_PER_SECOND as f32 * 4.0);
+
+#[derive(Debug, Clone)]
+pub enum BotBehaviour {
+    RandomWalk,
+    Circle,
+    /// Recorded inputs are replayed in a loop.
+    Replay(Vec<ReplayedInput>),
+}
+
+/// A direction that is held for `frames`, a jump (if any) happens at the first frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayedInput {
+    pub frames: u16,
+    pub direction: Vec2,
+    pub jump: bool,
+}
+
+/// Drives the inputs of a headless client (see `MuddleHeadlessClientPlugin`).
+pub struct Bot {
+    behaviour: BotBehaviour,
+    /// Xorshift state, bots with different seeds walk in different directions.
+    rng_state: u64,
+    current_frame: Option<FrameNumber>,
+    current_input: (Vec2, bool),
+    /// Frames that have passed since the bot started walking.
+    frames: u32,
+    segment: ReplayedInput,
+    segment_frames_left: u16,
+    replay_index: usize,
+}
+
+impl Bot {
+    pub fn new(behaviour: BotBehaviour, seed: u64) -> Self {
+        Self {
+            behaviour,
+            // Xorshift gets stuck at zero, and adjacent seeds shouldn't produce similar sequences.
+            rng_state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+            current_frame: None,
+            current_input: (Vec2::ZERO, false),
+            frames: 0,
+            segment: ReplayedInput {
+                frames: 0,
+                direction: Vec2::ZERO,
+                jump: false,
+            },
+            segment_frames_left: 0,
+            replay_index: 0,
+        }
+    }
+
+    /// Advances the behaviour once per frame, returning the input for the current one.
+    fn input(&mut self, frame_number: FrameNumber) -> (Vec2, bool) {
+        // Inputs are tracked on every app update, while a frame may last for several updates.
+        if self.current_frame != Some(frame_number) {
+            self.current_frame = Some(frame_number);
+            self.current_input = self.next_input();
+        }
+        self.current_input
+    }
+
+    fn next_input(&mut self) -> (Vec2, bool) {
+        self.frames = self.frames.wrapping_add(1);
+
+        if let BotBehaviour::Circle = self.behaviour {
+            let angle = self.frames as f32 * CIRCLE_RADIANS_PER_FRAME;
+            return (Vec2::new(angle.cos(), angle.sin()), false);
+        }
+
+        let is_new_segment = self.segment_frames_left == 0;
+        if is_new_segment {
+            self.segment = self.next_segment();
+            self.segment_frames_left = self.segment.frames;
+        }
+        self.segment_frames_left = self.segment_frames_left.saturating_sub(1);
+        (self.segment.direction, is_new_segment && self.segment.jump)
+    }
+
+    fn next_segment(&mut self) -> ReplayedInput {
+        match &self.behaviour {
+            BotBehaviour::Replay(inputs) if !inputs.is_empty() => {
+                let input = inputs[self.replay_index % inputs.len()].clone();
+                self.replay_index = (self.replay_index + 1) % inputs.len();
+                input
+            }
+            BotBehaviour::RandomWalk => {
+                let angle = (self.next_random() % 360) as f32 * std::f32::consts::PI / 180.0;
+                // Bots stand still every once in a while, like real players.
+                let direction = if self.next_random() % 8 == 0 {
+                    Vec2::ZERO
+                } else {
+                    Vec2::new(angle.cos(), angle.sin())
+                };
+                ReplayedInput {
+                    frames: RANDOM_WALK_SEGMENT_FRAMES,
+                    direction,
+                    jump: self.next_random() % RANDOM_WALK_JUMP_ODDS == 0,
+                }
+            }
+            BotBehaviour::Replay(_) | BotBehaviour::Circle => ReplayedInput {
+                frames: RANDOM_WALK_SEGMENT_FRAMES,
+                direction: Vec2::ZERO,
+                jump: false,
+            },
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        self.rng_state
+    }
+}
+
+/// Replaces `input::track_input_events` for headless clients.
+pub fn drive_bot_input(
+    time: Res<GameTime>,
+    current_player_net_id: Res<CurrentPlayerNetId>,
+    mut player_updates: ResMut<PlayerUpdates>,
+    mut bot: ResMut<Bot>,
+) {
+    let player_net_id = match current_player_net_id.0 {
+        Some(player_net_id) => player_net_id,
+        None => return,
+    };
+
+    let (direction, jump) = bot.input(time.frame_number);
+    player_updates
+        .get_direction_mut(
+            player_net_id,
+            time.frame_number,
+            COMPONENT_FRAMEBUFFER_LIMIT,
+        )
+        .insert(
+            time.frame_number,
+            Some(PlayerDirectionUpdate {
+                direction: quantize_direction(direction),
+                jump,
+                is_processed_client_input: Some(false),
+            }),
+        );
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BotStats {
+    pub is_connected: bool,
+    pub rtt_millis: f32,
+    pub packet_loss: f32,
+    pub jitter_millis: f32,
+    pub desyncs: u32,
+}
+
+impl BotStats {
+    /// Reads the stats of a headless client from its world.
+    pub fn from_world(world: &World) -> Self {
+        let connection_state = world
+            .get_resource::<ConnectionState>()
+            .expect("Expected a headless client world");
+        Self {
+            is_connected: matches!(connection_state.status(), ConnectionStatus::Connected),
+            rtt_millis: connection_state.rtt_millis(),
+            packet_loss: connection_state.packet_loss(),
+            jitter_millis: connection_state.jitter_millis(),
+            desyncs: world
+                .get_resource::<DesyncDetection>()
+                .map_or(0, |desync_detection| desync_detection.desyncs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bots::{
+        Bot, BotBehaviour, ReplayedInput, CIRCLE_RADIANS_PER_FRAME, RANDOM_WALK_SEGMENT_FRAMES,
+    };
+    use bevy::math::Vec2;
+    use mr_shared_lib::{framebuffer::FrameNumber, SIMULATIONS_PER_SECOND};
+
+    fn inputs(bot: &mut Bot, frames: u16) -> Vec<(Vec2, bool)> {
+        (0..frames)
+            .map(|frame| bot.input(FrameNumber::new(frame)))
+            .collect()
+    }
+
+    #[test]
+    fn test_input_advances_once_per_frame() {
+        let mut bot = Bot::new(BotBehaviour::Circle, 0);
+        let input = bot.input(FrameNumber::new(5));
+        assert_eq!(bot.input(FrameNumber::new(5)), input);
+        assert_ne!(bot.input(FrameNumber::new(6)), input);
+    }
+
+    #[test]
+    fn test_random_walk() {
+        let mut bot = Bot::new(BotBehaviour::RandomWalk, 1);
+        let walk = inputs(&mut bot, RANDOM_WALK_SEGMENT_FRAMES * 8);
+
+        for segment in walk.chunks(RANDOM_WALK_SEGMENT_FRAMES as usize) {
+            let (direction, _) = segment[0];
+            assert!(direction == Vec2::ZERO || (direction.length() - 1.0).abs() < 0.0001);
+            assert!(segment.iter().all(|&(d, _)| d == direction));
+            // Jumps happen only at the first frame of a segment.
+            assert!(segment[1..].iter().all(|&(_, jump)| !jump));
+        }
+
+        // Walks are reproducible, but differ for different seeds.
+        assert_eq!(
+            inputs(
+                &mut Bot::new(BotBehaviour::RandomWalk, 1),
+                RANDOM_WALK_SEGMENT_FRAMES * 8
+            ),
+            walk
+        );
+        assert_ne!(
+            inputs(
+                &mut Bot::new(BotBehaviour::RandomWalk, 2),
+                RANDOM_WALK_SEGMENT_FRAMES * 8
+            ),
+            walk
+        );
+    }
+
+    #[test]
+    fn test_circle() {
+        let mut bot = Bot::new(BotBehaviour::Circle, 0);
+        let full_turn_frames = SIMULATIONS_PER_SECOND * 4;
+        let circle = inputs(&mut bot, full_turn_frames + 1);
+
+        for window in circle.windows(2) {
+            let ((direction, jump), (next_direction, _)) = (window[0], window[1]);
+            assert!(!jump);
+            assert!((direction.length() - 1.0).abs() < 0.0001);
+            // Turning counter-clockwise by the same angle every frame.
+            assert!(
+                (direction.perp_dot(next_direction) - CIRCLE_RADIANS_PER_FRAME.sin()).abs()
+                    < 0.0001
+            );
+            assert!(
+                (direction.dot(next_direction) - CIRCLE_RADIANS_PER_FRAME.cos()).abs() < 0.0001
+            );
+        }
+        assert!((circle[full_turn_frames as usize].0 - circle[0].0).length() < 0.001);
+    }
+
+    #[test]
+    fn test_replay() {
+        let forward = ReplayedInput {
+            frames: 3,
+            direction: Vec2::new(0.0, 1.0),
+            jump: true,
+        };
+        let left = ReplayedInput {
+            frames: 1,
+            direction: Vec2::new(-1.0, 0.0),
+            jump: false,
+        };
+        let mut bot = Bot::new(BotBehaviour::Replay(vec![forward, left]), 0);
+
+        let up = Vec2::new(0.0, 1.0);
+        let left = Vec2::new(-1.0, 0.0);
+        // Jumps happen at the first frame of a segment, the replay gets looped.
+        assert_eq!(
+            inputs(&mut bot, 8),
+            vec![
+                (up, true),
+                (up, false),
+                (up, false),
+                (left, false),
+                (up, true),
+                (up, false),
+                (up, false),
+                (left, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_replay() {
+        let mut bot = Bot::new(BotBehaviour::Replay(Vec::new()), 0);
+        assert!(inputs(&mut bot, RANDOM_WALK_SEGMENT_FRAMES + 1)
+            .iter()
+            .all(|&input| input == (Vec2::ZERO, false)));
+    }
+}
@@ -0,0 +1,305 @@
+use bevy::{ecs::system::SystemParam, log, prelude::*};
+use mr_shared_lib::{
+    framebuffer::FrameNumber,
+    game::{
+        commands::{DespawnLevelObject, GameCommands, SpawnLevelObject, UpdateLevelObject},
+        level::{LevelObject, LevelObjectDesc, LevelState},
+    },
+    messages::{
+        ActionNetId, ConfirmedAction, DespawnLevelObjectRequest, EntityNetId, Message,
+        ReliableClientMessage, SpawnLevelObjectRequest, UpdateLevelObjectRequest,
+    },
+    net::{ConnectionState, ConnectionStatus},
+    net_stats::NetworkStats,
+    transport::Transport,
+    GameTime, FIRST_TEMPORARY_ENTITY_NET_ID, SIMULATIONS_PER_SECOND,
+};
+use std::collections::HashMap;
+
+/// The server confirms edits as soon as it processes them, so if a confirmation doesn't arrive
+/// in 5 seconds, it must have got lost (i.e. with the packets that a full state resync skips).
+const PENDING_LEVEL_EDIT_TIMEOUT_FRAMES: u16 = SIMULATIONS_PER_SECOND * 5;
+
+pub enum LevelEdit {
+    Spawn(LevelObjectDesc),
+    Update(LevelObject),
+    Despawn(EntityNetId),
+    /// Undo and redo aren't applied optimistically, as only the server knows the edit history.
+    Undo,
+    Redo,
+}
+
+/// Edits issued by the builder tools. They get applied optimistically and sent to the server.
+#[derive(Default)]
+pub struct LevelEdits {
+    edits: Vec<LevelEdit>,
+}
+
+impl LevelEdits {
+    pub fn push(&mut self, edit: LevelEdit) {
+        self.edits.push(edit);
+    }
+}
+
+enum PendingLevelEdit {
+    Spawn { temporary_net_id: EntityNetId },
+    Update { previous_object: LevelObject },
+    Despawn { previous_object: LevelObject },
+}
+
+/// Edits that are applied optimistically but not yet confirmed by the server.
+pub struct PendingLevelEdits {
+    // Wraps around, skipping the ids of the edits that are still pending.
+    action_net_id_counter: ActionNetId,
+    // Until the server confirms a spawned object, we don't know its real net id. Temporary ids
+    // are allocated from the range that the server never uses (they wrap around within it).
+    temporary_net_id_counter: u16,
+    /// Pending edits along with the frames they were sent at.
+    edits: HashMap<ActionNetId, (FrameNumber, PendingLevelEdit)>,
+}
+
+impl Default for PendingLevelEdits {
+    fn default() -> Self {
+        Self {
+            action_net_id_counter: ActionNetId::default(),
+            temporary_net_id_counter: FIRST_TEMPORARY_ENTITY_NET_ID,
+            edits: HashMap::new(),
+        }
+    }
+}
+
+impl PendingLevelEdits {
+    /// Forgets about the pending edits. Is expected to be called when restarting the game or
+    /// resyncing with the server, as the level is going to be replaced anyway.
+    pub fn clear(&mut self) {
+        self.edits.clear();
+        self.temporary_net_id_counter = FIRST_TEMPORARY_ENTITY_NET_ID;
+    }
+
+    fn next_action_net_id(&mut self) -> ActionNetId {
+        loop {
+            let action_net_id = self.action_net_id_counter;
+            self.action_net_id_counter = ActionNetId(action_net_id.0.wrapping_add(1));
+            if !self.edits.contains_key(&action_net_id) {
+                return action_net_id;
+            }
+        }
+    }
+
+    fn next_temporary_net_id(&mut self) -> EntityNetId {
+        let net_id = EntityNetId(self.temporary_net_id_counter);
+        self.temporary_net_id_counter = self
+            .temporary_net_id_counter
+            .checked_add(1)
+            .unwrap_or(FIRST_TEMPORARY_ENTITY_NET_ID);
+        net_id
+    }
+}
+
+#[derive(SystemParam)]
+pub struct LevelObjectCommands<'a> {
+    time: Res<'a, GameTime>,
+    spawn_level_object_commands: ResMut<'a, GameCommands<SpawnLevelObject>>,
+    update_level_object_commands: ResMut<'a, GameCommands<UpdateLevelObject>>,
+    despawn_level_object_commands: ResMut<'a, GameCommands<DespawnLevelObject>>,
+}
+
+impl<'a> LevelObjectCommands<'a> {
+    fn spawn(&mut self, object: LevelObject) {
+        self.spawn_level_object_commands.push(SpawnLevelObject {
+            object,
+            frame_number: self.time.frame_number,
+        });
+    }
+
+    fn update(&mut self, object: LevelObject) {
+        self.update_level_object_commands.push(UpdateLevelObject {
+            object,
+            frame_number: self.time.frame_number,
+        });
+    }
+
+    fn despawn(&mut self, net_id: EntityNetId) {
+        self.despawn_level_object_commands.push(DespawnLevelObject {
+            net_id,
+            frame_number: self.time.frame_number,
+        });
+    }
+
+    fn roll_back(&mut self, pending_edit: PendingLevelEdit) {
+        match pending_edit {
+            PendingLevelEdit::Spawn { temporary_net_id } => self.despawn(temporary_net_id),
+            PendingLevelEdit::Update { previous_object } => self.update(previous_object),
+            PendingLevelEdit::Despawn { previous_object } => self.spawn(previous_object),
+        }
+    }
+}
+
+pub fn send_level_edits<T: Transport>(
+    mut net: ResMut<T>,
+    mut network_stats: ResMut<NetworkStats>,
+    connection_state: Res<ConnectionState>,
+    level_state: Res<LevelState>,
+    mut level_edits: ResMut<LevelEdits>,
+    mut pending_level_edits: ResMut<PendingLevelEdits>,
+    mut level_object_commands: LevelObjectCommands,
+) {
+    if level_edits.edits.is_empty() {
+        return;
+    }
+
+    let connection_handle = match net.connection_handles().first() {
+        Some(&handle) if matches!(connection_state.status(), ConnectionStatus::Connected) => handle,
+        _ => {
+            log::warn!("Discarding level edits: not connected to the server");
+            level_edits.edits.clear();
+            return;
+        }
+    };
+
+    for edit in level_edits.edits.drain(..) {
+        let action_id = pending_level_edits.next_action_net_id();
+        let (message, pending_edit) = match edit {
+            LevelEdit::Spawn(desc) => {
+                let temporary_net_id = pending_level_edits.next_temporary_net_id();
+                level_object_commands.spawn(LevelObject {
+                    net_id: temporary_net_id,
+                    desc: desc.clone(),
+                });
+                (
+                    ReliableClientMessage::SpawnLevelObject(SpawnLevelObjectRequest {
+                        action_id,
+                        desc,
+                    }),
+                    Some(PendingLevelEdit::Spawn { temporary_net_id }),
+                )
+            }
+            LevelEdit::Update(object) => {
+                let previous_object = match find_object(&level_state, object.net_id) {
+                    Some(previous_object) => previous_object,
+                    None => continue,
+                };
+                level_object_commands.update(object.clone());
+                (
+                    ReliableClientMessage::UpdateLevelObject(UpdateLevelObjectRequest {
+                        action_id,
+                        object,
+                    }),
+                    Some(PendingLevelEdit::Update { previous_object }),
+                )
+            }
+            LevelEdit::Despawn(net_id) => {
+                let previous_object = match find_object(&level_state, net_id) {
+                    Some(previous_object) => previous_object,
+                    None => continue,
+                };
+                level_object_commands.despawn(net_id);
+                (
+                    ReliableClientMessage::DespawnLevelObject(DespawnLevelObjectRequest {
+                        action_id,
+                        net_id,
+                    }),
+                    Some(PendingLevelEdit::Despawn { previous_object }),
+                )
+            }
+            LevelEdit::Undo => (ReliableClientMessage::UndoLevelEdit, None),
+            LevelEdit::Redo => (ReliableClientMessage::RedoLevelEdit, None),
+        };
+
+        let result = network_stats.send_message(
+            &mut *net,
+            connection_handle,
+            Message {
+                session_id: connection_state.session_id,
+                message,
+            },
+        );
+        if let Err(err) = result {
+            log::error!("Failed to send a level edit: {:?}", err);
+            if let Some(pending_edit) = pending_edit {
+                level_object_commands.roll_back(pending_edit);
+            }
+            continue;
+        }
+        if let Some(pending_edit) = pending_edit {
+            pending_level_edits.edits.insert(
+                action_id,
+                (level_object_commands.time.frame_number, pending_edit),
+            );
+        }
+    }
+}
+
+pub fn process_confirmed_actions(
+    mut confirmed_actions: ResMut<Vec<ConfirmedAction>>,
+    mut pending_level_edits: ResMut<PendingLevelEdits>,
+    mut level_object_commands: LevelObjectCommands,
+) {
+    for confirmed_action in confirmed_actions.drain(..) {
+        // The server repeats confirmations until it gets an acknowledgment, so we may receive
+        // the same confirmation more than once.
+        let pending_edit = match pending_level_edits.edits.remove(&confirmed_action.id) {
+            Some((_, pending_edit)) => pending_edit,
+            None => continue,
+        };
+
+        match (confirmed_action.confirmed_frame, pending_edit) {
+            (Some(frame_number), PendingLevelEdit::Spawn { temporary_net_id }) => {
+                // The actual object comes with a `SpawnLevelObject` message, which is sent
+                // together with the confirmation.
+                log::debug!(
+                    "Level edit {} is confirmed (frame: {})",
+                    confirmed_action.id.0,
+                    frame_number
+                );
+                level_object_commands.despawn(temporary_net_id);
+            }
+            (Some(frame_number), _) => {
+                log::debug!(
+                    "Level edit {} is confirmed (frame: {})",
+                    confirmed_action.id.0,
+                    frame_number
+                );
+            }
+            (None, pending_edit) => {
+                log::warn!(
+                    "Level edit {} is rejected by the server, rolling back",
+                    confirmed_action.id.0
+                );
+                level_object_commands.roll_back(pending_edit);
+            }
+        }
+    }
+
+    let frame_number = level_object_commands.time.frame_number;
+    let expired_edits = pending_level_edits
+        .edits
+        .iter()
+        .filter(|(_, (sent_at, _))| {
+            (frame_number - *sent_at).value() > PENDING_LEVEL_EDIT_TIMEOUT_FRAMES
+        })
+        .map(|(action_id, _)| *action_id)
+        .collect::<Vec<_>>();
+    for action_id in expired_edits {
+        let (_, pending_edit) = pending_level_edits.edits.remove(&action_id).unwrap();
+        log::warn!("Level edit {} confirmation is lost", action_id.0);
+        // The server sends the actual state of the level regardless of confirmations, so we
+        // don't roll back updates and despawns (they might have been applied). Temporary objects
+        // are never a part of that state though.
+        if let PendingLevelEdit::Spawn { temporary_net_id } = pending_edit {
+            level_object_commands.despawn(temporary_net_id);
+        }
+    }
+}
+
+fn find_object(level_state: &LevelState, net_id: EntityNetId) -> Option<LevelObject> {
+    let object = level_state
+        .objects
+        .iter()
+        .find(|object| object.net_id == net_id)
+        .cloned();
+    if object.is_none() {
+        log::warn!("Can't edit a non-existing object ({})", net_id.0);
+    }
+    object
+}
@@ -0,0 +1,600 @@
+use crate::{
+    builder::{LevelEdit, LevelEdits},
+    input::MouseRay,
+};
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_egui::EguiContext;
+use bevy_rapier3d::{
+    physics::ColliderHandleComponent,
+    rapier::{
+        geometry::{ColliderSet, InteractionGroups, Ray},
+        pipeline::QueryPipeline,
+    },
+};
+use mr_shared_lib::{
+    game::{
+        level::{LevelObject, LevelObjectDesc, LevelState},
+        level_objects::{
+            CuboidDesc, CylinderDesc, DeathZoneDesc, MovingPlatformDesc, RampDesc, RouteSensorDesc,
+            RouteSensorKind, SpawnPointDesc,
+        },
+        meshes,
+    },
+    messages::EntityNetId,
+    registry::EntityRegistry,
+    PLANE_SIZE, SIMULATIONS_PER_SECOND,
+};
+
+const ROTATION_STEP: f32 = std::f32::consts::PI / 12.0;
+const HANDLE_RADIUS: f32 = 0.2;
+const MOVE_HANDLE_OFFSET: f32 = 0.75;
+const MAX_OBJECT_SIZE: f32 = PLANE_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuilderTool {
+    Select,
+    Cuboid,
+    Ramp,
+    Cylinder,
+    Start,
+    Checkpoint,
+    Finish,
+    SpawnPoint,
+    DeathZone,
+    MovingPlatform,
+}
+
+impl BuilderTool {
+    pub const ALL: [BuilderTool; 10] = [
+        BuilderTool::Select,
+        BuilderTool::Cuboid,
+        BuilderTool::Ramp,
+        BuilderTool::Cylinder,
+        BuilderTool::Start,
+        BuilderTool::Checkpoint,
+        BuilderTool::Finish,
+        BuilderTool::SpawnPoint,
+        BuilderTool::DeathZone,
+        BuilderTool::MovingPlatform,
+    ];
+
+    /// Returns a description of the object placed by the tool, without a transform applied.
+    fn placed_object(self) -> Option<LevelObjectDesc> {
+        let position = Vec3::ZERO;
+        let rotation = Quat::identity();
+        match self {
+            Self::Select => None,
+            Self::Cuboid => Some(LevelObjectDesc::Cuboid(CuboidDesc {
+                position,
+                rotation,
+                size: Vec3::splat(1.0),
+            })),
+            Self::Ramp => Some(LevelObjectDesc::Ramp(RampDesc {
+                position,
+                rotation,
+                size: Vec3::new(1.0, 1.0, 2.0),
+            })),
+            Self::Cylinder => Some(LevelObjectDesc::Cylinder(CylinderDesc {
+                position,
+                rotation,
+                radius: 0.5,
+                height: 1.0,
+            })),
+            Self::Start => Some(route_sensor(RouteSensorKind::Start)),
+            Self::Checkpoint => Some(route_sensor(RouteSensorKind::Checkpoint)),
+            Self::Finish => Some(route_sensor(RouteSensorKind::Finish)),
+            Self::SpawnPoint => Some(LevelObjectDesc::SpawnPoint(SpawnPointDesc { position })),
+            Self::DeathZone => Some(LevelObjectDesc::DeathZone(DeathZoneDesc {
+                position,
+                rotation,
+                size: Vec3::new(2.0, 0.5, 2.0),
+            })),
+            // An elevator, the path can be changed in the builder window.
+            Self::MovingPlatform => Some(LevelObjectDesc::MovingPlatform(MovingPlatformDesc {
+                position,
+                rotation,
+                size: Vec3::new(2.0, 0.25, 2.0),
+                waypoints: vec![Vec3::new(0.0, 2.0, 0.0)],
+                period: SIMULATIONS_PER_SECOND * 4,
+                phase: 0,
+            })),
+        }
+    }
+}
+
+fn route_sensor(kind: RouteSensorKind) -> LevelObjectDesc {
+    LevelObjectDesc::RouteSensor(RouteSensorDesc {
+        kind,
+        position: Vec3::ZERO,
+        rotation: Quat::identity(),
+        size: Vec3::new(4.0, 2.0, 0.5),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DragHandle {
+    Move,
+    /// Contains the index of a local axis (X, Y, Z) of an object.
+    Resize(usize),
+}
+
+const DRAG_HANDLES: [DragHandle; 4] = [
+    DragHandle::Move,
+    DragHandle::Resize(0),
+    DragHandle::Resize(1),
+    DragHandle::Resize(2),
+];
+
+struct Drag {
+    handle: DragHandle,
+    grab_offset: Vec3,
+    initial_object: LevelObject,
+    object: LevelObject,
+}
+
+pub struct BuilderState {
+    pub is_enabled: bool,
+    pub tool: BuilderTool,
+    pub grid_size: f32,
+    pub selected_object: Option<EntityNetId>,
+    placement_rotation: f32,
+    drag: Option<Drag>,
+}
+
+impl Default for BuilderState {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            tool: BuilderTool::Select,
+            grid_size: 0.5,
+            selected_object: None,
+            placement_rotation: 0.0,
+            drag: None,
+        }
+    }
+}
+
+impl BuilderState {
+    pub fn select_tool(&mut self, tool: BuilderTool) {
+        self.tool = tool;
+        self.drag = None;
+        if tool != BuilderTool::Select {
+            self.selected_object = None;
+        }
+    }
+}
+
+/// The ghost preview and drag handles. Meshes are of a unit size and get scaled via transforms.
+pub struct BuilderEntities {
+    ghost: Entity,
+    handles: Vec<(DragHandle, Entity)>,
+    cuboid_mesh: Handle<Mesh>,
+    ramp_mesh: Handle<Mesh>,
+    cylinder_mesh: Handle<Mesh>,
+}
+
+impl BuilderEntities {
+    fn mesh(&self, desc: &LevelObjectDesc) -> Option<Handle<Mesh>> {
+        match desc {
+            LevelObjectDesc::Plane(_) => None,
+            LevelObjectDesc::Cuboid(_)
+            | LevelObjectDesc::RouteSensor(_)
+            | LevelObjectDesc::SpawnPoint(_)
+            | LevelObjectDesc::DeathZone(_)
+            | LevelObjectDesc::MovingPlatform(_) => Some(self.cuboid_mesh.clone()),
+            LevelObjectDesc::Ramp(_) => Some(self.ramp_mesh.clone()),
+            LevelObjectDesc::Cylinder(_) => Some(self.cylinder_mesh.clone()),
+        }
+    }
+}
+
+pub fn spawn_builder_entities(
+    mut commands: Commands,
+    mut mesh_assets: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let cuboid_mesh = mesh_assets.add(Mesh::from(shape::Box::new(1.0, 1.0, 1.0)));
+    let ramp_mesh = mesh_assets.add(meshes::ramp(&RampDesc {
+        position: Vec3::ZERO,
+        rotation: Quat::identity(),
+        size: Vec3::splat(1.0),
+    }));
+    let cylinder_mesh = mesh_assets.add(meshes::cylinder(0.5, 1.0));
+
+    let ghost = commands
+        .spawn_bundle(PbrBundle {
+            mesh: cuboid_mesh.clone(),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgba(0.3, 0.6, 1.0, 0.4),
+                ..Default::default()
+            }),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .id();
+
+    let handle_mesh = mesh_assets.add(Mesh::from(shape::Icosphere {
+        radius: HANDLE_RADIUS,
+        subdivisions: 2,
+    }));
+    let handles = DRAG_HANDLES
+        .iter()
+        .map(|&handle| {
+            let color = match handle {
+                DragHandle::Move => Color::YELLOW,
+                DragHandle::Resize(0) => Color::RED,
+                DragHandle::Resize(1) => Color::GREEN,
+                DragHandle::Resize(_) => Color::BLUE,
+            };
+            let entity = commands
+                .spawn_bundle(PbrBundle {
+                    mesh: handle_mesh.clone(),
+                    material: materials.add(color.into()),
+                    visible: Visible {
+                        is_visible: false,
+                        is_transparent: false,
+                    },
+                    ..Default::default()
+                })
+                .id();
+            (handle, entity)
+        })
+        .collect();
+
+    commands.insert_resource(BuilderEntities {
+        ghost,
+        handles,
+        cuboid_mesh,
+        ramp_mesh,
+        cylinder_mesh,
+    });
+}
+
+#[derive(SystemParam)]
+pub struct PickingParams<'a> {
+    query_pipeline: Res<'a, QueryPipeline>,
+    collider_set: Res<'a, ColliderSet>,
+    colliders: Query<'a, (Entity, &'static ColliderHandleComponent)>,
+    object_entities: Res<'a, EntityRegistry<EntityNetId>>,
+    level_state: Res<'a, LevelState>,
+}
+
+impl<'a> PickingParams<'a> {
+    fn pick_object(&self, ray: &Ray) -> Option<EntityNetId> {
+        let (collider, _) = self.query_pipeline.cast_ray(
+            &self.collider_set,
+            ray,
+            f32::MAX,
+            true,
+            InteractionGroups::all(),
+            None,
+        )?;
+        let (entity, _) = self
+            .colliders
+            .iter()
+            .find(|(_, collider_component)| collider_component.handle() == collider)?;
+        let net_id = self.object_entities.get_id(entity)?;
+        // The ground plane can't be edited.
+        let is_plane = self.level_state.objects.iter().any(|object| {
+            object.net_id == net_id && matches!(object.desc, LevelObjectDesc::Plane(_))
+        });
+        if is_plane {
+            None
+        } else {
+            Some(net_id)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn process_builder_input(
+    // ResMut is intentional, to avoid fighting over the Mutex from different systems.
+    egui_context: ResMut<EguiContext>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    mouse_ray: Res<MouseRay>,
+    level_state: Res<LevelState>,
+    picking_params: PickingParams,
+    mut builder_state: ResMut<BuilderState>,
+    mut level_edits: ResMut<LevelEdits>,
+) {
+    if keyboard_input.just_pressed(KeyCode::B) {
+        builder_state.is_enabled = !builder_state.is_enabled;
+        builder_state.select_tool(BuilderTool::Select);
+        builder_state.selected_object = None;
+    }
+    if !builder_state.is_enabled {
+        return;
+    }
+
+    let ray = ray_vectors(&mouse_ray);
+    let is_ctrl_pressed =
+        keyboard_input.pressed(KeyCode::LControl) || keyboard_input.pressed(KeyCode::RControl);
+    let is_shift_pressed =
+        keyboard_input.pressed(KeyCode::LShift) || keyboard_input.pressed(KeyCode::RShift);
+
+    if is_ctrl_pressed && keyboard_input.just_pressed(KeyCode::Z) {
+        level_edits.push(if is_shift_pressed {
+            LevelEdit::Redo
+        } else {
+            LevelEdit::Undo
+        });
+    }
+    if is_ctrl_pressed && keyboard_input.just_pressed(KeyCode::Y) {
+        level_edits.push(LevelEdit::Redo);
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        builder_state.select_tool(BuilderTool::Select);
+        builder_state.selected_object = None;
+    }
+
+    let selected_object = builder_state.selected_object.and_then(|net_id| {
+        level_state
+            .objects
+            .iter()
+            .find(|object| object.net_id == net_id)
+            .cloned()
+    });
+    if selected_object.is_none() {
+        // The object might have been despawned by another builder.
+        builder_state.selected_object = None;
+        builder_state.drag = None;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::R) && builder_state.drag.is_none() {
+        match &selected_object {
+            Some(object) => {
+                let mut object = object.clone();
+                if let Some((position, rotation)) = object.desc.transform() {
+                    object
+                        .desc
+                        .set_transform(position, Quat::from_rotation_y(ROTATION_STEP) * rotation);
+                    level_edits.push(LevelEdit::Update(object));
+                }
+            }
+            None => builder_state.placement_rotation += ROTATION_STEP,
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::Delete) {
+        if let Some(object) = &selected_object {
+            level_edits.push(LevelEdit::Despawn(object.net_id));
+            builder_state.selected_object = None;
+            builder_state.drag = None;
+        }
+    }
+
+    let grid_size = builder_state.grid_size;
+    if let Some(drag) = &mut builder_state.drag {
+        if mouse_input.pressed(MouseButton::Left) {
+            if let Some(desc) = dragged_desc(drag, ray, grid_size) {
+                drag.object.desc = desc;
+            }
+            return;
+        }
+        if drag.object != drag.initial_object {
+            level_edits.push(LevelEdit::Update(drag.object.clone()));
+        }
+        builder_state.drag = None;
+        return;
+    }
+
+    if !mouse_input.just_pressed(MouseButton::Left) || egui_context.ctx().is_pointer_over_area() {
+        return;
+    }
+
+    if let Some(desc) = placement_desc(&builder_state, ray) {
+        level_edits.push(LevelEdit::Spawn(desc));
+        return;
+    }
+
+    if let Some(object) = selected_object {
+        if let Some(handle) = hovered_handle(&object.desc, ray) {
+            let grab_offset = match (handle, object.desc.transform()) {
+                (DragHandle::Move, Some((position, _))) => {
+                    intersect_horizontal_plane(ray, position.y)
+                        .map_or(Vec3::ZERO, |hit| position - hit)
+                }
+                _ => Vec3::ZERO,
+            };
+            builder_state.drag = Some(Drag {
+                handle,
+                grab_offset,
+                initial_object: object.clone(),
+                object,
+            });
+            return;
+        }
+    }
+    builder_state.selected_object = picking_params.pick_object(&mouse_ray.0);
+}
+
+pub fn update_builder_entities(
+    builder_state: Res<BuilderState>,
+    builder_entities: Res<BuilderEntities>,
+    mouse_ray: Res<MouseRay>,
+    level_state: Res<LevelState>,
+    mut entities: Query<(&mut Transform, &mut Visible, &mut Handle<Mesh>)>,
+) {
+    let ray = ray_vectors(&mouse_ray);
+    let (ghost_desc, selected_desc) = if builder_state.is_enabled {
+        match &builder_state.drag {
+            Some(drag) => (
+                Some(drag.object.desc.clone()),
+                Some(drag.object.desc.clone()),
+            ),
+            None => (
+                placement_desc(&builder_state, ray),
+                builder_state.selected_object.and_then(|net_id| {
+                    level_state
+                        .objects
+                        .iter()
+                        .find(|object| object.net_id == net_id)
+                        .map(|object| object.desc.clone())
+                }),
+            ),
+        }
+    } else {
+        (None, None)
+    };
+
+    if let Ok((mut transform, mut visible, mut mesh)) = entities.get_mut(builder_entities.ghost) {
+        let ghost = ghost_desc.and_then(|desc| {
+            let ((position, rotation), size) = desc.transform().zip(desc.size())?;
+            Some((builder_entities.mesh(&desc)?, position, rotation, size))
+        });
+        visible.is_visible = ghost.is_some();
+        if let Some((ghost_mesh, position, rotation, size)) = ghost {
+            *mesh = ghost_mesh;
+            *transform = Transform {
+                translation: position,
+                rotation,
+                scale: size,
+            };
+        }
+    }
+
+    let handle_positions = selected_desc.map_or_else(Vec::new, |desc| handle_positions(&desc));
+    for (handle, entity) in &builder_entities.handles {
+        if let Ok((mut transform, mut visible, _)) = entities.get_mut(*entity) {
+            let position = handle_positions
+                .iter()
+                .find(|(position_handle, _)| position_handle == handle)
+                .map(|(_, position)| *position);
+            visible.is_visible = position.is_some();
+            if let Some(position) = position {
+                transform.translation = position;
+            }
+        }
+    }
+}
+
+fn ray_vectors(mouse_ray: &MouseRay) -> (Vec3, Vec3) {
+    let origin = mouse_ray.0.origin;
+    let direction = mouse_ray.0.dir;
+    (
+        Vec3::new(origin.x, origin.y, origin.z),
+        Vec3::new(direction.x, direction.y, direction.z),
+    )
+}
+
+fn snap(value: f32, grid_size: f32) -> f32 {
+    (value / grid_size).round() * grid_size
+}
+
+fn intersect_horizontal_plane((origin, direction): (Vec3, Vec3), height: f32) -> Option<Vec3> {
+    if direction.y.abs() < f32::EPSILON {
+        return None;
+    }
+    let t = (height - origin.y) / direction.y;
+    if t < 0.0 {
+        return None;
+    }
+    Some(origin + direction * t)
+}
+
+/// Returns the parameter of a point on the line (defined by `point` and a unit `direction`)
+/// that is the closest to the ray.
+fn closest_line_parameter(
+    point: Vec3,
+    direction: Vec3,
+    (ray_origin, ray_direction): (Vec3, Vec3),
+) -> Option<f32> {
+    let w = point - ray_origin;
+    let b = direction.dot(ray_direction);
+    let denominator = 1.0 - b * b;
+    if denominator.abs() < f32::EPSILON {
+        return None;
+    }
+    Some((b * ray_direction.dot(w) - direction.dot(w)) / denominator)
+}
+
+/// Puts an object of the selected tool on the ground, where the mouse ray hits it.
+fn placement_desc(builder_state: &BuilderState, ray: (Vec3, Vec3)) -> Option<LevelObjectDesc> {
+    let mut desc = builder_state.tool.placed_object()?;
+    let size = desc.size()?;
+    let hit = intersect_horizontal_plane(ray, 0.0)?;
+    desc.set_transform(
+        Vec3::new(
+            snap(hit.x, builder_state.grid_size),
+            size.y / 2.0,
+            snap(hit.z, builder_state.grid_size),
+        ),
+        Quat::from_rotation_y(builder_state.placement_rotation),
+    );
+    Some(desc)
+}
+
+fn handle_positions(desc: &LevelObjectDesc) -> Vec<(DragHandle, Vec3)> {
+    let ((position, rotation), size) = match desc.transform().zip(desc.size()) {
+        Some(transform_and_size) => transform_and_size,
+        None => return Vec::new(),
+    };
+    let half_size = size / 2.0;
+    vec![
+        (
+            DragHandle::Move,
+            position + rotation * Vec3::new(0.0, half_size.y + MOVE_HANDLE_OFFSET, 0.0),
+        ),
+        (
+            DragHandle::Resize(0),
+            position + rotation * Vec3::new(half_size.x, 0.0, 0.0),
+        ),
+        (
+            DragHandle::Resize(1),
+            position + rotation * Vec3::new(0.0, half_size.y, 0.0),
+        ),
+        (
+            DragHandle::Resize(2),
+            position + rotation * Vec3::new(0.0, 0.0, half_size.z),
+        ),
+    ]
+}
+
+fn hovered_handle(desc: &LevelObjectDesc, (origin, direction): (Vec3, Vec3)) -> Option<DragHandle> {
+    handle_positions(desc)
+        .into_iter()
+        .filter_map(|(handle, center)| {
+            let t = (center - origin).dot(direction);
+            let is_hovered = t >= 0.0 && (origin + direction * t).distance(center) <= HANDLE_RADIUS;
+            if is_hovered {
+                Some((handle, t))
+            } else {
+                None
+            }
+        })
+        .min_by(|(_, lhs), (_, rhs)| lhs.partial_cmp(rhs).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(handle, _)| handle)
+}
+
+fn dragged_desc(drag: &Drag, ray: (Vec3, Vec3), grid_size: f32) -> Option<LevelObjectDesc> {
+    let mut desc = drag.initial_object.desc.clone();
+    let ((position, rotation), size) = desc.transform().zip(desc.size())?;
+    match drag.handle {
+        DragHandle::Move => {
+            let hit = intersect_horizontal_plane(ray, position.y)? + drag.grab_offset;
+            desc.set_transform(
+                Vec3::new(snap(hit.x, grid_size), position.y, snap(hit.z, grid_size)),
+                rotation,
+            );
+        }
+        DragHandle::Resize(axis) => {
+            // The opposite face of the object stays where it is.
+            let local_axis = [Vec3::X, Vec3::Y, Vec3::Z][axis];
+            let axis_direction = rotation * local_axis;
+            let length = size.dot(local_axis);
+            let anchor = position - axis_direction * length / 2.0;
+            let new_length = snap(
+                closest_line_parameter(anchor, axis_direction, ray)?,
+                grid_size,
+            )
+            .max(grid_size)
+            .min(MAX_OBJECT_SIZE);
+            desc.set_size(size + local_axis * (new_length - length));
+            desc.set_transform(anchor + axis_direction * new_length / 2.0, rotation);
+        }
+    }
+    Some(desc)
+}
@@ -0,0 +1,87 @@
+use crate::FullStateRequest;
+use bevy::{
+    ecs::system::{Query, Res, ResMut},
+    log,
+};
+use mr_shared_lib::{
+    codec::{position_checksum, position_checksums_match},
+    game::components::Position,
+    messages::{PlayerNetId, PositionsChecksum},
+    net::{ConnectionState, ConnectionStatus},
+    registry::EntityRegistry,
+    SimulationTime,
+};
+
+/// Compares the position checksums that the server sends with our own simulation.
+#[derive(Default)]
+pub struct DesyncDetection {
+    /// Checksums for the frames that we haven't simulated yet.
+    pending: Vec<PositionsChecksum>,
+    /// The number of checksums that didn't match.
+    pub desyncs: u32,
+    /// Makes the client request the full game state on a desync.
+    pub resync_on_desync: bool,
+}
+
+impl DesyncDetection {
+    pub fn push(&mut self, checksum: PositionsChecksum) {
+        self.pending.push(checksum);
+    }
+
+    /// Checksums of a previous game can't be compared with the current one.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+pub fn detect_desyncs(
+    mut desync_detection: ResMut<DesyncDetection>,
+    connection_state: Res<ConnectionState>,
+    mut full_state_request: ResMut<FullStateRequest>,
+    simulation_time: Res<SimulationTime>,
+    player_registry: Res<EntityRegistry<PlayerNetId>>,
+    positions: Query<&Position>,
+) {
+    let (simulated_checksums, pending): (Vec<_>, Vec<_>) =
+        std::mem::take(&mut desync_detection.pending)
+            .into_iter()
+            .partition(|checksum| checksum.frame_number < simulation_time.server_frame);
+    desync_detection.pending = pending;
+
+    for checksum in simulated_checksums {
+        let mut has_desynced = false;
+        for &(net_id, server_checksum) in &checksum.players {
+            // Players that we haven't spawned (or have already despawned) don't count.
+            let position = match player_registry
+                .get_entity(net_id)
+                .and_then(|entity| positions.get(entity).ok())
+                .and_then(|position| position.buffer.get(checksum.frame_number))
+            {
+                Some(position) => *position,
+                None => continue,
+            };
+            if !position_checksums_match(position_checksum(position), server_checksum) {
+                log::warn!(
+                    "Player {} position has desynced (frame: {}, our position: {:?})",
+                    net_id.0,
+                    checksum.frame_number,
+                    position
+                );
+                has_desynced = true;
+            }
+        }
+        if !has_desynced {
+            continue;
+        }
+
+        desync_detection.desyncs += 1;
+        if desync_detection.resync_on_desync
+            && matches!(connection_state.status(), ConnectionStatus::Connected)
+        {
+            log::warn!("Requesting the full game state to resync with the server");
+            full_state_request.is_requested = true;
+            desync_detection.clear();
+            return;
+        }
+    }
+}
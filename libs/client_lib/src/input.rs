@@ -8,6 +8,7 @@ use bevy::{
 };
 use bevy_rapier3d::{na, rapier::geometry::Ray};
 use mr_shared_lib::{
+    codec::quantize_direction,
     player::{PlayerDirectionUpdate, PlayerUpdates},
     GameTime, COMPONENT_FRAMEBUFFER_LIMIT,
 };
@@ -67,10 +68,13 @@ pub fn track_input_events(
         if keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down) {
             direction.y -= 1.0;
         }
+        // Jumps aren't repeated while the key is held, a player needs to press it again.
+        let jump = keyboard_input.just_pressed(KeyCode::Space);
         direction_updates.insert(
             time.frame_number,
             Some(PlayerDirectionUpdate {
-                direction,
+                direction: quantize_direction(direction),
+                jump,
                 is_processed_client_input: Some(false),
             }),
         );
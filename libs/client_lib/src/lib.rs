@@ -1,10 +1,13 @@
 use crate::{
+    builder::{LevelEdits, PendingLevelEdits},
+    desync::{detect_desyncs, DesyncDetection},
     input::MouseRay,
     net::{maintain_connection, process_network_events, send_network_updates},
     ui::debug_ui::update_debug_ui_state,
 };
 use bevy::{
     app::{AppBuilder, Plugin},
+    asset::{AddAsset, AssetPlugin},
     core::Time,
     diagnostic::FrameTimeDiagnosticsPlugin,
     ecs::{
@@ -18,24 +21,35 @@ use bevy::{
     },
     log,
     math::Vec3,
-    pbr::{Light, LightBundle},
-    render::entity::PerspectiveCameraBundle,
+    pbr::{Light, LightBundle, StandardMaterial},
+    render::{entity::PerspectiveCameraBundle, mesh::Mesh},
     transform::components::Transform,
 };
 use bevy_egui::EguiPlugin;
+use bevy_networking_turbulence::NetworkResource;
 use chrono::{DateTime, Utc};
 use mr_shared_lib::{
+    codec::SnapshotHistory,
     framebuffer::FrameNumber,
-    messages::PlayerNetId,
+    link_conditioner::{LinkConditioner, LinkConditionerSettings},
+    messages::{ConfirmedAction, ConnectionRejected, DisconnectReason, PlayerNetId, SessionToken},
     net::{ConnectionState, ConnectionStatus},
-    GameState, GameTime, MuddleSharedPlugin, SimulationTime, COMPONENT_FRAMEBUFFER_LIMIT,
-    SIMULATIONS_PER_SECOND,
+    net_stats::NetworkStats,
+    transport::Transport,
+    GameState, GameTime, MuddleSharedPlugin, SimulationRole, SimulationTime,
+    COMPONENT_FRAMEBUFFER_LIMIT, SIMULATIONS_PER_SECOND,
 };
-use std::borrow::Cow;
+use std::{borrow::Cow, marker::PhantomData};
 
+pub mod bots;
+pub mod builder;
+
+mod builder_tools;
+mod desync;
 mod helpers;
 mod input;
 mod net;
+mod race;
 mod ui;
 
 const TICKING_SPEED_FACTOR: u16 = 10;
@@ -47,15 +61,16 @@ impl Plugin for MuddleClientPlugin {
         let input_stage = SystemStage::single_threaded()
             // Processing network events should happen before tracking input
             // because we reset current's player inputs on each delta update.
-            .with_system(maintain_connection.system())
-            .with_system(process_network_events.system())
+            .with_system(maintain_connection::<NetworkResource>.system())
+            .with_system(process_network_events::<NetworkResource>.system())
+            .with_system(builder::process_confirmed_actions.system())
             .with_system(input::track_input_events.system())
-            .with_system(input::cast_mouse_ray.system());
-        let broadcast_updates_stage =
-            SystemStage::parallel().with_system(send_network_updates.system());
+            .with_system(input::cast_mouse_ray.system())
+            .with_system(builder::send_level_edits::<NetworkResource>.system());
         let post_tick_stage = SystemStage::single_threaded()
             .with_system(pause_simulation.system())
             .with_system(control_ticking_speed.system())
+            .with_system(detect_desyncs.system())
             .with_system(update_debug_ui_state.system());
 
         builder
@@ -64,36 +79,147 @@ impl Plugin for MuddleClientPlugin {
             .init_resource::<WindowInnerSize>()
             .init_resource::<input::MousePosition>()
             // Startup systems.
-            .add_startup_system(init_state.system())
             .add_startup_system(basic_scene.system())
-            // Game.
-            .add_plugin(MuddleSharedPlugin::new(
-                NetAdaptiveTimestemp::default(),
-                input_stage,
-                broadcast_updates_stage,
-                post_tick_stage,
-                None,
-            ))
+            .add_startup_system(builder_tools::spawn_builder_entities.system());
+        // Game.
+        build_client::<NetworkResource>(
+            builder,
+            NetAdaptiveTimestemp::default(),
+            input_stage,
+            post_tick_stage,
+        );
+        builder
             // Egui.
             .add_system(ui::debug_ui::update_ui_scale_factor.system())
             .add_system(ui::debug_ui::debug_ui.system())
             .add_system(ui::overlay_ui::connection_status_overlay.system())
-            .add_system(ui::debug_ui::inspect_object.system());
+            .add_system(ui::debug_ui::inspect_object.system())
+            .add_system(ui::builder_ui::builder_ui.system())
+            .add_system(ui::race_ui::race_ui.system())
+            // Builder tools.
+            .add_system(builder_tools::process_builder_input.system())
+            .add_system(builder_tools::update_builder_entities.system());
 
         let world = builder.world_mut();
-        world.get_resource_or_insert_with(InitialRtt::default);
-        world.get_resource_or_insert_with(EstimatedServerTime::default);
-        world.get_resource_or_insert_with(GameTicksPerSecond::default);
-        world.get_resource_or_insert_with(TargetFramesAhead::default);
-        world.get_resource_or_insert_with(PlayerDelay::default);
-        world.get_resource_or_insert_with(AdjustedSpeedReason::default);
         world.get_resource_or_insert_with(ui::debug_ui::DebugUiState::default);
-        world.get_resource_or_insert_with(CurrentPlayerNetId::default);
-        world.get_resource_or_insert_with(ConnectionState::default);
         world.get_resource_or_insert_with(MouseRay::default);
+        world.get_resource_or_insert_with(builder_tools::BuilderState::default);
+    }
+}
+
+/// Runs the client's networking and prediction without rendering, windows or UI. Inputs are
+/// driven by a `Bot`, which is expected to be inserted as a resource before adding the plugin.
+///
+/// It doesn't add `LogPlugin`, as it can be initialized only once per process. Task pools that
+/// are inserted as resources before adding the plugin get reused, which lets a process run many
+/// headless clients.
+///
+/// A transport other than `NetworkResource` is expected to be inserted as a resource before adding
+/// the plugin (see `LoopbackHub::client`).
+pub struct MuddleHeadlessClientPlugin<T: Transport = NetworkResource> {
+    is_stepped: bool,
+    _transport: PhantomData<T>,
+}
+
+impl<T: Transport> Default for MuddleHeadlessClientPlugin<T> {
+    fn default() -> Self {
+        Self {
+            is_stepped: false,
+            _transport: PhantomData,
+        }
+    }
+}
+
+impl<T: Transport> MuddleHeadlessClientPlugin<T> {
+    /// Ticks once per `App::update` instead of following the real time (unless the ticking speed
+    /// is adjusted), which lets tests step a client along with `MuddleLoopbackServerPlugin`.
+    pub fn stepped() -> Self {
+        Self {
+            is_stepped: true,
+            _transport: PhantomData,
+        }
     }
 }
 
+impl<T: Transport> Plugin for MuddleHeadlessClientPlugin<T> {
+    fn build(&self, builder: &mut AppBuilder) {
+        builder.add_plugin(bevy::core::CorePlugin::default());
+        builder.add_plugin(bevy::transform::TransformPlugin::default());
+        // Spawned entities still get meshes and materials, even though they never get rendered.
+        builder.add_plugin(AssetPlugin::default());
+        builder.add_asset::<Mesh>();
+        builder.add_asset::<StandardMaterial>();
+
+        let input_stage = SystemStage::single_threaded()
+            .with_system(maintain_connection::<T>.system())
+            .with_system(process_network_events::<T>.system())
+            .with_system(builder::process_confirmed_actions.system())
+            .with_system(bots::drive_bot_input.system());
+        let post_tick_stage = SystemStage::single_threaded()
+            .with_system(pause_simulation.system())
+            .with_system(control_ticking_speed.system())
+            .with_system(detect_desyncs.system());
+        let main_run_criteria = if self.is_stepped {
+            NetAdaptiveTimestemp::stepped()
+        } else {
+            NetAdaptiveTimestemp::default()
+        };
+        build_client::<T>(builder, main_run_criteria, input_stage, post_tick_stage);
+
+        // There's no one to notice desyncs, so bots recover from them on their own.
+        builder
+            .world_mut()
+            .get_resource_mut::<DesyncDetection>()
+            .unwrap()
+            .resync_on_desync = true;
+    }
+}
+
+fn build_client<T: Transport>(
+    builder: &mut AppBuilder,
+    main_run_criteria: NetAdaptiveTimestemp,
+    input_stage: SystemStage,
+    post_tick_stage: SystemStage,
+) {
+    T::build(builder);
+
+    let broadcast_updates_stage =
+        SystemStage::parallel().with_system(send_network_updates::<T>.system());
+
+    builder
+        .add_startup_system(init_state.system())
+        .add_plugin(MuddleSharedPlugin::new(
+            SimulationRole::Client,
+            main_run_criteria,
+            input_stage,
+            broadcast_updates_stage,
+            post_tick_stage,
+            None,
+        ));
+
+    let world = builder.world_mut();
+    world.get_resource_or_insert_with(InitialRtt::default);
+    world.get_resource_or_insert_with(EstimatedServerTime::default);
+    world.get_resource_or_insert_with(GameTicksPerSecond::default);
+    world.get_resource_or_insert_with(TargetFramesAhead::default);
+    world.get_resource_or_insert_with(PlayerDelay::default);
+    world.get_resource_or_insert_with(AdjustedSpeedReason::default);
+    world.get_resource_or_insert_with(CurrentPlayerNetId::default);
+    world.get_resource_or_insert_with(ServerRejection::default);
+    world.get_resource_or_insert_with(ServerDisconnectReason::default);
+    world.get_resource_or_insert_with(ResumableSession::default);
+    world.get_resource_or_insert_with(FullStateRequest::default);
+    world.get_resource_or_insert_with(SnapshotHistory::default);
+    world.get_resource_or_insert_with(DesyncDetection::default);
+    world.get_resource_or_insert_with(|| LinkConditioner::new(LinkConditionerSettings::from_env()));
+    world.get_resource_or_insert_with(ConnectionState::default);
+    world.get_resource_or_insert_with(NetworkStats::default);
+    world.get_resource_or_insert_with(LevelEdits::default);
+    world.get_resource_or_insert_with(PendingLevelEdits::default);
+    world.get_resource_or_insert_with(race::RaceTimes::default);
+    world.get_resource_or_insert_with(Vec::<ConfirmedAction>::default);
+}
+
 // Resources.
 #[derive(Default)]
 pub struct WindowInnerSize {
@@ -167,6 +293,29 @@ pub struct CurrentPlayerNetId(pub Option<PlayerNetId>);
 
 pub struct MainCameraEntity(pub Entity);
 
+/// Is set if the server rejected our `Connect` message. Retrying to connect is pointless in this
+/// case, so the client stays disconnected.
+#[derive(Default)]
+pub struct ServerRejection(pub Option<ConnectionRejected>);
+
+/// The reason of the latest disconnect initiated by the server. Is reset on starting a new game.
+#[derive(Default)]
+pub struct ServerDisconnectReason(pub Option<DisconnectReason>);
+
+/// A token of the latest session, is sent with handshakes to get the same player back after
+/// reconnecting.
+#[derive(Default)]
+pub struct ResumableSession(pub Option<SessionToken>);
+
+/// Lets the client recover from desyncs or falling behind without reconnecting (see
+/// `ReliableClientMessage::RequestFullState`). Is reset on receiving `FullState`.
+#[derive(Default)]
+pub struct FullStateRequest {
+    /// The request gets sent by `maintain_connection`.
+    pub is_requested: bool,
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
 fn init_state(mut game_state: ResMut<State<GameState>>) {
     log::info!("Pausing the game");
     game_state.push(GameState::Paused).unwrap();
@@ -385,16 +534,44 @@ impl Default for NetAdaptiveTimestemp {
 }
 
 impl NetAdaptiveTimestemp {
+    /// Accumulates the time of a single frame (at the default rate) per `App::update`, instead of
+    /// the real time.
+    pub fn stepped() -> Self {
+        Self {
+            state: NetAdaptiveTimestempState::default(),
+            internal_system: Box::new(Self::prepare_stepped_system.system()),
+        }
+    }
+
     fn prepare_system(
         mut state: Local<NetAdaptiveTimestempState>,
         time: Res<Time>,
         game_ticks_per_second: Res<GameTicksPerSecond>,
+    ) -> ShouldRun {
+        Self::advance(&mut state, time.delta_seconds_f64(), &game_ticks_per_second)
+    }
+
+    fn prepare_stepped_system(
+        mut state: Local<NetAdaptiveTimestempState>,
+        game_ticks_per_second: Res<GameTicksPerSecond>,
+    ) -> ShouldRun {
+        Self::advance(
+            &mut state,
+            1.0 / SIMULATIONS_PER_SECOND as f64,
+            &game_ticks_per_second,
+        )
+    }
+
+    fn advance(
+        state: &mut NetAdaptiveTimestempState,
+        delta_seconds: f64,
+        game_ticks_per_second: &GameTicksPerSecond,
     ) -> ShouldRun {
         let rate = game_ticks_per_second.rate;
         let step = 1.0 / rate as f64;
 
         if !state.looping {
-            state.accumulator += time.delta_seconds_f64();
+            state.accumulator += delta_seconds;
         }
 
         if state.accumulator >= step {
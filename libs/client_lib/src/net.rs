@@ -1,62 +1,94 @@
-use crate::{CurrentPlayerNetId, EstimatedServerTime, InitialRtt, PlayerDelay, TargetFramesAhead};
+use crate::{
+    builder::PendingLevelEdits, desync::DesyncDetection, race::RaceTimes, CurrentPlayerNetId,
+    EstimatedServerTime, FullStateRequest, InitialRtt, PlayerDelay, ResumableSession,
+    ServerDisconnectReason, ServerRejection, TargetFramesAhead,
+};
 use bevy::{ecs::system::SystemParam, log, prelude::*};
-use bevy_networking_turbulence::{NetworkEvent, NetworkResource};
+use bevy_networking_turbulence::NetworkEvent;
 use chrono::Utc;
 use mr_shared_lib::{
+    codec::{decode_delta_update, encode_player_update, SnapshotHistory},
     framebuffer::FrameNumber,
     game::{
         commands::{
             DespawnLevelObject, DespawnPlayer, GameCommands, RestartGame, SpawnLevelObject,
-            SpawnPlayer,
+            SpawnPlayer, UpdateLevelObject,
         },
         components::PlayerDirection,
+        level::LevelState,
     },
+    link_conditioner::LinkConditioner,
     messages::{
-        ConnectedPlayer, DeltaUpdate, DisconnectedPlayer, Message, PlayerInput, PlayerNetId,
-        PlayerUpdate, ReliableClientMessage, ReliableServerMessage, StartGame,
-        UnreliableClientMessage, UnreliableServerMessage,
+        ClientHandshake, ConfirmedAction, ConnectRequest, ConnectedPlayer, DeltaUpdate,
+        DisconnectReason, DisconnectedPlayer, EntityNetId, FullState, Message, PackedPlayerUpdate,
+        PlayerInput, PlayerNetId, PlayerUpdate, ReliableClientMessage, ReliableServerMessage,
+        StartGame, UnreliableClientMessage, UnreliableServerMessage,
     },
     net::{
-        AcknowledgeError, ConnectionState, ConnectionStatus, MessageId, SessionId,
-        CONNECTION_TIMEOUT_MILLIS,
+        schema_hash, AcknowledgeError, ConnectionState, ConnectionStatus, MessageId, SessionId,
+        CONNECTION_TIMEOUT_MILLIS, PROTOCOL_VERSION,
     },
-    player::{Player, PlayerDirectionUpdate, PlayerUpdates},
+    net_stats::NetworkStats,
+    player::{Player, PlayerDirectionUpdate, PlayerUpdates, PositionUpdate},
     registry::EntityRegistry,
+    transport::Transport,
     GameTime, SimulationTime, COMPONENT_FRAMEBUFFER_LIMIT, SIMULATIONS_PER_SECOND,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
 };
 
 const DEFAULT_SERVER_PORT: u16 = 3455;
 const DEFAULT_SERVER_IP_ADDR: &str = "127.0.0.1";
+/// The server delays repeated full state requests (for a second at most), so we are generous
+/// here before giving up and reconnecting.
+const FULL_STATE_TIMEOUT_MILLIS: u64 = 5000;
 
 #[derive(SystemParam)]
 pub struct UpdateParams<'a> {
     simulation_time: ResMut<'a, SimulationTime>,
     game_time: ResMut<'a, GameTime>,
     player_entities: Res<'a, EntityRegistry<PlayerNetId>>,
+    level_state: Res<'a, LevelState>,
     estimated_server_time: ResMut<'a, EstimatedServerTime>,
     target_frames_ahead: ResMut<'a, TargetFramesAhead>,
     player_delay: ResMut<'a, PlayerDelay>,
     initial_rtt: ResMut<'a, InitialRtt>,
     player_updates: ResMut<'a, PlayerUpdates>,
-    restart_game_commands: ResMut<'a, GameCommands<RestartGame>>,
-    spawn_level_object_commands: ResMut<'a, GameCommands<SpawnLevelObject>>,
-    despawn_level_object_commands: ResMut<'a, GameCommands<DespawnLevelObject>>,
-    spawn_player_commands: ResMut<'a, GameCommands<SpawnPlayer>>,
-    despawn_player_commands: ResMut<'a, GameCommands<DespawnPlayer>>,
+    commands: UpdateCommands<'a>,
+    confirmed_actions: ResMut<'a, Vec<ConfirmedAction>>,
+    pending_level_edits: ResMut<'a, PendingLevelEdits>,
+    race_times: ResMut<'a, RaceTimes>,
+    desync_detection: ResMut<'a, DesyncDetection>,
+}
+
+/// Is split from `UpdateParams`, as system params can't have more than 16 fields.
+#[derive(SystemParam)]
+pub struct UpdateCommands<'a> {
+    restart_game: ResMut<'a, GameCommands<RestartGame>>,
+    spawn_level_object: ResMut<'a, GameCommands<SpawnLevelObject>>,
+    update_level_object: ResMut<'a, GameCommands<UpdateLevelObject>>,
+    despawn_level_object: ResMut<'a, GameCommands<DespawnLevelObject>>,
+    spawn_player: ResMut<'a, GameCommands<SpawnPlayer>>,
+    despawn_player: ResMut<'a, GameCommands<DespawnPlayer>>,
 }
 
 #[derive(SystemParam)]
-pub struct NetworkParams<'a> {
-    net: ResMut<'a, NetworkResource>,
+pub struct NetworkParams<'a, T: Transport> {
+    net: ResMut<'a, T>,
     connection_state: ResMut<'a, ConnectionState>,
+    server_rejection: ResMut<'a, ServerRejection>,
+    server_disconnect_reason: ResMut<'a, ServerDisconnectReason>,
+    resumable_session: ResMut<'a, ResumableSession>,
+    snapshot_history: ResMut<'a, SnapshotHistory>,
+    full_state_request: ResMut<'a, FullStateRequest>,
+    link_conditioner: ResMut<'a, LinkConditioner>,
+    network_stats: ResMut<'a, NetworkStats>,
 }
 
-pub fn process_network_events(
-    mut network_params: NetworkParams,
+pub fn process_network_events<T: Transport>(
+    mut network_params: NetworkParams<T>,
     mut network_events: EventReader<NetworkEvent>,
     mut current_player_net_id: ResMut<CurrentPlayerNetId>,
     mut players: ResMut<HashMap<PlayerNetId, Player>>,
@@ -73,7 +105,8 @@ pub fn process_network_events(
                     "Sending an Initialize message: {}",
                     network_params.connection_state.handshake_id
                 );
-                if let Err(err) = network_params.net.send_message(
+                if let Err(err) = network_params.network_stats.send_message(
+                    &mut *network_params.net,
                     *handle,
                     Message {
                         // The server is expected to accept any session id for this message.
@@ -97,16 +130,18 @@ pub fn process_network_events(
     let mut connect_message_to_send = None;
     let mut handshake_message_to_send = None;
 
-    for (handle, connection) in network_params.net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
-
-        while let Some(message) = channels.recv::<Message<UnreliableServerMessage>>() {
+    for handle in network_params.net.connection_handles().iter() {
+        while let Some(message) = network_params
+            .link_conditioner
+            .recv_message::<Message<UnreliableServerMessage>, _>(&mut *network_params.net, *handle)
+        {
             log::trace!(
                 "UnreliableServerMessage received on [{}]: {:?}",
                 handle,
                 message
             );
             network_params.connection_state.last_message_received_at = Utc::now();
+            network_params.network_stats.add_received(*handle, &message);
             let Message {
                 message,
                 session_id,
@@ -152,7 +187,10 @@ pub fn process_network_events(
                         *handle,
                         Message {
                             session_id: MessageId::new(0),
-                            message: ReliableClientMessage::Handshake(message_id),
+                            message: ReliableClientMessage::Handshake(ClientHandshake {
+                                handshake_id: message_id,
+                                session_token: network_params.resumable_session.0,
+                            }),
                         },
                     ));
 
@@ -163,9 +201,53 @@ pub fn process_network_events(
                     // thought that `Handshake` probably comes with less edge-cases, since we
                     // always get it before starting the game.
                     current_player_net_id.0 = None;
-                    update_params.restart_game_commands.push(RestartGame);
+                    update_params.commands.restart_game.push(RestartGame);
+                    update_params.confirmed_actions.clear();
+                    update_params.pending_level_edits.clear();
+                    network_params.snapshot_history.clear();
+                    *network_params.full_state_request = FullStateRequest::default();
+                    update_params.desync_detection.clear();
+                    *update_params.race_times = RaceTimes::default();
+                }
+                UnreliableServerMessage::ConnectionRejected(connection_rejected) => {
+                    if !matches!(
+                        network_params.connection_state.status(),
+                        ConnectionStatus::Connecting
+                    ) {
+                        log::warn!("Ignoring ConnectionRejected message for a connected client");
+                        continue;
+                    }
+                    log::error!(
+                        "The server rejected the connection: {:?} (server protocol version: {}, client protocol version: {})",
+                        connection_rejected.reason,
+                        connection_rejected.server_protocol_version,
+                        PROTOCOL_VERSION
+                    );
+                    network_params.server_disconnect_reason.0 = Some(
+                        DisconnectReason::VersionMismatch(connection_rejected.reason),
+                    );
+                    network_params.server_rejection.0 = Some(connection_rejected);
+                    network_params
+                        .connection_state
+                        .set_status(ConnectionStatus::Disconnecting);
+                    return;
                 }
                 UnreliableServerMessage::DeltaUpdate(update) => {
+                    // A message that we fail to decode doesn't get acknowledged, so the server
+                    // will stop using the missing snapshot as a baseline.
+                    let update =
+                        match decode_delta_update(&update.0, &network_params.snapshot_history) {
+                            Ok((update, snapshot)) => {
+                                network_params
+                                    .snapshot_history
+                                    .insert(update.frame_number, snapshot);
+                                update
+                            }
+                            Err(err) => {
+                                log::warn!("Failed to decode a DeltaUpdate message: {:?}", err);
+                                continue;
+                            }
+                        };
                     if let Err(err) = network_params
                         .connection_state
                         .acknowledge_incoming(update.frame_number)
@@ -209,15 +291,15 @@ pub fn process_network_events(
                     skip_update = skip_update || current_player_net_id.0.is_none();
                     if !skip_update {
                         if !can_process_delta_update_message(&update_params.game_time, &update) {
-                            log::error!(
-                                "Can't process update for frame {} (current frame: {})",
-                                update.frame_number,
-                                update_params.game_time.frame_number
-                            );
-                            network_params
-                                .connection_state
-                                .set_status(ConnectionStatus::Disconnecting);
-                            return;
+                            if !network_params.full_state_request.is_requested {
+                                log::warn!(
+                                    "Can't process update for frame {} (current frame: {}), requesting the full game state",
+                                    update.frame_number,
+                                    update_params.game_time.frame_number
+                                );
+                                network_params.full_state_request.is_requested = true;
+                            }
+                            continue;
                         }
 
                         process_delta_update_message(
@@ -232,13 +314,17 @@ pub fn process_network_events(
             }
         }
 
-        while let Some(message) = channels.recv::<Message<ReliableServerMessage>>() {
+        while let Some(message) = network_params
+            .link_conditioner
+            .recv_message::<Message<ReliableServerMessage>, _>(&mut *network_params.net, *handle)
+        {
             log::trace!(
                 "ReliableServerMessage received on [{}]: {:?}",
                 handle,
                 message
             );
             network_params.connection_state.last_message_received_at = Utc::now();
+            network_params.network_stats.add_received(*handle, &message);
             let Message {
                 message,
                 session_id,
@@ -246,7 +332,10 @@ pub fn process_network_events(
 
             // It is assumed that we can't get the same reliable message twice.
             // (Hopefully, the underlying stack does guarantee that.)
-            let ignore_session_id_check = matches!(message, ReliableServerMessage::StartGame(_));
+            let ignore_session_id_check = matches!(
+                message,
+                ReliableServerMessage::StartGame(_) | ReliableServerMessage::FullState(_)
+            );
 
             if session_id != network_params.connection_state.session_id && !ignore_session_id_check
             {
@@ -273,9 +362,11 @@ pub fn process_network_events(
                         Message {
                             // The server is expected to accept any session id for this message.
                             session_id: SessionId::new(0),
-                            message: UnreliableClientMessage::Connect(
-                                network_params.connection_state.handshake_id,
-                            ),
+                            message: UnreliableClientMessage::Connect(ConnectRequest {
+                                protocol_version: PROTOCOL_VERSION,
+                                schema_hash: schema_hash(),
+                                handshake_id: network_params.connection_state.handshake_id,
+                            }),
                         },
                     ));
                     update_params.initial_rtt.sent_at = Some(Utc::now());
@@ -300,6 +391,8 @@ pub fn process_network_events(
                     network_params
                         .connection_state
                         .set_status(ConnectionStatus::Connected);
+                    network_params.server_disconnect_reason.0 = None;
+                    network_params.resumable_session.0 = Some(start_game.session_token);
                     log::info!(
                         "Starting the game (update frame: {})",
                         start_game.game_state.frame_number
@@ -312,29 +405,102 @@ pub fn process_network_events(
                         &mut update_params,
                     );
                 }
+                ReliableServerMessage::FullState(full_state) => {
+                    if !matches!(
+                        network_params.connection_state.status(),
+                        ConnectionStatus::Connected
+                    ) || network_params.full_state_request.sent_at.is_none()
+                    {
+                        log::warn!("Ignoring a FullState message that we haven't requested");
+                        continue;
+                    }
+
+                    // Component buffers can't store the history that far behind.
+                    let frame_number = full_state.game_state.frame_number;
+                    if frame_number < update_params.game_time.frame_number
+                        && frame_number
+                            .diff_abs(update_params.game_time.frame_number)
+                            .value()
+                            > COMPONENT_FRAMEBUFFER_LIMIT / 2
+                    {
+                        log::warn!(
+                            "Can't resync with the server (update frame: {}, current frame: {}), reconnecting",
+                            frame_number,
+                            update_params.game_time.frame_number
+                        );
+                        network_params
+                            .connection_state
+                            .set_status(ConnectionStatus::Disconnecting);
+                        return;
+                    }
+
+                    log::info!("Resyncing with the server (update frame: {})", frame_number);
+                    // The server starts a new session, as our frame numbers are about to jump.
+                    network_params.connection_state.session_id = session_id;
+                    network_params.connection_state.reset_packets();
+                    network_params.snapshot_history.clear();
+                    *network_params.full_state_request = FullStateRequest::default();
+                    update_params.desync_detection.clear();
+                    // Confirmations of the pending edits might have been skipped, while the full
+                    // state replaces the optimistic changes anyway.
+                    update_params.pending_level_edits.clear();
+                    process_full_state_message(
+                        full_state,
+                        &network_params.connection_state,
+                        current_player_net_id.0,
+                        &mut players,
+                        &mut update_params,
+                    );
+                }
                 ReliableServerMessage::ConnectedPlayer(connected_player) => {
                     process_connected_player_message(connected_player, &mut players);
                 }
                 ReliableServerMessage::DisconnectedPlayer(disconnected_player) => {
-                    process_disconnected_player_message(disconnected_player);
+                    process_disconnected_player_message(
+                        disconnected_player,
+                        &mut players,
+                        &mut update_params,
+                    );
                 }
                 ReliableServerMessage::SpawnLevelObject(spawn_level_object) => {
                     update_params
                         .simulation_time
                         .rewind(spawn_level_object.frame_number);
                     update_params
-                        .spawn_level_object_commands
+                        .commands
+                        .spawn_level_object
                         .push(spawn_level_object);
                 }
+                ReliableServerMessage::UpdateLevelObject(update_level_object) => {
+                    update_params
+                        .simulation_time
+                        .rewind(update_level_object.frame_number);
+                    update_params
+                        .commands
+                        .update_level_object
+                        .push(update_level_object);
+                }
                 ReliableServerMessage::DespawnLevelObject(despawn_level_object) => {
                     update_params
                         .simulation_time
                         .rewind(despawn_level_object.frame_number);
                     update_params
-                        .despawn_level_object_commands
+                        .commands
+                        .despawn_level_object
                         .push(despawn_level_object);
                 }
-                ReliableServerMessage::Disconnect => {
+                ReliableServerMessage::RaceStarted(race_started) => {
+                    update_params.race_times.start(race_started);
+                }
+                ReliableServerMessage::RaceLap(race_lap) => {
+                    update_params.race_times.lap(race_lap);
+                }
+                ReliableServerMessage::RaceFinished(race_finished) => {
+                    update_params.race_times.finish(race_finished);
+                }
+                ReliableServerMessage::Disconnect(reason) => {
+                    log::warn!("The server disconnected us: {:?}", reason);
+                    network_params.server_disconnect_reason.0 = Some(reason);
                     network_params
                         .connection_state
                         .set_status(ConnectionStatus::Disconnecting);
@@ -343,8 +509,9 @@ pub fn process_network_events(
             }
         }
 
-        while channels
-            .recv::<Message<UnreliableClientMessage>>()
+        while network_params
+            .link_conditioner
+            .recv_message::<Message<UnreliableClientMessage>, _>(&mut *network_params.net, *handle)
             .is_some()
         {
             log::error!(
@@ -352,31 +519,57 @@ pub fn process_network_events(
                 handle
             );
         }
-        while channels.recv::<Message<ReliableClientMessage>>().is_some() {
+        while network_params
+            .link_conditioner
+            .recv_message::<Message<ReliableClientMessage>, _>(&mut *network_params.net, *handle)
+            .is_some()
+        {
             log::error!("Unexpected ReliableClientMessage received on [{}]", handle);
         }
     }
 
     if let Some((handle, message)) = connect_message_to_send {
-        if let Err(err) = network_params.net.send_message(handle, message) {
+        if let Err(err) =
+            network_params
+                .network_stats
+                .send_message(&mut *network_params.net, handle, message)
+        {
             log::error!("Failed to send Connect message: {:?}", err);
         }
     }
     if let Some((handle, message)) = handshake_message_to_send {
-        if let Err(err) = network_params.net.send_message(handle, message) {
+        if let Err(err) =
+            network_params
+                .network_stats
+                .send_message(&mut *network_params.net, handle, message)
+        {
             log::error!("Failed to send Handshake message: {:?}", err);
         }
     }
 }
 
-pub fn maintain_connection(
+pub fn maintain_connection<T: Transport>(
     time: Res<GameTime>,
-    mut network_params: NetworkParams,
+    mut network_params: NetworkParams<T>,
     mut initial_rtt: ResMut<InitialRtt>,
 ) {
     // TODO: if a client isn't getting any updates, we may also want to pause the game and wait for
     //  some time for a server to respond.
 
+    network_params.network_stats.update(Utc::now());
+
+    if network_params.server_rejection.0.is_some() {
+        if !network_params.net.connection_handles().is_empty() {
+            disconnect_all(&mut *network_params.net);
+            network_params.link_conditioner.clear();
+            network_params.network_stats.clear();
+            network_params
+                .connection_state
+                .set_status(ConnectionStatus::Disconnected);
+        }
+        return;
+    }
+
     let connection_timeout = Utc::now()
         .signed_duration_since(network_params.connection_state.last_message_received_at)
         .to_std()
@@ -400,33 +593,80 @@ pub fn maintain_connection(
         }
     });
 
-    if is_falling_behind {
+    if is_falling_behind && !network_params.full_state_request.is_requested {
         log::warn!(
-            "The client is falling behind, resetting (newest acknowledged frame: {}, current frame: {})",
+            "The client is falling behind, requesting the full game state (newest acknowledged frame: {}, current frame: {})",
             newest_acknowledged_incoming_packet.unwrap(),
             time.frame_number
         );
+        network_params.full_state_request.is_requested = true;
+    }
+
+    let full_state_timeout = network_params
+        .full_state_request
+        .sent_at
+        .map_or(false, |sent_at| {
+            Utc::now().signed_duration_since(sent_at).to_std().unwrap()
+                > std::time::Duration::from_millis(FULL_STATE_TIMEOUT_MILLIS)
+        });
+
+    if full_state_timeout {
+        log::warn!("The server hasn't sent the requested full game state, resetting");
     }
 
     if connection_timeout
-        || is_falling_behind
+        || full_state_timeout
         || matches!(
             network_params.connection_state.status(),
             ConnectionStatus::Disconnecting | ConnectionStatus::Disconnected
         )
     {
-        network_params.net.connections.clear();
+        disconnect_all(&mut *network_params.net);
+        network_params.link_conditioner.clear();
+        network_params.network_stats.clear();
         initial_rtt.sent_at = None;
+        *network_params.full_state_request = FullStateRequest::default();
         network_params
             .connection_state
             .set_status(ConnectionStatus::Uninitialized);
     }
 
-    if network_params.net.connections.is_empty() {
+    if network_params.full_state_request.is_requested
+        && network_params.full_state_request.sent_at.is_none()
+        && matches!(
+            network_params.connection_state.status(),
+            ConnectionStatus::Connected
+        )
+    {
+        let connection_handle = network_params.net.connection_handles().first().copied();
+        if let Some(handle) = connection_handle {
+            log::info!("Requesting the full game state");
+            let result = network_params.network_stats.send_message(
+                &mut *network_params.net,
+                handle,
+                Message {
+                    session_id: network_params.connection_state.session_id,
+                    message: ReliableClientMessage::RequestFullState,
+                },
+            );
+            match result {
+                Ok(_) => network_params.full_state_request.sent_at = Some(Utc::now()),
+                Err(err) => log::error!("Failed to send RequestFullState message: {:?}", err),
+            }
+        }
+    }
+
+    if network_params.net.connection_handles().is_empty() {
         let server_socket_addr = server_addr();
 
         log::info!("Connecting to {}", server_socket_addr);
-        network_params.net.connect(server_socket_addr);
+        network_params.net.connect_to_server(server_socket_addr);
+    }
+}
+
+fn disconnect_all<T: Transport>(net: &mut T) {
+    for handle in net.connection_handles() {
+        net.disconnect(handle);
     }
 }
 
@@ -435,15 +675,15 @@ pub struct PlayerUpdateParams<'a> {
     player_directions: Query<'a, &'static PlayerDirection>,
 }
 
-pub fn send_network_updates(
+pub fn send_network_updates<T: Transport>(
     time: Res<GameTime>,
-    mut network_params: NetworkParams,
+    mut network_params: NetworkParams<T>,
     current_player_net_id: Res<CurrentPlayerNetId>,
     player_registry: Res<EntityRegistry<PlayerNetId>>,
     player_update_params: PlayerUpdateParams,
 ) {
-    let (connection_handle, address) = match network_params.net.connections.iter_mut().next() {
-        Some((&handle, connection)) => (handle, connection.remote_address()),
+    let connection_handle = match network_params.net.connection_handles().first() {
+        Some(&handle) => handle,
         None => return,
     };
 
@@ -468,10 +708,17 @@ pub fn send_network_updates(
         .get(player_entity)
         .expect("Expected a created spawned player");
 
-    network_params
+    if let Err(err) = network_params
         .connection_state
         // Clients don't resend updates, so we can forget about unacknowledged packets.
-        .add_outgoing_packet(time.frame_number, Utc::now());
+        .add_outgoing_packet(time.frame_number, Utc::now())
+    {
+        log::error!("Failed to add an outgoing packet, reconnecting: {}", err);
+        network_params
+            .connection_state
+            .set_status(ConnectionStatus::Disconnecting);
+        return;
+    }
     // TODO: this makes the client send more packets than the server actually needs, as lost packets
     //  never get marked as acknowledged, even though we resend updates in future frames. Fix it.
     let first_unacknowledged_frame = network_params
@@ -486,20 +733,25 @@ pub fn send_network_updates(
         // TODO: should client always sent redundant inputs or only the current ones (unless packet loss is detected)?
         .skip_while(|(frame_number, _)| *frame_number < first_unacknowledged_frame)
     {
-        if Some(direction) != inputs.last().map(|i| i.direction) {
+        let jump = player_direction.is_jumping(frame_number);
+        if jump || Some(direction) != inputs.last().map(|i| i.direction) {
             inputs.push(PlayerInput {
                 frame_number,
                 direction,
+                jump,
             });
         }
     }
 
-    let message = UnreliableClientMessage::PlayerUpdate(PlayerUpdate {
-        frame_number: time.frame_number,
-        acknowledgments: network_params.connection_state.incoming_acknowledgments(),
-        inputs,
-    });
-    let result = network_params.net.send_message(
+    let message = UnreliableClientMessage::PlayerUpdate(PackedPlayerUpdate(encode_player_update(
+        &PlayerUpdate {
+            frame_number: time.frame_number,
+            acknowledgments: network_params.connection_state.incoming_acknowledgments(),
+            inputs,
+        },
+    )));
+    let result = network_params.network_stats.send_message(
+        &mut *network_params.net,
         connection_handle,
         Message {
             session_id: network_params.connection_state.session_id,
@@ -507,7 +759,11 @@ pub fn send_network_updates(
         },
     );
     if let Err(err) = result {
-        log::error!("Failed to send a message to {:?}: {:?}", address, err);
+        log::error!(
+            "Failed to send a message to {}: {:?}",
+            connection_handle,
+            err
+        );
     }
 }
 
@@ -587,33 +843,25 @@ fn process_delta_update_message(
         update_params.player_delay.frame_count = player_delay / 2;
     }
 
-    // Despawning players that aren't mentioned in the delta update.
-    let players_to_remove: Vec<PlayerNetId> = players
-        .keys()
-        .copied()
-        .filter(|player_net_id| {
-            !delta_update
-                .players
-                .iter()
-                .any(|player| player.net_id == *player_net_id)
-        })
-        .collect();
-
-    for player_net_id in players_to_remove {
-        update_params.despawn_player_commands.push(DespawnPlayer {
-            net_id: player_net_id,
-            frame_number: delta_update.frame_number,
-        });
-    }
-
+    // Delta updates contain only the players that are relevant to us, so we can't despawn players
+    // that aren't mentioned in them (see `process_disconnected_player_message`).
     for player_state in delta_update.players {
+        if !player_state.is_spawned {
+            despawn_dead_player(
+                player_state.net_id,
+                delta_update.frame_number,
+                update_params,
+            );
+            continue;
+        }
+
         if update_params
             .player_entities
             .get_entity(player_state.net_id)
             .is_none()
         {
             log::info!("First update with the new player {}", player_state.net_id.0);
-            update_params.spawn_player_commands.push(SpawnPlayer {
+            update_params.commands.spawn_player.push(SpawnPlayer {
                 net_id: player_state.net_id,
                 start_position: player_state.position,
                 is_player_frame_simulated: false,
@@ -657,6 +905,7 @@ fn process_delta_update_message(
                 input.frame_number,
                 Some(PlayerDirectionUpdate {
                     direction: input.direction,
+                    jump: input.jump,
                     is_processed_client_input: None,
                 }),
             );
@@ -679,7 +928,20 @@ fn process_delta_update_message(
             frame_to_update_position,
             player_state.position
         );
-        position_updates.insert(frame_to_update_position, Some(player_state.position));
+        position_updates.insert(
+            frame_to_update_position,
+            Some(PositionUpdate {
+                position: player_state.position,
+                velocity: player_state.velocity,
+            }),
+        );
+    }
+
+    update_params
+        .confirmed_actions
+        .extend(delta_update.confirmed_actions);
+    if let Some(checksum) = delta_update.checksum {
+        update_params.desync_detection.push(checksum);
     }
 
     // There's no need to rewind if we haven't started the game.
@@ -716,28 +978,17 @@ fn process_start_game_message(
                 nickname: start_game.nickname,
             },
         );
-        update_params.game_time.generation += 1;
-        let rtt_frames = FrameNumber::new(
-            (SIMULATIONS_PER_SECOND as f32 * connection_state.rtt_millis() / 1000.0) as u16,
-        );
-        let half_rtt_frames = FrameNumber::new(
-            (SIMULATIONS_PER_SECOND as f32 * connection_state.rtt_millis() / 1000.0 / 2.0) as u16,
+        reset_time(
+            start_game.game_state.frame_number,
+            connection_state,
+            update_params,
         );
-        update_params.target_frames_ahead.frames_count = rtt_frames;
-        update_params.simulation_time.server_frame = start_game.game_state.frame_number;
-        update_params.simulation_time.player_frame =
-            start_game.game_state.frame_number + rtt_frames;
-        update_params.game_time.frame_number = update_params.simulation_time.player_frame;
-
-        update_params.estimated_server_time.frame_number =
-            start_game.game_state.frame_number + half_rtt_frames;
-        update_params.estimated_server_time.updated_at = update_params.game_time.frame_number;
 
         log::debug!(
             "Spawning the current player ({})",
             current_player_net_id.0.unwrap().0
         );
-        update_params.spawn_player_commands.push(SpawnPlayer {
+        update_params.commands.spawn_player.push(SpawnPlayer {
             net_id: start_game.net_id,
             start_position,
             is_player_frame_simulated: true,
@@ -759,7 +1010,7 @@ fn process_start_game_message(
                     nickname: player.nickname,
                 },
             );
-            update_params.spawn_player_commands.push(SpawnPlayer {
+            update_params.commands.spawn_player.push(SpawnPlayer {
                 net_id: player.net_id,
                 start_position,
                 is_player_frame_simulated: false,
@@ -773,11 +1024,136 @@ fn process_start_game_message(
     }
     for spawn_level_object in start_game.objects {
         update_params
-            .spawn_level_object_commands
+            .commands
+            .spawn_level_object
             .push(spawn_level_object);
     }
 }
 
+/// Unlike `process_start_game_message`, keeps the entities that still exist, so that
+/// the client can resync without restarting the game.
+fn process_full_state_message(
+    full_state: FullState,
+    connection_state: &ConnectionState,
+    current_player_net_id: Option<PlayerNetId>,
+    players: &mut HashMap<PlayerNetId, Player>,
+    update_params: &mut UpdateParams,
+) {
+    // The updates that we've received so far are for the frames that we are skipping.
+    *update_params.player_updates = PlayerUpdates::default();
+    reset_time(
+        full_state.game_state.frame_number,
+        connection_state,
+        update_params,
+    );
+    let server_frame = update_params.simulation_time.server_frame;
+
+    let connected_players: HashSet<PlayerNetId> = full_state
+        .players
+        .iter()
+        .map(|player| player.net_id)
+        .collect();
+    for (&net_id, _) in update_params.player_entities.iter() {
+        if !connected_players.contains(&net_id) {
+            log::info!("Despawning player {} that has left", net_id.0);
+            update_params.commands.despawn_player.push(DespawnPlayer {
+                net_id,
+                frame_number: server_frame,
+            });
+        }
+    }
+    players.retain(|net_id, _| connected_players.contains(net_id));
+    for player in full_state.players {
+        players.insert(
+            player.net_id,
+            Player {
+                nickname: player.nickname,
+            },
+        );
+    }
+    // Respawning the players makes their buffers start from the new frame.
+    for player_state in &full_state.game_state.players {
+        if !player_state.is_spawned {
+            despawn_dead_player(player_state.net_id, server_frame, update_params);
+            continue;
+        }
+        update_params.commands.spawn_player.push(SpawnPlayer {
+            net_id: player_state.net_id,
+            start_position: player_state.position,
+            is_player_frame_simulated: current_player_net_id == Some(player_state.net_id),
+        });
+    }
+
+    let objects: HashSet<EntityNetId> = full_state
+        .objects
+        .iter()
+        .map(|spawn_level_object| spawn_level_object.object.net_id)
+        .collect();
+    for object in &update_params.level_state.objects {
+        if !objects.contains(&object.net_id) {
+            update_params
+                .commands
+                .despawn_level_object
+                .push(DespawnLevelObject {
+                    net_id: object.net_id,
+                    frame_number: server_frame,
+                });
+        }
+    }
+    for spawn_level_object in full_state.objects {
+        let existing_object = update_params
+            .level_state
+            .objects
+            .iter()
+            .find(|object| object.net_id == spawn_level_object.object.net_id);
+        match existing_object {
+            Some(object) if *object == spawn_level_object.object => {}
+            Some(_) => update_params
+                .commands
+                .update_level_object
+                .push(UpdateLevelObject {
+                    object: spawn_level_object.object,
+                    frame_number: spawn_level_object.frame_number,
+                }),
+            None => update_params
+                .commands
+                .spawn_level_object
+                .push(spawn_level_object),
+        }
+    }
+
+    process_delta_update_message(
+        full_state.game_state,
+        connection_state,
+        current_player_net_id,
+        players,
+        update_params,
+    );
+}
+
+/// Makes the client simulate the frames starting from `server_frame`, staying ahead of the server
+/// by the rtt.
+fn reset_time(
+    server_frame: FrameNumber,
+    connection_state: &ConnectionState,
+    update_params: &mut UpdateParams,
+) {
+    update_params.game_time.generation += 1;
+    let rtt_frames = FrameNumber::new(
+        (SIMULATIONS_PER_SECOND as f32 * connection_state.rtt_millis() / 1000.0) as u16,
+    );
+    let half_rtt_frames = FrameNumber::new(
+        (SIMULATIONS_PER_SECOND as f32 * connection_state.rtt_millis() / 1000.0 / 2.0) as u16,
+    );
+    update_params.target_frames_ahead.frames_count = rtt_frames;
+    update_params.simulation_time.server_frame = server_frame;
+    update_params.simulation_time.player_frame = server_frame + rtt_frames;
+    update_params.game_time.frame_number = update_params.simulation_time.player_frame;
+
+    update_params.estimated_server_time.frame_number = server_frame + half_rtt_frames;
+    update_params.estimated_server_time.updated_at = update_params.game_time.frame_number;
+}
+
 fn process_connected_player_message(
     connected_player: ConnectedPlayer,
     players: &mut HashMap<PlayerNetId, Player>,
@@ -796,19 +1172,53 @@ fn process_connected_player_message(
     );
 }
 
-fn process_disconnected_player_message(disconnected_player: DisconnectedPlayer) {
-    // We actually remove players if there's no mention of them in a DeltaUpdate message.
+fn process_disconnected_player_message(
+    disconnected_player: DisconnectedPlayer,
+    players: &mut HashMap<PlayerNetId, Player>,
+    update_params: &mut UpdateParams,
+) {
     log::info!("A player ({}) disconnected", disconnected_player.net_id.0);
+    players.remove(&disconnected_player.net_id);
+    // A player might have never been relevant to us, so we haven't spawned it.
+    if update_params
+        .player_entities
+        .get_entity(disconnected_player.net_id)
+        .is_some()
+    {
+        update_params.commands.despawn_player.push(DespawnPlayer {
+            net_id: disconnected_player.net_id,
+            frame_number: update_params.simulation_time.server_frame,
+        });
+    }
 }
 
-fn player_start_position(player_net_id: PlayerNetId, delta_update: &DeltaUpdate) -> Option<Vec2> {
+fn player_start_position(player_net_id: PlayerNetId, delta_update: &DeltaUpdate) -> Option<Vec3> {
     delta_update
         .players
         .iter()
-        .find(|player_state| player_state.net_id == player_net_id)
+        .find(|player_state| player_state.net_id == player_net_id && player_state.is_spawned)
         .map(|player_state| player_state.position)
 }
 
+/// Dead players are respawned with the first update that mentions them alive again, as their
+/// entities get removed soon after despawning.
+fn despawn_dead_player(
+    player_net_id: PlayerNetId,
+    frame_number: FrameNumber,
+    update_params: &mut UpdateParams,
+) {
+    if update_params
+        .player_entities
+        .get_entity(player_net_id)
+        .is_some()
+    {
+        update_params.commands.despawn_player.push(DespawnPlayer {
+            net_id: player_net_id,
+            frame_number,
+        });
+    }
+}
+
 fn server_addr() -> SocketAddr {
     let server_port = std::env::var("MUDDLE_SERVER_PORT")
         .ok()
@@ -0,0 +1,52 @@
+use mr_shared_lib::{
+    framebuffer::FrameNumber,
+    messages::{PlayerNetId, RaceFinished, RaceLap, RaceStarted},
+    SIMULATIONS_PER_SECOND,
+};
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct PlayerRaceTimes {
+    /// Is `None` if a player isn't racing at the moment.
+    pub started_at: Option<FrameNumber>,
+    pub lap_times: Vec<u32>,
+    pub last_finish_time: Option<u32>,
+    pub best_finish_time: Option<u32>,
+}
+
+/// Race times of players, as they are reported by the server.
+#[derive(Default)]
+pub struct RaceTimes {
+    pub players: HashMap<PlayerNetId, PlayerRaceTimes>,
+}
+
+impl RaceTimes {
+    pub fn start(&mut self, race_started: RaceStarted) {
+        let player = self.players.entry(race_started.player_net_id).or_default();
+        player.started_at = Some(race_started.frame_number);
+        player.lap_times.clear();
+    }
+
+    pub fn lap(&mut self, race_lap: RaceLap) {
+        let player = self.players.entry(race_lap.player_net_id).or_default();
+        player.lap_times.push(race_lap.lap_time);
+    }
+
+    pub fn finish(&mut self, race_finished: RaceFinished) {
+        let player = self.players.entry(race_finished.player_net_id).or_default();
+        player.started_at = None;
+        player.lap_times.push(race_finished.lap_time);
+        player.last_finish_time = Some(race_finished.finish_time);
+        player.best_finish_time = Some(
+            player
+                .best_finish_time
+                .map_or(race_finished.finish_time, |best_finish_time| {
+                    best_finish_time.min(race_finished.finish_time)
+                }),
+        );
+    }
+}
+
+pub fn format_race_time(frames: u32) -> String {
+    format!("{:.2}s", frames as f32 / SIMULATIONS_PER_SECOND as f32)
+}
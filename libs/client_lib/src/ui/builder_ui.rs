@@ -0,0 +1,139 @@
+use crate::{
+    builder::{LevelEdit, LevelEdits},
+    builder_tools::{BuilderState, BuilderTool},
+};
+use bevy::ecs::system::{Local, Res, ResMut};
+use bevy_egui::{egui, EguiContext};
+use mr_shared_lib::{
+    game::level::{LevelObject, LevelObjectDesc, LevelState},
+    SIMULATIONS_PER_SECOND,
+};
+
+/// Path changes get applied with a button, to avoid sending an edit on every widget change.
+#[derive(Default)]
+pub struct PlatformDraft {
+    /// The state of the object that the draft is based on. The draft gets reset if the object
+    /// changes (or another one gets selected).
+    base: Option<LevelObject>,
+    object: Option<LevelObject>,
+}
+
+pub fn builder_ui(
+    egui_context: ResMut<EguiContext>,
+    level_state: Res<LevelState>,
+    mut builder_state: ResMut<BuilderState>,
+    mut level_edits: ResMut<LevelEdits>,
+    mut platform_draft: Local<PlatformDraft>,
+) {
+    if !builder_state.is_enabled {
+        return;
+    }
+
+    let ctx = egui_context.ctx();
+    egui::Window::new("Builder").show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            for &tool in BuilderTool::ALL.iter() {
+                if ui
+                    .radio(builder_state.tool == tool, format!("{:?}", tool))
+                    .clicked()
+                {
+                    builder_state.select_tool(tool);
+                }
+            }
+        });
+        ui.add(egui::Slider::new(&mut builder_state.grid_size, 0.1..=2.0).text("Grid size"));
+
+        ui.horizontal(|ui| {
+            if ui.button("Undo").clicked() {
+                level_edits.push(LevelEdit::Undo);
+            }
+            if ui.button("Redo").clicked() {
+                level_edits.push(LevelEdit::Redo);
+            }
+        });
+
+        ui.separator();
+        match builder_state.selected_object {
+            Some(net_id) => {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Selected object: {}", net_id.0));
+                    if ui.button("Delete").clicked() {
+                        level_edits.push(LevelEdit::Despawn(net_id));
+                        builder_state.selected_object = None;
+                    }
+                });
+                let selected_platform = level_state.objects.iter().find(|object| {
+                    object.net_id == net_id
+                        && matches!(object.desc, LevelObjectDesc::MovingPlatform(_))
+                });
+                if let Some(object) = selected_platform {
+                    moving_platform_ui(ui, object, &mut platform_draft, &mut level_edits);
+                }
+            }
+            None => {
+                ui.label("No object selected");
+            }
+        }
+
+        ui.separator();
+        ui.label("LMB - place or select an object, drag handles to move or resize");
+        ui.label("R - rotate, Delete - remove, Esc - deselect");
+        ui.label("Ctrl+Z / Ctrl+Y - undo / redo, B - exit the builder mode");
+    });
+}
+
+fn moving_platform_ui(
+    ui: &mut egui::Ui,
+    object: &LevelObject,
+    draft: &mut PlatformDraft,
+    level_edits: &mut LevelEdits,
+) {
+    if draft.base.as_ref() != Some(object) {
+        draft.base = Some(object.clone());
+        draft.object = Some(object.clone());
+    }
+    let draft_object = draft.object.as_mut().expect("Expected a platform draft");
+    let platform = match &mut draft_object.desc {
+        LevelObjectDesc::MovingPlatform(platform) => platform,
+        _ => return,
+    };
+
+    ui.label("Waypoints (relative to the start):");
+    let mut removed_waypoint = None;
+    for (i, waypoint) in platform.waypoints.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut waypoint.x).speed(0.1));
+            ui.add(egui::DragValue::new(&mut waypoint.y).speed(0.1));
+            ui.add(egui::DragValue::new(&mut waypoint.z).speed(0.1));
+            if ui.button("Remove").clicked() {
+                removed_waypoint = Some(i);
+            }
+        });
+    }
+    if let Some(i) = removed_waypoint {
+        platform.waypoints.remove(i);
+    }
+    if ui.button("Add waypoint").clicked() {
+        let last_waypoint = platform.waypoints.last().copied().unwrap_or_default();
+        platform.waypoints.push(last_waypoint);
+    }
+
+    let max_period = SIMULATIONS_PER_SECOND * 30;
+    ui.add(egui::Slider::new(&mut platform.period, 1..=max_period).text("Period (frames)"));
+    let max_phase = platform.period.max(1) - 1;
+    platform.phase = platform.phase.min(max_phase);
+    ui.add(egui::Slider::new(&mut platform.phase, 0..=max_phase).text("Phase (frames)"));
+
+    if draft.object != draft.base {
+        ui.horizontal(|ui| {
+            if ui.button("Apply").clicked() {
+                level_edits.push(LevelEdit::Update(
+                    draft.object.clone().expect("Expected a platform draft"),
+                ));
+            }
+            if ui.button("Reset").clicked() {
+                draft.object = draft.base.clone();
+            }
+        });
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
-    input::MouseRay, ui::MuddleInspectable, AdjustedSpeedReason, EstimatedServerTime,
-    GameTicksPerSecond, PlayerDelay, TargetFramesAhead,
+    desync::DesyncDetection, input::MouseRay, ui::MuddleInspectable, AdjustedSpeedReason,
+    EstimatedServerTime, GameTicksPerSecond, PlayerDelay, TargetFramesAhead,
 };
 use bevy::{
     diagnostic::{DiagnosticMeasurement, Diagnostics, FrameTimeDiagnosticsPlugin},
@@ -18,9 +18,11 @@ use bevy_rapier3d::{
 };
 use mr_shared_lib::{
     framebuffer::FrameNumber,
-    game::components::{PlayerDirection, Position},
+    game::components::{PlayerDirection, Position, Velocity},
+    link_conditioner::LinkConditioner,
     messages::PlayerNetId,
     net::ConnectionState,
+    net_stats::{ConnectionTraffic, NetworkStats, TrafficStats},
     player::Player,
     registry::EntityRegistry,
     SimulationTime,
@@ -42,6 +44,8 @@ pub struct DebugData<'a> {
     target_frames_ahead: Res<'a, TargetFramesAhead>,
     estimated_server_time: Res<'a, EstimatedServerTime>,
     connection_state: Res<'a, ConnectionState>,
+    desync_detection: Res<'a, DesyncDetection>,
+    network_stats: Res<'a, NetworkStats>,
 }
 
 #[derive(Default)]
@@ -63,6 +67,8 @@ pub struct DebugUiState {
     pub rtt_millis: usize,
     pub packet_loss: f32,
     pub jitter_millis: usize,
+    pub desyncs: u32,
+    pub traffic: ConnectionTraffic,
 }
 
 pub fn update_debug_ui_state(mut debug_ui_state: ResMut<DebugUiState>, debug_data: DebugData) {
@@ -84,12 +90,22 @@ pub fn update_debug_ui_state(mut debug_ui_state: ResMut<DebugUiState>, debug_dat
     debug_ui_state.rtt_millis = debug_data.connection_state.rtt_millis() as usize;
     debug_ui_state.packet_loss = debug_data.connection_state.packet_loss() * 100.0;
     debug_ui_state.jitter_millis = debug_data.connection_state.jitter_millis() as usize;
+    debug_ui_state.desyncs = debug_data.desync_detection.desyncs;
+    // A client has a single connection at most.
+    debug_ui_state.traffic = debug_data
+        .network_stats
+        .iter()
+        .next()
+        .map(|(_, traffic)| traffic.clone())
+        .unwrap_or_default();
 }
 
 pub fn debug_ui(
     // ResMut is intentional, to avoid fighting over the Mutex from different systems.
     egui_context: ResMut<EguiContext>,
     mut debug_ui_state: ResMut<DebugUiState>,
+    mut link_conditioner: ResMut<LinkConditioner>,
+    mut desync_detection: ResMut<DesyncDetection>,
     diagnostics: Res<Diagnostics>,
 ) {
     let ctx = egui_context.ctx();
@@ -154,10 +170,70 @@ pub fn debug_ui(
             ui.label(format!("RTT: {}ms", debug_ui_state.rtt_millis));
             ui.label(format!("Packet loss: {:.2}%", debug_ui_state.packet_loss));
             ui.label(format!("Jitter: {}ms", debug_ui_state.jitter_millis));
+            ui.label(format!("Desyncs: {}", debug_ui_state.desyncs));
+            ui.checkbox(&mut desync_detection.resync_on_desync, "Resync on desync");
+
+            ui.separator();
+            traffic_label(ui, "Sent", &debug_ui_state.traffic.sent);
+            traffic_label(ui, "Received", &debug_ui_state.traffic.received);
+            egui::CollapsingHeader::new("📨 Messages")
+                .default_open(false)
+                .show(ui, |ui| {
+                    traffic_by_message(ui, "Sent", &debug_ui_state.traffic.sent);
+                    traffic_by_message(ui, "Received", &debug_ui_state.traffic.received);
+                });
+
+            ui.separator();
+            egui::CollapsingHeader::new("📶 Network simulation")
+                .default_open(false)
+                .show(ui, |ui| {
+                    let settings = &mut link_conditioner.settings;
+                    ui.add(
+                        egui::Slider::new(&mut settings.latency_millis, 0..=1000)
+                            .text("Latency (ms)"),
+                    );
+                    ui.add(
+                        egui::Slider::new(&mut settings.jitter_millis, 0..=500).text("Jitter (ms)"),
+                    );
+                    ui.add(egui::Slider::new(&mut settings.loss_rate, 0.0..=1.0).text("Loss"));
+                    ui.add(
+                        egui::Slider::new(&mut settings.duplication_rate, 0.0..=1.0)
+                            .text("Duplication"),
+                    );
+                    ui.add(
+                        egui::Slider::new(&mut settings.reordering_rate, 0.0..=1.0)
+                            .text("Reordering"),
+                    );
+                    if ui.button("Reset").clicked() {
+                        *settings = Default::default();
+                    }
+                });
         });
     }
 }
 
+fn traffic_label(ui: &mut egui::Ui, label: &str, traffic: &TrafficStats) {
+    ui.label(format!(
+        "{}: {:.2} kbps ({} messages, {} bytes)",
+        label,
+        traffic.kbps(),
+        traffic.total.messages,
+        traffic.total.bytes
+    ));
+}
+
+fn traffic_by_message(ui: &mut egui::Ui, label: &str, traffic: &TrafficStats) {
+    ui.label(format!("{}:", label));
+    let mut counters = traffic.by_message.iter().collect::<Vec<_>>();
+    counters.sort_unstable_by_key(|(variant_name, _)| **variant_name);
+    for (variant_name, counter) in counters {
+        ui.label(format!(
+            "  {}: {} ({} bytes)",
+            variant_name, counter.messages, counter.bytes
+        ));
+    }
+}
+
 pub struct InspectableObject {
     entity: Option<Entity>,
 }
@@ -174,6 +250,7 @@ pub struct InspectObjectQueries<'a> {
     player_registry: Res<'a, EntityRegistry<PlayerNetId>>,
     colliders: Query<'a, (Entity, &'static ColliderHandleComponent)>,
     positions: Query<'a, (Entity, &'static Position)>,
+    velocities: Query<'a, (Entity, &'static Velocity)>,
     player_directions: Query<'a, (Entity, &'static PlayerDirection)>,
 }
 
@@ -224,6 +301,9 @@ pub fn inspect_object(
             if let Ok((_, position)) = queries.positions.get(entity) {
                 position.inspect(ui);
             }
+            if let Ok((_, velocity)) = queries.velocities.get(entity) {
+                velocity.inspect(ui);
+            }
             if let Ok((_, player_direction)) = queries.player_directions.get(entity) {
                 player_direction.inspect(ui);
             }
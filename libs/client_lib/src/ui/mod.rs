@@ -1,8 +1,10 @@
 use bevy_egui::egui::{self, Ui};
-use mr_shared_lib::game::components::{PlayerDirection, Position};
+use mr_shared_lib::game::components::{PlayerDirection, Position, Velocity};
 
+pub mod builder_ui;
 pub mod debug_ui;
 pub mod overlay_ui;
+pub mod race_ui;
 
 pub trait MuddleInspectable {
     fn inspect(&self, ui: &mut Ui);
@@ -49,7 +51,7 @@ impl MuddleInspectable for Position {
         let first_value = self
             .buffer
             .last()
-            .map(|v| format!("[{: <5.2};{: >5.2}]", v.x, v.y))
+            .map(|v| format!("[{: <5.2};{: >5.2};{: >5.2}]", v.x, v.y, v.z))
             .unwrap_or_else(|| "[None]".to_owned());
 
         egui::CollapsingHeader::new(format!(
@@ -66,10 +68,45 @@ impl MuddleInspectable for Position {
             egui::ScrollArea::from_max_height(200.0).show(ui, |ui| {
                 for (frame_number, value) in self.buffer.iter().rev() {
                     ui.label(format!(
-                        "{}: [{: <5.2};{: >5.2}]",
+                        "{}: [{: <5.2};{: >5.2};{: >5.2}]",
+                        frame_number.value(),
+                        value.x,
+                        value.y,
+                        value.z
+                    ));
+                }
+            });
+        });
+    }
+}
+
+impl MuddleInspectable for Velocity {
+    fn inspect(&self, ui: &mut Ui) {
+        let first_value = self
+            .buffer
+            .last()
+            .map(|v| format!("[{: <5.2};{: >5.2};{: >5.2}]", v.x, v.y, v.z))
+            .unwrap_or_else(|| "[None]".to_owned());
+
+        egui::CollapsingHeader::new(format!(
+            "Velocity {}  -  ([{}; {}] ({}/{})",
+            first_value,
+            self.buffer.start_frame().value(),
+            self.buffer.end_frame().value(),
+            self.buffer.limit(),
+            self.buffer.len(),
+        ))
+        .id_source("velocity buffer")
+        .default_open(false)
+        .show(ui, |ui| {
+            egui::ScrollArea::from_max_height(200.0).show(ui, |ui| {
+                for (frame_number, value) in self.buffer.iter().rev() {
+                    ui.label(format!(
+                        "{}: [{: <5.2};{: >5.2};{: >5.2}]",
                         frame_number.value(),
                         value.x,
-                        value.y
+                        value.y,
+                        value.z
                     ));
                 }
             });
@@ -1,13 +1,16 @@
+use crate::{ServerDisconnectReason, ServerRejection};
 use bevy::{
     ecs::system::{Res, ResMut},
     window::Windows,
 };
 use bevy_egui::{egui, EguiContext};
-use mr_shared_lib::net::{ConnectionState, ConnectionStatus};
+use mr_shared_lib::net::{ConnectionState, ConnectionStatus, PROTOCOL_VERSION};
 
 pub fn connection_status_overlay(
     egui_context: ResMut<EguiContext>,
     connection_state: Res<ConnectionState>,
+    server_rejection: Res<ServerRejection>,
+    server_disconnect_reason: Res<ServerDisconnectReason>,
     windows: Res<Windows>,
 ) {
     if let ConnectionStatus::Connected = connection_state.status() {
@@ -15,8 +18,13 @@ pub fn connection_status_overlay(
     }
 
     let primary_window = windows.get_primary().unwrap();
-    let window_width = 200.0;
-    let window_height = 100.0;
+    let (window_width, window_height) = if server_rejection.0.is_some() {
+        (400.0, 150.0)
+    } else if server_disconnect_reason.0.is_some() {
+        (300.0, 100.0)
+    } else {
+        (200.0, 100.0)
+    };
 
     let ctx = egui_context.ctx();
     egui::CentralPanel::default()
@@ -34,7 +42,18 @@ pub fn connection_status_overlay(
                 .show(ui.ctx(), |ui| {
                     ui.centered_and_justified(|ui| {
                         ui.style_mut().body_text_style = egui::TextStyle::Heading;
-                        ui.label(format!("{:?}", connection_state.status()));
+                        if let Some(connection_rejected) = &server_rejection.0 {
+                            ui.label(format!(
+                                "{}\n(server protocol version: {}, client protocol version: {})",
+                                connection_rejected.reason,
+                                connection_rejected.server_protocol_version,
+                                PROTOCOL_VERSION
+                            ));
+                        } else if let Some(reason) = &server_disconnect_reason.0 {
+                            ui.label(format!("{:?}\n{}", connection_state.status(), reason));
+                        } else {
+                            ui.label(format!("{:?}", connection_state.status()));
+                        }
                     });
                 });
         });
@@ -0,0 +1,72 @@
+use crate::{
+    race::{format_race_time, RaceTimes},
+    CurrentPlayerNetId,
+};
+use bevy::ecs::system::{Res, ResMut};
+use bevy_egui::{egui, EguiContext};
+use mr_shared_lib::{
+    game::level::{LevelObjectDesc, LevelState},
+    messages::PlayerNetId,
+    player::Player,
+    GameTime,
+};
+use std::collections::HashMap;
+
+pub fn race_ui(
+    egui_context: ResMut<EguiContext>,
+    game_time: Res<GameTime>,
+    level_state: Res<LevelState>,
+    current_player_net_id: Res<CurrentPlayerNetId>,
+    players: Res<HashMap<PlayerNetId, Player>>,
+    race_times: Res<RaceTimes>,
+) {
+    let has_route = level_state
+        .objects
+        .iter()
+        .any(|object| matches!(object.desc, LevelObjectDesc::RouteSensor(_)));
+    if !has_route {
+        return;
+    }
+
+    let ctx = egui_context.ctx();
+    egui::Window::new("Race").resizable(false).show(ctx, |ui| {
+        let current_player_times = current_player_net_id
+            .0
+            .and_then(|net_id| race_times.players.get(&net_id));
+        match current_player_times {
+            Some(times) => {
+                if let Some(started_at) = times.started_at {
+                    let elapsed = game_time.frame_number - started_at;
+                    ui.label(format!(
+                        "Time: {}",
+                        format_race_time(elapsed.value() as u32)
+                    ));
+                }
+                for (i, lap_time) in times.lap_times.iter().enumerate() {
+                    ui.label(format!("Lap {}: {}", i + 1, format_race_time(*lap_time)));
+                }
+                if let Some(last_finish_time) = times.last_finish_time {
+                    ui.label(format!("Finished: {}", format_race_time(last_finish_time)));
+                }
+            }
+            None => {
+                ui.label("Cross the start line to begin");
+            }
+        }
+
+        ui.separator();
+        ui.label("Best times");
+        let mut best_times = race_times
+            .players
+            .iter()
+            .filter_map(|(net_id, times)| times.best_finish_time.map(|time| (*net_id, time)))
+            .collect::<Vec<_>>();
+        best_times.sort_by_key(|(_, time)| *time);
+        for (net_id, time) in best_times {
+            let nickname = players
+                .get(&net_id)
+                .map_or("?", |player| player.nickname.as_str());
+            ui.label(format!("{}: {}", nickname, format_race_time(time)));
+        }
+    });
+}
@@ -0,0 +1,109 @@
+use bevy::{
+    app::App,
+    ecs::system::{IntoSystem, Res, ResMut},
+    math::Vec2,
+};
+use mr_client_lib::{
+    bots::{Bot, BotBehaviour, BotStats, ReplayedInput},
+    MuddleHeadlessClientPlugin,
+};
+use mr_server_lib::MuddleLoopbackServerPlugin;
+use mr_shared_lib::{
+    stage,
+    transport::{LoopbackHub, LoopbackTransport},
+    GameTime, SimulationTime, SIMULATIONS_PER_SECOND,
+};
+
+/// Stays below the server's lag compensation (`MAX_LAG_COMPENSATION_MILLIS`), so that the inputs
+/// sent after the lag get applied instead of being discarded.
+const LAG_FRAMES: usize = 20;
+
+/// Depths of the rewinds that the server has made to apply late inputs.
+#[derive(Default)]
+struct Rewinds(Vec<u16>);
+
+/// Runs after the server reads inputs, but before it re-simulates the rewound frames.
+fn record_rewinds(
+    time: Res<GameTime>,
+    simulation_time: Res<SimulationTime>,
+    mut rewinds: ResMut<Rewinds>,
+) {
+    if simulation_time.server_frame < time.frame_number {
+        rewinds
+            .0
+            .push((time.frame_number - simulation_time.server_frame).value());
+    }
+}
+
+fn server_app(hub: &LoopbackHub) -> App {
+    let mut builder = App::build();
+    builder
+        .insert_resource(hub.server())
+        .add_plugin(MuddleLoopbackServerPlugin::<LoopbackTransport>::default())
+        .init_resource::<Rewinds>()
+        .add_system_to_stage(stage::READ_INPUT_UPDATES, record_rewinds.system());
+    builder.app
+}
+
+fn client_app(hub: &LoopbackHub) -> App {
+    // Walking back and forth keeps the bot on the plane.
+    let behaviour = BotBehaviour::Replay(vec![
+        ReplayedInput {
+            frames: 30,
+            direction: Vec2::new(1.0, 0.0),
+            jump: false,
+        },
+        ReplayedInput {
+            frames: 30,
+            direction: Vec2::new(-1.0, 0.0),
+            jump: false,
+        },
+    ]);
+    let mut builder = App::build();
+    builder
+        .insert_resource(hub.client())
+        .insert_resource(Bot::new(behaviour, 0))
+        .add_plugin(MuddleHeadlessClientPlugin::<LoopbackTransport>::stepped());
+    builder.app
+}
+
+fn step(server: &mut App, client: &mut App, frames: usize) {
+    for _ in 0..frames {
+        server.update();
+        client.update();
+    }
+}
+
+#[test]
+fn test_late_inputs_rewind_the_server() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    let mut client = client_app(&hub);
+
+    for _ in 0..SIMULATIONS_PER_SECOND {
+        step(&mut server, &mut client, 1);
+        if BotStats::from_world(&client.world).is_connected {
+            break;
+        }
+    }
+    assert!(BotStats::from_world(&client.world).is_connected);
+    // Letting the client catch up with the server and start sending inputs ahead of it.
+    step(&mut server, &mut client, SIMULATIONS_PER_SECOND as usize);
+    server
+        .world
+        .get_resource_mut::<Rewinds>()
+        .unwrap()
+        .0
+        .clear();
+
+    // The client stalls, so its inputs for the frames that the server simulates in the meantime
+    // arrive late.
+    for _ in 0..LAG_FRAMES {
+        server.update();
+    }
+    step(&mut server, &mut client, SIMULATIONS_PER_SECOND as usize);
+
+    let rewinds = &server.world.get_resource::<Rewinds>().unwrap().0;
+    assert!(!rewinds.is_empty());
+    assert!(BotStats::from_world(&client.world).is_connected);
+}
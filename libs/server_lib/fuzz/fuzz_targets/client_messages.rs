@@ -0,0 +1,94 @@
+#![no_main]
+
+//! Feeds arbitrary `Message<UnreliableClientMessage>` sequences to a server that runs on top of
+//! the loopback transport. Run it with `cargo fuzz run client_messages` (from `libs/server_lib`).
+
+use bevy::app::App;
+use libfuzzer_sys::fuzz_target;
+use mr_server_lib::MuddleLoopbackServerPlugin;
+use mr_shared_lib::{
+    messages::{
+        ClientHandshake, ConnectRequest, Message, ReliableClientMessage, ReliableServerMessage,
+        UnreliableClientMessage,
+    },
+    net::{schema_hash, MessageId, SessionId, PROTOCOL_VERSION},
+    transport::{LoopbackHub, LoopbackTransport, Transport},
+};
+
+/// Is enough for the server to register a player and send `StartGame`.
+const HANDSHAKE_FRAMES: usize = 4;
+
+fuzz_target!(|data: &[u8]| {
+    let hub = LoopbackHub::default();
+    let mut builder = App::build();
+    builder
+        .insert_resource(hub.server())
+        .add_plugin(MuddleLoopbackServerPlugin::<LoopbackTransport>::default());
+    let mut server = builder.app;
+    server.update();
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    // Completing the handshake first, otherwise the server would ignore everything but `Connect`.
+    let session_id = connect(&mut server, &mut client, handle);
+
+    // Every message is prefixed with its length, which makes it easier for the fuzzer to mutate
+    // messages independently.
+    let mut data = data;
+    while let Some((&len, rest)) = data.split_first() {
+        let (bytes, rest) = rest.split_at((len as usize).min(rest.len()));
+        data = rest;
+        if let Ok(mut message) = bincode::deserialize::<Message<UnreliableClientMessage>>(bytes) {
+            // Messages with random session ids would just be ignored.
+            if let Some(session_id) = session_id {
+                message.session_id = session_id;
+            }
+            if client.send_message(handle, message).is_err() {
+                // The server has disconnected us, there's nothing else to feed.
+                return;
+            }
+        }
+        server.update();
+    }
+});
+
+fn connect(server: &mut App, client: &mut LoopbackTransport, handle: u32) -> Option<SessionId> {
+    let handshake_id = MessageId::new(0);
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id: SessionId::new(0),
+                message: UnreliableClientMessage::Connect(ConnectRequest {
+                    protocol_version: PROTOCOL_VERSION,
+                    schema_hash: schema_hash(),
+                    handshake_id,
+                }),
+            },
+        )
+        .unwrap();
+    server.update();
+
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id: SessionId::new(0),
+                message: ReliableClientMessage::Handshake(ClientHandshake {
+                    handshake_id,
+                    session_token: None,
+                }),
+            },
+        )
+        .unwrap();
+    for _ in 0..HANDSHAKE_FRAMES {
+        server.update();
+    }
+
+    std::iter::from_fn(|| client.recv_message::<Message<ReliableServerMessage>>(handle)).find_map(
+        |message| match message.message {
+            ReliableServerMessage::StartGame(_) => Some(message.session_id),
+            _ => None,
+        },
+    )
+}
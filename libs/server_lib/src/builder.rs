@@ -0,0 +1,676 @@
+use crate::net::PlayerConnections;
+use bevy::{
+    ecs::system::SystemParam,
+    log,
+    math::{Quat, Vec3},
+    prelude::*,
+};
+use mr_shared_lib::{
+    framebuffer::FrameNumber,
+    game::{
+        commands::{DespawnLevelObject, GameCommands, SpawnLevelObject, UpdateLevelObject},
+        level::{LevelObject, LevelObjectDesc, LevelState},
+    },
+    messages::{
+        ConfirmedAction, DespawnLevelObjectRequest, EntityNetId, PlayerNetId,
+        ReliableServerMessage, SpawnLevelObjectRequest, UpdateLevelObjectRequest,
+    },
+    net::ConnectionState,
+    registry::IncrementId,
+    GameTime, FIRST_TEMPORARY_ENTITY_NET_ID, PLANE_SIZE,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+const MAX_OBJECT_SIZE: f32 = PLANE_SIZE;
+const EDIT_HISTORY_LIMIT: usize = 100;
+const MAX_PLATFORM_WAYPOINTS: usize = 16;
+
+pub enum LevelEditRequest {
+    Spawn(SpawnLevelObjectRequest),
+    Update(UpdateLevelObjectRequest),
+    Despawn(DespawnLevelObjectRequest),
+    Undo,
+    Redo,
+}
+
+/// An applied level edit, contains everything to revert it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LevelOperation {
+    Spawn(LevelObject),
+    Update {
+        previous: LevelObject,
+        new: LevelObject,
+    },
+    Despawn(LevelObject),
+}
+
+impl LevelOperation {
+    pub fn inverse(&self) -> Self {
+        match self {
+            Self::Spawn(object) => Self::Despawn(object.clone()),
+            Self::Update { previous, new } => Self::Update {
+                previous: new.clone(),
+                new: previous.clone(),
+            },
+            Self::Despawn(object) => Self::Spawn(object.clone()),
+        }
+    }
+
+    pub fn net_id(&self) -> EntityNetId {
+        match self {
+            Self::Spawn(object) | Self::Despawn(object) => object.net_id,
+            Self::Update { new, .. } => new.net_id,
+        }
+    }
+}
+
+/// Per-player undo and redo stacks.
+#[derive(Default)]
+pub struct EditHistory {
+    undo_stack: VecDeque<LevelOperation>,
+    redo_stack: Vec<LevelOperation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayError {
+    /// The objects that an operation touches have been changed since then (by other builders,
+    /// for instance).
+    Conflict,
+    /// The objects have already been edited during the current frame, the level state will
+    /// reflect it only after the frame is simulated.
+    Deferred,
+}
+
+impl EditHistory {
+    pub fn push(&mut self, operation: LevelOperation) {
+        self.redo_stack.clear();
+        self.push_undo(operation);
+    }
+
+    /// Operations that can't be reverted anymore (`ReplayError::Conflict`) are dropped from
+    /// the history. Returns `ReplayError::Deferred` if the request has to be repeated on the next
+    /// frame, the history is left untouched in that case.
+    pub fn undo(
+        &mut self,
+        mut apply: impl FnMut(&LevelOperation) -> Result<(), ReplayError>,
+    ) -> Result<(), ReplayError> {
+        while let Some(operation) = self.undo_stack.pop_back() {
+            match apply(&operation.inverse()) {
+                Ok(()) => {
+                    self.redo_stack.push(operation);
+                    return Ok(());
+                }
+                Err(ReplayError::Deferred) => {
+                    self.undo_stack.push_back(operation);
+                    return Err(ReplayError::Deferred);
+                }
+                Err(ReplayError::Conflict) => {
+                    log::warn!(
+                        "Dropping an operation that can't be undone: {:?}",
+                        operation
+                    );
+                }
+            }
+        }
+        log::debug!("Nothing to undo");
+        Ok(())
+    }
+
+    /// See `EditHistory::undo`.
+    pub fn redo(
+        &mut self,
+        mut apply: impl FnMut(&LevelOperation) -> Result<(), ReplayError>,
+    ) -> Result<(), ReplayError> {
+        while let Some(operation) = self.redo_stack.pop() {
+            match apply(&operation) {
+                Ok(()) => {
+                    self.push_undo(operation);
+                    return Ok(());
+                }
+                Err(ReplayError::Deferred) => {
+                    self.redo_stack.push(operation);
+                    return Err(ReplayError::Deferred);
+                }
+                Err(ReplayError::Conflict) => {
+                    log::warn!(
+                        "Dropping an operation that can't be redone: {:?}",
+                        operation
+                    );
+                }
+            }
+        }
+        log::debug!("Nothing to redo");
+        Ok(())
+    }
+
+    fn push_undo(&mut self, operation: LevelOperation) {
+        if self.undo_stack.len() == EDIT_HISTORY_LIMIT {
+            self.undo_stack.pop_front();
+        }
+        self.undo_stack.push_back(operation);
+    }
+}
+
+/// Level edit requests received from clients, paired with connection handles.
+#[derive(Default)]
+pub struct DeferredLevelEditRequests {
+    requests: Vec<(u32, LevelEditRequest)>,
+}
+
+impl DeferredLevelEditRequests {
+    pub fn push(&mut self, connection_handle: u32, request: LevelEditRequest) {
+        self.requests.push((connection_handle, request));
+    }
+}
+
+/// Reliable messages about changed level objects that have to be broadcast to all the clients.
+#[derive(Default)]
+pub struct LevelObjectUpdates {
+    pub messages: Vec<ReliableServerMessage>,
+}
+
+struct PendingConfirmation {
+    action: ConfirmedAction,
+    first_sent_at: Option<FrameNumber>,
+}
+
+/// Confirmations are sent with unreliable `DeltaUpdate` messages, so we keep including them
+/// into every update for a connection until one of these updates gets acknowledged.
+#[derive(Default)]
+pub struct PendingConfirmations {
+    confirmations: HashMap<u32, Vec<PendingConfirmation>>,
+}
+
+impl PendingConfirmations {
+    pub fn push(&mut self, connection_handle: u32, action: ConfirmedAction) {
+        self.confirmations
+            .entry(connection_handle)
+            .or_default()
+            .push(PendingConfirmation {
+                action,
+                first_sent_at: None,
+            });
+    }
+
+    /// Forgets about the delivered confirmations and returns the rest to be sent with an update
+    /// for the `frame_number` frame.
+    pub fn prepare_for_update(
+        &mut self,
+        connection_handle: u32,
+        connection_state: &ConnectionState,
+        frame_number: FrameNumber,
+    ) -> Vec<ConfirmedAction> {
+        let confirmations = match self.confirmations.get_mut(&connection_handle) {
+            Some(confirmations) => confirmations,
+            None => return Vec::new(),
+        };
+        let newest_acknowledged = connection_state.newest_acknowledged_outgoing_packet();
+        confirmations.retain(|confirmation| {
+            confirmation
+                .first_sent_at
+                .zip(newest_acknowledged)
+                .map_or(true, |(first_sent_at, newest_acknowledged)| {
+                    newest_acknowledged < first_sent_at
+                })
+        });
+        confirmations
+            .iter_mut()
+            .map(|confirmation| {
+                confirmation.first_sent_at.get_or_insert(frame_number);
+                confirmation.action.clone()
+            })
+            .collect()
+    }
+}
+
+/// The level state gets updated only when a frame is simulated, so edits of the same object
+/// within a frame would be validated against its stale state.
+#[derive(Default)]
+pub struct EditedObjects {
+    frame_number: FrameNumber,
+    net_ids: HashSet<EntityNetId>,
+}
+
+#[derive(SystemParam)]
+pub struct LevelEditParams<'a> {
+    time: Res<'a, GameTime>,
+    edited_objects: Local<'a, EditedObjects>,
+    entity_net_id_counter: ResMut<'a, EntityNetId>,
+    level_state: Res<'a, LevelState>,
+    spawn_level_object_commands: ResMut<'a, GameCommands<SpawnLevelObject>>,
+    update_level_object_commands: ResMut<'a, GameCommands<UpdateLevelObject>>,
+    despawn_level_object_commands: ResMut<'a, GameCommands<DespawnLevelObject>>,
+    level_object_updates: ResMut<'a, LevelObjectUpdates>,
+}
+
+impl<'a> LevelEditParams<'a> {
+    pub fn spawn(&mut self, desc: LevelObjectDesc) -> Option<LevelOperation> {
+        if !is_editable_desc(&desc) {
+            log::warn!("Rejecting to spawn an invalid object: {:?}", desc);
+            return None;
+        }
+        if self.entity_net_id_counter.0 >= FIRST_TEMPORARY_ENTITY_NET_ID {
+            log::warn!("Rejecting to spawn an object: ran out of entity net ids");
+            return None;
+        }
+
+        let object = LevelObject {
+            net_id: self.entity_net_id_counter.increment(),
+            desc,
+        };
+        self.push_spawn(object.clone());
+        Some(LevelOperation::Spawn(object))
+    }
+
+    pub fn update(&mut self, object: LevelObject) -> Option<LevelOperation> {
+        let existing_object = self.find_editable_object(object.net_id)?;
+        if !is_editable_desc(&object.desc) {
+            log::warn!(
+                "Rejecting to update an object with invalid data: {:?}",
+                object
+            );
+            return None;
+        }
+
+        self.push_update(object.clone());
+        Some(LevelOperation::Update {
+            previous: existing_object,
+            new: object,
+        })
+    }
+
+    pub fn despawn(&mut self, net_id: EntityNetId) -> Option<LevelOperation> {
+        let existing_object = self.find_editable_object(net_id)?;
+        self.push_despawn(net_id);
+        Some(LevelOperation::Despawn(existing_object))
+    }
+
+    /// Returns true if the object has already been edited during the current frame. Such
+    /// edits have to wait until the next one.
+    pub fn is_edited(&self, net_id: EntityNetId) -> bool {
+        self.edited_objects.frame_number == self.time.frame_number
+            && self.edited_objects.net_ids.contains(&net_id)
+    }
+
+    /// Replays an operation from the edit history.
+    pub fn apply(&mut self, operation: &LevelOperation) -> Result<(), ReplayError> {
+        if self.is_edited(operation.net_id()) {
+            return Err(ReplayError::Deferred);
+        }
+
+        match operation {
+            LevelOperation::Spawn(object) => {
+                let is_taken = self
+                    .level_state
+                    .objects
+                    .iter()
+                    .any(|existing_object| existing_object.net_id == object.net_id);
+                if is_taken {
+                    log::warn!(
+                        "Can't respawn object {}: it already exists",
+                        object.net_id.0
+                    );
+                    return Err(ReplayError::Conflict);
+                }
+                self.push_spawn(object.clone());
+            }
+            LevelOperation::Update { previous, new } => {
+                if !self.is_unchanged(previous) {
+                    return Err(ReplayError::Conflict);
+                }
+                self.push_update(new.clone());
+            }
+            LevelOperation::Despawn(object) => {
+                if !self.is_unchanged(object) {
+                    return Err(ReplayError::Conflict);
+                }
+                self.push_despawn(object.net_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn mark_edited(&mut self, net_id: EntityNetId) {
+        if self.edited_objects.frame_number != self.time.frame_number {
+            self.edited_objects.frame_number = self.time.frame_number;
+            self.edited_objects.net_ids.clear();
+        }
+        self.edited_objects.net_ids.insert(net_id);
+    }
+
+    fn push_spawn(&mut self, object: LevelObject) {
+        self.mark_edited(object.net_id);
+        let command = SpawnLevelObject {
+            object,
+            frame_number: self.time.frame_number,
+        };
+        self.spawn_level_object_commands.push(command.clone());
+        self.level_object_updates
+            .messages
+            .push(ReliableServerMessage::SpawnLevelObject(command));
+    }
+
+    fn push_update(&mut self, object: LevelObject) {
+        self.mark_edited(object.net_id);
+        let command = UpdateLevelObject {
+            object,
+            frame_number: self.time.frame_number,
+        };
+        self.update_level_object_commands.push(command.clone());
+        self.level_object_updates
+            .messages
+            .push(ReliableServerMessage::UpdateLevelObject(command));
+    }
+
+    fn push_despawn(&mut self, net_id: EntityNetId) {
+        self.mark_edited(net_id);
+        let command = DespawnLevelObject {
+            net_id,
+            frame_number: self.time.frame_number,
+        };
+        self.despawn_level_object_commands.push(command.clone());
+        self.level_object_updates
+            .messages
+            .push(ReliableServerMessage::DespawnLevelObject(command));
+    }
+
+    fn is_unchanged(&self, object: &LevelObject) -> bool {
+        let is_unchanged = self.find_editable_object(object.net_id).as_ref() == Some(object);
+        if !is_unchanged {
+            log::warn!(
+                "Can't replay an operation: object {} has been changed",
+                object.net_id.0
+            );
+        }
+        is_unchanged
+    }
+
+    fn find_editable_object(&self, net_id: EntityNetId) -> Option<LevelObject> {
+        let object = self
+            .level_state
+            .objects
+            .iter()
+            .find(|object| object.net_id == net_id);
+        match object {
+            Some(object) if !matches!(object.desc, LevelObjectDesc::Plane(_)) => {
+                Some(object.clone())
+            }
+            Some(_) => {
+                log::warn!("Rejecting to edit the ground plane ({})", net_id.0);
+                None
+            }
+            None => {
+                log::warn!("Rejecting to edit a non-existing object ({})", net_id.0);
+                None
+            }
+        }
+    }
+}
+
+pub fn process_level_edit_requests(
+    mut level_edit_requests: ResMut<DeferredLevelEditRequests>,
+    mut pending_confirmations: ResMut<PendingConfirmations>,
+    mut edit_histories: ResMut<HashMap<PlayerNetId, EditHistory>>,
+    connection_states: Res<HashMap<u32, ConnectionState>>,
+    player_connections: Res<PlayerConnections>,
+    mut level_edit_params: LevelEditParams,
+) {
+    pending_confirmations
+        .confirmations
+        .retain(|connection_handle, _| connection_states.contains_key(connection_handle));
+    edit_histories
+        .retain(|player_net_id, _| player_connections.get_value(*player_net_id).is_some());
+
+    let mut requests = std::mem::take(&mut level_edit_requests.requests).into_iter();
+    while let Some((connection_handle, request)) = requests.next() {
+        let player_net_id = match player_connections.get_id(connection_handle) {
+            Some(player_net_id) => player_net_id,
+            None => {
+                log::warn!(
+                    "Ignoring a level edit from an unknown connection ({})",
+                    connection_handle
+                );
+                continue;
+            }
+        };
+        let edit_history = edit_histories.entry(player_net_id).or_default();
+
+        let replay_result = match &request {
+            LevelEditRequest::Update(UpdateLevelObjectRequest { object, .. })
+                if level_edit_params.is_edited(object.net_id) =>
+            {
+                Err(ReplayError::Deferred)
+            }
+            LevelEditRequest::Despawn(DespawnLevelObjectRequest { net_id, .. })
+                if level_edit_params.is_edited(*net_id) =>
+            {
+                Err(ReplayError::Deferred)
+            }
+            LevelEditRequest::Undo => {
+                edit_history.undo(|operation| level_edit_params.apply(operation))
+            }
+            LevelEditRequest::Redo => {
+                edit_history.redo(|operation| level_edit_params.apply(operation))
+            }
+            _ => Ok(()),
+        };
+        if replay_result == Err(ReplayError::Deferred) {
+            // Keeping the order of the requests, everything that comes after is deferred too.
+            level_edit_requests
+                .requests
+                .extend(std::iter::once((connection_handle, request)).chain(requests));
+            break;
+        }
+
+        let (action_id, operation) = match request {
+            LevelEditRequest::Spawn(request) => {
+                (request.action_id, level_edit_params.spawn(request.desc))
+            }
+            LevelEditRequest::Update(request) => {
+                (request.action_id, level_edit_params.update(request.object))
+            }
+            LevelEditRequest::Despawn(request) => {
+                (request.action_id, level_edit_params.despawn(request.net_id))
+            }
+            LevelEditRequest::Undo | LevelEditRequest::Redo => continue,
+        };
+
+        let confirmed_frame = operation.map(|operation| {
+            edit_history.push(operation);
+            level_edit_params.time.frame_number
+        });
+        pending_confirmations.push(
+            connection_handle,
+            ConfirmedAction {
+                id: action_id,
+                confirmed_frame,
+            },
+        );
+    }
+}
+
+/// Builders can't add or edit planes: a level has a single ground plane.
+fn is_editable_desc(desc: &LevelObjectDesc) -> bool {
+    let is_valid_path = match desc {
+        LevelObjectDesc::MovingPlatform(platform) => {
+            platform.period > 0
+                && platform.waypoints.len() <= MAX_PLATFORM_WAYPOINTS
+                && platform.waypoints.iter().all(|waypoint| {
+                    (platform.position + *waypoint).is_finite()
+                        && (platform.position + *waypoint).abs().max_element() <= PLANE_SIZE
+                })
+        }
+        _ => true,
+    };
+    match desc.transform().zip(desc.size()) {
+        Some(((position, rotation), size)) => {
+            is_valid_path && is_valid_transform(position, rotation) && is_valid_size(size)
+        }
+        None => false,
+    }
+}
+
+fn is_valid_transform(position: Vec3, rotation: Quat) -> bool {
+    position.is_finite()
+        && position.abs().max_element() <= PLANE_SIZE
+        && rotation.is_finite()
+        && (rotation.length() - 1.0).abs() < 0.001
+}
+
+fn is_valid_size(size: Vec3) -> bool {
+    size.is_finite() && size.min_element() > 0.0 && size.max_element() <= MAX_OBJECT_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::{EditHistory, LevelOperation, ReplayError, EDIT_HISTORY_LIMIT};
+    use bevy::math::{Quat, Vec3};
+    use mr_shared_lib::{
+        game::{
+            level::{LevelObject, LevelObjectDesc},
+            level_objects::CuboidDesc,
+        },
+        messages::EntityNetId,
+    };
+
+    fn object(net_id: u16, x: f32) -> LevelObject {
+        LevelObject {
+            net_id: EntityNetId(net_id),
+            desc: LevelObjectDesc::Cuboid(CuboidDesc {
+                position: Vec3::new(x, 0.5, 0.0),
+                rotation: Quat::identity(),
+                size: Vec3::ONE,
+            }),
+        }
+    }
+
+    /// Applies the operations that the history replays, returning `result` for all of them.
+    fn replay(
+        applied: &mut Vec<LevelOperation>,
+        result: Result<(), ReplayError>,
+    ) -> impl FnMut(&LevelOperation) -> Result<(), ReplayError> + '_ {
+        move |operation| {
+            if result.is_ok() {
+                applied.push(operation.clone());
+            }
+            result
+        }
+    }
+
+    #[test]
+    fn test_level_operation_inverse() {
+        let spawn = LevelOperation::Spawn(object(1, 0.0));
+        assert_eq!(spawn.inverse(), LevelOperation::Despawn(object(1, 0.0)));
+        assert_eq!(spawn.inverse().inverse(), spawn);
+
+        let update = LevelOperation::Update {
+            previous: object(1, 0.0),
+            new: object(1, 2.0),
+        };
+        assert_eq!(
+            update.inverse(),
+            LevelOperation::Update {
+                previous: object(1, 2.0),
+                new: object(1, 0.0),
+            }
+        );
+        assert_eq!(update.inverse().inverse(), update);
+        assert_eq!(update.inverse().net_id(), EntityNetId(1));
+    }
+
+    #[test]
+    fn test_edit_history_undo_redo() {
+        let spawn = LevelOperation::Spawn(object(1, 0.0));
+        let update = LevelOperation::Update {
+            previous: object(1, 0.0),
+            new: object(1, 2.0),
+        };
+        let mut history = EditHistory::default();
+        history.push(spawn.clone());
+        history.push(update.clone());
+
+        let mut applied = Vec::new();
+        history.undo(replay(&mut applied, Ok(()))).unwrap();
+        history.undo(replay(&mut applied, Ok(()))).unwrap();
+        // There's nothing left to undo.
+        history.undo(replay(&mut applied, Ok(()))).unwrap();
+        history.redo(replay(&mut applied, Ok(()))).unwrap();
+        assert_eq!(
+            applied,
+            vec![update.inverse(), spawn.inverse(), spawn.clone()]
+        );
+
+        // New edits clear the redo stack.
+        applied.clear();
+        history.push(LevelOperation::Despawn(object(1, 0.0)));
+        history.redo(replay(&mut applied, Ok(()))).unwrap();
+        assert!(applied.is_empty());
+        history.undo(replay(&mut applied, Ok(()))).unwrap();
+        history.undo(replay(&mut applied, Ok(()))).unwrap();
+        assert_eq!(
+            applied,
+            vec![LevelOperation::Spawn(object(1, 0.0)), spawn.inverse()]
+        );
+    }
+
+    #[test]
+    fn test_edit_history_conflicts_and_deferred_operations() {
+        let first = LevelOperation::Spawn(object(1, 0.0));
+        let second = LevelOperation::Spawn(object(2, 0.0));
+        let mut history = EditHistory::default();
+        history.push(first.clone());
+        history.push(second.clone());
+
+        // Deferred operations stay in the history.
+        let mut applied = Vec::new();
+        assert_eq!(
+            history.undo(replay(&mut applied, Err(ReplayError::Deferred))),
+            Err(ReplayError::Deferred)
+        );
+        history.undo(replay(&mut applied, Ok(()))).unwrap();
+        assert_eq!(applied, vec![second.inverse()]);
+        assert_eq!(
+            history.redo(replay(&mut applied, Err(ReplayError::Deferred))),
+            Err(ReplayError::Deferred)
+        );
+        history.redo(replay(&mut applied, Ok(()))).unwrap();
+        assert_eq!(applied, vec![second.inverse(), second.clone()]);
+
+        // Conflicting operations are dropped, undo moves on to the next one.
+        applied.clear();
+        let mut results = vec![Err(ReplayError::Conflict), Ok(())].into_iter();
+        history
+            .undo(|operation| {
+                let result = results.next().unwrap();
+                if result.is_ok() {
+                    applied.push(operation.clone());
+                }
+                result
+            })
+            .unwrap();
+        assert_eq!(applied, vec![first.inverse()]);
+        history.undo(replay(&mut applied, Ok(()))).unwrap();
+        history.redo(replay(&mut applied, Ok(()))).unwrap();
+        history.redo(replay(&mut applied, Ok(()))).unwrap();
+        assert_eq!(applied, vec![first.inverse(), first]);
+    }
+
+    #[test]
+    fn test_edit_history_limit() {
+        let mut history = EditHistory::default();
+        for i in 0..EDIT_HISTORY_LIMIT + 1 {
+            history.push(LevelOperation::Spawn(object(i as u16, 0.0)));
+        }
+
+        let mut applied = Vec::new();
+        for _ in 0..EDIT_HISTORY_LIMIT + 1 {
+            history.undo(replay(&mut applied, Ok(()))).unwrap();
+        }
+        assert_eq!(applied.len(), EDIT_HISTORY_LIMIT);
+        // The oldest operation is forgotten.
+        assert_eq!(
+            applied.last(),
+            Some(&LevelOperation::Despawn(object(1, 0.0)))
+        );
+    }
+}
@@ -2,27 +2,65 @@
 #![feature(hash_drain_filter)]
 
 use crate::{
-    net::{process_network_events, send_network_updates, startup, PlayerConnections},
+    builder::{
+        process_level_edit_requests, DeferredLevelEditRequests, EditHistory, LevelObjectUpdates,
+        PendingConfirmations,
+    },
+    metrics::{
+        count_disconnects, metrics_addr, serve_metrics, start_tick_timer, stop_tick_timer, Metrics,
+        TICK_TIMER_END, TICK_TIMER_START,
+    },
+    net::{
+        disconnect_clients_on_exit, process_network_events, send_network_updates, startup,
+        FullStateRequests, NewPlayerConnection, PlayerConnections,
+    },
+    persistence::{
+        level_file_path, load_level, process_save_level_requests, LevelFilePath, SaveLevelRequest,
+    },
     player_updates::{process_player_input_updates, DeferredUpdates},
+    relevance::Relevance,
+    sessions::Sessions,
+    validation::ConnectionLimits,
 };
-use bevy::{core::FixedTimestep, prelude::*};
+use bevy::{core::FixedTimestep, ecs::schedule::ShouldRun, log, prelude::*};
+use bevy_networking_turbulence::NetworkResource;
 use mr_shared_lib::{
+    codec::SnapshotHistory,
     framebuffer::FrameNumber,
     game::{
         commands::{GameCommands, SpawnLevelObject},
         level::{LevelObject, LevelObjectDesc},
         level_objects::PlaneDesc,
+        respawn::RespawnDelay,
     },
+    link_conditioner::{LinkConditioner, LinkConditionerSettings},
     messages::{EntityNetId, PlayerInput, PlayerNetId},
     net::ConnectionState,
+    net_stats::NetworkStats,
     registry::IncrementId,
-    MuddleSharedPlugin, PLANE_SIZE, SIMULATIONS_PER_SECOND,
+    stage,
+    transport::{LoopbackTransport, Transport},
+    MuddleSharedPlugin, SimulationRole, COMPONENT_FRAMEBUFFER_LIMIT, PLANE_SIZE,
+    SIMULATIONS_PER_SECOND,
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, marker::PhantomData};
+
+pub use metrics::MetricsServer;
+pub use net::ClientDisconnected;
 
+mod builder;
+mod metrics;
 mod net;
+mod persistence;
 mod player_updates;
+mod relevance;
+mod sessions;
+mod validation;
 
+/// Serves clients of the transport that the server is built with (`use-udp` or `use-webrtc`).
+/// It can't use `CompositeTransport` to serve both, as `NetworkResource` is driven by
+/// `NetworkingPlugin` instead of the composite transport, and only one socket implementation can be
+/// compiled in.
 pub struct MuddleServerPlugin;
 
 impl Plugin for MuddleServerPlugin {
@@ -34,43 +72,196 @@ impl Plugin for MuddleServerPlugin {
         builder.add_plugin(bevy::diagnostic::DiagnosticsPlugin::default());
         builder.add_plugin(bevy::app::ScheduleRunnerPlugin::default());
 
-        builder.add_startup_system(init_level.system());
         builder.add_startup_system(startup.system());
+        if let Some(addr) = metrics_addr() {
+            match MetricsServer::bind(addr) {
+                Ok(metrics_server) => {
+                    log::info!("Serving metrics on {}", addr);
+                    builder.insert_resource(metrics_server);
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to bind the metrics endpoint to {}, running without metrics: {}",
+                        addr,
+                        err
+                    );
+                }
+            }
+        }
+        build_server::<NetworkResource, _>(
+            builder,
+            FixedTimestep::steps_per_second(SIMULATIONS_PER_SECOND as f64),
+        );
+    }
+}
 
-        let input_stage = SystemStage::single_threaded()
-            .with_system(process_network_events.system())
-            .with_system(process_player_input_updates.system());
-        let broadcast_updates_stage =
-            SystemStage::parallel().with_system(send_network_updates.system());
+/// Runs the server on top of an in-process transport (`LoopbackTransport`, or
+/// `CompositeTransport` of several ones), which is expected to be inserted as a resource
+/// (see `LoopbackHub::server`) before adding the plugin. Every `App::update` call simulates exactly
+/// one frame, which lets tests step a server deterministically.
+///
+/// Unlike `MuddleServerPlugin`, it doesn't add `LogPlugin`, as it can be initialized only once per
+/// process.
+pub struct MuddleLoopbackServerPlugin<T: Transport = LoopbackTransport>(PhantomData<T>);
 
-        // Game.
-        builder.add_plugin(MuddleSharedPlugin::new(
-            FixedTimestep::steps_per_second(SIMULATIONS_PER_SECOND as f64),
-            input_stage,
-            broadcast_updates_stage,
-            SystemStage::single_threaded(),
-            None,
-        ));
-
-        let resources = builder.world_mut();
-        resources.get_resource_or_insert_with(EntityNetId::default);
-        resources.get_resource_or_insert_with(PlayerNetId::default);
-        resources.get_resource_or_insert_with(PlayerConnections::default);
-        resources.get_resource_or_insert_with(Vec::<(PlayerNetId, u32)>::default);
-        resources.get_resource_or_insert_with(HashMap::<u32, ConnectionState>::default);
-        resources.get_resource_or_insert_with(DeferredUpdates::<PlayerInput>::default);
+impl<T: Transport> Default for MuddleLoopbackServerPlugin<T> {
+    fn default() -> Self {
+        Self(PhantomData)
     }
 }
 
+impl<T: Transport> Plugin for MuddleLoopbackServerPlugin<T> {
+    fn build(&self, builder: &mut AppBuilder) {
+        builder.add_plugin(bevy::core::CorePlugin::default());
+        builder.add_plugin(bevy::transform::TransformPlugin::default());
+        builder.add_plugin(bevy::diagnostic::DiagnosticsPlugin::default());
+
+        build_server::<T, _>(builder, run_every_update.system());
+    }
+}
+
+fn build_server<T: Transport, S: System<In = (), Out = ShouldRun>>(
+    builder: &mut AppBuilder,
+    main_run_criteria: S,
+) {
+    T::build(builder);
+
+    builder.insert_resource(LevelFilePath(level_file_path()));
+    builder.insert_resource(respawn_delay());
+    builder.insert_resource(relevance());
+    builder.insert_resource(LinkConditioner::new(LinkConditionerSettings::from_env()));
+    builder.add_event::<SaveLevelRequest>();
+    builder.add_event::<ClientDisconnected>();
+    builder.add_startup_system(init_level.system());
+    builder.add_system(process_save_level_requests.system());
+    builder.add_system(disconnect_clients_on_exit::<T>.system());
+    builder.add_system(count_disconnects.system());
+    builder.add_system(serve_metrics.system());
+
+    let input_stage = SystemStage::single_threaded()
+        .with_system(process_network_events::<T>.system())
+        .with_system(process_player_input_updates.system())
+        .with_system(process_level_edit_requests.system());
+    let broadcast_updates_stage =
+        SystemStage::parallel().with_system(send_network_updates::<T>.system());
+
+    // Game.
+    builder.add_plugin(MuddleSharedPlugin::new(
+        SimulationRole::Server,
+        main_run_criteria,
+        input_stage,
+        broadcast_updates_stage,
+        SystemStage::single_threaded(),
+        None,
+    ));
+    builder.add_stage_before(
+        stage::MAIN_SCHEDULE,
+        TICK_TIMER_START,
+        SystemStage::single_threaded().with_system(start_tick_timer.system()),
+    );
+    builder.add_stage_after(
+        stage::MAIN_SCHEDULE,
+        TICK_TIMER_END,
+        SystemStage::single_threaded().with_system(stop_tick_timer.system()),
+    );
+
+    let resources = builder.world_mut();
+    resources.get_resource_or_insert_with(EntityNetId::default);
+    resources.get_resource_or_insert_with(PlayerNetId::default);
+    resources.get_resource_or_insert_with(PlayerConnections::default);
+    resources.get_resource_or_insert_with(Vec::<NewPlayerConnection>::default);
+    resources.get_resource_or_insert_with(HashMap::<u32, ConnectionState>::default);
+    resources.get_resource_or_insert_with(HashMap::<u32, SnapshotHistory>::default);
+    resources.get_resource_or_insert_with(DeferredUpdates::<PlayerInput>::default);
+    resources.get_resource_or_insert_with(DeferredLevelEditRequests::default);
+    resources.get_resource_or_insert_with(PendingConfirmations::default);
+    resources.get_resource_or_insert_with(HashMap::<PlayerNetId, EditHistory>::default);
+    resources.get_resource_or_insert_with(LevelObjectUpdates::default);
+    resources.get_resource_or_insert_with(Sessions::default);
+    resources.get_resource_or_insert_with(ConnectionLimits::default);
+    resources.get_resource_or_insert_with(FullStateRequests::default);
+    resources.get_resource_or_insert_with(NetworkStats::default);
+    resources.get_resource_or_insert_with(Metrics::default);
+}
+
 pub fn init_level(
+    mut level_file_path: ResMut<LevelFilePath>,
     mut entity_net_id_counter: ResMut<EntityNetId>,
     mut spawn_level_object_commands: ResMut<GameCommands<SpawnLevelObject>>,
 ) {
-    spawn_level_object_commands.push(SpawnLevelObject {
-        frame_number: FrameNumber::new(0),
-        object: LevelObject {
-            net_id: entity_net_id_counter.increment(),
-            desc: LevelObjectDesc::Plane(PlaneDesc { size: PLANE_SIZE }),
-        },
-    });
+    let objects = match level_file_path.0.clone() {
+        Some(path) if path.exists() => {
+            log::info!("Loading the level from {}", path.display());
+            load_level(&path).unwrap_or_else(|err| {
+                log::error!(
+                    "Failed to load the level from {} (saving is disabled to keep the file intact), starting with the default level: {}",
+                    path.display(),
+                    err
+                );
+                level_file_path.0 = None;
+                default_level()
+            })
+        }
+        Some(path) => {
+            log::info!(
+                "Level file {} doesn't exist, starting with the default level",
+                path.display()
+            );
+            default_level()
+        }
+        None => default_level(),
+    };
+
+    for desc in objects {
+        spawn_level_object_commands.push(SpawnLevelObject {
+            frame_number: FrameNumber::new(0),
+            object: LevelObject {
+                net_id: entity_net_id_counter.increment(),
+                desc,
+            },
+        });
+    }
+}
+
+/// Reads the `MUDDLE_RESPAWN_DELAY_MS` env variable, defaults to 2 seconds. The delay can't be
+/// longer than the history we keep for entities (see `COMPONENT_FRAMEBUFFER_LIMIT`).
+fn respawn_delay() -> RespawnDelay {
+    let delay_millis = std::env::var("MUDDLE_RESPAWN_DELAY_MS")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_RESPAWN_DELAY_MS").map(str::to_owned))
+        .map(|delay| {
+            delay
+                .parse::<u32>()
+                .expect("Expected MUDDLE_RESPAWN_DELAY_MS to be a number of milliseconds")
+        });
+    match delay_millis {
+        Some(delay_millis) => RespawnDelay(FrameNumber::new(
+            (delay_millis as u64 * SIMULATIONS_PER_SECOND as u64 / 1000)
+                .min(COMPONENT_FRAMEBUFFER_LIMIT as u64 / 2) as u16,
+        )),
+        None => RespawnDelay::default(),
+    }
+}
+
+/// Reads the `MUDDLE_RELEVANCE_RADIUS` env variable, which sets the distance (in meters) within
+/// which players are replicated to a client every frame.
+fn relevance() -> Relevance {
+    std::env::var("MUDDLE_RELEVANCE_RADIUS")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_RELEVANCE_RADIUS").map(str::to_owned))
+        .map_or_else(Relevance::default, |radius| {
+            Relevance::new(
+                radius
+                    .parse::<f32>()
+                    .expect("Expected MUDDLE_RELEVANCE_RADIUS to be a number"),
+            )
+        })
+}
+
+fn run_every_update() -> ShouldRun {
+    ShouldRun::Yes
+}
+
+fn default_level() -> Vec<LevelObjectDesc> {
+    vec![LevelObjectDesc::Plane(PlaneDesc { size: PLANE_SIZE })]
 }
@@ -0,0 +1,399 @@
+use crate::net::ClientDisconnected;
+use bevy::{log, prelude::*};
+use mr_shared_lib::{
+    framebuffer::FrameNumber,
+    messages::DisconnectReason,
+    net::{ConnectionState, ConnectionStatus},
+    net_stats::NetworkStats,
+    GameTime,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{Read, Write as _},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+pub const TICK_TIMER_START: &str = "mr_server_tick_timer_start";
+pub const TICK_TIMER_END: &str = "mr_server_tick_timer_end";
+
+/// Scrapers that don't send a request in time get the response anyway.
+const REQUEST_READ_TIMEOUT_MILLIS: u64 = 1000;
+/// Responses are written by a single worker, so a slow scraper mustn't hold it for long.
+const RESPONSE_WRITE_TIMEOUT_MILLIS: u64 = 1000;
+/// Connections that are accepted while this many are waiting for the worker get dropped.
+const MAX_PENDING_RESPONSES: usize = 16;
+
+/// Serves metrics in the Prometheus text format. Requests are accepted by `serve_metrics`
+/// without blocking the game loop, responses are written by a worker thread.
+pub struct MetricsServer {
+    listener: TcpListener,
+    pending_responses: Mutex<SyncSender<(TcpStream, Arc<str>)>>,
+}
+
+impl MetricsServer {
+    pub fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let (pending_responses, receiver) =
+            sync_channel::<(TcpStream, Arc<str>)>(MAX_PENDING_RESPONSES);
+        // The worker stops once the server gets dropped.
+        std::thread::Builder::new()
+            .name("metrics".to_owned())
+            .spawn(move || {
+                for (stream, body) in receiver {
+                    if let Err(err) = respond(stream, &body) {
+                        log::warn!("Failed to respond to a metrics request: {:?}", err);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            listener,
+            pending_responses: Mutex::new(pending_responses),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    sum: f64,
+    count: u64,
+}
+
+impl Summary {
+    fn observe(&mut self, value: f64, count: u64) {
+        self.sum += value;
+        self.count += count;
+    }
+}
+
+/// Counters that aren't available from other resources (such as `ConnectionState` or
+/// `NetworkStats`) and are collected only for the metrics endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    tick_timer: Option<(Instant, FrameNumber)>,
+    tick_duration_secs: Summary,
+    rewind_depth_frames: Summary,
+    disconnects: HashMap<&'static str, u64>,
+}
+
+impl Metrics {
+    /// Is expected to be called once per `process_player_input_updates` run that rewinds
+    /// the simulation, with the number of frames that will get re-simulated.
+    pub fn add_rewind(&mut self, depth: u16) {
+        self.rewind_depth_frames.observe(depth as f64, 1);
+    }
+}
+
+pub fn start_tick_timer(time: Res<GameTime>, mut metrics: ResMut<Metrics>) {
+    metrics.tick_timer = Some((Instant::now(), time.frame_number));
+}
+
+/// The main schedule can run several ticks (or none) per update, so the elapsed time is divided
+/// between the ticks that have run.
+pub fn stop_tick_timer(time: Res<GameTime>, mut metrics: ResMut<Metrics>) {
+    let (started_at, frame_number) = match metrics.tick_timer.take() {
+        Some(timer) => timer,
+        None => return,
+    };
+    if time.frame_number <= frame_number {
+        return;
+    }
+    let ticks = (time.frame_number - frame_number).value() as u64;
+    metrics
+        .tick_duration_secs
+        .observe(started_at.elapsed().as_secs_f64(), ticks);
+}
+
+pub fn count_disconnects(
+    mut client_disconnected_events: EventReader<ClientDisconnected>,
+    mut metrics: ResMut<Metrics>,
+) {
+    for event in client_disconnected_events.iter() {
+        *metrics
+            .disconnects
+            .entry(disconnect_reason_label(event.reason))
+            .or_default() += 1;
+    }
+}
+
+pub fn serve_metrics(
+    metrics_server: Option<Res<MetricsServer>>,
+    metrics: Res<Metrics>,
+    connection_states: Res<HashMap<u32, ConnectionState>>,
+    network_stats: Res<NetworkStats>,
+) {
+    let metrics_server = match metrics_server {
+        Some(metrics_server) => metrics_server,
+        None => return,
+    };
+
+    let pending_responses = metrics_server.pending_responses.lock().unwrap();
+    let mut rendered: Option<Arc<str>> = None;
+    loop {
+        let stream = match metrics_server.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) => {
+                log::error!("Failed to accept a metrics connection: {:?}", err);
+                break;
+            }
+        };
+        let body = rendered
+            .get_or_insert_with(|| {
+                render_metrics(&metrics, &connection_states, &network_stats).into()
+            })
+            .clone();
+        match pending_responses.try_send((stream, body)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Too many pending metrics requests, dropping a connection");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                log::error!("The metrics worker has stopped");
+                break;
+            }
+        }
+    }
+}
+
+/// Reads the `MUDDLE_METRICS_PORT` env variable, the endpoint is disabled if it's not set.
+/// Listens on `127.0.0.1`, unless `MUDDLE_METRICS_IP_ADDR` is set.
+pub fn metrics_addr() -> Option<SocketAddr> {
+    let port = std::env::var("MUDDLE_METRICS_PORT")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_METRICS_PORT").map(str::to_owned))
+        .map(|port| {
+            port.parse::<u16>()
+                .expect("Expected MUDDLE_METRICS_PORT to be a port number")
+        })?;
+
+    let ip_addr = std::env::var("MUDDLE_METRICS_IP_ADDR")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_METRICS_IP_ADDR").map(str::to_owned))
+        .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |addr| {
+            addr.parse::<IpAddr>()
+                .expect("Expected MUDDLE_METRICS_IP_ADDR to be an ip address")
+        });
+    Some(SocketAddr::new(ip_addr, port))
+}
+
+fn respond(mut stream: TcpStream, body: &str) -> std::io::Result<()> {
+    // Accepted sockets may inherit the non-blocking mode of the listener on some platforms.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(REQUEST_READ_TIMEOUT_MILLIS)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(RESPONSE_WRITE_TIMEOUT_MILLIS)))?;
+
+    // We serve metrics for any path, so the request is read only to let a client finish sending
+    // it before we respond and close the connection.
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => request.extend_from_slice(&buf[..read]),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn render_metrics(
+    metrics: &Metrics,
+    connection_states: &HashMap<u32, ConnectionState>,
+    network_stats: &NetworkStats,
+) -> String {
+    let mut connected = connection_states
+        .iter()
+        .filter(|(_, connection_state)| {
+            matches!(connection_state.status(), ConnectionStatus::Connected)
+        })
+        .collect::<Vec<_>>();
+    connected.sort_unstable_by_key(|(handle, _)| **handle);
+    let mut traffic = network_stats.iter().collect::<Vec<_>>();
+    traffic.sort_unstable_by_key(|(handle, _)| *handle);
+
+    let mut out = String::new();
+    header(
+        &mut out,
+        "muddle_connected_players",
+        "gauge",
+        "Number of connected players.",
+    );
+    writeln!(out, "muddle_connected_players {}", connected.len()).unwrap();
+
+    header(
+        &mut out,
+        "muddle_connection_rtt_seconds",
+        "gauge",
+        "Round-trip time of a connection.",
+    );
+    for (handle, connection_state) in &connected {
+        writeln!(
+            out,
+            "muddle_connection_rtt_seconds{{connection=\"{}\"}} {}",
+            handle,
+            connection_state.rtt_millis() / 1000.0
+        )
+        .unwrap();
+    }
+    header(
+        &mut out,
+        "muddle_connection_packet_loss_ratio",
+        "gauge",
+        "Ratio of lost packets of a connection.",
+    );
+    for (handle, connection_state) in &connected {
+        writeln!(
+            out,
+            "muddle_connection_packet_loss_ratio{{connection=\"{}\"}} {}",
+            handle,
+            connection_state.packet_loss()
+        )
+        .unwrap();
+    }
+    header(
+        &mut out,
+        "muddle_connection_jitter_seconds",
+        "gauge",
+        "Jitter of a connection.",
+    );
+    for (handle, connection_state) in &connected {
+        writeln!(
+            out,
+            "muddle_connection_jitter_seconds{{connection=\"{}\"}} {}",
+            handle,
+            connection_state.jitter_millis() / 1000.0
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "muddle_connection_messages_per_second",
+        "gauge",
+        "Moving average of messages per second of a connection.",
+    );
+    for (handle, traffic) in &traffic {
+        for (direction, stats) in &[("sent", &traffic.sent), ("received", &traffic.received)] {
+            writeln!(
+                out,
+                "muddle_connection_messages_per_second{{connection=\"{}\",direction=\"{}\"}} {}",
+                handle,
+                direction,
+                stats.messages_per_second()
+            )
+            .unwrap();
+        }
+    }
+    header(
+        &mut out,
+        "muddle_connection_kilobits_per_second",
+        "gauge",
+        "Moving average of traffic of a connection.",
+    );
+    for (handle, traffic) in &traffic {
+        for (direction, stats) in &[("sent", &traffic.sent), ("received", &traffic.received)] {
+            writeln!(
+                out,
+                "muddle_connection_kilobits_per_second{{connection=\"{}\",direction=\"{}\"}} {}",
+                handle,
+                direction,
+                stats.kbps()
+            )
+            .unwrap();
+        }
+    }
+
+    header(
+        &mut out,
+        "muddle_tick_duration_seconds",
+        "summary",
+        "Time spent on simulation ticks.",
+    );
+    summary(
+        &mut out,
+        "muddle_tick_duration_seconds",
+        &metrics.tick_duration_secs,
+    );
+    header(
+        &mut out,
+        "muddle_rewind_depth_frames",
+        "summary",
+        "Number of frames re-simulated by rewinds to apply late inputs.",
+    );
+    summary(
+        &mut out,
+        "muddle_rewind_depth_frames",
+        &metrics.rewind_depth_frames,
+    );
+
+    header(
+        &mut out,
+        "muddle_disconnects_total",
+        "counter",
+        "Number of disconnected (or rejected) clients.",
+    );
+    let mut disconnects = metrics.disconnects.iter().collect::<Vec<_>>();
+    disconnects.sort_unstable_by_key(|(reason, _)| **reason);
+    for (reason, count) in disconnects {
+        writeln!(
+            out,
+            "muddle_disconnects_total{{reason=\"{}\"}} {}",
+            reason, count
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+fn summary(out: &mut String, name: &str, summary: &Summary) {
+    writeln!(out, "{}_sum {}", name, summary.sum).unwrap();
+    writeln!(out, "{}_count {}", name, summary.count).unwrap();
+}
+
+fn disconnect_reason_label(reason: DisconnectReason) -> &'static str {
+    match reason {
+        DisconnectReason::ConnectionClosed => "connection_closed",
+        DisconnectReason::InvalidUpdate => "invalid_update",
+        DisconnectReason::Lagging => "lagging",
+        DisconnectReason::HandshakeTimeout => "handshake_timeout",
+        DisconnectReason::Idle => "idle",
+        DisconnectReason::TooManyViolations => "too_many_violations",
+        DisconnectReason::Kicked => "kicked",
+        DisconnectReason::ServerShutdown => "server_shutdown",
+        DisconnectReason::VersionMismatch(_) => "version_mismatch",
+    }
+}
@@ -1,27 +1,67 @@
-use crate::player_updates::DeferredUpdates;
-use bevy::{ecs::system::SystemParam, log, prelude::*, utils::HashSet};
+use crate::{
+    builder::{
+        DeferredLevelEditRequests, LevelEditRequest, LevelObjectUpdates, PendingConfirmations,
+    },
+    persistence::SaveLevelRequest,
+    player_updates::{DeferredUpdates, LAG_COMPENSATED_FRAMES},
+    relevance::Relevance,
+    sessions::{stop_player, Sessions},
+    validation::{validate_inputs, ConnectionLimits, Violation},
+};
+use bevy::{app::AppExit, ecs::system::SystemParam, log, prelude::*, utils::HashSet};
 use bevy_networking_turbulence::{NetworkEvent, NetworkResource};
 use chrono::Utc;
 use mr_shared_lib::{
+    codec::{
+        decode_player_update, encode_delta_update, position_checksum, CodecError, SnapshotHistory,
+    },
+    framebuffer::FrameNumber,
     game::{
         commands::{DespawnPlayer, GameCommands, SpawnLevelObject, SpawnPlayer},
-        components::{PlayerDirection, Position, Spawned},
+        components::{PlayerDirection, Position, Spawned, Velocity},
         level::LevelState,
+        race::RaceState,
+        respawn::RespawnState,
     },
+    link_conditioner::LinkConditioner,
     messages::{
-        ConnectedPlayer, DeltaUpdate, DisconnectedPlayer, Message, PlayerInput, PlayerNetId,
-        PlayerState, ReliableClientMessage, ReliableServerMessage, StartGame,
-        UnreliableClientMessage, UnreliableServerMessage,
+        ConfirmedAction, ConnectedPlayer, ConnectionRejected, DeltaUpdate, DisconnectReason,
+        DisconnectedPlayer, FullState, Message, PackedDeltaUpdate, PackedPlayerUpdate, PlayerInput,
+        PlayerNetId, PlayerState, PositionsChecksum, RejectionReason, ReliableClientMessage,
+        ReliableServerMessage, StartGame, UnreliableClientMessage, UnreliableServerMessage,
     },
-    net::{ConnectionState, ConnectionStatus, SessionId, CONNECTION_TIMEOUT_MILLIS},
-    player::{random_name, Player},
+    net::{
+        schema_hash, AcknowledgeError, ConnectionState, ConnectionStatus, SessionId,
+        CONNECTION_TIMEOUT_MILLIS, PROTOCOL_VERSION,
+    },
+    net_stats::NetworkStats,
+    player::{random_name, Player, PlayerUpdates},
     registry::{EntityRegistry, Registry},
-    GameTime, COMPONENT_FRAMEBUFFER_LIMIT,
+    transport::Transport,
+    GameTime, COMPONENT_FRAMEBUFFER_LIMIT, PLAYER_SIZE, SIMULATIONS_PER_SECOND,
 };
 use std::{
     collections::{hash_map::Entry, HashMap},
     net::{IpAddr, Ipv4Addr, SocketAddr},
 };
+use thiserror::Error;
+
+/// Position checksums are sent once a second.
+const CHECKSUM_INTERVAL_FRAMES: u16 = SIMULATIONS_PER_SECOND;
+/// Is bigger than the number of frames that the server can rewind to apply late inputs (see
+/// `MAX_LAG_COMPENSATION_MILLIS`), so that the checksummed positions are final.
+const CHECKSUM_FRAME_DELAY: u16 = SIMULATIONS_PER_SECOND / 2;
+/// Preparing a full state is as expensive as starting a game, so a client gets it at most once
+/// a second.
+const FULL_STATE_INTERVAL_FRAMES: u16 = SIMULATIONS_PER_SECOND;
+
+type PlayerEntitiesQuery<'a> = (
+    Entity,
+    &'a Position,
+    &'a Velocity,
+    &'a PlayerDirection,
+    &'a Spawned,
+);
 
 pub fn startup(mut net: ResMut<NetworkResource>) {
     log::info!("Starting the server");
@@ -33,27 +73,131 @@ pub fn startup(mut net: ResMut<NetworkResource>) {
 
 pub type PlayerConnections = Registry<PlayerNetId, u32>;
 
+/// Connections that have completed the handshake and are waiting for `StartGame`.
+pub struct NewPlayerConnection {
+    pub player_net_id: PlayerNetId,
+    pub handle: u32,
+    /// Other clients don't get notified about players resuming their sessions, as suspended
+    /// players aren't announced as disconnected.
+    pub is_resumed: bool,
+}
+
+/// Connections that have requested `FullState` (see `ReliableClientMessage::RequestFullState`).
+#[derive(Default)]
+pub struct FullStateRequests {
+    pending: Vec<u32>,
+    served_at: HashMap<u32, FrameNumber>,
+}
+
+impl FullStateRequests {
+    pub fn push(&mut self, connection_handle: u32) {
+        if !self.pending.contains(&connection_handle) {
+            self.pending.push(connection_handle);
+        }
+    }
+
+    /// Returns the connections that can be served at the frame, the rest of them stay pending
+    /// until `FULL_STATE_INTERVAL_FRAMES` pass.
+    pub fn take_servable(&mut self, frame_number: FrameNumber) -> Vec<u32> {
+        let served_at = &self.served_at;
+        let (servable, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|connection_handle| {
+                served_at.get(connection_handle).map_or(true, |&served_at| {
+                    (frame_number - served_at).value() >= FULL_STATE_INTERVAL_FRAMES
+                })
+            });
+        self.pending = pending;
+        for &connection_handle in &servable {
+            self.served_at.insert(connection_handle, frame_number);
+        }
+        servable
+    }
+
+    pub fn remove_connection(&mut self, connection_handle: u32) {
+        self.pending.retain(|&handle| handle != connection_handle);
+        self.served_at.remove(&connection_handle);
+    }
+}
+
+/// Is sent when a client gets disconnected (or rejected when connecting), so that game code
+/// could react to it.
+pub struct ClientDisconnected {
+    pub handle: u32,
+    /// Is `None` if a client disconnected before we registered a player for it.
+    pub player_net_id: Option<PlayerNetId>,
+    pub reason: DisconnectReason,
+}
+
+/// Is returned when a client sends data that we can't make sense of. Such clients get
+/// disconnected instead of being trusted with anything else they send.
+#[derive(Error, Debug)]
+pub enum ClientMessageError {
+    #[error("failed to decode a player update: {0}")]
+    Decode(#[from] CodecError),
+    #[error("failed to acknowledge an incoming packet (update frame: {frame_number}): {err}")]
+    AcknowledgeIncoming {
+        frame_number: FrameNumber,
+        err: AcknowledgeError,
+    },
+    #[error(
+        "failed to apply outgoing packet acknowledgments (update frame: {frame_number}): {err}"
+    )]
+    ApplyAcknowledgments {
+        frame_number: FrameNumber,
+        err: AcknowledgeError,
+    },
+    #[error("input is out of sync (input frame: {input_frame})")]
+    OutOfSync { input_frame: FrameNumber },
+    /// Unlike other errors, violations don't result in disconnecting right away.
+    #[error(transparent)]
+    Violation(#[from] Violation),
+}
+
+impl ClientMessageError {
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            ClientMessageError::OutOfSync { .. } => DisconnectReason::Lagging,
+            ClientMessageError::Violation(_) => DisconnectReason::TooManyViolations,
+            _ => DisconnectReason::InvalidUpdate,
+        }
+    }
+}
+
 #[derive(SystemParam)]
 pub struct UpdateParams<'a> {
     deferred_player_updates: ResMut<'a, DeferredUpdates<PlayerInput>>,
     spawn_player_commands: ResMut<'a, GameCommands<SpawnPlayer>>,
     despawn_player_commands: ResMut<'a, GameCommands<DespawnPlayer>>,
+    save_level_requests: EventWriter<'a, SaveLevelRequest>,
+    client_disconnected_events: EventWriter<'a, ClientDisconnected>,
+    level_edit_requests: ResMut<'a, DeferredLevelEditRequests>,
+    level_state: Res<'a, LevelState>,
+    respawn_state: ResMut<'a, RespawnState>,
+    sessions: ResMut<'a, Sessions>,
+    player_updates: ResMut<'a, PlayerUpdates>,
 }
 
 #[derive(SystemParam)]
-pub struct NetworkParams<'a> {
-    net: ResMut<'a, NetworkResource>,
+pub struct NetworkParams<'a, T: Transport> {
+    net: ResMut<'a, T>,
     connection_states: ResMut<'a, HashMap<u32, ConnectionState>>,
+    snapshot_histories: ResMut<'a, HashMap<u32, SnapshotHistory>>,
+    relevance: ResMut<'a, Relevance>,
+    link_conditioner: ResMut<'a, LinkConditioner>,
+    connection_limits: ResMut<'a, ConnectionLimits>,
+    full_state_requests: ResMut<'a, FullStateRequests>,
+    network_stats: ResMut<'a, NetworkStats>,
     player_connections: ResMut<'a, PlayerConnections>,
-    new_player_connections: ResMut<'a, Vec<(PlayerNetId, u32)>>,
+    new_player_connections: ResMut<'a, Vec<NewPlayerConnection>>,
 }
 
-pub fn process_network_events(
+pub fn process_network_events<T: Transport>(
     mut despawned_players_for_handles: Local<HashSet<u32>>,
     time: Res<GameTime>,
     mut players: ResMut<HashMap<PlayerNetId, Player>>,
     mut network_events: EventReader<NetworkEvent>,
-    mut network_params: NetworkParams,
+    mut network_params: NetworkParams<T>,
     mut update_params: UpdateParams,
 ) {
     log::trace!("Processing network updates (frame: {})", time.frame_number);
@@ -83,10 +227,15 @@ pub fn process_network_events(
             }
             NetworkEvent::Disconnected(handle) => {
                 log::info!("Disconnected: {}", handle);
-                let connection_state = network_params
-                    .connection_states
-                    .get_mut(&handle)
-                    .expect("Expected a connection when receiving a Disconnect event");
+                let connection_state = match network_params.connection_states.get_mut(&handle) {
+                    Some(connection_state) => connection_state,
+                    None => {
+                        log::warn!(
+                            "Received a Disconnected event for an unknown connection, skipped"
+                        );
+                        continue;
+                    }
+                };
                 if matches!(
                     connection_state.status(),
                     ConnectionStatus::Disconnecting | ConnectionStatus::Disconnected
@@ -94,7 +243,7 @@ pub fn process_network_events(
                     log::info!("Received a Disconnected event for a player that's already disconnected, skipped");
                     continue;
                 }
-                connection_state.set_status(ConnectionStatus::Disconnecting);
+                connection_state.disconnect(DisconnectReason::ConnectionClosed);
             }
             NetworkEvent::Error(handle, err) => {
                 log::error!("Network error ({}): {:?}", handle, err);
@@ -105,22 +254,67 @@ pub fn process_network_events(
 
     let mut initialize_messages_to_send = Vec::new();
     let mut handshake_messages_to_send = Vec::new();
+    let mut rejection_messages_to_send = Vec::new();
 
     // Reading message channels.
-    for (handle, connection) in network_params.net.connections.iter_mut() {
-        let channels = connection.channels().unwrap();
-
-        'channel: while let Some(client_message) =
-            channels.recv::<Message<UnreliableClientMessage>>()
+    for handle in network_params.net.connection_handles().iter() {
+        'channel: while let Some(client_message) = network_params
+            .link_conditioner
+            .recv_message::<Message<UnreliableClientMessage>, _>(&mut *network_params.net, *handle)
         {
             log::trace!(
                 "UnreliableClientMessage received on [{}]: {:?}",
                 handle,
                 client_message
             );
+            network_params
+                .network_stats
+                .add_received(*handle, &client_message);
+
+            if let Err(violation) = network_params
+                .connection_limits
+                .take_message_token(*handle, time.frame_number)
+            {
+                if add_violation(&mut network_params, *handle, time.frame_number, violation) {
+                    break 'channel;
+                }
+                continue;
+            }
 
-            if let UnreliableClientMessage::Connect(message_id) = &client_message.message {
+            if let UnreliableClientMessage::Connect(connect_request) = &client_message.message {
+                let message_id = &connect_request.handshake_id;
                 log::info!("New client ({}) Connect message: {}", handle, message_id);
+                if let Some(reason) =
+                    RejectionReason::check(PROTOCOL_VERSION, schema_hash(), connect_request)
+                {
+                    log::warn!(
+                        "Rejecting client ({}): {:?} (client protocol version: {}, server protocol version: {})",
+                        handle,
+                        reason,
+                        connect_request.protocol_version,
+                        PROTOCOL_VERSION
+                    );
+                    update_params
+                        .client_disconnected_events
+                        .send(ClientDisconnected {
+                            handle: *handle,
+                            player_net_id: None,
+                            reason: DisconnectReason::VersionMismatch(reason),
+                        });
+                    rejection_messages_to_send.push((
+                        *handle,
+                        Message {
+                            session_id: SessionId::new(0),
+                            message: UnreliableServerMessage::ConnectionRejected(
+                                ConnectionRejected {
+                                    reason,
+                                    server_protocol_version: PROTOCOL_VERSION,
+                                },
+                            ),
+                        },
+                    ));
+                    continue;
+                }
                 let connection_state_entry = match network_params.connection_states.entry(*handle) {
                     Entry::Occupied(connection_state_entry) => {
                         let connection_state = connection_state_entry.get();
@@ -208,60 +402,70 @@ pub fn process_network_events(
 
             match client_message {
                 UnreliableClientMessage::PlayerUpdate(update) => {
-                    if let Err(err) = connection_state.acknowledge_incoming(update.frame_number) {
-                        connection_state.set_status(ConnectionStatus::Disconnecting);
-                        log::error!(
-                            "Failed to acknowledge an incoming packet (player: {}, update frame: {}, current frame: {}), disconnecting: {:?}",
-                            player_net_id.0,
-                                    update.frame_number,
-                            time.frame_number,
-                            err
-                        );
-                        break 'channel;
-                    }
-                    if let (Some(frame_number), ack_bit_set) = update.acknowledgments {
-                        if let Err(err) = connection_state
-                            .apply_outgoing_acknowledgements(frame_number, ack_bit_set)
-                        {
-                            connection_state.set_status(ConnectionStatus::Disconnecting);
-                            log::error!(
-                                "Failed to apply outgoing packet acknowledgments (player: {}, update frame: {}, current frame: {}), disconnecting: {:?}",
-                                player_net_id.0,
-                                update.frame_number,
+                    match process_player_update(
+                        &update,
+                        player_net_id,
+                        connection_state,
+                        time.frame_number,
+                        &mut update_params.deferred_player_updates,
+                    ) {
+                        Ok(()) => {}
+                        Err(ClientMessageError::Violation(violation)) => {
+                            if add_violation(
+                                &mut network_params,
+                                *handle,
                                 time.frame_number,
-                                err
-                            );
-                            break 'channel;
+                                violation,
+                            ) {
+                                break 'channel;
+                            }
                         }
-                    }
-                    for input in update.inputs {
-                        if input.frame_number.diff_abs(time.frame_number).value()
-                            > COMPONENT_FRAMEBUFFER_LIMIT / 2
-                        {
+                        Err(err) => {
                             log::warn!(
-                                "Player {} is out of sync (input frame {}, current frame: {}), disconnecting",
+                                "Invalid PlayerUpdate message (player: {}, current frame: {}), disconnecting: {}",
                                 player_net_id.0,
-                                input.frame_number,
-                                time.frame_number
+                                time.frame_number,
+                                err
                             );
-                            connection_state.set_status(ConnectionStatus::Disconnecting);
+                            connection_state.disconnect(err.disconnect_reason());
                             break 'channel;
                         }
-                        update_params
-                            .deferred_player_updates
-                            .push(player_net_id, input);
                     }
                 }
                 UnreliableClientMessage::Connect(_) => {}
             }
         }
 
-        while let Some(client_message) = channels.recv::<Message<ReliableClientMessage>>() {
+        while let Some(client_message) = network_params.link_conditioner.recv_message::<Message<
+            ReliableClientMessage,
+        >, _>(
+            &mut *network_params.net,
+            *handle,
+        ) {
             log::trace!(
                 "ReliableClientMessage received on [{}]: {:?}",
                 handle,
                 client_message
             );
+            network_params
+                .network_stats
+                .add_received(*handle, &client_message);
+
+            let is_rate_limited = !matches!(
+                client_message.message,
+                ReliableClientMessage::Initialize | ReliableClientMessage::Handshake(_)
+            );
+            if is_rate_limited {
+                if let Err(violation) = network_params
+                    .connection_limits
+                    .take_reliable_message_token(*handle, time.frame_number)
+                {
+                    if add_violation(&mut network_params, *handle, time.frame_number, violation) {
+                        break;
+                    }
+                    continue;
+                }
+            }
 
             match client_message.message {
                 ReliableClientMessage::Initialize => {
@@ -276,12 +480,19 @@ pub fn process_network_events(
                 }
                 // NOTE: before adding new messages, make sure to ignore them if connection status
                 // is not `Connected`.
-                ReliableClientMessage::Handshake(handshake_id) => {
+                ReliableClientMessage::Handshake(client_handshake) => {
+                    let handshake_id = client_handshake.handshake_id;
                     log::info!("Client ({}) handshake: {}", handle, handshake_id);
-                    let connection_state = network_params
-                        .connection_states
-                        .get_mut(handle)
-                        .expect("Expected a connection state for an existing connection");
+                    let connection_state = match network_params.connection_states.get_mut(handle) {
+                        Some(connection_state) => connection_state,
+                        None => {
+                            log::warn!(
+                                "Ignoring a client's ({}) Handshake message: no Connect message was received",
+                                handle
+                            );
+                            continue;
+                        }
+                    };
 
                     if connection_state.handshake_id != handshake_id
                         || !matches!(connection_state.status(), ConnectionStatus::Connecting)
@@ -296,19 +507,66 @@ pub fn process_network_events(
                         break;
                     }
 
-                    let player_net_id = network_params.player_connections.register(*handle);
                     connection_state.set_status(ConnectionStatus::Handshaking);
                     connection_state.last_message_received_at = Utc::now();
 
+                    let resumed_player_net_id = client_handshake
+                        .session_token
+                        .and_then(|session_token| update_params.sessions.resume(session_token))
+                        .filter(|player_net_id| players.contains_key(player_net_id));
+                    if let Some(player_net_id) = resumed_player_net_id {
+                        log::info!(
+                            "Client ({}) resumed the session of player {}",
+                            handle,
+                            player_net_id.0
+                        );
+                        // The server might not have detected yet that the previous connection
+                        // got lost.
+                        if let Some(previous_handle) = network_params
+                            .player_connections
+                            .remove_by_id(player_net_id)
+                        {
+                            if let Some(previous_connection_state) =
+                                network_params.connection_states.get_mut(&previous_handle)
+                            {
+                                previous_connection_state
+                                    .disconnect(DisconnectReason::ConnectionClosed);
+                            }
+                            // The previous connection might have been waiting for `StartGame`.
+                            network_params
+                                .new_player_connections
+                                .retain(|connection| connection.handle != previous_handle);
+                        }
+                        network_params
+                            .player_connections
+                            .insert(player_net_id, *handle);
+                        network_params
+                            .new_player_connections
+                            .push(NewPlayerConnection {
+                                player_net_id,
+                                handle: *handle,
+                                is_resumed: true,
+                            });
+                        continue;
+                    }
+
+                    let player_net_id = network_params.player_connections.register(*handle);
                     network_params
                         .new_player_connections
-                        .push((player_net_id, *handle));
+                        .push(NewPlayerConnection {
+                            player_net_id,
+                            handle: *handle,
+                            is_resumed: false,
+                        });
 
                     let nickname = random_name();
                     players.insert(player_net_id, Player { nickname });
+                    let start_position = update_params
+                        .respawn_state
+                        .next_spawn_position(&update_params.level_state);
                     update_params.spawn_player_commands.push(SpawnPlayer {
                         net_id: player_net_id,
-                        start_position: Vec2::ZERO,
+                        start_position,
                         is_player_frame_simulated: false,
                     });
                     // Add an initial update to have something to extrapolate from.
@@ -317,17 +575,77 @@ pub fn process_network_events(
                         PlayerInput {
                             frame_number: time.frame_number,
                             direction: Vec2::ZERO,
+                            jump: false,
                         },
                     );
                 }
+                ReliableClientMessage::SaveLevel => {
+                    if !is_connected(&network_params.connection_states, *handle) {
+                        continue;
+                    }
+                    log::info!("Client ({}) requested to save the level", handle);
+                    update_params.save_level_requests.send(SaveLevelRequest);
+                }
+                ReliableClientMessage::SpawnLevelObject(request) => {
+                    if !is_connected(&network_params.connection_states, *handle) {
+                        continue;
+                    }
+                    update_params
+                        .level_edit_requests
+                        .push(*handle, LevelEditRequest::Spawn(request));
+                }
+                ReliableClientMessage::UpdateLevelObject(request) => {
+                    if !is_connected(&network_params.connection_states, *handle) {
+                        continue;
+                    }
+                    update_params
+                        .level_edit_requests
+                        .push(*handle, LevelEditRequest::Update(request));
+                }
+                ReliableClientMessage::DespawnLevelObject(request) => {
+                    if !is_connected(&network_params.connection_states, *handle) {
+                        continue;
+                    }
+                    update_params
+                        .level_edit_requests
+                        .push(*handle, LevelEditRequest::Despawn(request));
+                }
+                ReliableClientMessage::UndoLevelEdit => {
+                    if !is_connected(&network_params.connection_states, *handle) {
+                        continue;
+                    }
+                    update_params
+                        .level_edit_requests
+                        .push(*handle, LevelEditRequest::Undo);
+                }
+                ReliableClientMessage::RedoLevelEdit => {
+                    if !is_connected(&network_params.connection_states, *handle) {
+                        continue;
+                    }
+                    update_params
+                        .level_edit_requests
+                        .push(*handle, LevelEditRequest::Redo);
+                }
+                ReliableClientMessage::RequestFullState => {
+                    if !is_connected(&network_params.connection_states, *handle) {
+                        continue;
+                    }
+                    log::info!("Client ({}) requested the full game state", handle);
+                    network_params.full_state_requests.push(*handle);
+                }
             }
         }
 
-        while channels.recv::<Message<ReliableServerMessage>>().is_some() {
+        while network_params
+            .link_conditioner
+            .recv_message::<Message<ReliableServerMessage>, _>(&mut *network_params.net, *handle)
+            .is_some()
+        {
             log::error!("Unexpected ReliableServerMessage received on [{}]", handle);
         }
-        while channels
-            .recv::<Message<UnreliableServerMessage>>()
+        while network_params
+            .link_conditioner
+            .recv_message::<Message<UnreliableServerMessage>, _>(&mut *network_params.net, *handle)
             .is_some()
         {
             log::error!(
@@ -338,15 +656,32 @@ pub fn process_network_events(
     }
 
     for (handle, message) in initialize_messages_to_send {
-        if let Err(err) = network_params.net.send_message(handle, message) {
+        if let Err(err) =
+            network_params
+                .network_stats
+                .send_message(&mut *network_params.net, handle, message)
+        {
             log::error!("Failed to send Initialize message: {:?}", err);
         }
     }
     for (handle, message) in handshake_messages_to_send {
-        if let Err(err) = network_params.net.send_message(handle, message) {
+        if let Err(err) =
+            network_params
+                .network_stats
+                .send_message(&mut *network_params.net, handle, message)
+        {
             log::error!("Failed to send Handshake message: {:?}", err);
         }
     }
+    for (handle, message) in rejection_messages_to_send {
+        if let Err(err) =
+            network_params
+                .network_stats
+                .send_message(&mut *network_params.net, handle, message)
+        {
+            log::error!("Failed to send ConnectionRejected message: {:?}", err);
+        }
+    }
 
     disconnect_players(
         &mut despawned_players_for_handles,
@@ -356,10 +691,97 @@ pub fn process_network_events(
     );
 }
 
-fn disconnect_players(
+/// Inputs are deferred only if the whole update is valid.
+fn process_player_update(
+    update: &PackedPlayerUpdate,
+    player_net_id: PlayerNetId,
+    connection_state: &mut ConnectionState,
+    current_frame: FrameNumber,
+    deferred_player_updates: &mut DeferredUpdates<PlayerInput>,
+) -> Result<(), ClientMessageError> {
+    let mut update = decode_player_update(&update.0)?;
+    validate_inputs(&mut update.inputs)?;
+    connection_state
+        .acknowledge_incoming(update.frame_number)
+        .map_err(|err| ClientMessageError::AcknowledgeIncoming {
+            frame_number: update.frame_number,
+            err,
+        })?;
+    if let (Some(frame_number), ack_bit_set) = update.acknowledgments {
+        connection_state
+            .apply_outgoing_acknowledgements(frame_number, ack_bit_set)
+            .map_err(|err| ClientMessageError::ApplyAcknowledgments {
+                frame_number: update.frame_number,
+                err,
+            })?;
+    }
+    if let Some(input) = update.inputs.iter().find(|input| {
+        input.frame_number.diff_abs(current_frame).value() > COMPONENT_FRAMEBUFFER_LIMIT / 2
+    }) {
+        return Err(ClientMessageError::OutOfSync {
+            input_frame: input.frame_number,
+        });
+    }
+    // Inputs of such an update would get discarded anyway, but a client isn't expected to lag
+    // that much.
+    if update.frame_number < current_frame - FrameNumber::new(LAG_COMPENSATED_FRAMES) {
+        return Err(Violation::LateUpdate(update.frame_number).into());
+    }
+    for input in update.inputs {
+        deferred_player_updates.push(player_net_id, input);
+    }
+    Ok(())
+}
+
+/// Counts a violation, disconnecting a client once it exceeds the limit. Returns `true` if
+/// the client got disconnected.
+fn add_violation<T: Transport>(
+    network_params: &mut NetworkParams<T>,
+    handle: u32,
+    frame_number: FrameNumber,
+    violation: Violation,
+) -> bool {
+    log::debug!("Client ({}) violation: {}", handle, violation);
+    if !network_params
+        .connection_limits
+        .add_violation(handle, frame_number)
+    {
+        return false;
+    }
+    log::warn!(
+        "Client ({}) has exceeded the limit of violations, disconnecting",
+        handle
+    );
+    if let Some(connection_state) = network_params.connection_states.get_mut(&handle) {
+        if !matches!(
+            connection_state.status(),
+            ConnectionStatus::Disconnecting | ConnectionStatus::Disconnected
+        ) {
+            connection_state.disconnect(DisconnectReason::TooManyViolations);
+        }
+    }
+    true
+}
+
+fn is_connected(connection_states: &HashMap<u32, ConnectionState>, handle: u32) -> bool {
+    let is_connected = connection_states
+        .get(&handle)
+        .map_or(false, |connection_state| {
+            matches!(connection_state.status(), ConnectionStatus::Connected)
+        });
+    if !is_connected {
+        log::warn!(
+            "Ignoring a message from a client ({}) that isn't connected",
+            handle
+        );
+    }
+    is_connected
+}
+
+fn disconnect_players<T: Transport>(
     despawned_players_for_handles: &mut HashSet<u32>,
     time: &GameTime,
-    network_params: &mut NetworkParams,
+    network_params: &mut NetworkParams<T>,
     update_params: &mut UpdateParams,
 ) {
     // Disconnecting players that have been failing to deliver updates for some time.
@@ -380,7 +802,7 @@ fn disconnect_players(
                 > COMPONENT_FRAMEBUFFER_LIMIT / 2
             {
                 log::warn!("Disconnecting {}: lagging or falling behind", handle);
-                connection_state.set_status(ConnectionStatus::Disconnecting);
+                connection_state.disconnect(DisconnectReason::Lagging);
             }
         } else if Utc::now()
             .signed_duration_since(connection_state.status_updated_at())
@@ -392,7 +814,7 @@ fn disconnect_players(
             // in the `Connecting` or `Handshaking` status) if they are staying in this state
             // for 5 seconds.
             log::warn!("Disconnecting {}: handshake timeout", handle);
-            connection_state.set_status(ConnectionStatus::Disconnecting);
+            connection_state.disconnect(DisconnectReason::HandshakeTimeout);
         }
 
         // Disconnecting players that haven't sent any message for `CONNECTION_TIMEOUT_MILLIS`.
@@ -403,7 +825,7 @@ fn disconnect_players(
             > std::time::Duration::from_secs(CONNECTION_TIMEOUT_MILLIS)
         {
             log::warn!("Disconnecting {}: idle", handle);
-            connection_state.set_status(ConnectionStatus::Disconnecting);
+            connection_state.disconnect(DisconnectReason::Idle);
         }
     }
 
@@ -423,6 +845,22 @@ fn disconnect_players(
             if let Some(player_net_id) =
                 network_params.player_connections.get_id(*connection_handle)
             {
+                let reason = connection_state
+                    .disconnect_reason()
+                    .unwrap_or(DisconnectReason::ConnectionClosed);
+                if update_params.sessions.suspend(player_net_id, reason) {
+                    log::info!(
+                        "Suspending the session of player {} (frame: {})",
+                        player_net_id.0,
+                        time.frame_number
+                    );
+                    stop_player(
+                        &mut update_params.player_updates,
+                        player_net_id,
+                        time.frame_number,
+                    );
+                    continue;
+                }
                 log::debug!(
                     "Adding a DespawnPlayer command (frame: {}, player: {})",
                     time.frame_number,
@@ -432,14 +870,29 @@ fn disconnect_players(
                     net_id: player_net_id,
                     frame_number: time.frame_number,
                 });
+                update_params.respawn_state.remove_player(player_net_id);
             } else {
-                log::warn!("A disconnected player wasn't in the connections list");
+                // A player might have been taken over by a new connection resuming the session.
+                log::debug!("A disconnected player wasn't in the connections list");
             }
         } else {
             despawned_players_for_handles.remove(connection_handle);
         }
     }
 
+    for player_net_id in update_params.sessions.expire() {
+        log::info!(
+            "The session of player {} expired, adding a DespawnPlayer command (frame: {})",
+            player_net_id.0,
+            time.frame_number
+        );
+        update_params.despawn_player_commands.push(DespawnPlayer {
+            net_id: player_net_id,
+            frame_number: time.frame_number,
+        });
+        update_params.respawn_state.remove_player(player_net_id);
+    }
+
     // Cleaning up connections with `Disconnected` status.
     let disconnected_handles: Vec<u32> = network_params
         .connection_states
@@ -451,22 +904,78 @@ fn disconnect_players(
     for handle in disconnected_handles {
         log::info!("Removing connection {}", handle);
         network_params.connection_states.remove(&handle);
-        network_params.net.connections.remove(&handle);
+        network_params.snapshot_histories.remove(&handle);
+        network_params.relevance.remove_connection(handle);
+        network_params.link_conditioner.remove_connection(handle);
+        network_params.connection_limits.remove_connection(handle);
+        network_params.full_state_requests.remove_connection(handle);
+        network_params.network_stats.remove_connection(handle);
+        network_params.net.disconnect(handle);
         network_params.player_connections.remove_by_value(handle);
     }
 }
 
-pub fn send_network_updates(
-    mut network_params: NetworkParams,
+/// Notifies clients on a graceful shutdown, so that they don't wait for the connection to time
+/// out. Messages are sent right away, as the fixed timestep stages might not run again.
+pub fn disconnect_clients_on_exit<T: Transport>(
+    mut app_exit_events: EventReader<AppExit>,
+    mut network_params: NetworkParams<T>,
+) {
+    if app_exit_events.iter().next().is_none() {
+        return;
+    }
+
+    log::info!("Shutting down, disconnecting the clients");
+    for (&connection_handle, connection_state) in network_params.connection_states.iter_mut() {
+        if !matches!(
+            connection_state.status(),
+            ConnectionStatus::Connected | ConnectionStatus::Handshaking
+        ) {
+            continue;
+        }
+
+        connection_state.disconnect(DisconnectReason::ServerShutdown);
+        if let Err(err) = network_params.network_stats.send_message(
+            &mut *network_params.net,
+            connection_handle,
+            Message {
+                session_id: connection_state.session_id,
+                message: ReliableServerMessage::Disconnect(DisconnectReason::ServerShutdown),
+            },
+        ) {
+            log::error!("Failed to send a message: {:?}", err);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn send_network_updates<T: Transport>(
+    mut last_checksum_frame: Local<Option<FrameNumber>>,
+    mut network_params: NetworkParams<T>,
     time: Res<GameTime>,
     level_state: Res<LevelState>,
     players: Res<HashMap<PlayerNetId, Player>>,
-    player_entities: Query<(Entity, &Position, &PlayerDirection, &Spawned)>,
+    player_entities: Query<PlayerEntitiesQuery>,
     players_registry: Res<EntityRegistry<PlayerNetId>>,
+    mut pending_confirmations: ResMut<PendingConfirmations>,
+    mut level_object_updates: ResMut<LevelObjectUpdates>,
+    mut race_state: ResMut<RaceState>,
+    mut client_disconnected_events: EventWriter<ClientDisconnected>,
+    mut sessions: ResMut<Sessions>,
 ) {
     log::trace!("Sending network updates (frame: {})", time.frame_number);
+    network_params.network_stats.update(Utc::now());
 
     broadcast_start_game_messages(
+        &mut network_params,
+        &mut sessions,
+        &time,
+        &level_state,
+        &players,
+        &player_entities,
+        &players_registry,
+    );
+    broadcast_full_state_messages(
         &mut network_params,
         &time,
         &level_state,
@@ -475,9 +984,27 @@ pub fn send_network_updates(
         &players_registry,
     );
 
-    broadcast_disconnected_players(&mut network_params);
+    for disconnected_player in broadcast_disconnected_players(
+        &mut network_params,
+        &mut sessions,
+        &mut client_disconnected_events,
+    ) {
+        race_state.remove_player(disconnected_player);
+        network_params.relevance.remove_player(disconnected_player);
+    }
 
-    for (&_connection_player_net_id, &connection_handle) in network_params.player_connections.iter()
+    let level_object_updates = std::mem::take(&mut level_object_updates.messages);
+    let race_updates = std::mem::take(&mut race_state.updates);
+    // Counting elapsed frames instead of taking the frame number modulo the interval, as frame
+    // numbers wrap around at `u16::MAX`, which isn't a multiple of the interval.
+    let include_checksum = last_checksum_frame.map_or(true, |frame_number| {
+        (time.frame_number - frame_number).value() >= CHECKSUM_INTERVAL_FRAMES
+    });
+    if include_checksum {
+        *last_checksum_frame = Some(time.frame_number);
+    }
+
+    for (&connection_player_net_id, &connection_handle) in network_params.player_connections.iter()
     {
         let connection_state = network_params
             .connection_states
@@ -488,18 +1015,48 @@ pub fn send_network_updates(
             continue;
         }
 
+        let confirmed_actions = pending_confirmations.prepare_for_update(
+            connection_handle,
+            connection_state,
+            time.frame_number,
+        );
         broadcast_delta_update_messages(
             &mut network_params.net,
+            &mut network_params.network_stats,
             &time,
             &players,
             &player_entities,
             &players_registry,
+            &mut network_params.relevance,
+            connection_player_net_id,
+            connection_handle,
+            connection_state,
+            network_params
+                .snapshot_histories
+                .entry(connection_handle)
+                .or_default(),
+            confirmed_actions,
+            include_checksum,
+        );
+
+        broadcast_reliable_messages(
+            &mut network_params.net,
+            &mut network_params.network_stats,
+            &level_object_updates,
+            connection_handle,
+            connection_state,
+        );
+        broadcast_reliable_messages(
+            &mut network_params.net,
+            &mut network_params.network_stats,
+            &race_updates,
             connection_handle,
             connection_state,
         );
 
         broadcast_new_player_messages(
             &mut network_params.net,
+            &mut network_params.network_stats,
             &network_params.new_player_connections,
             &players,
             connection_handle,
@@ -508,24 +1065,48 @@ pub fn send_network_updates(
     }
 }
 
-fn broadcast_disconnected_players(network_params: &mut NetworkParams) {
-    let mut disconnected_players = Vec::new();
+/// Returns the players that got disconnected.
+fn broadcast_disconnected_players<T: Transport>(
+    network_params: &mut NetworkParams<T>,
+    sessions: &mut Sessions,
+    client_disconnected_events: &mut EventWriter<ClientDisconnected>,
+) -> Vec<PlayerNetId> {
+    let mut disconnected_players = std::mem::take(&mut sessions.expired_players);
     for (&connection_handle, connection_state) in network_params.connection_states.iter_mut() {
         if !matches!(connection_state.status(), ConnectionStatus::Disconnecting) {
             continue;
         }
 
-        if let Some(connection_player_net_id) =
-            network_params.player_connections.get_id(connection_handle)
-        {
-            disconnected_players.push(connection_player_net_id);
+        let player_net_id = network_params.player_connections.get_id(connection_handle);
+        if let Some(connection_player_net_id) = player_net_id {
+            // Players of suspended sessions are announced only when the sessions expire.
+            if !sessions.is_suspended(connection_player_net_id) {
+                disconnected_players.push(connection_player_net_id);
+            }
         }
 
-        if let Err(err) = network_params.net.send_message(
+        // Every place that disconnects clients is expected to specify a reason, but we don't
+        // want to panic if someone just sets the status.
+        let reason = connection_state
+            .disconnect_reason()
+            .unwrap_or(DisconnectReason::ConnectionClosed);
+        log::info!(
+            "Disconnecting connection {}: {:?}",
+            connection_handle,
+            reason
+        );
+        client_disconnected_events.send(ClientDisconnected {
+            handle: connection_handle,
+            player_net_id,
+            reason,
+        });
+
+        if let Err(err) = network_params.network_stats.send_message(
+            &mut *network_params.net,
             connection_handle,
             Message {
                 session_id: connection_state.session_id,
-                message: ReliableServerMessage::Disconnect,
+                message: ReliableServerMessage::Disconnect(reason),
             },
         ) {
             log::error!("Failed to send a message: {:?}", err);
@@ -545,7 +1126,8 @@ fn broadcast_disconnected_players(network_params: &mut NetworkParams) {
         }
 
         for disconnected_player in &disconnected_players {
-            if let Err(err) = network_params.net.send_message(
+            if let Err(err) = network_params.network_stats.send_message(
+                &mut *network_params.net,
                 connection_handle,
                 Message {
                     session_id: connection_state.session_id,
@@ -558,45 +1140,91 @@ fn broadcast_disconnected_players(network_params: &mut NetworkParams) {
             }
         }
     }
+
+    disconnected_players
 }
 
-fn broadcast_delta_update_messages(
-    net: &mut NetworkResource,
+#[allow(clippy::too_many_arguments)]
+fn broadcast_delta_update_messages<T: Transport>(
+    net: &mut T,
+    network_stats: &mut NetworkStats,
     time: &GameTime,
     players: &HashMap<PlayerNetId, Player>,
-    player_entities: &Query<(Entity, &Position, &PlayerDirection, &Spawned)>,
+    player_entities: &Query<PlayerEntitiesQuery>,
     players_registry: &EntityRegistry<PlayerNetId>,
+    relevance: &mut Relevance,
+    recipient_net_id: PlayerNetId,
     connection_handle: u32,
     connection_state: &mut ConnectionState,
+    snapshot_history: &mut SnapshotHistory,
+    confirmed_actions: Vec<ConfirmedAction>,
+    include_checksum: bool,
 ) {
     // Checks that a player that we broadcast the message to is connected.
     if !matches!(connection_state.status(), ConnectionStatus::Connected) {
         return;
     }
 
-    let message = UnreliableServerMessage::DeltaUpdate(DeltaUpdate {
+    let recipient_position = players_registry
+        .get_entity(recipient_net_id)
+        .and_then(|entity| current_player_position(entity, time, player_entities));
+    let mut delta_update = DeltaUpdate {
         frame_number: time.frame_number,
         acknowledgments: connection_state.incoming_acknowledgments(),
         players: players
             .iter()
             .filter_map(|(&player_net_id, _player)| {
-                players_registry
-                    .get_entity(player_net_id)
-                    .and_then(|entity| {
-                        create_player_state(
-                            player_net_id,
-                            &time,
-                            connection_state,
-                            entity,
-                            &player_entities,
-                        )
-                    })
+                let entity = players_registry.get_entity(player_net_id)?;
+                // Clients don't despawn players that are missing in delta updates, so it's fine
+                // to skip the ones that are far away. Dead players are always sent though.
+                let position = current_player_position(entity, time, player_entities);
+                if let Some(position) = position.filter(|_| player_net_id != recipient_net_id) {
+                    if !relevance.is_relevant(
+                        connection_handle,
+                        recipient_position,
+                        player_net_id,
+                        position,
+                    ) {
+                        return None;
+                    }
+                }
+                create_player_state(
+                    player_net_id,
+                    &time,
+                    connection_state,
+                    entity,
+                    &player_entities,
+                )
             })
             .collect(),
-        confirmed_actions: vec![],
-    });
+        confirmed_actions,
+        checksum: None,
+    };
+    if include_checksum {
+        delta_update.checksum = Some(positions_checksum(
+            &delta_update.players,
+            time,
+            players_registry,
+            player_entities,
+            relevance,
+            recipient_position,
+        ));
+    }
+    // If a client hasn't acknowledged any of the snapshots that we still keep, positions are
+    // encoded without a baseline.
+    let baseline = connection_state
+        .newest_acknowledged_outgoing_packet()
+        .and_then(|frame_number| {
+            snapshot_history
+                .get(frame_number)
+                .map(|snapshot| (frame_number, snapshot))
+        });
+    let (bytes, snapshot) = encode_delta_update(&delta_update, baseline);
+    snapshot_history.insert(time.frame_number, snapshot);
+    let message = UnreliableServerMessage::DeltaUpdate(PackedDeltaUpdate(bytes));
 
-    if let Err(err) = net.send_message(
+    if let Err(err) = network_stats.send_message(
+        net,
         connection_handle,
         Message {
             session_id: connection_state.session_id,
@@ -606,27 +1234,61 @@ fn broadcast_delta_update_messages(
         log::error!("Failed to send a message: {:?}", err);
     }
 
-    connection_state.add_outgoing_packet(time.frame_number, Utc::now());
+    if let Err(err) = connection_state.add_outgoing_packet(time.frame_number, Utc::now()) {
+        log::error!(
+            "Failed to add an outgoing packet (player: {}), disconnecting: {}",
+            recipient_net_id.0,
+            err
+        );
+        connection_state.disconnect(DisconnectReason::Lagging);
+    }
+}
+
+fn broadcast_reliable_messages<T: Transport>(
+    net: &mut T,
+    network_stats: &mut NetworkStats,
+    messages: &[ReliableServerMessage],
+    connection_handle: u32,
+    connection_state: &ConnectionState,
+) {
+    for message in messages {
+        if let Err(err) = network_stats.send_message(
+            net,
+            connection_handle,
+            Message {
+                session_id: connection_state.session_id,
+                message: message.clone(),
+            },
+        ) {
+            log::error!("Failed to send a message: {:?}", err);
+        }
+    }
 }
 
-fn broadcast_new_player_messages(
-    net: &mut NetworkResource,
-    new_player_connections: &[(PlayerNetId, u32)],
+fn broadcast_new_player_messages<T: Transport>(
+    net: &mut T,
+    network_stats: &mut NetworkStats,
+    new_player_connections: &[NewPlayerConnection],
     players: &HashMap<PlayerNetId, Player>,
     connection_handle: u32,
     connection_state: &mut ConnectionState,
 ) {
     // Broadcasting updates about new connected players.
-    for (connected_player_net_id, _connection_handle) in new_player_connections.iter() {
+    for new_player_connection in new_player_connections {
+        if new_player_connection.is_resumed {
+            continue;
+        }
+        let connected_player_net_id = new_player_connection.player_net_id;
         let player = players
             .get(&connected_player_net_id)
             .expect("Expected a registered Player");
         let message = ReliableServerMessage::ConnectedPlayer(ConnectedPlayer {
-            net_id: *connected_player_net_id,
+            net_id: connected_player_net_id,
             nickname: player.nickname.clone(),
         });
 
-        if let Err(err) = net.send_message(
+        if let Err(err) = network_stats.send_message(
+            net,
             connection_handle,
             Message {
                 session_id: connection_state.session_id,
@@ -638,30 +1300,56 @@ fn broadcast_new_player_messages(
     }
 }
 
-fn broadcast_start_game_messages(
-    network_params: &mut NetworkParams,
+#[allow(clippy::too_many_arguments)]
+fn broadcast_start_game_messages<T: Transport>(
+    network_params: &mut NetworkParams<T>,
+    sessions: &mut Sessions,
     time: &GameTime,
     level_state: &LevelState,
     players: &HashMap<PlayerNetId, Player>,
-    player_entities: &Query<(Entity, &Position, &PlayerDirection, &Spawned)>,
+    player_entities: &Query<PlayerEntitiesQuery>,
     players_registry: &EntityRegistry<PlayerNetId>,
 ) {
     // Broadcasting updates about new connected players.
-    for (connected_player_net_id, connected_player_connection_handle) in
-        network_params.new_player_connections.drain(..)
+    for NewPlayerConnection {
+        player_net_id: connected_player_net_id,
+        handle: connected_player_connection_handle,
+        is_resumed,
+    } in network_params.new_player_connections.drain(..)
     {
-        let connection_state = network_params
+        let connection_state = match network_params
             .connection_states
             .get_mut(&connected_player_connection_handle)
-            .expect("Expected a ConnectionState for a new player");
+        {
+            Some(connection_state) => connection_state,
+            None => continue,
+        };
+        // A connection might have got closed (or taken over by a resumed session) after
+        // the handshake.
+        if !matches!(connection_state.status(), ConnectionStatus::Handshaking) {
+            log::warn!(
+                "Skipping StartGame for a client ({}) that isn't handshaking: {:?}",
+                connected_player_connection_handle,
+                connection_state.status()
+            );
+            continue;
+        }
+        // Snapshots of a previous session can't be used as baselines.
+        network_params
+            .snapshot_histories
+            .remove(&connected_player_connection_handle);
+        network_params
+            .relevance
+            .remove_connection(connected_player_connection_handle);
         let connected_player = players
             .get(&connected_player_net_id)
             .expect("Expected a new Player to exist");
 
-        assert!(matches!(
-            connection_state.status(),
-            ConnectionStatus::Handshaking
-        ));
+        let connected_player_position = players_registry
+            .get_entity(connected_player_net_id)
+            .and_then(|entity| player_entities.get(entity).ok())
+            .and_then(|(_, position, _, _, _)| position.buffer.get(time.frame_number).copied())
+            .unwrap_or_else(|| Vec3::new(0.0, PLAYER_SIZE / 2.0, 0.0));
 
         // TODO: prepare the update in another system.
         let mut players_state: Vec<PlayerState> = players
@@ -670,58 +1358,55 @@ fn broadcast_start_game_messages(
                 players_registry
                     .get_entity(iter_player_net_id)
                     .and_then(|entity| {
-                        if connected_player_net_id == iter_player_net_id {
-                            Some(PlayerState {
-                                net_id: connected_player_net_id,
-                                position: Vec2::ZERO,
-                                inputs: Vec::new(),
-                            })
-                        } else {
-                            create_player_state(
-                                iter_player_net_id,
-                                &time,
-                                connection_state,
-                                entity,
-                                &player_entities,
-                            )
-                        }
+                        create_player_state(
+                            iter_player_net_id,
+                            &time,
+                            connection_state,
+                            entity,
+                            &player_entities,
+                        )
                     })
             })
             .collect();
-        players_state.push(PlayerState {
-            net_id: connected_player_net_id,
-            position: Vec2::ZERO,
-            inputs: Vec::new(),
-        });
+        // New players get spawned in the next frame, while resumed ones (even dead or moving)
+        // are already covered above.
+        if !players_state
+            .iter()
+            .any(|player_state| player_state.net_id == connected_player_net_id)
+        {
+            players_state.push(PlayerState {
+                net_id: connected_player_net_id,
+                is_spawned: true,
+                position: connected_player_position,
+                velocity: Vec3::ZERO,
+                inputs: Vec::new(),
+            });
+        }
 
+        // Players keep their tokens when resuming sessions, so clients that never receive
+        // `StartGame` can try resuming again.
+        let session_token = match sessions.token(connected_player_net_id) {
+            Some(session_token) if is_resumed => session_token,
+            _ => sessions.issue_token(connected_player_net_id),
+        };
         let message = ReliableServerMessage::StartGame(StartGame {
             handshake_id: connection_state.handshake_id,
             net_id: connected_player_net_id,
             nickname: connected_player.nickname.clone(),
-            objects: level_state
-                .objects
-                .iter()
-                .map(|level_object| SpawnLevelObject {
-                    object: level_object.clone(),
-                    frame_number: time.frame_number,
-                })
-                .collect(),
-            players: players
-                .iter()
-                .map(|(&net_id, player)| ConnectedPlayer {
-                    net_id,
-                    nickname: player.nickname.clone(),
-                })
-                .collect(),
+            session_token,
+            objects: spawn_level_objects(level_state, time),
+            players: connected_players(players),
             game_state: DeltaUpdate {
                 frame_number: time.frame_number,
                 acknowledgments: connection_state.incoming_acknowledgments(),
                 players: players_state,
                 confirmed_actions: Vec::new(),
+                checksum: None,
             },
         });
 
-        let result = network_params.net.send_message(
+        let result = network_params.network_stats.send_message(
+            &mut *network_params.net,
             connected_player_connection_handle,
             Message {
                 session_id: connection_state.session_id,
@@ -736,17 +1421,163 @@ fn broadcast_start_game_messages(
     }
 }
 
-/// Returns `None` if the entity is not spawned for the current frame.
+fn broadcast_full_state_messages<T: Transport>(
+    network_params: &mut NetworkParams<T>,
+    time: &GameTime,
+    level_state: &LevelState,
+    players: &HashMap<PlayerNetId, Player>,
+    player_entities: &Query<PlayerEntitiesQuery>,
+    players_registry: &EntityRegistry<PlayerNetId>,
+) {
+    for connection_handle in network_params
+        .full_state_requests
+        .take_servable(time.frame_number)
+    {
+        let connection_state = match network_params.connection_states.get_mut(&connection_handle) {
+            Some(connection_state)
+                if matches!(connection_state.status(), ConnectionStatus::Connected) =>
+            {
+                connection_state
+            }
+            _ => continue,
+        };
+        log::info!(
+            "Sending the full game state to client ({}) (frame: {})",
+            connection_handle,
+            time.frame_number
+        );
+
+        // Frame numbers of the client's updates are going to jump, so we start a new session
+        // to ignore the messages that are still in flight, and forget about the old packets.
+        // Snapshots that the client has acknowledged can't be used as baselines either.
+        connection_state.session_id += SessionId::new(1);
+        connection_state.reset_packets();
+        network_params.snapshot_histories.remove(&connection_handle);
+        network_params
+            .relevance
+            .remove_connection(connection_handle);
+
+        let players_state = players
+            .keys()
+            .filter_map(|&player_net_id| {
+                let entity = players_registry.get_entity(player_net_id)?;
+                create_player_state(
+                    player_net_id,
+                    time,
+                    connection_state,
+                    entity,
+                    player_entities,
+                )
+            })
+            .collect();
+        let message = ReliableServerMessage::FullState(FullState {
+            objects: spawn_level_objects(level_state, time),
+            players: connected_players(players),
+            game_state: DeltaUpdate {
+                frame_number: time.frame_number,
+                acknowledgments: connection_state.incoming_acknowledgments(),
+                players: players_state,
+                confirmed_actions: Vec::new(),
+                checksum: None,
+            },
+        });
+
+        if let Err(err) = network_params.network_stats.send_message(
+            &mut *network_params.net,
+            connection_handle,
+            Message {
+                session_id: connection_state.session_id,
+                message,
+            },
+        ) {
+            log::error!("Failed to send a message: {:?}", err);
+        }
+    }
+}
+
+fn spawn_level_objects(level_state: &LevelState, time: &GameTime) -> Vec<SpawnLevelObject> {
+    level_state
+        .objects
+        .iter()
+        .map(|level_object| SpawnLevelObject {
+            object: level_object.clone(),
+            frame_number: time.frame_number,
+        })
+        .collect()
+}
+
+fn connected_players(players: &HashMap<PlayerNetId, Player>) -> Vec<ConnectedPlayer> {
+    players
+        .iter()
+        .map(|(&net_id, player)| ConnectedPlayer {
+            net_id,
+            nickname: player.nickname.clone(),
+        })
+        .collect()
+}
+
+/// Hashes the positions of the players that a recipient gets updates for every frame, so that
+/// it doesn't have to extrapolate them. The positions are taken for `CHECKSUM_FRAME_DELAY`
+/// frames ago.
+fn positions_checksum(
+    player_states: &[PlayerState],
+    time: &GameTime,
+    players_registry: &EntityRegistry<PlayerNetId>,
+    player_entities: &Query<PlayerEntitiesQuery>,
+    relevance: &Relevance,
+    recipient_position: Option<Vec3>,
+) -> PositionsChecksum {
+    let frame_number = time.frame_number - FrameNumber::new(CHECKSUM_FRAME_DELAY);
+    let players = player_states
+        .iter()
+        .filter(|player_state| {
+            player_state.is_spawned
+                && relevance.is_within_radius(recipient_position, player_state.position)
+        })
+        .filter_map(|player_state| {
+            let entity = players_registry.get_entity(player_state.net_id)?;
+            let (_, position, _, _, spawned) = player_entities.get(entity).ok()?;
+            if !spawned.is_spawned(frame_number) {
+                return None;
+            }
+            let position = position.buffer.get(frame_number)?;
+            Some((player_state.net_id, position_checksum(*position)))
+        })
+        .collect();
+    PositionsChecksum {
+        frame_number,
+        players,
+    }
+}
+
+/// Returns `None` if a player isn't spawned.
+fn current_player_position(
+    entity: Entity,
+    time: &GameTime,
+    player_entities: &Query<PlayerEntitiesQuery>,
+) -> Option<Vec3> {
+    let (_, position, _, _, spawned) = player_entities.get(entity).ok()?;
+    if !spawned.is_spawned(time.frame_number) {
+        return None;
+    }
+    position
+        .buffer
+        .get(time.frame_number)
+        .or_else(|| position.buffer.last())
+        .copied()
+}
+
+/// Returns `None` if a player's entity or position for the requested frames doesn't exist.
 fn create_player_state(
     net_id: PlayerNetId,
     time: &GameTime,
     connection_state: &ConnectionState,
     entity: Entity,
-    player_entities: &Query<(Entity, &Position, &PlayerDirection, &Spawned)>,
+    player_entities: &Query<PlayerEntitiesQuery>,
 ) -> Option<PlayerState> {
-    let (_, position, player_direction, spawned) = player_entities.get(entity).unwrap();
+    let (_, position, velocity, player_direction, spawned) = player_entities.get(entity).ok()?;
     if !spawned.is_spawned(time.frame_number) {
-        return None;
+        return Some(PlayerState::dead(net_id));
     }
 
     let updates_start_frame = if connection_state.packet_loss() > 0.0 {
@@ -766,10 +1597,12 @@ fn create_player_state(
         .iter_with_interpolation()
         .skip_while(|(frame_number, _)| *frame_number < updates_start_frame)
     {
-        if Some(direction) != inputs.last().map(|i| i.direction) {
+        let jump = player_direction.is_jumping(frame_number);
+        if jump || Some(direction) != inputs.last().map(|i| i.direction) {
             inputs.push(PlayerInput {
                 frame_number,
                 direction,
+                jump,
             });
         }
     }
@@ -779,19 +1612,30 @@ fn create_player_state(
         |input| input.frame_number,
     );
 
+    // Inputs come from clients, so we can't rely on them being within the stored positions range.
+    let position = match position.buffer.get(start_position_frame) {
+        Some(position) => *position,
+        None => {
+            log::error!(
+                "Player ({}) position for frame {} doesn't exist (current frame: {})",
+                net_id.0,
+                start_position_frame.value(),
+                time.frame_number.value()
+            );
+            return None;
+        }
+    };
+
     Some(PlayerState {
         net_id,
-        position: *position
+        is_spawned: true,
+        position,
+        // Velocities are stored for the same frames as positions.
+        velocity: velocity
             .buffer
             .get(start_position_frame)
-            .unwrap_or_else(|| {
-                panic!(
-                    "Player ({}) position for frame {} doesn't exist (current frame: {})",
-                    net_id.0,
-                    start_position_frame.value(),
-                    time.frame_number.value()
-                )
-            }),
+            .copied()
+            .unwrap_or(Vec3::ZERO),
         inputs,
     })
 }
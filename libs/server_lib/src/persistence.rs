@@ -0,0 +1,276 @@
+use bevy::{log, prelude::*};
+use mr_shared_lib::game::level::{LevelObjectDesc, LevelState};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Bump this every time `LevelObjectDesc` (or anything it contains) changes its serialized form
+/// and add a migration step to `migrate` that upgrades the previous version.
+pub const LEVEL_FORMAT_VERSION: u32 = 1;
+
+/// Sent when a client asks the server to persist the current level.
+pub struct SaveLevelRequest;
+
+/// Path of the level file, is resolved from the `--level <path>` CLI argument or the
+/// `MUDDLE_LEVEL_PATH` env variable. Gets reset if the file exists but can't be loaded, so that
+/// saving doesn't overwrite it.
+#[derive(Default)]
+pub struct LevelFilePath(pub Option<PathBuf>);
+
+#[derive(Serialize, Deserialize)]
+struct LevelFile {
+    version: u32,
+    objects: Vec<LevelObjectDesc>,
+}
+
+#[derive(Error, Debug)]
+pub enum LevelFileError {
+    #[error("failed to access the level file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to (de)serialize the level file: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("level file doesn't have a valid version field")]
+    MissingVersion,
+    #[error("unsupported level file version: {0}")]
+    UnsupportedVersion(u64),
+}
+
+pub fn level_file_path() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--level" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--level=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+
+    std::env::var("MUDDLE_LEVEL_PATH")
+        .ok()
+        .or_else(|| std::option_env!("MUDDLE_LEVEL_PATH").map(str::to_owned))
+        .map(PathBuf::from)
+}
+
+pub fn load_level(path: &Path) -> Result<Vec<LevelObjectDesc>, LevelFileError> {
+    let contents = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = serde_json::from_str(&contents)?;
+    let version = value
+        .get("version")
+        .and_then(serde_json::Value::as_u64)
+        .ok_or(LevelFileError::MissingVersion)?;
+    let version =
+        u32::try_from(version).map_err(|_| LevelFileError::UnsupportedVersion(version))?;
+    let level_file: LevelFile = serde_json::from_value(migrate(value, version)?)?;
+    Ok(level_file.objects)
+}
+
+pub fn save_level(path: &Path, objects: Vec<LevelObjectDesc>) -> Result<(), LevelFileError> {
+    let level_file = LevelFile {
+        version: LEVEL_FORMAT_VERSION,
+        objects,
+    };
+    let contents = serde_json::to_string_pretty(&level_file)?;
+    // Writing to a temporary file first, so that a failed write doesn't corrupt the level.
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Upgrades a level file of the `version` version to the current format, one version at a time.
+fn migrate(value: serde_json::Value, version: u32) -> Result<serde_json::Value, LevelFileError> {
+    match version {
+        LEVEL_FORMAT_VERSION => Ok(value),
+        // Migrations go here, for instance:
+        // 1 => migrate(migrate_v1_to_v2(value), 2),
+        version => Err(LevelFileError::UnsupportedVersion(version.into())),
+    }
+}
+
+pub fn process_save_level_requests(
+    mut save_level_requests: EventReader<SaveLevelRequest>,
+    level_file_path: Res<LevelFilePath>,
+    level_state: Res<LevelState>,
+) {
+    // Several requests may arrive during the same frame, there's no point in saving more than once.
+    if save_level_requests.iter().count() == 0 {
+        return;
+    }
+
+    let path = match &level_file_path.0 {
+        Some(path) => path,
+        None => {
+            log::warn!("Can't save the level: no level file is set (or it failed to load)");
+            return;
+        }
+    };
+
+    let objects = level_state
+        .objects
+        .iter()
+        .map(|object| object.desc.clone())
+        .collect();
+    match save_level(path, objects) {
+        Ok(()) => log::info!("Saved the level to {}", path.display()),
+        Err(err) => log::error!("Failed to save the level to {}: {}", path.display(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        init_level,
+        persistence::{load_level, save_level, LevelFileError, LevelFilePath},
+    };
+    use bevy::{
+        ecs::{
+            schedule::{Stage, SystemStage},
+            system::IntoSystem,
+            world::World,
+        },
+        math::{Quat, Vec3},
+    };
+    use mr_shared_lib::{
+        game::{
+            commands::{GameCommands, SpawnLevelObject},
+            level::LevelObjectDesc,
+            level_objects::{CuboidDesc, CylinderDesc, PlaneDesc, RampDesc},
+        },
+        messages::EntityNetId,
+    };
+    use std::path::PathBuf;
+
+    /// A level saved with the first version of the format, it must keep loading after any changes
+    /// to `LevelObjectDesc` (by bumping `LEVEL_FORMAT_VERSION` and adding a migration, if needed).
+    const LEVEL_FILE_V1: &str = r#"{
+  "version": 1,
+  "objects": [
+    { "Plane": { "size": 128.0 } },
+    {
+      "Cuboid": {
+        "position": [1.0, 0.5, -2.0],
+        "rotation": [0.0, 0.0, 0.0, 1.0],
+        "size": [1.0, 1.0, 2.0]
+      }
+    },
+    {
+      "Ramp": {
+        "position": [0.0, 1.0, 4.0],
+        "rotation": [0.0, 0.0, 0.0, 1.0],
+        "size": [2.0, 2.0, 4.0]
+      }
+    },
+    {
+      "Cylinder": {
+        "position": [-3.0, 1.0, 0.0],
+        "rotation": [0.0, 0.0, 0.0, 1.0],
+        "radius": 0.5,
+        "height": 2.0
+      }
+    }
+  ]
+}"#;
+
+    fn level_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mr_level_{}_{}.json", std::process::id(), name))
+    }
+
+    fn load_level_str(name: &str, contents: &str) -> Result<Vec<LevelObjectDesc>, LevelFileError> {
+        let path = level_path(name);
+        std::fs::write(&path, contents).unwrap();
+        let loaded = load_level(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    fn v1_objects() -> Vec<LevelObjectDesc> {
+        vec![
+            LevelObjectDesc::Plane(PlaneDesc { size: 128.0 }),
+            LevelObjectDesc::Cuboid(CuboidDesc {
+                position: Vec3::new(1.0, 0.5, -2.0),
+                rotation: Quat::identity(),
+                size: Vec3::new(1.0, 1.0, 2.0),
+            }),
+            LevelObjectDesc::Ramp(RampDesc {
+                position: Vec3::new(0.0, 1.0, 4.0),
+                rotation: Quat::identity(),
+                size: Vec3::new(2.0, 2.0, 4.0),
+            }),
+            LevelObjectDesc::Cylinder(CylinderDesc {
+                position: Vec3::new(-3.0, 1.0, 0.0),
+                rotation: Quat::identity(),
+                radius: 0.5,
+                height: 2.0,
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let path = level_path("round_trip");
+        let objects = v1_objects();
+        save_level(&path, objects.clone()).unwrap();
+        let loaded = load_level(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), objects);
+    }
+
+    #[test]
+    fn test_load_v1_level() {
+        assert_eq!(load_level_str("v1", LEVEL_FILE_V1).unwrap(), v1_objects());
+    }
+
+    #[test]
+    fn test_load_invalid_level() {
+        assert!(matches!(
+            load_level_str("missing_version", r#"{ "objects": [] }"#),
+            Err(LevelFileError::MissingVersion)
+        ));
+        assert!(matches!(
+            load_level_str("future_version", r#"{ "version": 9999, "objects": [] }"#),
+            Err(LevelFileError::UnsupportedVersion(9999))
+        ));
+        assert!(matches!(
+            load_level_str(
+                "overflowing_version",
+                r#"{ "version": 4294967297, "objects": [] }"#
+            ),
+            Err(LevelFileError::UnsupportedVersion(4294967297))
+        ));
+        assert!(matches!(
+            load_level_str(
+                "invalid_object",
+                r#"{ "version": 1, "objects": [{ "Plane": {} }] }"#
+            ),
+            Err(LevelFileError::Serde(_))
+        ));
+        assert!(matches!(
+            load_level_str("truncated", r#"{ "version": 1, "#),
+            Err(LevelFileError::Serde(_))
+        ));
+    }
+
+    #[test]
+    fn test_unreadable_level_disables_saving() {
+        let path = level_path("unreadable");
+        std::fs::write(&path, r#"{ "version": 1, "#).unwrap();
+        let mut world = World::default();
+        world.insert_resource(LevelFilePath(Some(path.clone())));
+        world.insert_resource(EntityNetId::default());
+        world.insert_resource(GameCommands::<SpawnLevelObject>::default());
+        SystemStage::single(init_level.system()).run(&mut world);
+        std::fs::remove_file(&path).unwrap();
+
+        // The server starts with the default level, but won't overwrite the file with it.
+        assert!(world.get_resource::<LevelFilePath>().unwrap().0.is_none());
+        assert!(!world
+            .get_resource_mut::<GameCommands<SpawnLevelObject>>()
+            .unwrap()
+            .drain()
+            .is_empty());
+    }
+}
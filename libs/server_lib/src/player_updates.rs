@@ -1,4 +1,4 @@
-use crate::net::PlayerConnections;
+use crate::{metrics::Metrics, net::PlayerConnections};
 use bevy::{
     ecs::system::{Res, ResMut},
     log,
@@ -15,6 +15,9 @@ use std::collections::HashMap;
 
 pub const SERVER_UPDATES_LIMIT: u16 = 64;
 pub const MAX_LAG_COMPENSATION_MILLIS: u16 = 200;
+/// Inputs for the frames that are older than this get discarded.
+pub const LAG_COMPENSATED_FRAMES: u16 =
+    (MAX_LAG_COMPENSATION_MILLIS as u32 * SIMULATIONS_PER_SECOND as u32 / 1000) as u16;
 
 pub struct DeferredUpdates<T> {
     updates: HashMap<PlayerNetId, Vec<T>>,
@@ -46,18 +49,20 @@ pub fn process_player_input_updates(
     mut simulation_time: ResMut<SimulationTime>,
     mut updates: ResMut<PlayerUpdates>,
     mut deferred_updates: ResMut<DeferredUpdates<PlayerInput>>,
+    mut metrics: ResMut<Metrics>,
 ) {
-    let lag_compensated_frames =
-        (MAX_LAG_COMPENSATION_MILLIS as f32 / (1000.0 / SIMULATIONS_PER_SECOND as f32)) as u16;
-    let min_frame_number = time.frame_number - FrameNumber::new(lag_compensated_frames);
+    let min_frame_number = time.frame_number - FrameNumber::new(LAG_COMPENSATED_FRAMES);
 
+    let server_frame = simulation_time.server_frame;
     let deferred_updates = deferred_updates.drain();
     for (player_net_id, mut player_updates) in deferred_updates {
-        let player_connection = player_connections.get_value(player_net_id).unwrap();
-        let player_connection_state = connection_states.get(&player_connection).unwrap();
-        let player_frame_number = player_connection_state
-            .incoming_acknowledgments()
-            .0
+        // A player might have lost its connection while we were reading its updates.
+        let player_frame_number = player_connections
+            .get_value(player_net_id)
+            .and_then(|player_connection| connection_states.get(&player_connection))
+            .and_then(|player_connection_state| {
+                player_connection_state.incoming_acknowledgments().0
+            })
             // A player has just connected, and it's got only the initial empty update, so it's fine.
             .unwrap_or(time.frame_number);
 
@@ -90,26 +95,21 @@ pub fn process_player_input_updates(
             let duplicate_updates_to =
                 next_player_update.map_or(player_frame_number, |update| update.frame_number);
 
-            let update_to_insert = Some(PlayerDirectionUpdate {
-                direction: player_update.direction,
-                is_processed_client_input: None,
-            });
-
             // We fill the buffer of player direction commands with the updates that come from
             // clients. We populate each frame until a command changes or we've reached the last
             // acknowledged client's frame (`PlayerUpdate::frame_number`).
             for frame_number in duplicate_updates_from..duplicate_updates_to {
+                // Jumps aren't duplicated, as they happen only at the frame of an input.
+                let update_to_insert = Some(PlayerDirectionUpdate {
+                    direction: player_update.direction,
+                    jump: player_update.jump && frame_number == player_update.frame_number,
+                    is_processed_client_input: None,
+                });
                 let existing_update = updates.get(frame_number);
                 // We don't want to allow re-writing updates.
                 if existing_update.is_none() && updates.can_insert(frame_number) {
                     simulation_time.rewind(frame_number);
-                    updates.insert(
-                        frame_number,
-                        Some(PlayerDirectionUpdate {
-                            direction: player_update.direction,
-                            is_processed_client_input: None,
-                        }),
-                    );
+                    updates.insert(frame_number, update_to_insert);
                 } else if existing_update != Some(&update_to_insert) {
                     // TODO: is just discarding old updates good enough?
                     log::warn!(
@@ -122,4 +122,8 @@ pub fn process_player_input_updates(
             }
         }
     }
+
+    if simulation_time.server_frame < server_frame {
+        metrics.add_rewind((server_frame - simulation_time.server_frame).value());
+    }
 }
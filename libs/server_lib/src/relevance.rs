@@ -0,0 +1,169 @@
+use bevy::math::Vec3;
+use mr_shared_lib::messages::PlayerNetId;
+use std::collections::HashMap;
+
+/// Players within this distance from a recipient's player are replicated every frame.
+pub const DEFAULT_RELEVANCE_RADIUS: f32 = 40.0;
+/// A player accumulates `radius / distance` priority points every broadcast and gets replicated
+/// once it reaches this threshold. I.e. a player right outside the radius is sent with every 6th
+/// delta update, and a player twice as far is sent with every 12th.
+const FAR_PLAYER_PRIORITY_THRESHOLD: f32 = 6.0;
+/// Guarantees that even the most distant players get sent with about every 60th delta update.
+const MIN_PRIORITY_GAIN: f32 = FAR_PLAYER_PRIORITY_THRESHOLD / 60.0;
+
+/// Decides which players are replicated to which connections. Players that are far from
+/// a recipient's player are sent less often, keeping delta updates small with many players.
+pub struct Relevance {
+    radius: f32,
+    /// Accumulated priorities of far players, per connection handle.
+    priorities: HashMap<u32, HashMap<PlayerNetId, f32>>,
+}
+
+impl Default for Relevance {
+    fn default() -> Self {
+        Self::new(DEFAULT_RELEVANCE_RADIUS)
+    }
+}
+
+impl Relevance {
+    pub fn new(radius: f32) -> Self {
+        Self {
+            radius,
+            priorities: HashMap::new(),
+        }
+    }
+
+    /// Expected to be called once per broadcast (see `TICKS_PER_NETWORK_BROADCAST`) for every
+    /// spawned player that might be sent to a connection. A recipient without a player (or with
+    /// a player that isn't spawned) gets everyone.
+    pub fn is_relevant(
+        &mut self,
+        connection_handle: u32,
+        recipient_position: Option<Vec3>,
+        player_net_id: PlayerNetId,
+        player_position: Vec3,
+    ) -> bool {
+        let distance = match recipient_position {
+            Some(recipient_position) => recipient_position.distance(player_position),
+            None => return true,
+        };
+        let priorities = self.priorities.entry(connection_handle).or_default();
+        if distance <= self.radius {
+            priorities.remove(&player_net_id);
+            return true;
+        }
+
+        let priority = priorities.entry(player_net_id).or_insert(0.0);
+        *priority += (self.radius / distance).max(MIN_PRIORITY_GAIN);
+        if *priority >= FAR_PLAYER_PRIORITY_THRESHOLD {
+            *priority = 0.0;
+            return true;
+        }
+        false
+    }
+
+    /// Players within the radius are replicated every frame (unlike the far ones, which clients
+    /// have to extrapolate between updates).
+    pub fn is_within_radius(
+        &self,
+        recipient_position: Option<Vec3>,
+        player_position: Vec3,
+    ) -> bool {
+        recipient_position.map_or(true, |recipient_position| {
+            recipient_position.distance(player_position) <= self.radius
+        })
+    }
+
+    pub fn remove_connection(&mut self, connection_handle: u32) {
+        self.priorities.remove(&connection_handle);
+    }
+
+    pub fn remove_player(&mut self, player_net_id: PlayerNetId) {
+        for priorities in self.priorities.values_mut() {
+            priorities.remove(&player_net_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::relevance::Relevance;
+    use bevy::math::Vec3;
+    use mr_shared_lib::messages::PlayerNetId;
+
+    fn relevant_broadcasts(
+        relevance: &mut Relevance,
+        connection_handle: u32,
+        player_position: Vec3,
+        broadcasts: usize,
+    ) -> Vec<usize> {
+        (0..broadcasts)
+            .filter(|_| {
+                relevance.is_relevant(
+                    connection_handle,
+                    Some(Vec3::ZERO),
+                    PlayerNetId(1),
+                    player_position,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_near_players_are_always_relevant() {
+        let mut relevance = Relevance::new(10.0);
+        assert_eq!(
+            relevant_broadcasts(&mut relevance, 0, Vec3::new(10.0, 0.0, 0.0), 5),
+            vec![0, 1, 2, 3, 4]
+        );
+        // Recipients without spawned players get everyone.
+        assert!(relevance.is_relevant(0, None, PlayerNetId(1), Vec3::new(1000.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_far_players_are_sent_less_often() {
+        let mut relevance = Relevance::new(10.0);
+        assert_eq!(
+            relevant_broadcasts(&mut relevance, 0, Vec3::new(20.0, 0.0, 0.0), 36),
+            vec![11, 23, 35]
+        );
+        // The most distant players still get sent about every 60th broadcast (give or take
+        // a rounding error).
+        let broadcasts = relevant_broadcasts(&mut relevance, 1, Vec3::new(10_000.0, 0.0, 0.0), 200);
+        assert_eq!(broadcasts.len(), 3);
+        assert!(broadcasts[0] >= 58 && broadcasts[0] <= 61);
+    }
+
+    #[test]
+    fn test_priorities_are_per_connection() {
+        let mut relevance = Relevance::new(10.0);
+        let far_position = Vec3::new(20.0, 0.0, 0.0);
+        assert!(relevant_broadcasts(&mut relevance, 0, far_position, 11).is_empty());
+        assert!(relevant_broadcasts(&mut relevance, 1, far_position, 6).is_empty());
+
+        // Getting close resets the accumulated priority.
+        assert!(relevance.is_relevant(0, Some(Vec3::ZERO), PlayerNetId(1), Vec3::ZERO));
+        assert_eq!(
+            relevant_broadcasts(&mut relevance, 0, far_position, 12),
+            vec![11]
+        );
+        assert_eq!(
+            relevant_broadcasts(&mut relevance, 1, far_position, 6),
+            vec![5]
+        );
+
+        relevance.remove_connection(1);
+        assert_eq!(
+            relevant_broadcasts(&mut relevance, 1, far_position, 12),
+            vec![11]
+        );
+    }
+
+    #[test]
+    fn test_is_within_radius() {
+        let relevance = Relevance::new(10.0);
+        assert!(relevance.is_within_radius(Some(Vec3::ZERO), Vec3::new(0.0, 0.0, 10.0)));
+        assert!(!relevance.is_within_radius(Some(Vec3::ZERO), Vec3::new(0.0, 0.0, 10.1)));
+        assert!(relevance.is_within_radius(None, Vec3::new(0.0, 0.0, 10.1)));
+    }
+}
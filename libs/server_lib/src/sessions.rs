@@ -0,0 +1,202 @@
+use crate::player_updates::SERVER_UPDATES_LIMIT;
+use bevy::math::Vec2;
+use chrono::{DateTime, Duration, Utc};
+use mr_shared_lib::{
+    framebuffer::FrameNumber,
+    messages::{DisconnectReason, PlayerNetId, SessionToken},
+    player::{PlayerDirectionUpdate, PlayerUpdates},
+};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hash, Hasher},
+};
+
+/// For how long players of disconnected clients are kept in the game, waiting for the clients to
+/// reconnect.
+pub const SESSION_GRACE_PERIOD_SECS: i64 = 15;
+
+/// Keeps the tokens issued to players and the sessions of disconnected players, that can be
+/// resumed within `SESSION_GRACE_PERIOD_SECS`.
+#[derive(Default)]
+pub struct Sessions {
+    tokens: HashMap<SessionToken, PlayerNetId>,
+    suspended: HashMap<PlayerNetId, DateTime<Utc>>,
+    issued_tokens_count: u64,
+    /// Players whose sessions expired, they are expected to be announced as disconnected.
+    pub expired_players: Vec<PlayerNetId>,
+}
+
+impl Sessions {
+    /// Issues a new token for a player, invalidating the previous one.
+    pub fn issue_token(&mut self, player_net_id: PlayerNetId) -> SessionToken {
+        self.tokens.retain(|_, id| *id != player_net_id);
+
+        // Not cryptographically secure, but guessing a token still requires brute-forcing
+        // randomly seeded SipHash.
+        let mut hasher = RandomState::new().build_hasher();
+        self.issued_tokens_count.hash(&mut hasher);
+        Utc::now().timestamp_nanos().hash(&mut hasher);
+        let token = SessionToken(hasher.finish());
+        self.issued_tokens_count += 1;
+
+        self.tokens.insert(token, player_net_id);
+        token
+    }
+
+    /// Returns `false` if a player doesn't have a session that can be resumed (i.e. a client
+    /// disconnected before getting a token or got disconnected for misbehaving).
+    pub fn suspend(&mut self, player_net_id: PlayerNetId, reason: DisconnectReason) -> bool {
+        let is_resumable_reason = matches!(
+            reason,
+            DisconnectReason::ConnectionClosed | DisconnectReason::Idle | DisconnectReason::Lagging
+        );
+        if !is_resumable_reason || !self.tokens.values().any(|id| *id == player_net_id) {
+            self.remove_player(player_net_id);
+            return false;
+        }
+        self.suspended.insert(player_net_id, Utc::now());
+        true
+    }
+
+    pub fn token(&self, player_net_id: PlayerNetId) -> Option<SessionToken> {
+        self.tokens
+            .iter()
+            .find_map(|(token, id)| (*id == player_net_id).then_some(*token))
+    }
+
+    pub fn is_suspended(&self, player_net_id: PlayerNetId) -> bool {
+        self.suspended.contains_key(&player_net_id)
+    }
+
+    /// Returns the player of a token, lifting the suspension of their session. A player might
+    /// still be connected, if the server hasn't detected that the previous connection got lost.
+    ///
+    /// The token stays valid, so a client can retry if the handshake gets interrupted (for
+    /// instance, if `StartGame` never reaches it).
+    pub fn resume(&mut self, token: SessionToken) -> Option<PlayerNetId> {
+        let player_net_id = *self.tokens.get(&token)?;
+        self.suspended.remove(&player_net_id);
+        Some(player_net_id)
+    }
+
+    /// Returns the players whose grace period has passed, also adding them to `expired_players`.
+    pub fn expire(&mut self) -> Vec<PlayerNetId> {
+        let expired_at = Utc::now() - Duration::seconds(SESSION_GRACE_PERIOD_SECS);
+        let expired_players = self
+            .suspended
+            .iter()
+            .filter(|(_, suspended_at)| **suspended_at < expired_at)
+            .map(|(player_net_id, _)| *player_net_id)
+            .collect::<Vec<_>>();
+        for player_net_id in &expired_players {
+            self.remove_player(*player_net_id);
+        }
+        self.expired_players.extend_from_slice(&expired_players);
+        expired_players
+    }
+
+    pub fn remove_player(&mut self, player_net_id: PlayerNetId) {
+        self.tokens.retain(|_, id| *id != player_net_id);
+        self.suspended.remove(&player_net_id);
+    }
+}
+
+/// Makes a player of a disconnected client stand still, as otherwise the last direction would get
+/// extrapolated until the client reconnects.
+pub fn stop_player(
+    player_updates: &mut PlayerUpdates,
+    player_net_id: PlayerNetId,
+    frame_number: FrameNumber,
+) {
+    let updates =
+        player_updates.get_direction_mut(player_net_id, frame_number, SERVER_UPDATES_LIMIT);
+    // We can't re-write the updates that we already have, and we don't want to rewind either.
+    let stop_frame = std::cmp::max(frame_number, updates.end_frame()) + FrameNumber::new(1);
+    if updates.can_insert(stop_frame) {
+        updates.insert(
+            stop_frame,
+            Some(PlayerDirectionUpdate {
+                direction: Vec2::ZERO,
+                jump: false,
+                is_processed_client_input: None,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sessions::{Sessions, SESSION_GRACE_PERIOD_SECS};
+    use chrono::{Duration, Utc};
+    use mr_shared_lib::messages::{DisconnectReason, PlayerNetId};
+
+    #[test]
+    fn test_issue_token() {
+        let mut sessions = Sessions::default();
+        let first_token = sessions.issue_token(PlayerNetId(1));
+        let second_token = sessions.issue_token(PlayerNetId(1));
+        let other_player_token = sessions.issue_token(PlayerNetId(2));
+        assert_ne!(first_token, second_token);
+
+        assert_eq!(sessions.resume(first_token), None);
+        assert_eq!(sessions.resume(second_token), Some(PlayerNetId(1)));
+        assert_eq!(sessions.resume(other_player_token), Some(PlayerNetId(2)));
+    }
+
+    #[test]
+    fn test_suspend_and_resume() {
+        let mut sessions = Sessions::default();
+        let token = sessions.issue_token(PlayerNetId(1));
+
+        assert!(sessions.suspend(PlayerNetId(1), DisconnectReason::ConnectionClosed));
+        assert!(sessions.is_suspended(PlayerNetId(1)));
+        assert_eq!(sessions.resume(token), Some(PlayerNetId(1)));
+        assert!(!sessions.is_suspended(PlayerNetId(1)));
+
+        assert_eq!(sessions.token(PlayerNetId(1)), Some(token));
+
+        // The handshake got interrupted, the client can retry with the same token.
+        assert!(sessions.suspend(PlayerNetId(1), DisconnectReason::ConnectionClosed));
+        assert_eq!(sessions.resume(token), Some(PlayerNetId(1)));
+    }
+
+    #[test]
+    fn test_suspend_without_resumable_session() {
+        let mut sessions = Sessions::default();
+        // A client disconnected before getting a token.
+        assert!(!sessions.suspend(PlayerNetId(1), DisconnectReason::ConnectionClosed));
+        assert!(!sessions.is_suspended(PlayerNetId(1)));
+
+        let token = sessions.issue_token(PlayerNetId(2));
+        assert!(!sessions.suspend(PlayerNetId(2), DisconnectReason::Kicked));
+        assert!(!sessions.is_suspended(PlayerNetId(2)));
+        assert_eq!(sessions.resume(token), None);
+
+        let token = sessions.issue_token(PlayerNetId(3));
+        assert!(!sessions.suspend(PlayerNetId(3), DisconnectReason::InvalidUpdate));
+        assert!(!sessions.is_suspended(PlayerNetId(3)));
+        assert_eq!(sessions.resume(token), None);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut sessions = Sessions::default();
+        let expired_token = sessions.issue_token(PlayerNetId(1));
+        let token = sessions.issue_token(PlayerNetId(2));
+        assert!(sessions.suspend(PlayerNetId(1), DisconnectReason::Idle));
+        assert!(sessions.suspend(PlayerNetId(2), DisconnectReason::Idle));
+        sessions.suspended.insert(
+            PlayerNetId(1),
+            Utc::now() - Duration::seconds(SESSION_GRACE_PERIOD_SECS + 1),
+        );
+
+        assert_eq!(sessions.expire(), vec![PlayerNetId(1)]);
+        assert_eq!(sessions.expired_players, vec![PlayerNetId(1)]);
+        assert!(!sessions.is_suspended(PlayerNetId(1)));
+        assert!(sessions.is_suspended(PlayerNetId(2)));
+        assert!(sessions.expire().is_empty());
+
+        assert_eq!(sessions.resume(expired_token), None);
+        assert_eq!(sessions.resume(token), Some(PlayerNetId(2)));
+    }
+}
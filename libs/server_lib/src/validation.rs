@@ -0,0 +1,167 @@
+use mr_shared_lib::{
+    framebuffer::FrameNumber, messages::PlayerInput, SIMULATIONS_PER_SECOND,
+    TICKS_PER_NETWORK_BROADCAST,
+};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Clients resend inputs starting from the first unacknowledged packet, and acknowledgments cover
+/// 64 packets, so there's at most an input per frame of those packets.
+pub const MAX_INPUTS_PER_UPDATE: usize = 64 * TICKS_PER_NETWORK_BROADCAST as usize;
+/// Clients send an unreliable message every `TICKS_PER_NETWORK_BROADCAST` frames, we let through
+/// twice as many to be tolerant to jitter.
+const MESSAGES_PER_FRAME: f32 = 2.0 / TICKS_PER_NETWORK_BROADCAST as f32;
+/// Messages that got delayed by the network arrive in bursts.
+const MAX_MESSAGES_BURST: f32 = 30.0;
+/// Reliable messages are sent on user actions (i.e. editing a level), which don't happen nearly
+/// as often as unreliable updates, while being more expensive to process.
+const RELIABLE_MESSAGES_PER_FRAME: f32 = 10.0 / SIMULATIONS_PER_SECOND as f32;
+const MAX_RELIABLE_MESSAGES_BURST: f32 = 20.0;
+/// A connection gets disconnected once it exceeds this number of violations.
+pub const MAX_VIOLATIONS: u32 = 30;
+/// Violations get forgiven if a connection doesn't commit new ones for 10 seconds.
+const VIOLATIONS_RESET_FRAMES: u16 = SIMULATIONS_PER_SECOND * 10;
+
+/// Client misbehaviour that isn't severe enough to disconnect right away: offending messages
+/// are dropped, but violations are counted (see `ConnectionLimits`).
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    #[error("exceeded the rate limit of unreliable messages")]
+    RateLimited,
+    #[error("exceeded the rate limit of reliable messages")]
+    ReliableRateLimited,
+    #[error("an update is too late to be applied (update frame: {0})")]
+    LateUpdate(FrameNumber),
+    #[error("an update has too many inputs: {0}")]
+    TooManyInputs(usize),
+    #[error("an input direction isn't finite")]
+    InvalidDirection,
+    #[error("input frame numbers aren't ascending")]
+    UnorderedInputs,
+}
+
+struct TokenBucket {
+    tokens: f32,
+    refilled_at: FrameNumber,
+}
+
+impl TokenBucket {
+    fn take(&mut self, frame_number: FrameNumber, tokens_per_frame: f32, max_tokens: f32) -> bool {
+        let elapsed_frames = (frame_number - self.refilled_at).value();
+        self.tokens = (self.tokens + elapsed_frames as f32 * tokens_per_frame).min(max_tokens);
+        self.refilled_at = frame_number;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+struct ConnectionLimit {
+    messages: TokenBucket,
+    reliable_messages: TokenBucket,
+    violations: u32,
+    last_violation_at: FrameNumber,
+}
+
+/// Rate limits of client messages and violation counters, per connection handle.
+#[derive(Default)]
+pub struct ConnectionLimits {
+    connections: HashMap<u32, ConnectionLimit>,
+}
+
+impl ConnectionLimits {
+    /// Is expected to be called for every received unreliable message, before processing it.
+    pub fn take_message_token(
+        &mut self,
+        connection_handle: u32,
+        frame_number: FrameNumber,
+    ) -> Result<(), Violation> {
+        let limit = self.get_or_insert(connection_handle, frame_number);
+        if !limit
+            .messages
+            .take(frame_number, MESSAGES_PER_FRAME, MAX_MESSAGES_BURST)
+        {
+            return Err(Violation::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Is expected to be called for every received reliable message that a connected client
+    /// sends (i.e. level edits), before processing it.
+    pub fn take_reliable_message_token(
+        &mut self,
+        connection_handle: u32,
+        frame_number: FrameNumber,
+    ) -> Result<(), Violation> {
+        let limit = self.get_or_insert(connection_handle, frame_number);
+        if !limit.reliable_messages.take(
+            frame_number,
+            RELIABLE_MESSAGES_PER_FRAME,
+            MAX_RELIABLE_MESSAGES_BURST,
+        ) {
+            return Err(Violation::ReliableRateLimited);
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if a connection has exceeded `MAX_VIOLATIONS` and has to be disconnected.
+    pub fn add_violation(&mut self, connection_handle: u32, frame_number: FrameNumber) -> bool {
+        let limit = self.get_or_insert(connection_handle, frame_number);
+        if (frame_number - limit.last_violation_at).value() > VIOLATIONS_RESET_FRAMES {
+            limit.violations = 0;
+        }
+        limit.violations += 1;
+        limit.last_violation_at = frame_number;
+        limit.violations > MAX_VIOLATIONS
+    }
+
+    pub fn remove_connection(&mut self, connection_handle: u32) {
+        self.connections.remove(&connection_handle);
+    }
+
+    fn get_or_insert(
+        &mut self,
+        connection_handle: u32,
+        frame_number: FrameNumber,
+    ) -> &mut ConnectionLimit {
+        self.connections
+            .entry(connection_handle)
+            .or_insert_with(|| ConnectionLimit {
+                messages: TokenBucket {
+                    tokens: MAX_MESSAGES_BURST,
+                    refilled_at: frame_number,
+                },
+                reliable_messages: TokenBucket {
+                    tokens: MAX_RELIABLE_MESSAGES_BURST,
+                    refilled_at: frame_number,
+                },
+                violations: 0,
+                last_violation_at: frame_number,
+            })
+    }
+}
+
+/// Rejects inputs that a well-behaved client can't send. Directions that are longer than
+/// a unit vector are normalized (the movement system doesn't distinguish them anyway).
+pub fn validate_inputs(inputs: &mut [PlayerInput]) -> Result<(), Violation> {
+    if inputs.len() > MAX_INPUTS_PER_UPDATE {
+        return Err(Violation::TooManyInputs(inputs.len()));
+    }
+    if inputs
+        .windows(2)
+        .any(|pair| pair[0].frame_number >= pair[1].frame_number)
+    {
+        return Err(Violation::UnorderedInputs);
+    }
+    for input in inputs {
+        if !input.direction.is_finite() {
+            return Err(Violation::InvalidDirection);
+        }
+        if input.direction.length_squared() > 1.0 {
+            input.direction = input.direction.normalize();
+        }
+    }
+    Ok(())
+}
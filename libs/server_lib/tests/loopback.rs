@@ -0,0 +1,686 @@
+use bevy::{
+    app::App,
+    math::{Quat, Vec3},
+};
+use mr_server_lib::{MetricsServer, MuddleLoopbackServerPlugin};
+use mr_shared_lib::{
+    codec::encode_player_update,
+    game::{
+        level::{LevelObjectDesc, LevelState},
+        level_objects::CuboidDesc,
+    },
+    messages::{
+        ActionNetId, ClientHandshake, ConnectRequest, DisconnectReason, Message,
+        PackedPlayerUpdate, PlayerNetId, PlayerUpdate, ReliableClientMessage,
+        ReliableServerMessage, SessionToken, SpawnLevelObjectRequest, StartGame,
+        UnreliableClientMessage, UnreliableServerMessage,
+    },
+    net::{schema_hash, ConnectionState, ConnectionStatus, MessageId, SessionId, PROTOCOL_VERSION},
+    net_stats::NetworkStats,
+    player::Player,
+    transport::{
+        CompositeTransport, LoopbackHub, LoopbackTransport, NetworkMessage, Transport,
+        SECONDARY_HANDLE_OFFSET,
+    },
+};
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+fn server_app(hub: &LoopbackHub) -> App {
+    let mut builder = App::build();
+    builder
+        .insert_resource(hub.server())
+        .add_plugin(MuddleLoopbackServerPlugin::<LoopbackTransport>::default());
+    builder.app
+}
+
+fn step(app: &mut App, frames: usize) {
+    for _ in 0..frames {
+        app.update();
+    }
+}
+
+fn recv_all<M: NetworkMessage>(client: &mut LoopbackTransport, handle: u32) -> Vec<M> {
+    std::iter::from_fn(|| client.recv_message::<M>(handle)).collect()
+}
+
+/// Goes through the handshake, returning the session id along with the `StartGame` message.
+fn connect(
+    server: &mut App,
+    client: &mut LoopbackTransport,
+    handle: u32,
+) -> (SessionId, StartGame) {
+    resume(server, client, handle, None)
+}
+
+/// Goes through the handshake, trying to resume a session if a token is passed.
+fn resume(
+    server: &mut App,
+    client: &mut LoopbackTransport,
+    handle: u32,
+    session_token: Option<SessionToken>,
+) -> (SessionId, StartGame) {
+    let handshake_id = MessageId::new(0);
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id: SessionId::new(0),
+                message: UnreliableClientMessage::Connect(ConnectRequest {
+                    protocol_version: PROTOCOL_VERSION,
+                    schema_hash: schema_hash(),
+                    handshake_id,
+                }),
+            },
+        )
+        .unwrap();
+    step(server, 1);
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id: SessionId::new(0),
+                message: ReliableClientMessage::Handshake(ClientHandshake {
+                    handshake_id,
+                    session_token,
+                }),
+            },
+        )
+        .unwrap();
+    step(server, 4);
+    recv_all::<Message<ReliableServerMessage>>(client, handle)
+        .into_iter()
+        .find_map(|message| match message.message {
+            ReliableServerMessage::StartGame(start_game) => Some((message.session_id, start_game)),
+            _ => None,
+        })
+        .expect("Expected a StartGame message")
+}
+
+#[test]
+fn test_handshake_and_disconnect() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    let handshake_id = MessageId::new(0);
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id: SessionId::new(0),
+                message: UnreliableClientMessage::Connect(ConnectRequest {
+                    protocol_version: PROTOCOL_VERSION,
+                    schema_hash: schema_hash(),
+                    handshake_id,
+                }),
+            },
+        )
+        .unwrap();
+    step(&mut server, 1);
+
+    let messages = recv_all::<Message<UnreliableServerMessage>>(&mut client, handle);
+    assert!(matches!(
+        messages.as_slice(),
+        [Message { message: UnreliableServerMessage::Handshake(id), .. }] if *id == handshake_id
+    ));
+
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id: SessionId::new(0),
+                message: ReliableClientMessage::Handshake(ClientHandshake {
+                    handshake_id,
+                    session_token: None,
+                }),
+            },
+        )
+        .unwrap();
+    step(&mut server, 4);
+
+    let start_game = recv_all::<Message<ReliableServerMessage>>(&mut client, handle)
+        .into_iter()
+        .find_map(|message| match message.message {
+            ReliableServerMessage::StartGame(start_game) => Some(start_game),
+            _ => None,
+        })
+        .expect("Expected a StartGame message");
+    assert_eq!(start_game.handshake_id, handshake_id);
+    assert!(server
+        .world
+        .get_resource::<HashMap<PlayerNetId, Player>>()
+        .unwrap()
+        .contains_key(&start_game.net_id));
+
+    // The server keeps sending delta updates once a client is connected.
+    step(&mut server, 4);
+    assert!(
+        recv_all::<Message<UnreliableServerMessage>>(&mut client, handle)
+            .iter()
+            .any(|message| matches!(message.message, UnreliableServerMessage::DeltaUpdate(_)))
+    );
+
+    client.disconnect(handle);
+    step(&mut server, 1);
+    assert!(server
+        .world
+        .get_resource::<HashMap<u32, ConnectionState>>()
+        .unwrap()
+        .values()
+        .all(|connection_state| !matches!(connection_state.status(), ConnectionStatus::Connected)));
+}
+
+#[test]
+fn test_resume_session() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    let (_, start_game) = connect(&mut server, &mut client, handle);
+    let mut other_client = hub.client();
+    let other_handle = other_client.connect();
+    connect(&mut server, &mut other_client, other_handle);
+
+    client.disconnect(handle);
+    step(&mut server, 4);
+
+    // Dropping the connection right after the handshake (as if `StartGame` got lost) doesn't end
+    // the session.
+    let mut resuming_client = hub.client();
+    let resuming_handle = resuming_client.connect();
+    let (_, resumed_start_game) = resume(
+        &mut server,
+        &mut resuming_client,
+        resuming_handle,
+        Some(start_game.session_token),
+    );
+    assert_eq!(resumed_start_game.net_id, start_game.net_id);
+    resuming_client.disconnect(resuming_handle);
+    step(&mut server, 4);
+
+    let resuming_handle = resuming_client.connect();
+    let (_, resumed_start_game) = resume(
+        &mut server,
+        &mut resuming_client,
+        resuming_handle,
+        Some(start_game.session_token),
+    );
+    assert_eq!(resumed_start_game.net_id, start_game.net_id);
+    assert_eq!(resumed_start_game.session_token, start_game.session_token);
+    step(&mut server, 4);
+
+    // Other clients never see the player leave or join again.
+    assert!(
+        recv_all::<Message<ReliableServerMessage>>(&mut other_client, other_handle)
+            .iter()
+            .all(|message| !matches!(
+                message.message,
+                ReliableServerMessage::ConnectedPlayer(_)
+                    | ReliableServerMessage::DisconnectedPlayer(_)
+            ))
+    );
+}
+
+#[test]
+fn test_resume_session_twice_in_one_frame() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    let (_, start_game) = connect(&mut server, &mut client, handle);
+    client.disconnect(handle);
+    step(&mut server, 4);
+
+    // Both connections try to resume the same session, the latter one takes it over.
+    let handshake_id = MessageId::new(0);
+    let mut resuming_clients = (0..2)
+        .map(|_| {
+            let mut resuming_client = hub.client();
+            let resuming_handle = resuming_client.connect();
+            resuming_client
+                .send_message(
+                    resuming_handle,
+                    Message {
+                        session_id: SessionId::new(0),
+                        message: UnreliableClientMessage::Connect(ConnectRequest {
+                            protocol_version: PROTOCOL_VERSION,
+                            schema_hash: schema_hash(),
+                            handshake_id,
+                        }),
+                    },
+                )
+                .unwrap();
+            (resuming_client, resuming_handle)
+        })
+        .collect::<Vec<_>>();
+    step(&mut server, 1);
+    for (resuming_client, resuming_handle) in &mut resuming_clients {
+        resuming_client
+            .send_message(
+                *resuming_handle,
+                Message {
+                    session_id: SessionId::new(0),
+                    message: ReliableClientMessage::Handshake(ClientHandshake {
+                        handshake_id,
+                        session_token: Some(start_game.session_token),
+                    }),
+                },
+            )
+            .unwrap();
+    }
+    step(&mut server, 4);
+
+    let start_games = resuming_clients
+        .iter_mut()
+        .map(|(resuming_client, resuming_handle)| {
+            recv_all::<Message<ReliableServerMessage>>(resuming_client, *resuming_handle)
+                .into_iter()
+                .find_map(|message| match message.message {
+                    ReliableServerMessage::StartGame(start_game) => Some(start_game),
+                    _ => None,
+                })
+        })
+        .collect::<Vec<_>>();
+    assert!(start_games[0].is_none());
+    let resumed_start_game = start_games[1]
+        .as_ref()
+        .expect("Expected a StartGame message");
+    assert_eq!(resumed_start_game.net_id, start_game.net_id);
+    assert_eq!(
+        resumed_start_game
+            .game_state
+            .players
+            .iter()
+            .filter(|player_state| player_state.net_id == start_game.net_id)
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn test_composite_transport() {
+    let (primary_hub, secondary_hub) = (LoopbackHub::default(), LoopbackHub::default());
+    let mut builder = App::build();
+    builder
+        .insert_resource(CompositeTransport::new(
+            primary_hub.server(),
+            secondary_hub.server(),
+        ))
+        .add_plugin(MuddleLoopbackServerPlugin::<
+            CompositeTransport<LoopbackTransport, LoopbackTransport>,
+        >::default());
+    let mut server = builder.app;
+    step(&mut server, 1);
+
+    // Both clients get into the same game.
+    let mut primary_client = primary_hub.client();
+    let primary_handle = primary_client.connect();
+    let (_, primary_start_game) = connect(&mut server, &mut primary_client, primary_handle);
+    let mut secondary_client = secondary_hub.client();
+    let secondary_handle = secondary_client.connect();
+    let (_, secondary_start_game) = connect(&mut server, &mut secondary_client, secondary_handle);
+    assert_ne!(primary_start_game.net_id, secondary_start_game.net_id);
+    assert!(secondary_start_game
+        .players
+        .iter()
+        .any(|player| player.net_id == primary_start_game.net_id));
+    assert!(
+        recv_all::<Message<ReliableServerMessage>>(&mut primary_client, primary_handle)
+            .iter()
+            .any(|message| matches!(
+                &message.message,
+                ReliableServerMessage::ConnectedPlayer(player)
+                    if player.net_id == secondary_start_game.net_id
+            ))
+    );
+
+    let connection_states = server
+        .world
+        .get_resource::<HashMap<u32, ConnectionState>>()
+        .unwrap();
+    let mut handles = connection_states.keys().copied().collect::<Vec<_>>();
+    handles.sort_unstable();
+    assert_eq!(handles, vec![0, SECONDARY_HANDLE_OFFSET]);
+    assert!(connection_states
+        .values()
+        .all(|connection_state| matches!(connection_state.status(), ConnectionStatus::Connected)));
+
+    secondary_client.disconnect(secondary_handle);
+    step(&mut server, 1);
+    assert!(!matches!(
+        server
+            .world
+            .get_resource::<HashMap<u32, ConnectionState>>()
+            .unwrap()
+            .get(&SECONDARY_HANDLE_OFFSET)
+            .map(|connection_state| connection_state.status()),
+        Some(ConnectionStatus::Connected)
+    ));
+}
+
+#[test]
+fn test_flooding_client_gets_disconnected() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    let (session_id, start_game) = connect(&mut server, &mut client, handle);
+
+    // Valid updates, but way more than a client is expected to send in a single frame.
+    let update = PackedPlayerUpdate(encode_player_update(&PlayerUpdate {
+        frame_number: start_game.game_state.frame_number,
+        acknowledgments: (None, 0),
+        inputs: Vec::new(),
+    }));
+    for _ in 0..100 {
+        client
+            .send_message(
+                handle,
+                Message {
+                    session_id,
+                    message: UnreliableClientMessage::PlayerUpdate(update.clone()),
+                },
+            )
+            .unwrap();
+    }
+
+    // Messages are read after every frame, as the server drops the connection once it sends
+    // the reason.
+    let mut messages = Vec::new();
+    for _ in 0..4 {
+        step(&mut server, 1);
+        messages.extend(recv_all::<Message<ReliableServerMessage>>(
+            &mut client,
+            handle,
+        ));
+    }
+    assert!(messages.iter().any(|message| matches!(
+        message.message,
+        ReliableServerMessage::Disconnect(DisconnectReason::TooManyViolations)
+    )));
+}
+
+#[test]
+fn test_flooding_with_level_edits_gets_disconnected() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    let (session_id, _) = connect(&mut server, &mut client, handle);
+
+    // Reliable messages have their own (stricter) rate limit.
+    for _ in 0..100 {
+        client
+            .send_message(
+                handle,
+                Message {
+                    session_id,
+                    message: ReliableClientMessage::UndoLevelEdit,
+                },
+            )
+            .unwrap();
+    }
+
+    let mut messages = Vec::new();
+    for _ in 0..4 {
+        step(&mut server, 1);
+        messages.extend(recv_all::<Message<ReliableServerMessage>>(
+            &mut client,
+            handle,
+        ));
+    }
+    assert!(messages.iter().any(|message| matches!(
+        message.message,
+        ReliableServerMessage::Disconnect(DisconnectReason::TooManyViolations)
+    )));
+}
+
+#[test]
+fn test_full_state_request() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    let (session_id, start_game) = connect(&mut server, &mut client, handle);
+
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id,
+                message: ReliableClientMessage::RequestFullState,
+            },
+        )
+        .unwrap();
+    step(&mut server, 4);
+
+    let (new_session_id, full_state) =
+        recv_all::<Message<ReliableServerMessage>>(&mut client, handle)
+            .into_iter()
+            .find_map(|message| match message.message {
+                ReliableServerMessage::FullState(full_state) => {
+                    Some((message.session_id, full_state))
+                }
+                _ => None,
+            })
+            .expect("Expected a FullState message");
+    assert_ne!(new_session_id, session_id);
+    assert_eq!(full_state.objects.len(), start_game.objects.len());
+    assert!(full_state
+        .game_state
+        .players
+        .iter()
+        .any(|player_state| player_state.net_id == start_game.net_id));
+
+    // Updates of the previous session are ignored instead of being treated as invalid.
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id,
+                message: UnreliableClientMessage::PlayerUpdate(PackedPlayerUpdate(
+                    encode_player_update(&PlayerUpdate {
+                        frame_number: start_game.game_state.frame_number,
+                        acknowledgments: (None, 0),
+                        inputs: Vec::new(),
+                    }),
+                )),
+            },
+        )
+        .unwrap();
+    step(&mut server, 2);
+    assert!(server
+        .world
+        .get_resource::<HashMap<u32, ConnectionState>>()
+        .unwrap()
+        .values()
+        .all(|connection_state| matches!(connection_state.status(), ConnectionStatus::Connected)));
+}
+
+#[test]
+fn test_level_edits_in_the_same_frame() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    let (session_id, _) = connect(&mut server, &mut client, handle);
+
+    // The server hasn't simulated the spawn by the time it gets the undo request (and the undo by
+    // the time it gets the redo one), but none of them is expected to get lost.
+    let desc = LevelObjectDesc::Cuboid(CuboidDesc {
+        position: Vec3::new(2.0, 0.5, 2.0),
+        rotation: Quat::identity(),
+        size: Vec3::ONE,
+    });
+    for message in vec![
+        ReliableClientMessage::SpawnLevelObject(SpawnLevelObjectRequest {
+            action_id: ActionNetId(0),
+            desc: desc.clone(),
+        }),
+        ReliableClientMessage::UndoLevelEdit,
+        ReliableClientMessage::RedoLevelEdit,
+        ReliableClientMessage::UndoLevelEdit,
+    ] {
+        client
+            .send_message(
+                handle,
+                Message {
+                    session_id,
+                    message,
+                },
+            )
+            .unwrap();
+    }
+    step(&mut server, 8);
+
+    let edits = recv_all::<Message<ReliableServerMessage>>(&mut client, handle)
+        .into_iter()
+        .filter_map(|message| match message.message {
+            ReliableServerMessage::SpawnLevelObject(command) => {
+                Some(("spawn", command.object.net_id))
+            }
+            ReliableServerMessage::DespawnLevelObject(command) => Some(("despawn", command.net_id)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let net_id = edits.first().expect("Expected a spawned object").1;
+    assert_eq!(
+        edits,
+        vec![
+            ("spawn", net_id),
+            ("despawn", net_id),
+            ("spawn", net_id),
+            ("despawn", net_id)
+        ]
+    );
+    let level_object = |server: &App| {
+        server
+            .world
+            .get_resource::<LevelState>()
+            .unwrap()
+            .objects
+            .iter()
+            .find(|object| object.net_id == net_id)
+            .map(|object| object.desc.clone())
+    };
+    assert_eq!(level_object(&server), None);
+
+    // The history is intact.
+    client
+        .send_message(
+            handle,
+            Message {
+                session_id,
+                message: ReliableClientMessage::RedoLevelEdit,
+            },
+        )
+        .unwrap();
+    step(&mut server, 4);
+    assert_eq!(level_object(&server), Some(desc));
+}
+
+#[test]
+fn test_network_stats() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    connect(&mut server, &mut client, handle);
+    step(&mut server, 4);
+
+    let network_stats = server.world.get_resource::<NetworkStats>().unwrap();
+    let traffic = match network_stats.iter().collect::<Vec<_>>().as_slice() {
+        [(_, traffic)] => (*traffic).clone(),
+        connections => panic!("Expected a single connection, got {}", connections.len()),
+    };
+    assert_eq!(traffic.received.total.messages, 2);
+    assert_eq!(
+        traffic.received.by_message["UnreliableClientMessage::Connect"].messages,
+        1
+    );
+    assert_eq!(
+        traffic.received.by_message["ReliableClientMessage::Handshake"].messages,
+        1
+    );
+    assert_eq!(
+        traffic.sent.by_message["ReliableServerMessage::StartGame"].messages,
+        1
+    );
+    assert!(traffic.sent.by_message["UnreliableServerMessage::DeltaUpdate"].bytes > 0);
+    assert!(traffic.sent.total.bytes > traffic.received.total.bytes);
+
+    // Statistics are removed along with connections.
+    client.disconnect(handle);
+    step(&mut server, 4);
+    assert!(server
+        .world
+        .get_resource::<NetworkStats>()
+        .unwrap()
+        .iter()
+        .next()
+        .is_none());
+}
+
+#[test]
+fn test_metrics_endpoint() {
+    let hub = LoopbackHub::default();
+    let mut server = server_app(&hub);
+    let metrics_server = MetricsServer::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let metrics_addr = metrics_server.local_addr().unwrap();
+    server.world.insert_resource(metrics_server);
+    step(&mut server, 1);
+
+    let mut client = hub.client();
+    let handle = client.connect();
+    connect(&mut server, &mut client, handle);
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    // Connections are accepted by the server systems, so we wait until one picks it up.
+    stream
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let mut response = Vec::new();
+    for _ in 0..100 {
+        step(&mut server, 1);
+        let mut buf = [0; 4096];
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => response.extend_from_slice(&buf[..read]),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => panic!("Failed to read the metrics: {:?}", err),
+        }
+    }
+    let response = String::from_utf8(response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("\nmuddle_connected_players 1\n"));
+    assert!(response.contains("\nmuddle_connection_rtt_seconds{connection=\""));
+    assert!(response.contains("\nmuddle_tick_duration_seconds_count "));
+    assert!(response.contains(
+        "\nmuddle_connection_messages_per_second{connection=\"0\",direction=\"received\"} "
+    ));
+}
@@ -0,0 +1,127 @@
+//! Compares the sizes of messages encoded with `codec` against the plain bincode encoding, and
+//! measures the encoding/decoding time.
+//!
+//! Run with `cargo bench -p mr_shared_lib --bench codec_size`.
+
+use bevy::math::{Vec2, Vec3};
+use mr_shared_lib::{
+    codec::{decode_delta_update, encode_delta_update, encode_player_update, SnapshotHistory},
+    framebuffer::FrameNumber,
+    messages::{
+        DeltaUpdate, PackedDeltaUpdate, PackedPlayerUpdate, PlayerInput, PlayerNetId, PlayerState,
+        PlayerUpdate,
+    },
+};
+use std::time::Instant;
+
+const ITERATIONS: u32 = 10_000;
+
+fn inputs(frame_number: FrameNumber, count: u16, seed: f32) -> Vec<PlayerInput> {
+    (0..count)
+        .map(|i| {
+            let angle = seed + i as f32;
+            PlayerInput {
+                frame_number: frame_number - FrameNumber::new(count - i),
+                direction: if i % 3 == 0 {
+                    Vec2::ZERO
+                } else {
+                    Vec2::new(angle.cos(), angle.sin())
+                },
+                jump: i % 5 == 0,
+            }
+        })
+        .collect()
+}
+
+fn delta_update(frame_number: u16, players: u16, inputs_per_player: u16) -> DeltaUpdate {
+    let frame_number = FrameNumber::new(frame_number);
+    DeltaUpdate {
+        frame_number,
+        acknowledgments: (Some(frame_number - FrameNumber::new(6)), u64::MAX),
+        players: (0..players)
+            .map(|i| {
+                let seed = i as f32 * 1.7;
+                let moved = frame_number.value() as f32 * 0.04;
+                PlayerState {
+                    net_id: PlayerNetId(i),
+                    is_spawned: true,
+                    position: Vec3::new(seed.sin() * 8.0 + moved, 0.5, seed.cos() * 8.0),
+                    velocity: Vec3::new(5.0, 0.0, 0.3),
+                    inputs: inputs(frame_number, inputs_per_player, seed),
+                }
+            })
+            .collect(),
+        confirmed_actions: Vec::new(),
+        checksum: None,
+    }
+}
+
+fn bincode_size<T: serde::Serialize>(value: &T) -> u64 {
+    bincode::serialized_size(value).unwrap()
+}
+
+fn bench_delta_update(players: u16, inputs_per_player: u16) {
+    let baseline_update = delta_update(1000, players, inputs_per_player);
+    let update = delta_update(1012, players, inputs_per_player);
+
+    let mut sender_history = SnapshotHistory::default();
+    let (_, baseline_snapshot) = encode_delta_update(&baseline_update, None);
+    let mut recipient_history = SnapshotHistory::default();
+    recipient_history.insert(baseline_update.frame_number, baseline_snapshot.clone());
+    sender_history.insert(baseline_update.frame_number, baseline_snapshot);
+
+    let baseline = Some((
+        baseline_update.frame_number,
+        sender_history.get(baseline_update.frame_number).unwrap(),
+    ));
+    let (full, _) = encode_delta_update(&update, None);
+    let (delta, _) = encode_delta_update(&update, baseline);
+
+    let started_at = Instant::now();
+    for _ in 0..ITERATIONS {
+        encode_delta_update(&update, baseline);
+    }
+    let encode_time = started_at.elapsed() / ITERATIONS;
+    let started_at = Instant::now();
+    for _ in 0..ITERATIONS {
+        decode_delta_update(&delta, &recipient_history).unwrap();
+    }
+    let decode_time = started_at.elapsed() / ITERATIONS;
+
+    println!(
+        "DeltaUpdate ({:>2} players, {:>2} inputs each): bincode {:>5} B, packed {:>5} B, packed with a baseline {:>5} B (encode: {:?}, decode: {:?})",
+        players,
+        inputs_per_player,
+        bincode_size(&update),
+        bincode_size(&PackedDeltaUpdate(full)),
+        bincode_size(&PackedDeltaUpdate(delta)),
+        encode_time,
+        decode_time,
+    );
+}
+
+fn bench_player_update(inputs_count: u16) {
+    let frame_number = FrameNumber::new(1000);
+    let update = PlayerUpdate {
+        frame_number,
+        acknowledgments: (Some(frame_number - FrameNumber::new(8)), u64::MAX),
+        inputs: inputs(frame_number, inputs_count, 0.5),
+    };
+    println!(
+        "PlayerUpdate ({:>2} inputs): bincode {:>5} B, packed {:>5} B",
+        inputs_count,
+        bincode_size(&update),
+        bincode_size(&PackedPlayerUpdate(encode_player_update(&update))),
+    );
+}
+
+fn main() {
+    for &players in &[1, 8, 32] {
+        for &inputs_per_player in &[1, 8] {
+            bench_delta_update(players, inputs_per_player);
+        }
+    }
+    for &inputs_count in &[1, 8, 32] {
+        bench_player_update(inputs_count);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{env, fs, path::Path};
+
+/// Source files that define everything that gets serialized and sent over the network, along with
+/// the items of each file that make up the schema.
+const SCHEMA_FILES: &[(&str, SchemaItems)] = &[
+    // The custom encoding of some messages is a part of the schema as well.
+    ("src/codec.rs", SchemaItems::AllButTests),
+    ("src/messages.rs", SchemaItems::TypeDefinitions),
+    ("src/game/commands.rs", SchemaItems::TypeDefinitions),
+    ("src/game/level.rs", SchemaItems::TypeDefinitions),
+    ("src/game/level_objects.rs", SchemaItems::TypeDefinitions),
+];
+
+#[derive(Clone, Copy)]
+enum SchemaItems {
+    /// Every top-level item, except for the ones marked with `#[cfg(test)]`.
+    AllButTests,
+    /// Struct, enum and type alias definitions (along with their attributes).
+    TypeDefinitions,
+}
+
+// FNV-1a, as std's hashers aren't guaranteed to be stable between Rust releases.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for (file, schema_items) in SCHEMA_FILES {
+        println!("cargo:rerun-if-changed={}", file);
+        let source = fs::read_to_string(Path::new(&manifest_dir).join(file))
+            .unwrap_or_else(|err| panic!("Failed to read {}: {:?}", file, err));
+        // Comments (including docs), formatting, tests and helper functions don't affect
+        // the schema, so we don't want them to make clients and servers incompatible.
+        for item in top_level_items(&strip_comments(&source)) {
+            if !is_schema_item(&item, *schema_items) {
+                continue;
+            }
+            for byte in item.bytes().filter(|byte| !byte.is_ascii_whitespace()) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        }
+    }
+
+    println!("cargo:rustc-env=MUDDLE_SCHEMA_HASH={}", hash);
+}
+
+/// Removes line and block comments, leaving string literals intact. Char literals aren't
+/// recognized, so the schema files are expected not to contain `'"'`.
+fn strip_comments(source: &str) -> String {
+    let mut stripped = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut is_in_string = false;
+    while let Some(c) = chars.next() {
+        if is_in_string {
+            stripped.push(c);
+            match c {
+                '\\' => stripped.extend(chars.next()),
+                '"' => is_in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        let next = chars.peek().copied();
+        match (c, next) {
+            ('/', Some('/')) => {
+                for c in &mut chars {
+                    if c == '\n' {
+                        stripped.push(c);
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in &mut chars {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                stripped.push(' ');
+            }
+            _ => {
+                is_in_string = c == '"';
+                stripped.push(c);
+            }
+        }
+    }
+    stripped
+}
+
+/// Splits source code (without comments) into top-level items, each one including its attributes.
+fn top_level_items(source: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut depth = 0;
+    let mut is_in_string = false;
+    let mut is_escaped = false;
+    for c in source.chars() {
+        item.push(c);
+        if is_in_string {
+            match c {
+                _ if is_escaped => is_escaped = false,
+                '\\' => is_escaped = true,
+                '"' => is_in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => is_in_string = true,
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    items.push(std::mem::take(&mut item));
+                }
+            }
+            ';' if depth == 0 => items.push(std::mem::take(&mut item)),
+            _ => {}
+        }
+    }
+    items
+}
+
+fn is_schema_item(item: &str, schema_items: SchemaItems) -> bool {
+    let mut declaration = item.trim_start();
+    while declaration.starts_with("#[") {
+        let (attribute, rest) = declaration.split_at(attribute_len(declaration));
+        if attribute.split_whitespace().collect::<String>() == "#[cfg(test)]" {
+            return false;
+        }
+        declaration = rest.trim_start();
+    }
+
+    match schema_items {
+        SchemaItems::AllButTests => true,
+        SchemaItems::TypeDefinitions => {
+            let keyword = declaration
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .find(|word| !matches!(*word, "" | "pub" | "crate" | "super" | "in"));
+            matches!(keyword, Some("struct" | "enum" | "type"))
+        }
+    }
+}
+
+/// Returns the length of the attribute that a declaration starts with.
+fn attribute_len(declaration: &str) -> usize {
+    let mut depth = 0;
+    for (i, c) in declaration.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    declaration.len()
+}
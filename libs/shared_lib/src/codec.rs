@@ -0,0 +1,715 @@
+//! A compact encoding of the messages that get sent every network tick (`DeltaUpdate` and
+//! `PlayerUpdate`). Values are quantized and written with bit granularity, frame numbers are
+//! written relative to the frame of a message, and player positions are delta-encoded against
+//! the latest snapshot acknowledged by a recipient.
+//!
+//! The module also defines position checksums, which are player positions quantized coarser than
+//! the ones sent in updates.
+
+use crate::{
+    framebuffer::FrameNumber,
+    messages::{
+        ActionNetId, ConfirmedAction, DeltaUpdate, PlayerInput, PlayerNetId, PlayerState,
+        PlayerUpdate, PositionsChecksum,
+    },
+};
+use bevy::math::{Vec2, Vec3};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+};
+use thiserror::Error;
+
+pub const POSITION_QUANTUM: f32 = 1.0 / 512.0;
+/// Checksums only need to reveal diverged simulations, so they don't need the precision
+/// of `POSITION_QUANTUM`.
+pub const CHECKSUM_POSITION_QUANTUM: f32 = 1.0 / 16.0;
+/// Positions that are this many `CHECKSUM_POSITION_QUANTUM`s apart still match. Two positions that
+/// are arbitrarily close can round to adjacent values, if they straddle a rounding boundary.
+const CHECKSUM_TOLERANCE: i32 = 1;
+pub const VELOCITY_QUANTUM: f32 = 1.0 / 256.0;
+const DIRECTION_SCALE: f32 = 127.0;
+/// Matches the number of packets that acknowledgment bit sets cover.
+pub const SNAPSHOT_HISTORY_LIMIT: usize = 64;
+/// Small values (which most of relative frame numbers and position deltas are) fit into one chunk.
+const VARINT_CHUNK_BITS: u32 = 4;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CodecError {
+    #[error("unexpected end of a message")]
+    UnexpectedEnd,
+    #[error("varint value doesn't fit into 64 bits")]
+    VarintOverflow,
+    #[error("decoded value doesn't fit into 32 bits")]
+    ValueOverflow,
+    #[error("baseline snapshot for frame {0} is missing")]
+    MissingBaseline(FrameNumber),
+}
+
+/// Quantized player positions of a sent (or received) `DeltaUpdate`.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    positions: HashMap<PlayerNetId, [i32; 3]>,
+}
+
+/// Snapshots of the latest `SNAPSHOT_HISTORY_LIMIT` delta updates. Senders look up the snapshots
+/// that recipients acknowledged, recipients look up the snapshots that senders used as baselines.
+#[derive(Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<(FrameNumber, Snapshot)>,
+}
+
+impl SnapshotHistory {
+    pub fn get(&self, frame_number: FrameNumber) -> Option<&Snapshot> {
+        self.snapshots
+            .iter()
+            .find(|(snapshot_frame_number, _)| *snapshot_frame_number == frame_number)
+            .map(|(_, snapshot)| snapshot)
+    }
+
+    pub fn insert(&mut self, frame_number: FrameNumber, snapshot: Snapshot) {
+        self.snapshots
+            .retain(|(snapshot_frame_number, _)| *snapshot_frame_number != frame_number);
+        self.snapshots.push_back((frame_number, snapshot));
+        while self.snapshots.len() > SNAPSHOT_HISTORY_LIMIT {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
+
+/// Returns the encoded message and the snapshot that the sender is expected to store (under
+/// the message's frame number). `baseline` is expected to be the newest snapshot acknowledged by
+/// the recipient.
+pub fn encode_delta_update(
+    update: &DeltaUpdate,
+    baseline: Option<(FrameNumber, &Snapshot)>,
+) -> (Vec<u8>, Snapshot) {
+    let mut writer = BitWriter::default();
+    let mut snapshot = Snapshot::default();
+
+    writer.write_bits(update.frame_number.value() as u64, 16);
+    write_acknowledgments(&mut writer, update.frame_number, update.acknowledgments);
+
+    writer.write_bit(baseline.is_some());
+    if let Some((baseline_frame_number, _)) = baseline {
+        writer.write_varint((update.frame_number - baseline_frame_number).value() as u64);
+    }
+
+    writer.write_varint(update.players.len() as u64);
+    for player in &update.players {
+        writer.write_varint(player.net_id.0 as u64);
+        writer.write_bit(player.is_spawned);
+        if !player.is_spawned {
+            continue;
+        }
+
+        let position = quantize_vec3(player.position, POSITION_QUANTUM);
+        let baseline_position =
+            baseline.and_then(|(_, baseline)| baseline.positions.get(&player.net_id));
+        writer.write_bit(baseline_position.is_some());
+        let reference = baseline_position.copied().unwrap_or([0; 3]);
+        for (value, reference) in position.iter().zip(reference.iter()) {
+            writer.write_signed_varint(*value as i64 - *reference as i64);
+        }
+        snapshot.positions.insert(player.net_id, position);
+
+        for value in &quantize_vec3(player.velocity, VELOCITY_QUANTUM) {
+            writer.write_signed_varint(*value as i64);
+        }
+        write_inputs(&mut writer, update.frame_number, &player.inputs);
+    }
+
+    writer.write_varint(update.confirmed_actions.len() as u64);
+    for confirmed_action in &update.confirmed_actions {
+        writer.write_varint(confirmed_action.id.0 as u64);
+        write_optional_frame_number(
+            &mut writer,
+            update.frame_number,
+            confirmed_action.confirmed_frame,
+        );
+    }
+
+    writer.write_bit(update.checksum.is_some());
+    if let Some(checksum) = &update.checksum {
+        writer.write_varint((update.frame_number - checksum.frame_number).value() as u64);
+        writer.write_varint(checksum.players.len() as u64);
+        for (net_id, player_checksum) in &checksum.players {
+            writer.write_varint(net_id.0 as u64);
+            for value in player_checksum {
+                writer.write_signed_varint(*value as i64);
+            }
+        }
+    }
+
+    (writer.into_bytes(), snapshot)
+}
+
+/// Returns the decoded message and the snapshot that the recipient is expected to store (under
+/// the message's frame number) before acknowledging it.
+pub fn decode_delta_update(
+    bytes: &[u8],
+    history: &SnapshotHistory,
+) -> Result<(DeltaUpdate, Snapshot), CodecError> {
+    let mut reader = BitReader::new(bytes);
+    let mut snapshot = Snapshot::default();
+
+    let frame_number = FrameNumber::new(reader.read_bits(16)? as u16);
+    let acknowledgments = read_acknowledgments(&mut reader, frame_number)?;
+
+    let baseline = if reader.read_bit()? {
+        let baseline_frame_number = frame_number - FrameNumber::new(reader.read_varint()? as u16);
+        let baseline = history
+            .get(baseline_frame_number)
+            .ok_or(CodecError::MissingBaseline(baseline_frame_number))?;
+        Some((baseline_frame_number, baseline))
+    } else {
+        None
+    };
+
+    let players_len = reader.read_len()?;
+    let mut players = Vec::with_capacity(players_len);
+    for _ in 0..players_len {
+        let net_id = PlayerNetId(reader.read_varint()? as u16);
+        if !reader.read_bit()? {
+            players.push(PlayerState::dead(net_id));
+            continue;
+        }
+
+        let is_delta = reader.read_bit()?;
+        let reference = match baseline {
+            Some((baseline_frame_number, baseline)) if is_delta => *baseline
+                .positions
+                .get(&net_id)
+                .ok_or(CodecError::MissingBaseline(baseline_frame_number))?,
+            // A message can't reference a baseline that it doesn't specify.
+            None if is_delta => return Err(CodecError::MissingBaseline(frame_number)),
+            _ => [0; 3],
+        };
+        let mut position = [0; 3];
+        for (value, reference) in position.iter_mut().zip(reference.iter()) {
+            *value = (*reference as i64)
+                .checked_add(reader.read_signed_varint()?)
+                .and_then(|value| i32::try_from(value).ok())
+                .ok_or(CodecError::ValueOverflow)?;
+        }
+        snapshot.positions.insert(net_id, position);
+
+        let mut velocity = [0; 3];
+        for value in velocity.iter_mut() {
+            *value = reader.read_signed_i32()?;
+        }
+        let inputs = read_inputs(&mut reader, frame_number)?;
+
+        players.push(PlayerState {
+            net_id,
+            is_spawned: true,
+            position: dequantize_vec3(position, POSITION_QUANTUM),
+            velocity: dequantize_vec3(velocity, VELOCITY_QUANTUM),
+            inputs,
+        });
+    }
+
+    let confirmed_actions_len = reader.read_len()?;
+    let mut confirmed_actions = Vec::with_capacity(confirmed_actions_len);
+    for _ in 0..confirmed_actions_len {
+        confirmed_actions.push(ConfirmedAction {
+            id: ActionNetId(reader.read_varint()? as u16),
+            confirmed_frame: read_optional_frame_number(&mut reader, frame_number)?,
+        });
+    }
+
+    let checksum = if reader.read_bit()? {
+        let checksum_frame_number = frame_number - FrameNumber::new(reader.read_varint()? as u16);
+        let players_len = reader.read_len()?;
+        let mut players = Vec::with_capacity(players_len);
+        for _ in 0..players_len {
+            let net_id = PlayerNetId(reader.read_varint()? as u16);
+            let mut player_checksum = [0; 3];
+            for value in &mut player_checksum {
+                *value = reader.read_signed_i32()?;
+            }
+            players.push((net_id, player_checksum));
+        }
+        Some(PositionsChecksum {
+            frame_number: checksum_frame_number,
+            players,
+        })
+    } else {
+        None
+    };
+
+    Ok((
+        DeltaUpdate {
+            frame_number,
+            acknowledgments,
+            players,
+            confirmed_actions,
+            checksum,
+        },
+        snapshot,
+    ))
+}
+
+pub fn encode_player_update(update: &PlayerUpdate) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.write_bits(update.frame_number.value() as u64, 16);
+    write_acknowledgments(&mut writer, update.frame_number, update.acknowledgments);
+    write_inputs(&mut writer, update.frame_number, &update.inputs);
+    writer.into_bytes()
+}
+
+pub fn decode_player_update(bytes: &[u8]) -> Result<PlayerUpdate, CodecError> {
+    let mut reader = BitReader::new(bytes);
+    let frame_number = FrameNumber::new(reader.read_bits(16)? as u16);
+    let acknowledgments = read_acknowledgments(&mut reader, frame_number)?;
+    let inputs = read_inputs(&mut reader, frame_number)?;
+    Ok(PlayerUpdate {
+        frame_number,
+        acknowledgments,
+        inputs,
+    })
+}
+
+fn write_acknowledgments(
+    writer: &mut BitWriter,
+    frame_number: FrameNumber,
+    (ack_frame_number, ack_bit_set): (Option<FrameNumber>, u64),
+) {
+    write_optional_frame_number(writer, frame_number, ack_frame_number);
+    // Most of the packets are expected to be acknowledged, so inverting the bit set makes it
+    // a small number.
+    writer.write_varint(!ack_bit_set);
+}
+
+fn read_acknowledgments(
+    reader: &mut BitReader,
+    frame_number: FrameNumber,
+) -> Result<(Option<FrameNumber>, u64), CodecError> {
+    let ack_frame_number = read_optional_frame_number(reader, frame_number)?;
+    let ack_bit_set = !reader.read_varint()?;
+    Ok((ack_frame_number, ack_bit_set))
+}
+
+fn write_inputs(writer: &mut BitWriter, frame_number: FrameNumber, inputs: &[PlayerInput]) {
+    writer.write_varint(inputs.len() as u64);
+    for input in inputs {
+        write_frame_number(writer, frame_number, input.frame_number);
+        let is_idle = input.direction == Vec2::ZERO;
+        writer.write_bit(is_idle);
+        if !is_idle {
+            for value in &encode_direction(input.direction) {
+                writer.write_bits(*value as u8 as u64, 8);
+            }
+        }
+        writer.write_bit(input.jump);
+    }
+}
+
+fn read_inputs(
+    reader: &mut BitReader,
+    frame_number: FrameNumber,
+) -> Result<Vec<PlayerInput>, CodecError> {
+    let inputs_len = reader.read_len()?;
+    let mut inputs = Vec::with_capacity(inputs_len);
+    for _ in 0..inputs_len {
+        let input_frame_number = read_frame_number(reader, frame_number)?;
+        let direction = if reader.read_bit()? {
+            Vec2::ZERO
+        } else {
+            let x = reader.read_bits(8)? as u8 as i8;
+            let y = reader.read_bits(8)? as u8 as i8;
+            decode_direction([x, y])
+        };
+        inputs.push(PlayerInput {
+            frame_number: input_frame_number,
+            direction,
+            jump: reader.read_bit()?,
+        });
+    }
+    Ok(inputs)
+}
+
+/// Rounds a direction to the precision it gets sent with. Clients are expected to simulate their
+/// players with quantized directions, otherwise their predictions would diverge from the server.
+pub fn quantize_direction(direction: Vec2) -> Vec2 {
+    decode_direction(encode_direction(direction))
+}
+
+fn encode_direction(direction: Vec2) -> [i8; 2] {
+    let encode = |value: f32| (value.max(-1.0).min(1.0) * DIRECTION_SCALE).round() as i8;
+    [encode(direction.x), encode(direction.y)]
+}
+
+fn decode_direction([x, y]: [i8; 2]) -> Vec2 {
+    Vec2::new(x as f32, y as f32) / DIRECTION_SCALE
+}
+
+fn write_frame_number(writer: &mut BitWriter, relative_to: FrameNumber, frame_number: FrameNumber) {
+    writer.write_signed_varint((frame_number - relative_to).value() as i16 as i64);
+}
+
+fn read_frame_number(
+    reader: &mut BitReader,
+    relative_to: FrameNumber,
+) -> Result<FrameNumber, CodecError> {
+    let diff = reader.read_signed_varint()?;
+    Ok(relative_to + FrameNumber::new(diff as i16 as u16))
+}
+
+fn write_optional_frame_number(
+    writer: &mut BitWriter,
+    relative_to: FrameNumber,
+    frame_number: Option<FrameNumber>,
+) {
+    writer.write_bit(frame_number.is_some());
+    if let Some(frame_number) = frame_number {
+        write_frame_number(writer, relative_to, frame_number);
+    }
+}
+
+fn read_optional_frame_number(
+    reader: &mut BitReader,
+    relative_to: FrameNumber,
+) -> Result<Option<FrameNumber>, CodecError> {
+    if reader.read_bit()? {
+        read_frame_number(reader, relative_to).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Quantizes a position with `CHECKSUM_POSITION_QUANTUM`. Checksums are meant to be compared with
+/// `position_checksums_match`, as positions that clients receive in delta updates lose precision.
+pub fn position_checksum(position: Vec3) -> [i32; 3] {
+    quantize_vec3(position, CHECKSUM_POSITION_QUANTUM)
+}
+
+pub fn position_checksums_match(a: [i32; 3], b: [i32; 3]) -> bool {
+    a.iter()
+        .zip(&b)
+        .all(|(a, b)| (a - b).abs() <= CHECKSUM_TOLERANCE)
+}
+
+fn quantize_vec3(value: Vec3, quantum: f32) -> [i32; 3] {
+    // Float to int casts saturate, so we don't need to care about overflows.
+    [
+        (value.x / quantum).round() as i32,
+        (value.y / quantum).round() as i32,
+        (value.z / quantum).round() as i32,
+    ]
+}
+
+fn dequantize_vec3(value: [i32; 3], quantum: f32) -> Vec3 {
+    Vec3::new(
+        value[0] as f32 * quantum,
+        value[1] as f32 * quantum,
+        value[2] as f32 * quantum,
+    )
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits_len: usize,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.bits_len % 8 == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits_len % 8);
+        }
+        self.bits_len += 1;
+    }
+
+    fn write_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    /// Every chunk is prefixed with a bit that tells whether there's a next chunk.
+    fn write_varint(&mut self, mut value: u64) {
+        loop {
+            let chunk = value & ((1 << VARINT_CHUNK_BITS) - 1);
+            value >>= VARINT_CHUNK_BITS;
+            self.write_bit(value != 0);
+            self.write_bits(chunk, VARINT_CHUNK_BITS);
+            if value == 0 {
+                break;
+            }
+        }
+    }
+
+    fn write_signed_varint(&mut self, value: i64) {
+        // Zigzag encoding, to make small negative values small as well.
+        self.write_varint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, CodecError> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or(CodecError::UnexpectedEnd)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u64, CodecError> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+
+    fn read_varint(&mut self) -> Result<u64, CodecError> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let has_next_chunk = self.read_bit()?;
+            let chunk = self.read_bits(VARINT_CHUNK_BITS)?;
+            if shift >= 64 {
+                return Err(CodecError::VarintOverflow);
+            }
+            value |= chunk << shift;
+            shift += VARINT_CHUNK_BITS;
+            if !has_next_chunk {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_signed_varint(&mut self) -> Result<i64, CodecError> {
+        let value = self.read_varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn read_signed_i32(&mut self) -> Result<i32, CodecError> {
+        i32::try_from(self.read_signed_varint()?).map_err(|_| CodecError::ValueOverflow)
+    }
+
+    /// Reads a length of a collection. As every element takes at least one bit, lengths that
+    /// exceed the number of remaining bits are rejected (instead of trying to allocate them).
+    fn read_len(&mut self) -> Result<usize, CodecError> {
+        let len = self.read_varint()?;
+        let remaining_bits = (self.bytes.len() * 8).saturating_sub(self.position);
+        if len > remaining_bits as u64 {
+            return Err(CodecError::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        codec::{
+            decode_delta_update, decode_player_update, encode_delta_update, encode_player_update,
+            position_checksum, position_checksums_match, quantize_direction, CodecError, Snapshot,
+            SnapshotHistory, CHECKSUM_POSITION_QUANTUM, POSITION_QUANTUM,
+        },
+        framebuffer::FrameNumber,
+        messages::{
+            ActionNetId, ConfirmedAction, DeltaUpdate, PlayerInput, PlayerNetId, PlayerState,
+            PlayerUpdate, PositionsChecksum,
+        },
+    };
+    use bevy::math::{Vec2, Vec3};
+
+    fn delta_update(frame_number: u16, position: Vec3) -> DeltaUpdate {
+        let frame_number = FrameNumber::new(frame_number);
+        DeltaUpdate {
+            frame_number,
+            acknowledgments: (Some(frame_number - FrameNumber::new(3)), u64::MAX - 2),
+            players: vec![PlayerState {
+                net_id: PlayerNetId(3),
+                is_spawned: true,
+                position,
+                velocity: Vec3::new(0.0, -2.5, 1.0),
+                inputs: vec![
+                    PlayerInput {
+                        frame_number: frame_number - FrameNumber::new(2),
+                        direction: Vec2::ZERO,
+                        jump: false,
+                    },
+                    PlayerInput {
+                        frame_number: frame_number + FrameNumber::new(1),
+                        direction: Vec2::new(-1.0, 0.0),
+                        jump: true,
+                    },
+                ],
+            }],
+            confirmed_actions: vec![ConfirmedAction {
+                id: ActionNetId(7),
+                confirmed_frame: Some(frame_number + FrameNumber::new(5)),
+            }],
+            checksum: Some(PositionsChecksum {
+                frame_number: frame_number - FrameNumber::new(60),
+                players: vec![(PlayerNetId(3), position_checksum(position))],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_delta_update_roundtrip() {
+        let mut sender_history = SnapshotHistory::default();
+        let mut recipient_history = SnapshotHistory::default();
+
+        // Frame numbers wrap around between the updates.
+        let first_update = delta_update(u16::MAX - 1, Vec3::new(1.0, 0.5, -3.0));
+        let (bytes, snapshot) = encode_delta_update(&first_update, None);
+        sender_history.insert(first_update.frame_number, snapshot);
+        let (decoded, snapshot) = decode_delta_update(&bytes, &recipient_history).unwrap();
+        recipient_history.insert(decoded.frame_number, snapshot);
+        assert_eq!(decoded, first_update);
+
+        let second_update = delta_update(2, Vec3::new(1.3, 0.75, -3.1));
+        let baseline = sender_history.get(first_update.frame_number).unwrap();
+        let (bytes, _) =
+            encode_delta_update(&second_update, Some((first_update.frame_number, baseline)));
+        let (decoded, _) = decode_delta_update(&bytes, &recipient_history).unwrap();
+        assert!(
+            (decoded.players[0].position - second_update.players[0].position)
+                .abs()
+                .max_element()
+                <= POSITION_QUANTUM / 2.0
+        );
+        assert_eq!(decoded.players[0].inputs, second_update.players[0].inputs);
+
+        assert_eq!(
+            decode_delta_update(&bytes, &SnapshotHistory::default()),
+            Err(CodecError::MissingBaseline(first_update.frame_number))
+        );
+    }
+
+    #[test]
+    fn test_dead_player_roundtrip() {
+        let mut history = SnapshotHistory::default();
+        let first_update = delta_update(10, Vec3::new(1.0, 0.5, -3.0));
+        let (_, first_snapshot) = encode_delta_update(&first_update, None);
+        history.insert(first_update.frame_number, first_snapshot.clone());
+
+        let dead_update = DeltaUpdate {
+            players: vec![PlayerState::dead(PlayerNetId(3))],
+            ..delta_update(12, Vec3::ZERO)
+        };
+        let (bytes, snapshot) = encode_delta_update(
+            &dead_update,
+            Some((first_update.frame_number, &first_snapshot)),
+        );
+        let (decoded, _) = decode_delta_update(&bytes, &history).unwrap();
+        assert_eq!(decoded, dead_update);
+
+        // Dead players don't end up in snapshots, so respawned ones can't be delta-encoded
+        // against them.
+        history.insert(dead_update.frame_number, snapshot.clone());
+        let respawn_update = delta_update(14, Vec3::new(4.0, 0.5, 2.0));
+        let (bytes, _) =
+            encode_delta_update(&respawn_update, Some((dead_update.frame_number, &snapshot)));
+        let (decoded, _) = decode_delta_update(&bytes, &history).unwrap();
+        assert_eq!(decoded, respawn_update);
+    }
+
+    #[test]
+    fn test_overflowing_position_delta() {
+        let update = delta_update(10, Vec3::new(1.0, 0.5, -3.0));
+        let mut baseline = Snapshot::default();
+        baseline.positions.insert(PlayerNetId(3), [0; 3]);
+        let (bytes, _) = encode_delta_update(&update, Some((FrameNumber::new(8), &baseline)));
+
+        // A malicious (or broken) sender can reference a baseline that's close to overflowing.
+        let mut history = SnapshotHistory::default();
+        baseline.positions.insert(PlayerNetId(3), [i32::MAX; 3]);
+        history.insert(FrameNumber::new(8), baseline);
+        assert_eq!(
+            decode_delta_update(&bytes, &history),
+            Err(CodecError::ValueOverflow)
+        );
+    }
+
+    #[test]
+    fn test_position_checksums_tolerate_quantization_error() {
+        // The server's position and the decoded one straddle a rounding boundary.
+        let boundary = Vec3::new(1.0 + CHECKSUM_POSITION_QUANTUM / 2.0, 0.5, -3.0);
+        let position = boundary + Vec3::new(POSITION_QUANTUM / 4.0, 0.0, 0.0);
+        let decoded_position = boundary - Vec3::new(POSITION_QUANTUM / 4.0, 0.0, 0.0);
+        assert_ne!(
+            position_checksum(decoded_position),
+            position_checksum(position)
+        );
+        assert!(position_checksums_match(
+            position_checksum(decoded_position),
+            position_checksum(position)
+        ));
+
+        let (bytes, _) = encode_delta_update(&delta_update(10, position), None);
+        let (decoded, _) = decode_delta_update(&bytes, &SnapshotHistory::default()).unwrap();
+        assert!(position_checksums_match(
+            position_checksum(decoded.players[0].position),
+            position_checksum(position)
+        ));
+
+        let diverged_position = position + Vec3::new(0.0, CHECKSUM_POSITION_QUANTUM * 2.5, 0.0);
+        assert!(!position_checksums_match(
+            position_checksum(diverged_position),
+            position_checksum(position)
+        ));
+    }
+
+    #[test]
+    fn test_quantized_direction_survives_encoding() {
+        let direction = quantize_direction(Vec2::new(0.3, -0.71));
+        assert_ne!(direction, Vec2::new(0.3, -0.71));
+        assert_eq!(quantize_direction(direction), direction);
+
+        let update = PlayerUpdate {
+            frame_number: FrameNumber::new(100),
+            acknowledgments: (None, 0),
+            inputs: vec![PlayerInput {
+                frame_number: FrameNumber::new(100),
+                direction,
+                jump: false,
+            }],
+        };
+        let decoded = decode_player_update(&encode_player_update(&update)).unwrap();
+        assert_eq!(decoded.inputs[0].direction, direction);
+    }
+
+    #[test]
+    fn test_player_update_roundtrip() {
+        let update = PlayerUpdate {
+            frame_number: FrameNumber::new(100),
+            acknowledgments: (None, 0),
+            inputs: delta_update(100, Vec3::ZERO).players[0].inputs.clone(),
+        };
+        let bytes = encode_player_update(&update);
+        assert_eq!(decode_player_update(&bytes), Ok(update));
+        assert_eq!(
+            decode_player_update(&bytes[..bytes.len() - 1]),
+            Err(CodecError::UnexpectedEnd)
+        );
+    }
+}
@@ -1,14 +1,21 @@
-use crate::game::level_objects::PlaneDesc;
+use crate::game::level_objects::{
+    CuboidDesc, CylinderDesc, DeathZoneDesc, MovingPlatformDesc, PlaneDesc, RampDesc,
+    RouteSensorDesc, SpawnPointDesc,
+};
 #[cfg(feature = "client")]
 use crate::{
-    game::components::{PlayerFrameSimulated, PredictedPosition},
+    game::{
+        components::{PlayerFrameSimulated, PredictedPosition},
+        level_objects::RouteSensorKind,
+        meshes,
+    },
     PLAYER_SIZE,
 };
 #[cfg(feature = "client")]
 use bevy::prelude::*;
 use bevy::{
     ecs::system::{EntityCommands, SystemParam},
-    math::Vec2,
+    math::Vec3,
 };
 
 pub trait ClientFactory<'a> {
@@ -29,7 +36,7 @@ pub struct PlayerClientFactory;
 
 impl<'a> ClientFactory<'a> for PlayerClientFactory {
     type Dependencies = PbrClientParams<'a>;
-    type Input = (Vec2, bool);
+    type Input = (Vec3, bool);
 
     #[cfg(feature = "client")]
     fn insert_components(
@@ -38,10 +45,8 @@ impl<'a> ClientFactory<'a> for PlayerClientFactory {
         (position, is_player_frame_simulated): &Self::Input,
     ) {
         commands.insert_bundle(PbrBundle {
-            mesh: deps
-                .meshes
-                .add(Mesh::from(shape::Cube { size: PLAYER_SIZE })),
-            material: deps.materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
+            mesh: deps.add_mesh(Mesh::from(shape::Cube { size: PLAYER_SIZE })),
+            material: deps.add_material(Color::rgb(0.8, 0.7, 0.6).into()),
             ..Default::default()
         });
         commands.insert(PredictedPosition { value: *position });
@@ -69,10 +74,235 @@ impl<'a> ClientFactory<'a> for PlaneClientFactory {
         (plane_desc, is_player_frame_simulated): &Self::Input,
     ) {
         commands.insert_bundle(PbrBundle {
-            mesh: deps.meshes.add(Mesh::from(shape::Plane {
+            mesh: deps.add_mesh(Mesh::from(shape::Plane {
                 size: plane_desc.size,
             })),
-            material: deps.materials.add(Color::rgb(0.3, 0.5, 0.3).into()),
+            material: deps.add_material(Color::rgb(0.3, 0.5, 0.3).into()),
+            ..Default::default()
+        });
+        if *is_player_frame_simulated {
+            commands.insert(PlayerFrameSimulated);
+        }
+    }
+}
+
+pub struct CuboidClientFactory;
+
+impl<'a> ClientFactory<'a> for CuboidClientFactory {
+    type Dependencies = PbrClientParams<'a>;
+    type Input = (CuboidDesc, bool);
+
+    #[cfg(feature = "client")]
+    fn insert_components(
+        commands: &mut EntityCommands,
+        deps: &mut Self::Dependencies,
+        (cuboid_desc, is_player_frame_simulated): &Self::Input,
+    ) {
+        commands.insert_bundle(PbrBundle {
+            mesh: deps.add_mesh(Mesh::from(shape::Box::new(
+                cuboid_desc.size.x,
+                cuboid_desc.size.y,
+                cuboid_desc.size.z,
+            ))),
+            material: deps.add_material(Color::rgb(0.4, 0.4, 0.45).into()),
+            transform: Transform {
+                translation: cuboid_desc.position,
+                rotation: cuboid_desc.rotation,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        if *is_player_frame_simulated {
+            commands.insert(PlayerFrameSimulated);
+        }
+    }
+}
+
+pub struct RampClientFactory;
+
+impl<'a> ClientFactory<'a> for RampClientFactory {
+    type Dependencies = PbrClientParams<'a>;
+    type Input = (RampDesc, bool);
+
+    #[cfg(feature = "client")]
+    fn insert_components(
+        commands: &mut EntityCommands,
+        deps: &mut Self::Dependencies,
+        (ramp_desc, is_player_frame_simulated): &Self::Input,
+    ) {
+        commands.insert_bundle(PbrBundle {
+            mesh: deps.add_mesh(meshes::ramp(ramp_desc)),
+            material: deps.add_material(Color::rgb(0.5, 0.45, 0.35).into()),
+            transform: Transform {
+                translation: ramp_desc.position,
+                rotation: ramp_desc.rotation,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        if *is_player_frame_simulated {
+            commands.insert(PlayerFrameSimulated);
+        }
+    }
+}
+
+pub struct CylinderClientFactory;
+
+impl<'a> ClientFactory<'a> for CylinderClientFactory {
+    type Dependencies = PbrClientParams<'a>;
+    type Input = (CylinderDesc, bool);
+
+    #[cfg(feature = "client")]
+    fn insert_components(
+        commands: &mut EntityCommands,
+        deps: &mut Self::Dependencies,
+        (cylinder_desc, is_player_frame_simulated): &Self::Input,
+    ) {
+        commands.insert_bundle(PbrBundle {
+            mesh: deps.add_mesh(meshes::cylinder(cylinder_desc.radius, cylinder_desc.height)),
+            material: deps.add_material(Color::rgb(0.35, 0.4, 0.5).into()),
+            transform: Transform {
+                translation: cylinder_desc.position,
+                rotation: cylinder_desc.rotation,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        if *is_player_frame_simulated {
+            commands.insert(PlayerFrameSimulated);
+        }
+    }
+}
+
+pub struct RouteSensorClientFactory;
+
+impl<'a> ClientFactory<'a> for RouteSensorClientFactory {
+    type Dependencies = PbrClientParams<'a>;
+    type Input = (RouteSensorDesc, bool);
+
+    #[cfg(feature = "client")]
+    fn insert_components(
+        commands: &mut EntityCommands,
+        deps: &mut Self::Dependencies,
+        (sensor_desc, is_player_frame_simulated): &Self::Input,
+    ) {
+        let color = match sensor_desc.kind {
+            RouteSensorKind::Start => Color::rgba(0.2, 0.8, 0.3, 0.35),
+            RouteSensorKind::Checkpoint => Color::rgba(0.9, 0.8, 0.2, 0.35),
+            RouteSensorKind::Finish => Color::rgba(0.9, 0.25, 0.2, 0.35),
+        };
+        commands.insert_bundle(PbrBundle {
+            mesh: deps.add_mesh(Mesh::from(shape::Box::new(
+                sensor_desc.size.x,
+                sensor_desc.size.y,
+                sensor_desc.size.z,
+            ))),
+            material: deps.add_material(color.into()),
+            transform: Transform {
+                translation: sensor_desc.position,
+                rotation: sensor_desc.rotation,
+                ..Default::default()
+            },
+            visible: Visible {
+                is_visible: true,
+                is_transparent: true,
+            },
+            ..Default::default()
+        });
+        if *is_player_frame_simulated {
+            commands.insert(PlayerFrameSimulated);
+        }
+    }
+}
+
+pub struct SpawnPointClientFactory;
+
+impl<'a> ClientFactory<'a> for SpawnPointClientFactory {
+    type Dependencies = PbrClientParams<'a>;
+    type Input = (SpawnPointDesc, bool);
+
+    #[cfg(feature = "client")]
+    fn insert_components(
+        commands: &mut EntityCommands,
+        deps: &mut Self::Dependencies,
+        (spawn_point_desc, is_player_frame_simulated): &Self::Input,
+    ) {
+        commands.insert_bundle(PbrBundle {
+            mesh: deps.add_mesh(Mesh::from(shape::Cube { size: PLAYER_SIZE })),
+            material: deps.add_material(Color::rgba(0.3, 0.5, 0.9, 0.3).into()),
+            transform: Transform::from_translation(spawn_point_desc.position),
+            visible: Visible {
+                is_visible: true,
+                is_transparent: true,
+            },
+            ..Default::default()
+        });
+        if *is_player_frame_simulated {
+            commands.insert(PlayerFrameSimulated);
+        }
+    }
+}
+
+pub struct DeathZoneClientFactory;
+
+impl<'a> ClientFactory<'a> for DeathZoneClientFactory {
+    type Dependencies = PbrClientParams<'a>;
+    type Input = (DeathZoneDesc, bool);
+
+    #[cfg(feature = "client")]
+    fn insert_components(
+        commands: &mut EntityCommands,
+        deps: &mut Self::Dependencies,
+        (death_zone_desc, is_player_frame_simulated): &Self::Input,
+    ) {
+        commands.insert_bundle(PbrBundle {
+            mesh: deps.add_mesh(Mesh::from(shape::Box::new(
+                death_zone_desc.size.x,
+                death_zone_desc.size.y,
+                death_zone_desc.size.z,
+            ))),
+            material: deps.add_material(Color::rgba(0.8, 0.1, 0.1, 0.5).into()),
+            transform: Transform {
+                translation: death_zone_desc.position,
+                rotation: death_zone_desc.rotation,
+                ..Default::default()
+            },
+            visible: Visible {
+                is_visible: true,
+                is_transparent: true,
+            },
+            ..Default::default()
+        });
+        if *is_player_frame_simulated {
+            commands.insert(PlayerFrameSimulated);
+        }
+    }
+}
+
+pub struct MovingPlatformClientFactory;
+
+impl<'a> ClientFactory<'a> for MovingPlatformClientFactory {
+    type Dependencies = PbrClientParams<'a>;
+    type Input = (MovingPlatformDesc, bool);
+
+    #[cfg(feature = "client")]
+    fn insert_components(
+        commands: &mut EntityCommands,
+        deps: &mut Self::Dependencies,
+        (platform_desc, is_player_frame_simulated): &Self::Input,
+    ) {
+        commands.insert_bundle(PbrBundle {
+            mesh: deps.add_mesh(Mesh::from(shape::Box::new(
+                platform_desc.size.x,
+                platform_desc.size.y,
+                platform_desc.size.z,
+            ))),
+            material: deps.add_material(Color::rgb(0.3, 0.6, 0.7).into()),
+            transform: Transform {
+                translation: platform_desc.position,
+                rotation: platform_desc.rotation,
+                ..Default::default()
+            },
             ..Default::default()
         });
         if *is_player_frame_simulated {
@@ -84,8 +314,25 @@ impl<'a> ClientFactory<'a> for PlaneClientFactory {
 #[cfg(feature = "client")]
 #[derive(SystemParam)]
 pub struct PbrClientParams<'a> {
-    meshes: ResMut<'a, Assets<Mesh>>,
-    materials: ResMut<'a, Assets<StandardMaterial>>,
+    // Servers don't have these assets even if the feature is enabled (i.e. when sharing a process
+    // with clients in tests), their entities get default handles.
+    meshes: Option<ResMut<'a, Assets<Mesh>>>,
+    materials: Option<ResMut<'a, Assets<StandardMaterial>>>,
+}
+
+#[cfg(feature = "client")]
+impl<'a> PbrClientParams<'a> {
+    fn add_mesh(&mut self, mesh: Mesh) -> Handle<Mesh> {
+        self.meshes
+            .as_mut()
+            .map_or_else(Handle::default, |meshes| meshes.add(mesh))
+    }
+
+    fn add_material(&mut self, material: StandardMaterial) -> Handle<StandardMaterial> {
+        self.materials
+            .as_mut()
+            .map_or_else(Handle::default, |materials| materials.add(material))
+    }
 }
 
 #[cfg(not(feature = "client"))]
@@ -3,7 +3,7 @@ use crate::{
     game::level::LevelObject,
     messages::{EntityNetId, PlayerNetId},
 };
-use bevy::math::Vec2;
+use bevy::math::Vec3;
 use serde::{Deserialize, Serialize};
 
 pub struct GameCommands<T> {
@@ -35,7 +35,7 @@ pub struct RestartGame;
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SpawnPlayer {
     pub net_id: PlayerNetId,
-    pub start_position: Vec2,
+    pub start_position: Vec3,
     pub is_player_frame_simulated: bool,
 }
 
@@ -51,6 +51,13 @@ pub struct SpawnLevelObject {
     pub frame_number: FrameNumber,
 }
 
+/// Replaces an existing object with the new description (keeping its net id).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UpdateLevelObject {
+    pub object: LevelObject,
+    pub frame_number: FrameNumber,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct DespawnLevelObject {
     pub net_id: EntityNetId,
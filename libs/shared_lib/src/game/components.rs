@@ -2,7 +2,7 @@ use crate::{
     framebuffer::{FrameNumber, Framebuffer},
     COMPONENT_FRAMEBUFFER_LIMIT,
 };
-use bevy::math::Vec2;
+use bevy::math::{Vec2, Vec3};
 
 // NOTE: After adding new components, make sure that related entities are cleaned up in the
 // `restart_game` system.
@@ -11,25 +11,50 @@ use bevy::math::Vec2;
 pub struct PlayerDirection {
     /// `None` indicates a missing network input.
     pub buffer: Framebuffer<Option<Vec2>>,
+    /// Frames at which a player initiated a jump. Unlike directions, jumps are never
+    /// interpolated or extrapolated, as a missing input must not replay a jump.
+    pub jumps: Framebuffer<bool>,
 }
 
 impl PlayerDirection {
     pub fn new(initial_value: Vec2, buffer_start_frame: FrameNumber, frames_to_fill: u16) -> Self {
         let mut buffer = Framebuffer::new(buffer_start_frame, COMPONENT_FRAMEBUFFER_LIMIT);
+        let mut jumps = Framebuffer::new(buffer_start_frame, COMPONENT_FRAMEBUFFER_LIMIT);
         for _ in 0..frames_to_fill {
             buffer.push(Some(initial_value));
+            jumps.push(false);
         }
-        Self { buffer }
+        Self { buffer, jumps }
+    }
+
+    pub fn is_jumping(&self, frame_number: FrameNumber) -> bool {
+        self.jumps.get(frame_number).copied().unwrap_or(false)
     }
 }
 
 /// Represents start positions before moving an entity.
 pub struct Position {
-    pub buffer: Framebuffer<Vec2>,
+    pub buffer: Framebuffer<Vec3>,
 }
 
 impl Position {
-    pub fn new(initial_value: Vec2, buffer_start_frame: FrameNumber, frames_to_fill: u16) -> Self {
+    pub fn new(initial_value: Vec3, buffer_start_frame: FrameNumber, frames_to_fill: u16) -> Self {
+        let mut buffer = Framebuffer::new(buffer_start_frame, COMPONENT_FRAMEBUFFER_LIMIT);
+        for _ in 0..frames_to_fill {
+            buffer.push(initial_value);
+        }
+        Self { buffer }
+    }
+}
+
+/// Represents linear velocities of an entity's rigid body before moving it. Together with
+/// `Position`, this lets rewinds restore the exact physics state of a frame.
+pub struct Velocity {
+    pub buffer: Framebuffer<Vec3>,
+}
+
+impl Velocity {
+    pub fn new(initial_value: Vec3, buffer_start_frame: FrameNumber, frames_to_fill: u16) -> Self {
         let mut buffer = Framebuffer::new(buffer_start_frame, COMPONENT_FRAMEBUFFER_LIMIT);
         for _ in 0..frames_to_fill {
             buffer.push(initial_value);
@@ -41,7 +66,7 @@ impl Position {
 /// Is used only by the client, to lerp the position if an authoritative update arrives from the
 /// server.
 pub struct PredictedPosition {
-    pub value: Vec2,
+    pub value: Vec3,
 }
 
 /// The purpose of this component is providing a frame number of when a component was spawned,
@@ -105,6 +130,21 @@ impl Spawned {
         self.respawned_at = Some(frame_number);
     }
 
+    /// Returns `true` if an entity is despawned at the frame, but is scheduled to be respawned.
+    pub fn is_respawning(&self, frame_number: FrameNumber) -> bool {
+        match (self.despawned_at, self.respawned_at) {
+            (Some(despawned_at), Some(respawned_at)) => {
+                frame_number >= despawned_at && frame_number < respawned_at
+            }
+            _ => false,
+        }
+    }
+
+    /// Makes a respawning entity despawned for good (so it can be removed).
+    pub fn cancel_respawn(&mut self) {
+        self.respawned_at = None;
+    }
+
     pub fn can_be_removed(&self, frame_number: FrameNumber) -> bool {
         let can_be_despawned = self.despawned_at.map_or(false, |despawned_at| {
             despawned_at + FrameNumber::new(COMPONENT_FRAMEBUFFER_LIMIT) >= frame_number
@@ -1,6 +1,20 @@
-use crate::{game::level_objects::PlaneDesc, messages::EntityNetId};
+use crate::{
+    game::level_objects::{
+        isometry, CuboidDesc, CylinderDesc, DeathZoneDesc, MovingPlatformDesc, PlaneDesc, RampDesc,
+        RouteSensorDesc, SpawnPointDesc,
+    },
+    messages::EntityNetId,
+    PLAYER_SIZE,
+};
+use bevy::{
+    log,
+    math::{Quat, Vec3},
+};
+use bevy_rapier3d::rapier::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder, math::Point};
 use serde::{Deserialize, Serialize};
 
+const PLANE_THICKNESS: f32 = 0.1;
+
 #[derive(Default)]
 pub struct LevelState {
     pub objects: Vec<LevelObject>,
@@ -15,4 +29,167 @@ pub struct LevelObject {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum LevelObjectDesc {
     Plane(PlaneDesc),
+    Cuboid(CuboidDesc),
+    Ramp(RampDesc),
+    Cylinder(CylinderDesc),
+    RouteSensor(RouteSensorDesc),
+    SpawnPoint(SpawnPointDesc),
+    DeathZone(DeathZoneDesc),
+    MovingPlatform(MovingPlatformDesc),
+}
+
+impl LevelObjectDesc {
+    /// Returns `None` for objects that can't be moved (the ground plane).
+    pub fn transform(&self) -> Option<(Vec3, Quat)> {
+        match self {
+            Self::Plane(_) => None,
+            Self::Cuboid(cuboid) => Some((cuboid.position, cuboid.rotation)),
+            Self::Ramp(ramp) => Some((ramp.position, ramp.rotation)),
+            Self::Cylinder(cylinder) => Some((cylinder.position, cylinder.rotation)),
+            Self::RouteSensor(sensor) => Some((sensor.position, sensor.rotation)),
+            Self::SpawnPoint(spawn_point) => Some((spawn_point.position, Quat::identity())),
+            Self::DeathZone(death_zone) => Some((death_zone.position, death_zone.rotation)),
+            Self::MovingPlatform(platform) => Some((platform.position, platform.rotation)),
+        }
+    }
+
+    pub fn set_transform(&mut self, position: Vec3, rotation: Quat) {
+        match self {
+            Self::Plane(_) => {}
+            Self::Cuboid(cuboid) => {
+                cuboid.position = position;
+                cuboid.rotation = rotation;
+            }
+            Self::Ramp(ramp) => {
+                ramp.position = position;
+                ramp.rotation = rotation;
+            }
+            Self::Cylinder(cylinder) => {
+                cylinder.position = position;
+                cylinder.rotation = rotation;
+            }
+            Self::RouteSensor(sensor) => {
+                sensor.position = position;
+                sensor.rotation = rotation;
+            }
+            // Spawn points don't have any orientation.
+            Self::SpawnPoint(spawn_point) => spawn_point.position = position,
+            Self::DeathZone(death_zone) => {
+                death_zone.position = position;
+                death_zone.rotation = rotation;
+            }
+            Self::MovingPlatform(platform) => {
+                platform.position = position;
+                platform.rotation = rotation;
+            }
+        }
+    }
+
+    /// Returns the size of the object's bounding box (in its local space).
+    pub fn size(&self) -> Option<Vec3> {
+        match self {
+            Self::Plane(_) => None,
+            Self::Cuboid(cuboid) => Some(cuboid.size),
+            Self::Ramp(ramp) => Some(ramp.size),
+            Self::Cylinder(cylinder) => Some(Vec3::new(
+                cylinder.radius * 2.0,
+                cylinder.height,
+                cylinder.radius * 2.0,
+            )),
+            Self::RouteSensor(sensor) => Some(sensor.size),
+            Self::SpawnPoint(_) => Some(Vec3::splat(PLAYER_SIZE)),
+            Self::DeathZone(death_zone) => Some(death_zone.size),
+            Self::MovingPlatform(platform) => Some(platform.size),
+        }
+    }
+
+    /// For cylinders, the largest of X and Z sizes is used as the diameter.
+    pub fn set_size(&mut self, size: Vec3) {
+        match self {
+            Self::Plane(_) => {}
+            Self::Cuboid(cuboid) => cuboid.size = size,
+            Self::Ramp(ramp) => ramp.size = size,
+            Self::Cylinder(cylinder) => {
+                cylinder.radius = size.x.max(size.z) / 2.0;
+                cylinder.height = size.y;
+            }
+            Self::RouteSensor(sensor) => sensor.size = size,
+            Self::SpawnPoint(_) => {}
+            Self::DeathZone(death_zone) => death_zone.size = size,
+            Self::MovingPlatform(platform) => platform.size = size,
+        }
+    }
+
+    /// Returns `None` for objects that don't participate in physics simulation.
+    pub fn physics_body(&self) -> Option<(RigidBodyBuilder, ColliderBuilder)> {
+        match self {
+            // The collider is shifted down, so that its top face matches the rendered plane.
+            Self::Plane(plane) => Some((
+                RigidBodyBuilder::new_static(),
+                ColliderBuilder::cuboid(plane.size / 2.0, PLANE_THICKNESS / 2.0, plane.size / 2.0)
+                    .translation(0.0, -PLANE_THICKNESS / 2.0, 0.0),
+            )),
+            Self::Cuboid(cuboid) => Some((
+                RigidBodyBuilder::new_static().position(isometry(cuboid.position, cuboid.rotation)),
+                ColliderBuilder::cuboid(
+                    cuboid.size.x / 2.0,
+                    cuboid.size.y / 2.0,
+                    cuboid.size.z / 2.0,
+                ),
+            )),
+            Self::Ramp(ramp) => {
+                let points = ramp
+                    .vertices()
+                    .iter()
+                    .map(|vertex: &Vec3| Point::new(vertex.x, vertex.y, vertex.z))
+                    .collect::<Vec<_>>();
+                let collider = match ColliderBuilder::convex_hull(&points) {
+                    Some(collider) => collider,
+                    None => {
+                        log::error!("Failed to build a ramp collider: {:?}", ramp);
+                        return None;
+                    }
+                };
+                Some((
+                    RigidBodyBuilder::new_static().position(isometry(ramp.position, ramp.rotation)),
+                    collider,
+                ))
+            }
+            Self::Cylinder(cylinder) => Some((
+                RigidBodyBuilder::new_static()
+                    .position(isometry(cylinder.position, cylinder.rotation)),
+                ColliderBuilder::cylinder(cylinder.height / 2.0, cylinder.radius),
+            )),
+            Self::RouteSensor(sensor) => Some((
+                RigidBodyBuilder::new_static().position(isometry(sensor.position, sensor.rotation)),
+                ColliderBuilder::cuboid(
+                    sensor.size.x / 2.0,
+                    sensor.size.y / 2.0,
+                    sensor.size.z / 2.0,
+                )
+                .sensor(true),
+            )),
+            Self::SpawnPoint(_) => None,
+            Self::DeathZone(death_zone) => Some((
+                RigidBodyBuilder::new_static()
+                    .position(isometry(death_zone.position, death_zone.rotation)),
+                ColliderBuilder::cuboid(
+                    death_zone.size.x / 2.0,
+                    death_zone.size.y / 2.0,
+                    death_zone.size.z / 2.0,
+                )
+                .sensor(true),
+            )),
+            // The actual position is set every frame (see `move_platforms`).
+            Self::MovingPlatform(platform) => Some((
+                RigidBodyBuilder::new_kinematic()
+                    .position(isometry(platform.position, platform.rotation)),
+                ColliderBuilder::cuboid(
+                    platform.size.x / 2.0,
+                    platform.size.y / 2.0,
+                    platform.size.z / 2.0,
+                ),
+            )),
+        }
+    }
 }
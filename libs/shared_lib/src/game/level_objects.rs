@@ -1,6 +1,165 @@
+use crate::framebuffer::FrameNumber;
+use bevy::math::{Quat, Vec3};
+use bevy_rapier3d::rapier::{
+    math::{Isometry, Real},
+    na,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlaneDesc {
     pub size: f32,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CuboidDesc {
+    pub position: Vec3,
+    pub rotation: Quat,
+    /// Full size of the cuboid along each of its local axes.
+    pub size: Vec3,
+}
+
+/// A wedge that rises along its local Z axis: the lowest edge lies on the bottom face at `-size.z / 2`,
+/// the highest one is at `+size.z / 2`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RampDesc {
+    pub position: Vec3,
+    pub rotation: Quat,
+    /// Full size of the bounding box along each of the ramp's local axes.
+    pub size: Vec3,
+}
+
+impl RampDesc {
+    /// Returns the vertices of the wedge in local space.
+    pub fn vertices(&self) -> [Vec3; 6] {
+        let half = self.size / 2.0;
+        [
+            // Low edge.
+            Vec3::new(-half.x, -half.y, -half.z),
+            Vec3::new(half.x, -half.y, -half.z),
+            // Bottom of the back face.
+            Vec3::new(-half.x, -half.y, half.z),
+            Vec3::new(half.x, -half.y, half.z),
+            // High edge.
+            Vec3::new(-half.x, half.y, half.z),
+            Vec3::new(half.x, half.y, half.z),
+        ]
+    }
+}
+
+/// A cylinder that is aligned with its local Y axis.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CylinderDesc {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub radius: f32,
+    pub height: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteSensorKind {
+    /// Starts (or restarts) the race timer of a player.
+    Start,
+    /// Has to be crossed (in any order) before the finish line counts.
+    Checkpoint,
+    Finish,
+}
+
+/// A box that players pass through. Its collider is a sensor, which only registers crossings.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RouteSensorDesc {
+    pub kind: RouteSensorKind,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub size: Vec3,
+}
+
+/// A place where players appear when they join or respawn.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SpawnPointDesc {
+    pub position: Vec3,
+}
+
+/// A sensor box that kills players touching it (they get respawned after a delay).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeathZoneDesc {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub size: Vec3,
+}
+
+/// A kinematic box that travels along a closed path at a constant speed. Its position is a pure
+/// function of a frame number, so rewinding the game state doesn't need to store it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MovingPlatformDesc {
+    /// The starting point of the path.
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub size: Vec3,
+    /// Points relative to `position`. A platform visits them in order and then returns to
+    /// `position`.
+    pub waypoints: Vec<Vec3>,
+    /// The number of frames it takes to travel the whole path.
+    pub period: u16,
+    /// Shifts the platform along its path by the number of frames.
+    pub phase: u16,
+}
+
+/// Frame numbers wrap around after this many frames.
+const FRAME_NUMBER_RANGE: u64 = u16::MAX as u64 + 1;
+
+impl MovingPlatformDesc {
+    /// As frame numbers wrap around, platforms make a whole number of trips within their range,
+    /// so the actual period can slightly differ from `period` (by 0.4% at most for 4 second
+    /// periods). Otherwise, platforms would jump every time frame numbers wrap around.
+    pub fn position_at(&self, frame_number: FrameNumber) -> Vec3 {
+        if self.period == 0 || self.waypoints.is_empty() {
+            return self.position;
+        }
+
+        let points = std::iter::once(Vec3::ZERO)
+            .chain(self.waypoints.iter().copied())
+            .chain(std::iter::once(Vec3::ZERO))
+            .collect::<Vec<_>>();
+        let path_length = points
+            .windows(2)
+            .map(|segment| (segment[1] - segment[0]).length())
+            .sum::<f32>();
+        if path_length == 0.0 {
+            return self.position;
+        }
+
+        let trips = self.trips_per_frame_number_range();
+        let frames = frame_number.value() as u64 + self.phase as u64;
+        let progress = (frames * trips % FRAME_NUMBER_RANGE) as f32 / FRAME_NUMBER_RANGE as f32;
+        let mut distance_left = path_length * progress;
+        for segment in points.windows(2) {
+            let segment_length = (segment[1] - segment[0]).length();
+            if distance_left <= segment_length {
+                return self.position
+                    + segment[0]
+                        .lerp(segment[1], distance_left / segment_length.max(f32::EPSILON));
+            }
+            distance_left -= segment_length;
+        }
+        self.position
+    }
+
+    /// Returns the displacement per frame, at which the platform carries entities standing on it.
+    pub fn displacement_at(&self, frame_number: FrameNumber) -> Vec3 {
+        self.position_at(frame_number + FrameNumber::new(1)) - self.position_at(frame_number)
+    }
+
+    fn trips_per_frame_number_range(&self) -> u64 {
+        ((FRAME_NUMBER_RANGE as f64 / self.period as f64).round() as u64).max(1)
+    }
+}
+
+pub fn isometry(position: Vec3, rotation: Quat) -> Isometry<Real> {
+    Isometry::from_parts(
+        na::Translation3::new(position.x, position.y, position.z),
+        na::UnitQuaternion::from_quaternion(na::Quaternion::new(
+            rotation.w, rotation.x, rotation.y, rotation.z,
+        )),
+    )
+}
@@ -0,0 +1,116 @@
+use crate::game::level_objects::RampDesc;
+use bevy::{
+    math::Vec3,
+    render::{
+        mesh::{Indices, Mesh},
+        pipeline::PrimitiveTopology,
+    },
+};
+
+const CYLINDER_SEGMENTS: u32 = 32;
+const FACE_UVS: [[f32; 2]; 4] = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+
+pub fn ramp(ramp_desc: &RampDesc) -> Mesh {
+    let [a, b, c, d, e, f] = ramp_desc.vertices();
+    let slope_normal = Vec3::new(0.0, ramp_desc.size.z, -ramp_desc.size.y).normalize();
+
+    // Every face gets its own vertices to keep the shading flat.
+    let faces = [
+        // Bottom.
+        (vec![a, b, d, c], -Vec3::Y),
+        // Back.
+        (vec![c, d, f, e], Vec3::Z),
+        // Slope.
+        (vec![a, e, f, b], slope_normal),
+        // Left side.
+        (vec![a, c, e], -Vec3::X),
+        // Right side.
+        (vec![b, f, d], Vec3::X),
+    ];
+
+    let mut positions = Vec::with_capacity(18);
+    let mut normals = Vec::with_capacity(18);
+    let mut uvs = Vec::with_capacity(18);
+    let mut indices = Vec::with_capacity(24);
+    for (vertices, normal) in faces.iter() {
+        let first_index = positions.len() as u32;
+        for (i, vertex) in vertices.iter().enumerate() {
+            positions.push([vertex.x, vertex.y, vertex.z]);
+            normals.push([normal.x, normal.y, normal.z]);
+            uvs.push(FACE_UVS[i]);
+        }
+        // Faces are either quads or triangles, we triangulate them as fans.
+        for i in 1..vertices.len() as u32 - 1 {
+            indices.extend_from_slice(&[first_index, first_index + i, first_index + i + 1]);
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+pub fn cylinder(radius: f32, height: f32) -> Mesh {
+    let half_height = height / 2.0;
+    let ring = (0..=CYLINDER_SEGMENTS)
+        .map(|i| {
+            let angle = i as f32 / CYLINDER_SEGMENTS as f32 * std::f32::consts::TAU;
+            (angle.cos(), angle.sin())
+        })
+        .collect::<Vec<_>>();
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut indices = Vec::new();
+
+    // Side.
+    for (i, &(cos, sin)) in ring.iter().enumerate() {
+        let u = i as f32 / CYLINDER_SEGMENTS as f32;
+        positions.push([radius * cos, -half_height, radius * sin]);
+        normals.push([cos, 0.0, sin]);
+        uvs.push([u, 1.0]);
+        positions.push([radius * cos, half_height, radius * sin]);
+        normals.push([cos, 0.0, sin]);
+        uvs.push([u, 0.0]);
+    }
+    for i in 0..CYLINDER_SEGMENTS {
+        let bottom = i * 2;
+        let top = bottom + 1;
+        let next_bottom = bottom + 2;
+        let next_top = bottom + 3;
+        indices.extend_from_slice(&[bottom, top, next_bottom, top, next_top, next_bottom]);
+    }
+
+    // Caps.
+    for &(y, normal_y) in [(half_height, 1.0), (-half_height, -1.0)].iter() {
+        let center_index = positions.len() as u32;
+        positions.push([0.0, y, 0.0]);
+        normals.push([0.0, normal_y, 0.0]);
+        uvs.push([0.5, 0.5]);
+        for &(cos, sin) in ring.iter() {
+            positions.push([radius * cos, y, radius * sin]);
+            normals.push([0.0, normal_y, 0.0]);
+            uvs.push([(cos + 1.0) / 2.0, (sin + 1.0) / 2.0]);
+        }
+        for i in 0..CYLINDER_SEGMENTS {
+            let current = center_index + 1 + i;
+            let next = current + 1;
+            if normal_y > 0.0 {
+                indices.extend_from_slice(&[center_index, next, current]);
+            } else {
+                indices.extend_from_slice(&[center_index, current, next]);
+            }
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
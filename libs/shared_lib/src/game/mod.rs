@@ -1,12 +1,18 @@
 use crate::{
-    game::commands::{
-        DespawnLevelObject, DespawnPlayer, GameCommands, RestartGame, SpawnLevelObject, SpawnPlayer,
+    game::{
+        commands::{
+            DespawnLevelObject, DespawnPlayer, GameCommands, RestartGame, SpawnLevelObject,
+            SpawnPlayer, UpdateLevelObject,
+        },
+        level::LevelState,
+        race::RaceState,
+        respawn::RespawnState,
     },
     messages::{EntityNetId, PlayerNetId},
     player::{Player, PlayerUpdates},
     registry::EntityRegistry,
 };
-use bevy::{ecs::world::World, log};
+use bevy::{ecs::world::World, log, transform::hierarchy::despawn_with_children_recursive};
 use std::collections::HashMap;
 
 pub mod client_factories;
@@ -14,7 +20,13 @@ pub mod commands;
 pub mod components;
 pub mod level;
 pub mod level_objects;
+#[cfg(feature = "client")]
+pub mod meshes;
 pub mod movement;
+pub mod platforms;
+pub mod race;
+pub mod respawn;
+pub mod sensors;
 pub mod spawn;
 
 // TODO: track https://github.com/bevyengine/rfcs/pull/16.
@@ -60,9 +72,14 @@ pub fn restart_game(world: &mut World) {
         entities_to_despawn.push(*object_entity);
     }
     objects_registry.clear();
+    world
+        .get_resource_mut::<LevelState>()
+        .unwrap()
+        .objects
+        .clear();
 
     for entity in entities_to_despawn {
-        world.despawn(entity);
+        despawn_with_children_recursive(world, entity);
     }
 
     world
@@ -77,9 +94,15 @@ pub fn restart_game(world: &mut World) {
         .get_resource_mut::<GameCommands<SpawnLevelObject>>()
         .unwrap()
         .drain();
+    world
+        .get_resource_mut::<GameCommands<UpdateLevelObject>>()
+        .unwrap()
+        .drain();
     world
         .get_resource_mut::<GameCommands<DespawnLevelObject>>()
         .unwrap()
         .drain();
     *world.get_resource_mut::<PlayerUpdates>().unwrap() = PlayerUpdates::default();
+    *world.get_resource_mut::<RaceState>().unwrap() = RaceState::default();
+    *world.get_resource_mut::<RespawnState>().unwrap() = RespawnState::default();
 }
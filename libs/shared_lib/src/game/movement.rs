@@ -1,12 +1,16 @@
 use crate::{
     framebuffer::FrameNumber,
-    game::components::{
-        PlayerDirection, PlayerFrameSimulated, Position, PredictedPosition, Spawned,
+    game::{
+        components::{
+            PlayerDirection, PlayerFrameSimulated, Position, PredictedPosition, Spawned, Velocity,
+        },
+        level::LevelState,
+        platforms::carrying_platform_displacement,
     },
     messages::PlayerNetId,
     player::PlayerUpdates,
     registry::EntityRegistry,
-    GameTime, SimulationTime, COMPONENT_FRAMEBUFFER_LIMIT, SIMULATIONS_PER_SECOND,
+    GameTime, SimulationRole, SimulationTime, COMPONENT_FRAMEBUFFER_LIMIT, SIMULATIONS_PER_SECOND,
 };
 use bevy::{
     ecs::{
@@ -14,12 +18,15 @@ use bevy::{
         system::{Query, Res, ResMut},
     },
     log,
-    math::Vec2,
+    math::{Vec2, Vec3},
     transform::components::Transform,
 };
 use bevy_rapier3d::{
     physics::RigidBodyHandleComponent,
-    rapier::{dynamics::RigidBodySet, math::Vector},
+    rapier::{
+        dynamics::{IntegrationParameters, RigidBodySet},
+        math::Vector,
+    },
 };
 
 /// Positions should align in half a second.
@@ -28,21 +35,37 @@ const LERP_FACTOR: f32 = 1.0 / SIMULATIONS_PER_SECOND as f32 * 2.0;
 // The scaling factor for the player's linear velocity
 const PLAYER_MOVEMENT_SPEED: f32 = 1.0;
 
+// The vertical linear velocity that a player gets when jumping
+const PLAYER_JUMP_SPEED: f32 = 5.0;
+
+/// In a free fall, height changes of two consecutive frames differ by `gravity * dt ^ 2`
+/// (~0.003 units with the default integration parameters), so they can't both stay below this
+/// value.
+const GROUNDED_HEIGHT_EPSILON: f32 = 0.0001;
+
 pub fn read_movement_updates(
     time: Res<GameTime>,
     simulation_time: Res<SimulationTime>,
+    role: Res<SimulationRole>,
     mut player_updates: ResMut<PlayerUpdates>,
     player_registry: Res<EntityRegistry<PlayerNetId>>,
     mut players: Query<(
         Entity,
         &mut Position,
+        &mut Velocity,
         &mut PlayerDirection,
         &Spawned,
         Option<&PlayerFrameSimulated>,
     )>,
 ) {
-    for (entity, mut position, mut player_direction, spawned, player_frame_simulated) in
-        players.iter_mut()
+    for (
+        entity,
+        mut position,
+        mut velocity,
+        mut player_direction,
+        spawned,
+        player_frame_simulated,
+    ) in players.iter_mut()
     {
         let player_net_id = player_registry
             .get_id(entity)
@@ -75,7 +98,12 @@ pub fn read_movement_updates(
                     frame_number,
                     position_update
                 );
-                position.buffer.insert(frame_number, position_update);
+                position
+                    .buffer
+                    .insert(frame_number, position_update.position);
+                velocity
+                    .buffer
+                    .insert(frame_number, position_update.velocity);
             } else {
                 log::trace!(
                     "No updates for player {} (frame_number: {})",
@@ -86,27 +114,27 @@ pub fn read_movement_updates(
 
             let direction_update = player_updates
                 .get_direction_mut(player_net_id, frame_number, COMPONENT_FRAMEBUFFER_LIMIT)
-                .get_mut(frame_number);
+                .get_mut(frame_number)
+                .and_then(|direction_update| direction_update.as_mut());
             // TODO: make sure that we don't leave all buffer filled with `None` (i.e. disconnect a player earlier).
             //  Document the implemented guarantees.
             let current_direction = player_direction
                 .buffer
                 .get(frame_number)
                 .and_then(|update| *update);
-            player_direction.buffer.insert(
-                frame_number,
-                direction_update
-                    .and_then(|direction_update| {
-                        direction_update.as_mut().map(|direction_update| {
-                            if cfg!(feature = "client") {
-                                direction_update.is_processed_client_input = Some(true);
-                            }
-                            direction_update.direction
-                        })
-                    })
-                    // Avoid replacing initial updates with None.
-                    .or(current_direction),
-            );
+            let current_jump = player_direction.is_jumping(frame_number);
+            // Avoid replacing initial updates with None.
+            let (direction, jump) = match direction_update {
+                Some(direction_update) => {
+                    if role.is_client() {
+                        direction_update.is_processed_client_input = Some(true);
+                    }
+                    (Some(direction_update.direction), direction_update.jump)
+                }
+                None => (current_direction, current_jump),
+            };
+            player_direction.buffer.insert(frame_number, direction);
+            player_direction.jumps.insert(frame_number, jump);
         }
     }
 }
@@ -116,12 +144,15 @@ type PlayersQuery<'a> = (
     &'a RigidBodyHandleComponent,
     &'a PlayerDirection,
     &'a Position,
+    &'a Velocity,
     Option<&'a PlayerFrameSimulated>,
     &'a Spawned,
 );
 
 pub fn player_movement(
     time: Res<SimulationTime>,
+    integration_parameters: Res<IntegrationParameters>,
+    level_state: Res<LevelState>,
     mut rigid_body_set: ResMut<RigidBodySet>,
     players: Query<PlayersQuery>,
 ) {
@@ -130,9 +161,17 @@ pub fn player_movement(
         time.server_frame,
         time.player_frame
     );
-    for (entity, rigid_body, player_direction, position, player_frame_simulated, _) in players
+    for (
+        entity,
+        rigid_body,
+        player_direction,
+        position,
+        velocity,
+        player_frame_simulated,
+        spawned,
+    ) in players
         .iter()
-        .filter(|(_, _, _, _, player_frame_simulated, spawned)| {
+        .filter(|(_, _, _, _, _, player_frame_simulated, spawned)| {
             spawned.is_spawned(time.entity_simulation_frame(*player_frame_simulated))
         })
     {
@@ -142,7 +181,7 @@ pub fn player_movement(
             .expect("expected a rigid body");
 
         let mut body_position = *rigid_body.position();
-        let current_position = position.buffer.get(frame_number).unwrap_or_else(|| {
+        let current_position = *position.buffer.get(frame_number).unwrap_or_else(|| {
             // This can happen only if our `sync_position` haven't created a new position for
             // the current frame. If we are catching this, it's definitely a bug.
             panic!(
@@ -153,10 +192,15 @@ pub fn player_movement(
                 position.buffer.len()
             );
         });
-        let wake_up = (body_position.translation.x - current_position.x).abs() > f32::EPSILON
-            || (body_position.translation.z - current_position.y).abs() > f32::EPSILON;
+        let body_translation = Vec3::new(
+            body_position.translation.x,
+            body_position.translation.y,
+            body_position.translation.z,
+        );
+        let wake_up = (body_translation - current_position).abs().max_element() > f32::EPSILON;
         body_position.translation.x = current_position.x;
-        body_position.translation.z = current_position.y;
+        body_position.translation.y = current_position.y;
+        body_position.translation.z = current_position.z;
         rigid_body.set_position(body_position, wake_up);
 
         let zero_vec = Vec2::new(0.0, 0.0);
@@ -180,17 +224,78 @@ pub fn player_movement(
                 }
             });
         let current_direction_norm = current_direction.normalize_or_zero() * PLAYER_MOVEMENT_SPEED;
-        let wake_up = current_direction_norm.length_squared() > 0.0;
+
+        // Players standing on moving platforms get carried along with them.
+        let carrier_velocity =
+            carrying_platform_displacement(&level_state, current_position, frame_number)
+                .map(|displacement| displacement / integration_parameters.dt);
+
+        // Horizontal velocity is driven by inputs, but the vertical one (falling, jumping) has to
+        // be restored, as the current body state might belong to another frame after a rewind.
+        let stored_velocity = velocity
+            .buffer
+            .get(frame_number)
+            .copied()
+            .unwrap_or(Vec3::ZERO);
+        let (vertical_speed, is_grounded) = match carrier_velocity {
+            Some(carrier_velocity) => (carrier_velocity.y, true),
+            None => (
+                stored_velocity.y,
+                is_grounded(position, spawned, frame_number),
+            ),
+        };
+        let vertical_speed = if is_grounded && player_direction.is_jumping(frame_number) {
+            log::trace!(
+                "Player (entity: {:?}) jumps (frame: {})",
+                entity,
+                frame_number
+            );
+            vertical_speed.max(0.0) + PLAYER_JUMP_SPEED
+        } else {
+            vertical_speed
+        };
+
+        let carrier_velocity = carrier_velocity.unwrap_or(Vec3::ZERO);
+        let wake_up = current_direction_norm.length_squared() > 0.0
+            || vertical_speed != 0.0
+            || carrier_velocity != Vec3::ZERO;
         rigid_body.set_linvel(
-            Vector::new(current_direction_norm.x, 0.0, current_direction_norm.y),
+            Vector::new(
+                current_direction_norm.x + carrier_velocity.x,
+                vertical_speed,
+                current_direction_norm.y + carrier_velocity.z,
+            ),
             wake_up,
         );
     }
 }
 
+/// An entity is considered grounded if its height hasn't changed during the last two frames, as
+/// in a free fall the height changes every frame (even at the peak of a jump).
+fn is_grounded(position: &Position, spawned: &Spawned, frame_number: FrameNumber) -> bool {
+    let height = |frames_ago: u16| {
+        let frame_number = frame_number - FrameNumber::new(frames_ago);
+        if !spawned.is_spawned(frame_number) {
+            return None;
+        }
+        position.buffer.get(frame_number).map(|position| position.y)
+    };
+    let current_height = match height(0) {
+        Some(height) => height,
+        None => return true,
+    };
+    // Missing history means that an entity has just been spawned (or respawned).
+    let previous_height = height(1).unwrap_or(current_height);
+    let before_previous_height = height(2).unwrap_or(previous_height);
+
+    (current_height - previous_height).abs() < GROUNDED_HEIGHT_EPSILON
+        && (previous_height - before_previous_height).abs() < GROUNDED_HEIGHT_EPSILON
+}
+
 type SimulatedEntitiesQuery<'a> = (
     &'a RigidBodyHandleComponent,
     &'a mut Position,
+    &'a mut Velocity,
     Option<&'a mut Transform>,
     Option<&'a mut PredictedPosition>,
     Option<&'a PlayerFrameSimulated>,
@@ -211,6 +316,7 @@ pub fn sync_position(
     for (
         rigid_body,
         mut position,
+        mut velocity,
         mut transform,
         mut predicted_position,
         player_frame_simulated,
@@ -218,7 +324,7 @@ pub fn sync_position(
     ) in
         simulated_entities
             .iter_mut()
-            .filter(|(_, _, _, _, player_frame_simulated, spawned)| {
+            .filter(|(_, _, _, _, _, player_frame_simulated, spawned)| {
                 spawned.is_spawned(time.entity_simulation_frame(*player_frame_simulated))
             })
     {
@@ -228,7 +334,11 @@ pub fn sync_position(
             .expect("expected a rigid body");
 
         let body_position = *rigid_body.position();
-        let new_position = Vec2::new(body_position.translation.x, body_position.translation.z);
+        let new_position = Vec3::new(
+            body_position.translation.x,
+            body_position.translation.y,
+            body_position.translation.z,
+        );
         if let Some(predicted_position) = predicted_position.as_mut() {
             let current_position = *position
                 .buffer
@@ -244,16 +354,54 @@ pub fn sync_position(
 
                 predicted_position.value = lerp;
                 let transform = transform.as_mut().expect("Expected a Transform component if entity has PredictedPosition (is supposed to be a client)");
-                transform.translation.x = lerp.x;
-                transform.translation.z = lerp.y;
+                transform.translation = lerp;
             }
         }
 
         // Positions buffer represents start positions before moving entities, so this is why
         // we save the new position in the next frame.
-        position.buffer.insert(
+        position
+            .buffer
+            .insert(frame_number + FrameNumber::new(1), new_position);
+        let linvel = rigid_body.linvel();
+        velocity.buffer.insert(
             frame_number + FrameNumber::new(1),
-            Vec2::new(body_position.translation.x, body_position.translation.z),
+            Vec3::new(linvel.x, linvel.y, linvel.z),
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        framebuffer::FrameNumber,
+        game::{
+            components::{Position, Spawned},
+            movement::is_grounded,
+        },
+        GRAVITY,
+    };
+    use bevy::math::Vec3;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    #[test]
+    fn test_is_grounded() {
+        let spawned = Spawned::new(FrameNumber::new(0));
+        let mut position = Position::new(Vec3::new(0.0, 0.5, 0.0), FrameNumber::new(0), 3);
+        assert!(is_grounded(&position, &spawned, FrameNumber::new(2)));
+
+        // Falling from the peak of a jump.
+        let mut height = 1.0;
+        let mut speed = 0.0;
+        for frame_number in 3..6 {
+            speed += GRAVITY * DT;
+            height += speed * DT;
+            position
+                .buffer
+                .insert(FrameNumber::new(frame_number), Vec3::new(0.0, height, 0.0));
+        }
+        assert!(!is_grounded(&position, &spawned, FrameNumber::new(4)));
+        assert!(!is_grounded(&position, &spawned, FrameNumber::new(5)));
+    }
+}
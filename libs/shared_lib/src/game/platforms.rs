@@ -0,0 +1,255 @@
+use crate::{
+    framebuffer::FrameNumber,
+    game::{
+        components::PlayerFrameSimulated,
+        level::{LevelObjectDesc, LevelState},
+        level_objects::{isometry, MovingPlatformDesc},
+    },
+    messages::EntityNetId,
+    registry::EntityRegistry,
+    SimulationTime, PLAYER_SIZE,
+};
+use bevy::{log, prelude::*};
+use bevy_rapier3d::{
+    physics::RigidBodyHandleComponent,
+    rapier::{dynamics::RigidBodySet, geometry::InteractionGroups},
+};
+
+/// How far (vertically) an entity can be from a platform's top face to be considered standing on
+/// it.
+const STANDING_EPSILON: f32 = 0.05;
+
+/// Collision group of entities simulated at `SimulationTime::player_frame`.
+const PLAYER_FRAME_GROUP: u32 = 0b01;
+/// Collision group of entities simulated at `SimulationTime::server_frame`.
+const SERVER_FRAME_GROUP: u32 = 0b10;
+
+/// Clients simulate some entities at `player_frame` and the rest at `server_frame` within the same
+/// physics step, while a kinematic body can be only at one place at a time. That's why clients
+/// have two bodies for each moving platform: this one is a child of a platform entity and
+/// collides with the entities that are simulated at `server_frame`.
+pub struct ServerFramePlatformBody;
+
+/// Collision groups of a dynamic entity (a player), which let platforms pick the entities
+/// simulated at the same frame.
+pub fn dynamic_body_groups(is_player_frame_simulated: bool) -> InteractionGroups {
+    let membership = if is_player_frame_simulated {
+        PLAYER_FRAME_GROUP
+    } else {
+        SERVER_FRAME_GROUP
+    };
+    InteractionGroups::new(membership, u32::MAX)
+}
+
+/// Collision groups of a platform body that collides only with the entities simulated at
+/// the same frame.
+pub fn platform_body_groups(is_player_frame_simulated: bool) -> InteractionGroups {
+    let ignored = if is_player_frame_simulated {
+        SERVER_FRAME_GROUP
+    } else {
+        PLAYER_FRAME_GROUP
+    };
+    InteractionGroups::new(u32::MAX, u32::MAX ^ ignored)
+}
+
+/// Moves kinematic bodies of moving platforms to their positions for the current frame. As
+/// the positions are pure functions of frame numbers, this works for rewinds as well.
+pub fn move_platforms(
+    time: Res<SimulationTime>,
+    level_state: Res<LevelState>,
+    object_entities: Res<EntityRegistry<EntityNetId>>,
+    mut rigid_body_set: ResMut<RigidBodySet>,
+    bodies: Query<(
+        &RigidBodyHandleComponent,
+        Option<&PlayerFrameSimulated>,
+        Option<&Children>,
+    )>,
+    server_frame_bodies: Query<&RigidBodyHandleComponent, With<ServerFramePlatformBody>>,
+) {
+    for object in &level_state.objects {
+        let platform = match &object.desc {
+            LevelObjectDesc::MovingPlatform(platform) => platform,
+            _ => continue,
+        };
+        let (rigid_body, player_frame_simulated, children) = match object_entities
+            .get_entity(object.net_id)
+            .and_then(|entity| bodies.get(entity).ok())
+        {
+            Some(body) => body,
+            // A body gets created a frame after spawning an entity.
+            None => continue,
+        };
+
+        let frame_number = time.entity_simulation_frame(player_frame_simulated);
+        move_platform_body(
+            &mut rigid_body_set,
+            rigid_body,
+            object.net_id,
+            platform,
+            frame_number,
+        );
+        for child in children.into_iter().flat_map(|children| children.iter()) {
+            if let Ok(rigid_body) = server_frame_bodies.get(*child) {
+                move_platform_body(
+                    &mut rigid_body_set,
+                    rigid_body,
+                    object.net_id,
+                    platform,
+                    time.server_frame,
+                );
+            }
+        }
+    }
+}
+
+fn move_platform_body(
+    rigid_body_set: &mut RigidBodySet,
+    rigid_body: &RigidBodyHandleComponent,
+    net_id: EntityNetId,
+    platform: &MovingPlatformDesc,
+    frame_number: FrameNumber,
+) {
+    let rigid_body = match rigid_body_set.get_mut(rigid_body.handle()) {
+        Some(rigid_body) => rigid_body,
+        None => {
+            log::error!("Missing a rigid body for a platform ({})", net_id.0);
+            return;
+        }
+    };
+
+    rigid_body.set_position(
+        isometry(platform.position_at(frame_number), platform.rotation),
+        true,
+    );
+    // Kinematic bodies get their velocities from the next positions, which makes
+    // the contacts with them behave correctly.
+    rigid_body.set_next_kinematic_position(isometry(
+        platform.position_at(frame_number + FrameNumber::new(1)),
+        platform.rotation,
+    ));
+}
+
+/// Returns the displacement (per frame) of a platform that a player stands on. Players don't get
+/// carried by the friction alone, as their horizontal velocity is overwritten every frame.
+pub fn carrying_platform_displacement(
+    level_state: &LevelState,
+    player_position: Vec3,
+    frame_number: FrameNumber,
+) -> Option<Vec3> {
+    level_state.objects.iter().find_map(|object| {
+        let platform = match &object.desc {
+            LevelObjectDesc::MovingPlatform(platform) => platform,
+            _ => return None,
+        };
+        let half_size = platform.size / 2.0;
+        let local_position =
+            platform.rotation.conjugate() * (player_position - platform.position_at(frame_number));
+        let player_half_size = PLAYER_SIZE / 2.0;
+        let is_above = local_position.x.abs() < half_size.x + player_half_size
+            && local_position.z.abs() < half_size.z + player_half_size;
+        let is_touching =
+            (local_position.y - half_size.y - player_half_size).abs() < STANDING_EPSILON;
+        if is_above && is_touching {
+            Some(platform.displacement_at(frame_number))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        framebuffer::FrameNumber,
+        game::{
+            level::{LevelObject, LevelObjectDesc, LevelState},
+            level_objects::MovingPlatformDesc,
+            platforms::carrying_platform_displacement,
+        },
+        messages::EntityNetId,
+    };
+    use bevy::math::{Quat, Vec3};
+
+    fn elevator() -> MovingPlatformDesc {
+        MovingPlatformDesc {
+            position: Vec3::new(0.0, 0.25, 0.0),
+            rotation: Quat::identity(),
+            size: Vec3::new(2.0, 0.5, 2.0),
+            waypoints: vec![Vec3::new(0.0, 2.0, 0.0)],
+            period: 128,
+            phase: 0,
+        }
+    }
+
+    #[test]
+    fn test_platform_position_is_periodic() {
+        let platform = elevator();
+        assert_eq!(platform.position_at(FrameNumber::new(0)), platform.position);
+        assert_eq!(
+            platform.position_at(FrameNumber::new(64)),
+            platform.position + Vec3::new(0.0, 2.0, 0.0)
+        );
+        assert_eq!(
+            platform.position_at(FrameNumber::new(32)),
+            platform.position_at(FrameNumber::new(160))
+        );
+
+        let shifted_platform = MovingPlatformDesc {
+            phase: 64,
+            ..elevator()
+        };
+        assert_eq!(
+            shifted_platform.position_at(FrameNumber::new(0)),
+            platform.position_at(FrameNumber::new(64))
+        );
+    }
+
+    #[test]
+    fn test_platform_position_is_continuous_across_frame_number_wraps() {
+        // 4 seconds, which doesn't divide the frame number range.
+        let platform = MovingPlatformDesc {
+            period: 480,
+            phase: 10,
+            ..elevator()
+        };
+        let speed = platform.displacement_at(FrameNumber::new(100)).length();
+        assert!(speed > 0.0);
+        for frame_number in [u16::MAX - 10, u16::MAX - 1, u16::MAX].iter() {
+            let displacement = platform.displacement_at(FrameNumber::new(*frame_number));
+            assert!((displacement.length() - speed).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_carrying_platform_displacement() {
+        let level_state = LevelState {
+            objects: vec![LevelObject {
+                net_id: EntityNetId(0),
+                desc: LevelObjectDesc::MovingPlatform(elevator()),
+            }],
+        };
+        let frame_number = FrameNumber::new(10);
+        let platform_top = elevator().position_at(frame_number).y + 0.25;
+
+        let displacement = carrying_platform_displacement(
+            &level_state,
+            Vec3::new(0.5, platform_top + 0.5, 0.0),
+            frame_number,
+        )
+        .unwrap();
+        assert!((displacement.y - 4.0 / 128.0).abs() < 0.0001);
+
+        assert!(carrying_platform_displacement(
+            &level_state,
+            Vec3::new(0.5, platform_top + 1.5, 0.0),
+            frame_number
+        )
+        .is_none());
+        assert!(carrying_platform_displacement(
+            &level_state,
+            Vec3::new(3.0, platform_top + 0.5, 0.0),
+            frame_number
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::{
+    framebuffer::FrameNumber,
+    game::{
+        components::Spawned, level::LevelObjectDesc, level_objects::RouteSensorKind,
+        sensors::SensorParams,
+    },
+    messages::{
+        EntityNetId, PlayerNetId, RaceFinished, RaceLap, RaceStarted, ReliableServerMessage,
+    },
+    SimulationTime,
+};
+use bevy::{log, prelude::*};
+use std::collections::{HashMap, HashSet};
+
+/// Lap and finish times of a player (in simulation frames).
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct RaceResults {
+    /// Lap times of the current (or the latest finished) race.
+    pub lap_times: Vec<u32>,
+    pub finish_times: Vec<u32>,
+}
+
+impl RaceResults {
+    pub fn best_finish_time(&self) -> Option<u32> {
+        self.finish_times.iter().copied().min()
+    }
+}
+
+#[derive(Default)]
+struct PlayerRace {
+    /// Is `None` if a player hasn't crossed the start yet (or has already finished).
+    started_at: Option<u32>,
+    last_lap_at: u32,
+    crossed_checkpoints: HashSet<EntityNetId>,
+    results: RaceResults,
+}
+
+/// Tracks the progress of players along the route. Is updated by the server only, clients get
+/// the results via `RaceStarted`, `RaceLap` and `RaceFinished` messages.
+#[derive(Default)]
+pub struct RaceState {
+    /// The newest frame checked for crossings. As frames that get re-simulated after a rewind
+    /// aren't checked again, a crossing can't be counted twice.
+    last_checked_frame: Option<FrameNumber>,
+    /// A monotonic count of checked frames. Race times are measured with it, as `FrameNumber`
+    /// wraps around.
+    elapsed_frames: u32,
+    /// Sensors that players intersected during the last checked frame.
+    intersections: HashSet<(PlayerNetId, EntityNetId)>,
+    players: HashMap<PlayerNetId, PlayerRace>,
+    /// Messages that are expected to be broadcast to clients.
+    pub updates: Vec<ReliableServerMessage>,
+}
+
+impl RaceState {
+    pub fn results(&self, player_net_id: PlayerNetId) -> Option<&RaceResults> {
+        self.players
+            .get(&player_net_id)
+            .map(|player_race| &player_race.results)
+    }
+
+    pub fn remove_player(&mut self, player_net_id: PlayerNetId) {
+        self.players.remove(&player_net_id);
+        self.intersections
+            .retain(|(intersecting_player_net_id, _)| *intersecting_player_net_id != player_net_id);
+    }
+
+    /// Returns `false` if the frame has been already checked.
+    fn begin_frame(&mut self, frame_number: FrameNumber) -> bool {
+        if let Some(last_checked_frame) = self.last_checked_frame {
+            if frame_number <= last_checked_frame {
+                return false;
+            }
+            self.elapsed_frames += (frame_number - last_checked_frame).value() as u32;
+        }
+        self.last_checked_frame = Some(frame_number);
+        true
+    }
+
+    fn process_intersections(
+        &mut self,
+        frame_number: FrameNumber,
+        intersections: HashSet<(PlayerNetId, EntityNetId)>,
+        sensors: &HashMap<EntityNetId, RouteSensorKind>,
+    ) {
+        let mut entered_sensors = intersections
+            .difference(&self.intersections)
+            .copied()
+            .collect::<Vec<_>>();
+        // Hash sets don't guarantee any order, but we want the results to be deterministic.
+        entered_sensors
+            .sort_by_key(|(player_net_id, sensor_net_id)| (player_net_id.0, sensor_net_id.0));
+        let checkpoints = sensors
+            .iter()
+            .filter(|(_, kind)| **kind == RouteSensorKind::Checkpoint)
+            .map(|(net_id, _)| *net_id)
+            .collect::<Vec<_>>();
+
+        for (player_net_id, sensor_net_id) in entered_sensors {
+            if let Some(kind) = sensors.get(&sensor_net_id) {
+                self.cross(
+                    frame_number,
+                    player_net_id,
+                    sensor_net_id,
+                    *kind,
+                    &checkpoints,
+                );
+            }
+        }
+        self.intersections = intersections;
+    }
+
+    fn cross(
+        &mut self,
+        frame_number: FrameNumber,
+        player_net_id: PlayerNetId,
+        sensor_net_id: EntityNetId,
+        kind: RouteSensorKind,
+        checkpoints: &[EntityNetId],
+    ) {
+        let now = self.elapsed_frames;
+        let player_race = self.players.entry(player_net_id).or_default();
+        match kind {
+            RouteSensorKind::Start => {
+                log::debug!(
+                    "Player ({}) started the race (frame: {})",
+                    player_net_id.0,
+                    frame_number
+                );
+                player_race.started_at = Some(now);
+                player_race.last_lap_at = now;
+                player_race.crossed_checkpoints.clear();
+                player_race.results.lap_times.clear();
+                self.updates
+                    .push(ReliableServerMessage::RaceStarted(RaceStarted {
+                        player_net_id,
+                        frame_number,
+                    }));
+            }
+            RouteSensorKind::Checkpoint => {
+                if player_race.started_at.is_none()
+                    || !player_race.crossed_checkpoints.insert(sensor_net_id)
+                {
+                    return;
+                }
+                let lap_time = now - player_race.last_lap_at;
+                player_race.last_lap_at = now;
+                player_race.results.lap_times.push(lap_time);
+                self.updates.push(ReliableServerMessage::RaceLap(RaceLap {
+                    player_net_id,
+                    checkpoint_net_id: sensor_net_id,
+                    frame_number,
+                    lap_time,
+                }));
+            }
+            RouteSensorKind::Finish => {
+                let started_at = match player_race.started_at {
+                    Some(started_at) => started_at,
+                    None => return,
+                };
+                if !checkpoints
+                    .iter()
+                    .all(|checkpoint| player_race.crossed_checkpoints.contains(checkpoint))
+                {
+                    return;
+                }
+                let lap_time = now - player_race.last_lap_at;
+                let finish_time = now - started_at;
+                log::info!(
+                    "Player ({}) finished the race in {} frames (frame: {})",
+                    player_net_id.0,
+                    finish_time,
+                    frame_number
+                );
+                player_race.started_at = None;
+                player_race.results.lap_times.push(lap_time);
+                player_race.results.finish_times.push(finish_time);
+                self.updates
+                    .push(ReliableServerMessage::RaceFinished(RaceFinished {
+                        player_net_id,
+                        frame_number,
+                        lap_time,
+                        finish_time,
+                    }));
+            }
+        }
+    }
+}
+
+pub fn detect_route_sensor_crossings(
+    time: Res<SimulationTime>,
+    sensor_params: SensorParams,
+    players: Query<&Spawned>,
+    mut race_state: ResMut<RaceState>,
+) {
+    if !race_state.begin_frame(time.server_frame) {
+        return;
+    }
+
+    let sensors = sensor_params
+        .level_state
+        .objects
+        .iter()
+        .filter_map(|object| match &object.desc {
+            LevelObjectDesc::RouteSensor(sensor) => Some((object.net_id, sensor.kind)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let intersections = sensor_params
+        .intersections(|desc| matches!(desc, LevelObjectDesc::RouteSensor(_)))
+        .into_iter()
+        .filter(|(_, player_entity, _)| {
+            players
+                .get(*player_entity)
+                .map_or(false, |spawned| spawned.is_spawned(time.server_frame))
+        })
+        .map(|(player_net_id, _, sensor_net_id)| (player_net_id, sensor_net_id))
+        .collect();
+
+    race_state.process_intersections(time.server_frame, intersections, &sensors);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        framebuffer::FrameNumber,
+        game::{level_objects::RouteSensorKind, race::RaceState},
+        messages::{EntityNetId, PlayerNetId, ReliableServerMessage},
+    };
+    use std::collections::{HashMap, HashSet};
+
+    const PLAYER: PlayerNetId = PlayerNetId(0);
+    const START: EntityNetId = EntityNetId(1);
+    const CHECKPOINT: EntityNetId = EntityNetId(2);
+    const FINISH: EntityNetId = EntityNetId(3);
+
+    fn sensors() -> HashMap<EntityNetId, RouteSensorKind> {
+        vec![
+            (START, RouteSensorKind::Start),
+            (CHECKPOINT, RouteSensorKind::Checkpoint),
+            (FINISH, RouteSensorKind::Finish),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn check_frame(race_state: &mut RaceState, frame_number: u16, sensor: Option<EntityNetId>) {
+        let frame_number = FrameNumber::new(frame_number);
+        if race_state.begin_frame(frame_number) {
+            let intersections = sensor
+                .map(|sensor| (PLAYER, sensor))
+                .into_iter()
+                .collect::<HashSet<_>>();
+            race_state.process_intersections(frame_number, intersections, &sensors());
+        }
+    }
+
+    #[test]
+    fn test_race_times() {
+        let mut race_state = RaceState::default();
+        check_frame(&mut race_state, 0, Some(START));
+        check_frame(&mut race_state, 1, None);
+        // The finish doesn't count until all the checkpoints are crossed.
+        check_frame(&mut race_state, 5, Some(FINISH));
+        check_frame(&mut race_state, 10, Some(CHECKPOINT));
+        check_frame(&mut race_state, 11, Some(CHECKPOINT));
+        check_frame(&mut race_state, 12, None);
+        check_frame(&mut race_state, 25, Some(FINISH));
+
+        let results = race_state.results(PLAYER).unwrap();
+        assert_eq!(results.lap_times, vec![10, 15]);
+        assert_eq!(results.finish_times, vec![25]);
+        assert_eq!(race_state.updates.len(), 3);
+        assert!(matches!(
+            race_state.updates.last(),
+            Some(ReliableServerMessage::RaceFinished(_))
+        ));
+    }
+
+    #[test]
+    fn test_rewind_doesnt_double_count_crossings() {
+        let mut race_state = RaceState::default();
+        check_frame(&mut race_state, 0, Some(START));
+        check_frame(&mut race_state, 1, None);
+        check_frame(&mut race_state, 2, Some(CHECKPOINT));
+        check_frame(&mut race_state, 3, None);
+        // Re-simulating the frames after a rewind.
+        check_frame(&mut race_state, 1, None);
+        check_frame(&mut race_state, 2, Some(CHECKPOINT));
+        check_frame(&mut race_state, 3, None);
+        check_frame(&mut race_state, 4, Some(START));
+        check_frame(&mut race_state, 4, Some(START));
+
+        let results = race_state.results(PLAYER).unwrap();
+        assert!(results.lap_times.is_empty());
+        assert_eq!(race_state.updates.len(), 3);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    framebuffer::FrameNumber,
+    game::{
+        commands::{GameCommands, SpawnPlayer},
+        components::Spawned,
+        level::{LevelObjectDesc, LevelState},
+        sensors::SensorParams,
+    },
+    messages::PlayerNetId,
+    registry::EntityRegistry,
+    SimulationTime, PLAYER_SIZE, SIMULATIONS_PER_SECOND,
+};
+use bevy::{log, prelude::*};
+
+/// How long players stay dead after touching a death zone.
+pub struct RespawnDelay(pub FrameNumber);
+
+impl Default for RespawnDelay {
+    fn default() -> Self {
+        Self(FrameNumber::new(SIMULATIONS_PER_SECOND * 2))
+    }
+}
+
+/// Is updated by the server only, clients learn about deaths and respawns with delta updates.
+#[derive(Default)]
+pub struct RespawnState {
+    /// The newest frame checked for death zone touches. Frames re-simulated after a rewind
+    /// aren't checked again, so that a player can't die twice.
+    last_checked_frame: Option<FrameNumber>,
+    pending_respawns: Vec<(PlayerNetId, FrameNumber)>,
+    next_spawn_point: usize,
+}
+
+impl RespawnState {
+    pub fn remove_player(&mut self, player_net_id: PlayerNetId) {
+        self.pending_respawns
+            .retain(|(respawning_player_net_id, _)| *respawning_player_net_id != player_net_id);
+    }
+
+    /// Cycles through the spawn points of the level. Falls back to the center of the level if
+    /// there are no spawn points.
+    pub fn next_spawn_position(&mut self, level_state: &LevelState) -> Vec3 {
+        let spawn_points = level_state
+            .objects
+            .iter()
+            .filter_map(|object| match &object.desc {
+                LevelObjectDesc::SpawnPoint(spawn_point) => Some(spawn_point.position),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if spawn_points.is_empty() {
+            return Vec3::new(0.0, PLAYER_SIZE / 2.0, 0.0);
+        }
+
+        let position = spawn_points[self.next_spawn_point % spawn_points.len()];
+        self.next_spawn_point = self.next_spawn_point.wrapping_add(1);
+        position
+    }
+
+    fn begin_frame(&mut self, frame_number: FrameNumber) -> bool {
+        if let Some(last_checked_frame) = self.last_checked_frame {
+            if frame_number <= last_checked_frame {
+                return false;
+            }
+        }
+        self.last_checked_frame = Some(frame_number);
+        true
+    }
+}
+
+pub fn detect_death_zone_touches(
+    time: Res<SimulationTime>,
+    respawn_delay: Res<RespawnDelay>,
+    sensor_params: SensorParams,
+    mut players: Query<&mut Spawned>,
+    mut respawn_state: ResMut<RespawnState>,
+) {
+    if !respawn_state.begin_frame(time.server_frame) {
+        return;
+    }
+
+    let intersections =
+        sensor_params.intersections(|desc| matches!(desc, LevelObjectDesc::DeathZone(_)));
+    for (player_net_id, player_entity, death_zone_net_id) in intersections {
+        let mut spawned = match players.get_mut(player_entity) {
+            Ok(spawned) => spawned,
+            Err(_) => continue,
+        };
+        // A player may touch several death zones at once.
+        if !spawned.is_spawned(time.server_frame)
+            || spawned.is_respawning(time.server_frame + FrameNumber::new(1))
+        {
+            continue;
+        }
+
+        let despawn_frame = time.server_frame + FrameNumber::new(1);
+        let respawn_frame = despawn_frame + respawn_delay.0;
+        log::info!(
+            "Player ({}) touched a death zone ({}), respawning at frame {} (frame: {})",
+            player_net_id.0,
+            death_zone_net_id.0,
+            respawn_frame,
+            time.server_frame
+        );
+        // Setting both frames right away, so that the entity doesn't get removed while it's dead.
+        spawned.set_despawned_at(despawn_frame);
+        spawned.set_respawned_at(respawn_frame);
+        respawn_state
+            .pending_respawns
+            .push((player_net_id, respawn_frame));
+    }
+}
+
+/// Queues `SpawnPlayer` commands for players whose respawn delay has passed. Is expected to run
+/// before `spawn_players`, so that respawns happen exactly at their frames.
+pub fn respawn_players(
+    time: Res<SimulationTime>,
+    level_state: Res<LevelState>,
+    player_entities: Res<EntityRegistry<PlayerNetId>>,
+    mut respawn_state: ResMut<RespawnState>,
+    mut spawn_player_commands: ResMut<GameCommands<SpawnPlayer>>,
+) {
+    let mut due_respawns = Vec::new();
+    respawn_state
+        .pending_respawns
+        .retain(|&(player_net_id, respawn_frame)| {
+            if respawn_frame > time.server_frame {
+                return true;
+            }
+            due_respawns.push(player_net_id);
+            false
+        });
+
+    for player_net_id in due_respawns {
+        // The player might have disconnected while being dead.
+        if player_entities.get_entity(player_net_id).is_none() {
+            continue;
+        }
+        let start_position = respawn_state.next_spawn_position(&level_state);
+        spawn_player_commands.push(SpawnPlayer {
+            net_id: player_net_id,
+            start_position,
+            is_player_frame_simulated: false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        game::{
+            level::{LevelObject, LevelObjectDesc, LevelState},
+            level_objects::SpawnPointDesc,
+            respawn::RespawnState,
+        },
+        messages::EntityNetId,
+        PLAYER_SIZE,
+    };
+    use bevy::math::Vec3;
+
+    #[test]
+    fn test_next_spawn_position_cycles_through_spawn_points() {
+        let mut respawn_state = RespawnState::default();
+        let mut level_state = LevelState::default();
+        assert_eq!(
+            respawn_state.next_spawn_position(&level_state),
+            Vec3::new(0.0, PLAYER_SIZE / 2.0, 0.0)
+        );
+
+        for (i, x) in [1.0, 2.0].iter().enumerate() {
+            level_state.objects.push(LevelObject {
+                net_id: EntityNetId(i as u16),
+                desc: LevelObjectDesc::SpawnPoint(SpawnPointDesc {
+                    position: Vec3::new(*x, 0.5, 3.0),
+                }),
+            });
+        }
+        let positions = (0..3)
+            .map(|_| respawn_state.next_spawn_position(&level_state))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions,
+            vec![
+                Vec3::new(1.0, 0.5, 3.0),
+                Vec3::new(2.0, 0.5, 3.0),
+                Vec3::new(1.0, 0.5, 3.0)
+            ]
+        );
+    }
+}
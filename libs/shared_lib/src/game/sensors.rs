@@ -0,0 +1,59 @@
+use crate::{
+    game::level::{LevelObjectDesc, LevelState},
+    messages::{EntityNetId, PlayerNetId},
+    registry::EntityRegistry,
+};
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_rapier3d::{physics::ColliderHandleComponent, rapier::geometry::NarrowPhase};
+
+/// Finds players intersecting sensor level objects (route sensors, death zones, etc).
+#[derive(SystemParam)]
+pub struct SensorParams<'a> {
+    pub level_state: Res<'a, LevelState>,
+    narrow_phase: Res<'a, NarrowPhase>,
+    object_entities: Res<'a, EntityRegistry<EntityNetId>>,
+    player_entities: Res<'a, EntityRegistry<PlayerNetId>>,
+    colliders: Query<'a, &'static ColliderHandleComponent>,
+}
+
+impl<'a> SensorParams<'a> {
+    /// Returns pairs of players (with their entities) and the sensors that they intersect.
+    /// Callers are expected to filter out players that aren't spawned.
+    pub fn intersections(
+        &self,
+        is_sensor: impl Fn(&LevelObjectDesc) -> bool,
+    ) -> Vec<(PlayerNetId, Entity, EntityNetId)> {
+        let sensor_colliders = self
+            .level_state
+            .objects
+            .iter()
+            .filter(|object| is_sensor(&object.desc))
+            .filter_map(|object| {
+                let entity = self.object_entities.get_entity(object.net_id)?;
+                let collider = self.colliders.get(entity).ok()?;
+                Some((object.net_id, collider.handle()))
+            })
+            .collect::<Vec<_>>();
+        if sensor_colliders.is_empty() {
+            return Vec::new();
+        }
+
+        let mut intersections = Vec::new();
+        for (&player_net_id, &player_entity) in self.player_entities.iter() {
+            let player_collider = match self.colliders.get(player_entity) {
+                Ok(collider) => collider.handle(),
+                Err(_) => continue,
+            };
+            for &(sensor_net_id, sensor_collider) in &sensor_colliders {
+                if self
+                    .narrow_phase
+                    .intersection_pair(player_collider, sensor_collider)
+                    == Some(true)
+                {
+                    intersections.push((player_net_id, player_entity, sensor_net_id));
+                }
+            }
+        }
+        intersections
+    }
+}
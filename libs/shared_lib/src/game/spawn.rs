@@ -1,19 +1,26 @@
 use crate::{
+    framebuffer::FrameNumber,
     game::{
         client_factories::{
-            ClientFactory, PbrClientParams, PlaneClientFactory, PlayerClientFactory,
+            ClientFactory, CuboidClientFactory, CylinderClientFactory, DeathZoneClientFactory,
+            MovingPlatformClientFactory, PbrClientParams, PlaneClientFactory, PlayerClientFactory,
+            RampClientFactory, RouteSensorClientFactory, SpawnPointClientFactory,
         },
-        commands::{DespawnPlayer, GameCommands, SpawnLevelObject, SpawnPlayer},
-        components::{PlayerDirection, Position, Spawned},
-        level::{LevelObjectDesc, LevelState},
+        commands::{
+            DespawnLevelObject, DespawnPlayer, GameCommands, SpawnLevelObject, SpawnPlayer,
+            UpdateLevelObject,
+        },
+        components::{PlayerDirection, Position, Spawned, Velocity},
+        level::{LevelObject, LevelObjectDesc, LevelState},
+        platforms::{dynamic_body_groups, platform_body_groups, ServerFramePlatformBody},
     },
     messages::{EntityNetId, PlayerNetId},
     player::Player,
     registry::EntityRegistry,
     util::dedup_by_key_unsorted,
-    GameTime, SimulationTime, PLAYER_SIZE,
+    GameTime, SimulationRole, SimulationTime, PLAYER_SIZE,
 };
-use bevy::{log, prelude::*};
+use bevy::{ecs::system::EntityCommands, log, prelude::*};
 use bevy_rapier3d::rapier::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder};
 use std::collections::HashMap;
 
@@ -23,7 +30,13 @@ pub fn spawn_players(
     mut pbr_client_params: PbrClientParams,
     mut spawn_player_commands: ResMut<GameCommands<SpawnPlayer>>,
     mut player_entities: ResMut<EntityRegistry<PlayerNetId>>,
-    mut players: Query<(Entity, &mut Spawned, &mut Position, &mut PlayerDirection)>,
+    mut players: Query<(
+        Entity,
+        &mut Spawned,
+        &mut Position,
+        &mut Velocity,
+        &mut PlayerDirection,
+    )>,
 ) {
     let mut spawn_player_commands = spawn_player_commands.drain();
     dedup_by_key_unsorted(&mut spawn_player_commands, |command| command.net_id);
@@ -44,14 +57,24 @@ pub fn spawn_players(
                 entity
             );
 
-            let (_, mut spawned, mut position, mut player_direction) =
+            let (_, mut spawned, mut position, mut velocity, mut player_direction) =
                 players.get_mut(entity).unwrap();
+            // Client components are removed on despawn (see `despawn_players`).
+            if !spawned.is_spawned(time.server_frame) {
+                PlayerClientFactory::insert_components(
+                    &mut commands.entity(entity),
+                    &mut pbr_client_params,
+                    &(command.start_position, command.is_player_frame_simulated),
+                );
+            }
             position
                 .buffer
                 .insert(time.server_frame, command.start_position);
+            velocity.buffer.insert(time.server_frame, Vec3::ZERO);
             player_direction
                 .buffer
                 .insert(time.server_frame, Some(Vec2::ZERO));
+            player_direction.jumps.insert(time.server_frame, false);
             spawned.set_respawned_at(time.server_frame);
 
             continue;
@@ -69,16 +92,20 @@ pub fn spawn_players(
                     .translation(0.0, PLAYER_SIZE / 2.0, 0.0)
                     .lock_rotations(),
             )
-            .insert(ColliderBuilder::cuboid(
-                PLAYER_SIZE / 2.0,
-                PLAYER_SIZE / 2.0,
-                PLAYER_SIZE / 2.0,
-            ))
+            .insert(
+                ColliderBuilder::cuboid(PLAYER_SIZE / 2.0, PLAYER_SIZE / 2.0, PLAYER_SIZE / 2.0)
+                    .collision_groups(dynamic_body_groups(command.is_player_frame_simulated)),
+            )
             .insert(Position::new(
                 command.start_position,
                 time.server_frame,
                 frames_ahead + 1,
             ))
+            .insert(Velocity::new(
+                Vec3::ZERO,
+                time.server_frame,
+                frames_ahead + 1,
+            ))
             .insert(PlayerDirection::new(
                 Vec2::ZERO,
                 time.server_frame,
@@ -121,6 +148,15 @@ pub fn despawn_players(
                 continue;
             }
         };
+        if spawned.is_respawning(command.frame_number) {
+            log::info!(
+                "Despawning respawning player {} (frame {})",
+                command.net_id.0,
+                command.frame_number
+            );
+            spawned.cancel_respawn();
+            continue;
+        }
         if !spawned.is_spawned(command.frame_number) {
             log::debug!(
                 "Player ({}) is not spawned at frame {}, skipping the despawn command",
@@ -142,6 +178,7 @@ pub fn despawn_players(
 
 pub fn spawn_level_objects(
     mut commands: Commands,
+    role: Res<SimulationRole>,
     mut pbr_client_params: PbrClientParams,
     mut spawn_level_object_commands: ResMut<GameCommands<SpawnLevelObject>>,
     mut object_entities: ResMut<EntityRegistry<EntityNetId>>,
@@ -159,18 +196,157 @@ pub fn spawn_level_objects(
         log::info!("Spawning an object: {:?}", command);
         level_state.objects.push(command.object.clone());
         let mut entity_commands = commands.spawn();
-        match command.object.desc {
-            LevelObjectDesc::Plane(plane) => PlaneClientFactory::insert_components(
-                &mut entity_commands,
-                &mut pbr_client_params,
-                &(plane, cfg!(feature = "client")),
-            ),
-        };
-        entity_commands.insert(Spawned::new(command.frame_number));
+        insert_level_object_components(
+            &mut entity_commands,
+            &mut pbr_client_params,
+            &command.object,
+            command.frame_number,
+            role.is_client(),
+        );
         object_entities.register(command.object.net_id, entity_commands.id());
     }
 }
 
+pub fn update_level_objects(
+    mut commands: Commands,
+    role: Res<SimulationRole>,
+    mut pbr_client_params: PbrClientParams,
+    mut update_level_object_commands: ResMut<GameCommands<UpdateLevelObject>>,
+    mut object_entities: ResMut<EntityRegistry<EntityNetId>>,
+    mut level_state: ResMut<LevelState>,
+) {
+    for command in update_level_object_commands.drain() {
+        let level_object = level_state
+            .objects
+            .iter_mut()
+            .find(|object| object.net_id == command.object.net_id);
+        let (level_object, entity) =
+            match level_object.zip(object_entities.remove_by_id(command.object.net_id)) {
+                Some(object) => object,
+                None => {
+                    log::debug!(
+                        "Object ({}) doesn't exist, skipping the update",
+                        command.object.net_id.0
+                    );
+                    continue;
+                }
+            };
+
+        log::info!("Updating an object: {:?}", command);
+        *level_object = command.object.clone();
+        // Components and physics bodies differ for each object type, so it's easier to replace
+        // the whole entity than to patch it.
+        commands.entity(entity).despawn_recursive();
+        let mut entity_commands = commands.spawn();
+        insert_level_object_components(
+            &mut entity_commands,
+            &mut pbr_client_params,
+            &command.object,
+            command.frame_number,
+            role.is_client(),
+        );
+        object_entities.register(command.object.net_id, entity_commands.id());
+    }
+}
+
+pub fn despawn_level_objects(
+    mut commands: Commands,
+    mut despawn_level_object_commands: ResMut<GameCommands<DespawnLevelObject>>,
+    mut object_entities: ResMut<EntityRegistry<EntityNetId>>,
+    mut level_state: ResMut<LevelState>,
+) {
+    for command in despawn_level_object_commands.drain() {
+        let entity = match object_entities.remove_by_id(command.net_id) {
+            Some(entity) => entity,
+            None => {
+                log::debug!(
+                    "Object ({}) doesn't exist, skipping the despawn command",
+                    command.net_id.0
+                );
+                continue;
+            }
+        };
+
+        log::info!("Despawning an object: {:?}", command);
+        level_state
+            .objects
+            .retain(|object| object.net_id != command.net_id);
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn insert_level_object_components(
+    entity_commands: &mut EntityCommands,
+    pbr_client_params: &mut PbrClientParams,
+    object: &LevelObject,
+    frame_number: FrameNumber,
+    is_player_frame_simulated: bool,
+) {
+    if let Some((rigid_body, mut collider)) = object.desc.physics_body() {
+        if let LevelObjectDesc::MovingPlatform(_) = object.desc {
+            if is_player_frame_simulated {
+                let server_frame_collider = collider
+                    .clone()
+                    .collision_groups(platform_body_groups(false));
+                entity_commands.with_children(|parent| {
+                    parent
+                        .spawn()
+                        .insert(rigid_body.clone())
+                        .insert(server_frame_collider)
+                        .insert(ServerFramePlatformBody);
+                });
+            }
+            collider = collider.collision_groups(platform_body_groups(is_player_frame_simulated));
+        }
+        entity_commands.insert(rigid_body).insert(collider);
+    }
+    match &object.desc {
+        LevelObjectDesc::Plane(plane) => PlaneClientFactory::insert_components(
+            entity_commands,
+            pbr_client_params,
+            &(plane.clone(), is_player_frame_simulated),
+        ),
+        LevelObjectDesc::Cuboid(cuboid) => CuboidClientFactory::insert_components(
+            entity_commands,
+            pbr_client_params,
+            &(cuboid.clone(), is_player_frame_simulated),
+        ),
+        LevelObjectDesc::Ramp(ramp) => RampClientFactory::insert_components(
+            entity_commands,
+            pbr_client_params,
+            &(ramp.clone(), is_player_frame_simulated),
+        ),
+        LevelObjectDesc::Cylinder(cylinder) => CylinderClientFactory::insert_components(
+            entity_commands,
+            pbr_client_params,
+            &(cylinder.clone(), is_player_frame_simulated),
+        ),
+        LevelObjectDesc::RouteSensor(sensor) => RouteSensorClientFactory::insert_components(
+            entity_commands,
+            pbr_client_params,
+            &(sensor.clone(), is_player_frame_simulated),
+        ),
+        LevelObjectDesc::SpawnPoint(spawn_point) => SpawnPointClientFactory::insert_components(
+            entity_commands,
+            pbr_client_params,
+            &(spawn_point.clone(), is_player_frame_simulated),
+        ),
+        LevelObjectDesc::DeathZone(death_zone) => DeathZoneClientFactory::insert_components(
+            entity_commands,
+            pbr_client_params,
+            &(death_zone.clone(), is_player_frame_simulated),
+        ),
+        LevelObjectDesc::MovingPlatform(platform) => {
+            MovingPlatformClientFactory::insert_components(
+                entity_commands,
+                pbr_client_params,
+                &(platform.clone(), is_player_frame_simulated),
+            )
+        }
+    };
+    entity_commands.insert(Spawned::new(frame_number));
+}
+
 pub fn process_spawned_entities(
     mut commands: Commands,
     game_time: Res<GameTime>,
@@ -183,7 +359,7 @@ pub fn process_spawned_entities(
         spawned.mark_if_mature(game_time.frame_number);
         if spawned.can_be_removed(game_time.frame_number) {
             log::debug!("Despawning entity {:?}", entity);
-            commands.entity(entity).despawn();
+            commands.entity(entity).despawn_recursive();
             if let Some(player_net_id) = player_entities.remove_by_entity(entity) {
                 players.remove(&player_net_id);
             }
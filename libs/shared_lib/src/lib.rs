@@ -7,13 +7,19 @@ use crate::{
     game::{
         commands::{
             DespawnLevelObject, DespawnPlayer, GameCommands, RestartGame, SpawnLevelObject,
-            SpawnPlayer,
+            SpawnPlayer, UpdateLevelObject,
         },
         components::PlayerFrameSimulated,
         level::LevelState,
         movement::{player_movement, read_movement_updates, sync_position},
+        platforms::move_platforms,
+        race::RaceState,
+        respawn::{detect_death_zone_touches, respawn_players, RespawnDelay, RespawnState},
         restart_game,
-        spawn::{despawn_players, process_spawned_entities, spawn_level_objects, spawn_players},
+        spawn::{
+            despawn_level_objects, despawn_players, process_spawned_entities, spawn_level_objects,
+            spawn_players, update_level_objects,
+        },
     },
     net::network_setup,
     player::{Player, PlayerUpdates},
@@ -48,12 +54,16 @@ use bevy_rapier3d::{
 use messages::{EntityNetId, PlayerNetId};
 use std::{borrow::Cow, collections::HashMap, sync::Mutex};
 
+pub mod codec;
 pub mod framebuffer;
 pub mod game;
+pub mod link_conditioner;
 pub mod messages;
 pub mod net;
+pub mod net_stats;
 pub mod player;
 pub mod registry;
+pub mod transport;
 pub mod util;
 pub mod wrapped_counter;
 
@@ -78,11 +88,16 @@ pub mod stage {
 }
 pub const PLAYER_SIZE: f32 = 1.0;
 pub const PLANE_SIZE: f32 = 20.0;
+pub const GRAVITY: f32 = -9.81;
 pub const SIMULATIONS_PER_SECOND: u16 = 120;
 pub const COMPONENT_FRAMEBUFFER_LIMIT: u16 = 120 * 10; // 10 seconds of 120fps
 pub const TICKS_PER_NETWORK_BROADCAST: u16 = 2;
+/// The server never allocates entity net ids from this number on, as clients use the rest of
+/// the range for temporary ids of optimistically spawned level objects.
+pub const FIRST_TEMPORARY_ENTITY_NET_ID: u16 = u16::MAX - 1023;
 
 pub struct MuddleSharedPlugin<S: System<In = (), Out = ShouldRun>> {
+    role: SimulationRole,
     main_run_criteria: Mutex<Option<S>>,
     input_stage: Mutex<Option<SystemStage>>,
     broadcast_updates_stage: Mutex<Option<SystemStage>>,
@@ -92,6 +107,7 @@ pub struct MuddleSharedPlugin<S: System<In = (), Out = ShouldRun>> {
 
 impl<S: System<In = (), Out = ShouldRun>> MuddleSharedPlugin<S> {
     pub fn new(
+        role: SimulationRole,
         main_run_criteria: S,
         input_stage: SystemStage,
         broadcast_updates_stage: SystemStage,
@@ -99,6 +115,7 @@ impl<S: System<In = (), Out = ShouldRun>> MuddleSharedPlugin<S> {
         link_conditioner: Option<LinkConditionerConfig>,
     ) -> Self {
         Self {
+            role,
             main_run_criteria: Mutex::new(Some(main_run_criteria)),
             input_stage: Mutex::new(Some(input_stage)),
             broadcast_updates_stage: Mutex::new(Some(broadcast_updates_stage)),
@@ -132,15 +149,34 @@ impl<S: System<In = (), Out = ShouldRun>> Plugin for MuddleSharedPlugin<S> {
             .lock()
             .expect("Can't initialize the plugin more than once");
 
+        let mut spawn_stage = SystemStage::single_threaded().with_system(despawn_players.system());
+        if let SimulationRole::Server = self.role {
+            spawn_stage.add_system(respawn_players.system());
+        }
+        spawn_stage = spawn_stage
+            .with_system(spawn_players.system())
+            .with_system(despawn_level_objects.system())
+            .with_system(spawn_level_objects.system())
+            .with_system(update_level_objects.system());
+
+        let mut post_physics_stage = SystemStage::parallel()
+            .with_system(physics::destroy_body_and_collider_system.system())
+            .with_system(
+                physics::sync_transform_system
+                    .system()
+                    .label("sync_transform"),
+            )
+            .with_system(sync_position.system().after("sync_transform"));
+        // Race results and deaths are authoritative, clients receive them via network updates.
+        if let SimulationRole::Server = self.role {
+            post_physics_stage
+                .add_system(game::race::detect_route_sensor_crossings.system())
+                .add_system(detect_death_zone_touches.system());
+        }
+
         let simulation_schedule = Schedule::default()
             .with_run_criteria(SimulationTickRunCriteria::default())
-            .with_stage(
-                stage::SPAWN,
-                SystemStage::single_threaded()
-                    .with_system(despawn_players.system())
-                    .with_system(spawn_players.system())
-                    .with_system(spawn_level_objects.system()),
-            )
+            .with_stage(stage::SPAWN, spawn_stage)
             .with_stage(
                 stage::PRE_GAME,
                 SystemStage::parallel()
@@ -149,23 +185,15 @@ impl<S: System<In = (), Out = ShouldRun>> Plugin for MuddleSharedPlugin<S> {
             )
             .with_stage(
                 stage::GAME,
-                SystemStage::parallel().with_system(player_movement.system()),
+                SystemStage::parallel()
+                    .with_system(move_platforms.system())
+                    .with_system(player_movement.system()),
             )
             .with_stage(
                 stage::PHYSICS,
                 SystemStage::parallel().with_system(physics::step_world_system.system()),
             )
-            .with_stage(
-                stage::POST_PHYSICS,
-                SystemStage::parallel()
-                    .with_system(physics::destroy_body_and_collider_system.system())
-                    .with_system(
-                        physics::sync_transform_system
-                            .system()
-                            .label("sync_transform"),
-                    )
-                    .with_system(sync_position.system().after("sync_transform")),
-            )
+            .with_stage(stage::POST_PHYSICS, post_physics_stage)
             .with_stage(
                 stage::POST_GAME,
                 SystemStage::parallel().with_system(tick_simulation_frame.system()),
@@ -229,14 +257,19 @@ impl<S: System<In = (), Out = ShouldRun>> Plugin for MuddleSharedPlugin<S> {
         builder.add_startup_system(network_setup.system());
 
         let resources = builder.world_mut();
+        resources.insert_resource(self.role);
         resources.get_resource_or_insert_with(GameTime::default);
         resources.get_resource_or_insert_with(SimulationTime::default);
         resources.get_resource_or_insert_with(LevelState::default);
         resources.get_resource_or_insert_with(PlayerUpdates::default);
+        resources.get_resource_or_insert_with(RaceState::default);
+        resources.get_resource_or_insert_with(RespawnState::default);
+        resources.get_resource_or_insert_with(RespawnDelay::default);
         resources.get_resource_or_insert_with(GameCommands::<RestartGame>::default);
         resources.get_resource_or_insert_with(GameCommands::<SpawnPlayer>::default);
         resources.get_resource_or_insert_with(GameCommands::<DespawnPlayer>::default);
         resources.get_resource_or_insert_with(GameCommands::<SpawnLevelObject>::default);
+        resources.get_resource_or_insert_with(GameCommands::<UpdateLevelObject>::default);
         resources.get_resource_or_insert_with(GameCommands::<DespawnLevelObject>::default);
         resources.get_resource_or_insert_with(EntityRegistry::<PlayerNetId>::default);
         resources.get_resource_or_insert_with(EntityRegistry::<EntityNetId>::default);
@@ -252,7 +285,7 @@ impl Plugin for RapierResourcesPlugin {
             .insert_resource(PhysicsPipeline::new())
             .insert_resource(QueryPipeline::new())
             .insert_resource(RapierConfiguration {
-                gravity: Vector::new(0.0, 0.0, 0.0),
+                gravity: Vector::new(0.0, GRAVITY, 0.0),
                 ..RapierConfiguration::default()
             })
             .insert_resource(IntegrationParameters::default())
@@ -271,6 +304,21 @@ impl Plugin for RapierResourcesPlugin {
     }
 }
 
+/// Clients predict the local player ahead of the server (see `SimulationTime`), while only
+/// the server decides on respawns, deaths and race results. Is inserted as a resource by
+/// `MuddleSharedPlugin`, which lets servers and clients share a process (i.e. in tests).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SimulationRole {
+    Server,
+    Client,
+}
+
+impl SimulationRole {
+    pub fn is_client(self) -> bool {
+        matches!(self, Self::Client)
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum GameState {
     Paused,
@@ -304,11 +352,7 @@ impl SimulationTime {
     }
 
     pub fn rewind(&mut self, frame_number: FrameNumber) {
-        if cfg!(not(feature = "client")) {
-            assert_eq!(self.player_frame, self.server_frame);
-        } else {
-            assert!(self.player_frame >= self.server_frame);
-        }
+        assert!(self.player_frame >= self.server_frame);
         let frames_ahead = self.player_frame - self.server_frame;
         self.server_frame = std::cmp::min(self.server_frame, frame_number);
         self.player_frame = self.server_frame + frames_ahead;
@@ -0,0 +1,295 @@
+use crate::{
+    messages::{Message, UnreliableClientMessage, UnreliableServerMessage},
+    transport::{NetworkMessage, Transport},
+};
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    any::{Any, TypeId},
+    str::FromStr,
+};
+
+/// Reordered messages are held back for this long on top of the usual delay, so that the messages
+/// received after them can overtake them.
+const REORDERING_DELAY_MILLIS: u32 = 30;
+
+/// Simulates bad network conditions for incoming messages. Losses, duplicates and reordering
+/// affect only unreliable channels, as reliable ones would recover from them anyway (latency and
+/// jitter still apply).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditionerSettings {
+    pub latency_millis: u32,
+    pub jitter_millis: u32,
+    /// From 0.0 to 1.0.
+    pub loss_rate: f32,
+    /// From 0.0 to 1.0.
+    pub duplication_rate: f32,
+    /// From 0.0 to 1.0.
+    pub reordering_rate: f32,
+}
+
+impl LinkConditionerSettings {
+    /// Reads the `MUDDLE_LINK_LATENCY_MS`, `MUDDLE_LINK_JITTER_MS`, `MUDDLE_LINK_LOSS`,
+    /// `MUDDLE_LINK_DUPLICATION` and `MUDDLE_LINK_REORDERING` env variables (rates are expected
+    /// to be in the range from 0.0 to 1.0).
+    pub fn from_env() -> Self {
+        Self {
+            latency_millis: env_var(
+                "MUDDLE_LINK_LATENCY_MS",
+                std::option_env!("MUDDLE_LINK_LATENCY_MS"),
+            )
+            .unwrap_or_default(),
+            jitter_millis: env_var(
+                "MUDDLE_LINK_JITTER_MS",
+                std::option_env!("MUDDLE_LINK_JITTER_MS"),
+            )
+            .unwrap_or_default(),
+            loss_rate: env_var("MUDDLE_LINK_LOSS", std::option_env!("MUDDLE_LINK_LOSS"))
+                .unwrap_or_default(),
+            duplication_rate: env_var(
+                "MUDDLE_LINK_DUPLICATION",
+                std::option_env!("MUDDLE_LINK_DUPLICATION"),
+            )
+            .unwrap_or_default(),
+            reordering_rate: env_var(
+                "MUDDLE_LINK_REORDERING",
+                std::option_env!("MUDDLE_LINK_REORDERING"),
+            )
+            .unwrap_or_default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+struct PendingMessage {
+    handle: u32,
+    type_id: TypeId,
+    deliver_at: DateTime<Utc>,
+    message: Box<dyn Any + Send + Sync>,
+}
+
+/// Sits between a transport and the code that reads messages from it, holding back messages
+/// according to `settings`, which can be changed at any moment.
+pub struct LinkConditioner {
+    pub settings: LinkConditionerSettings,
+    pending: Vec<PendingMessage>,
+    rng_state: u64,
+}
+
+impl Default for LinkConditioner {
+    fn default() -> Self {
+        Self::new(LinkConditionerSettings::default())
+    }
+}
+
+impl LinkConditioner {
+    pub fn new(settings: LinkConditionerSettings) -> Self {
+        Self {
+            settings,
+            pending: Vec::new(),
+            // Xorshift can't be seeded with 0.
+            rng_state: Utc::now().timestamp_nanos() as u64 | 1,
+        }
+    }
+
+    pub fn recv_message<M: NetworkMessage, T: Transport>(
+        &mut self,
+        net: &mut T,
+        handle: u32,
+    ) -> Option<M> {
+        let type_id = TypeId::of::<M>();
+        let has_pending = self
+            .pending
+            .iter()
+            .any(|pending| pending.handle == handle && pending.type_id == type_id);
+        if !self.settings.is_enabled() && !has_pending {
+            return net.recv_message(handle);
+        }
+
+        let now = Utc::now();
+        let is_unreliable = type_id == TypeId::of::<Message<UnreliableClientMessage>>()
+            || type_id == TypeId::of::<Message<UnreliableServerMessage>>();
+        while let Some(message) = net.recv_message::<M>(handle) {
+            if is_unreliable && self.roll(self.settings.loss_rate) {
+                continue;
+            }
+            let copies = if is_unreliable && self.roll(self.settings.duplication_rate) {
+                2
+            } else {
+                1
+            };
+            for _ in 0..copies {
+                let mut delay_millis =
+                    self.settings.latency_millis + self.random(self.settings.jitter_millis + 1);
+                if is_unreliable && self.roll(self.settings.reordering_rate) {
+                    delay_millis += self.settings.jitter_millis + REORDERING_DELAY_MILLIS;
+                }
+                let mut deliver_at = now + Duration::milliseconds(delay_millis as i64);
+                if !is_unreliable {
+                    // Reliable channels are ordered, jitter mustn't break that.
+                    if let Some(last) = self
+                        .pending
+                        .iter()
+                        .filter(|pending| pending.handle == handle && pending.type_id == type_id)
+                        .map(|pending| pending.deliver_at)
+                        .max()
+                    {
+                        deliver_at = std::cmp::max(deliver_at, last);
+                    }
+                }
+                self.pending.push(PendingMessage {
+                    handle,
+                    type_id,
+                    deliver_at,
+                    message: Box::new(message.clone()),
+                });
+            }
+        }
+
+        // `min_by_key` returns the first of equal elements, which keeps the order of messages
+        // that are delivered at the same time.
+        let index = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, pending)| {
+                pending.handle == handle && pending.type_id == type_id && pending.deliver_at <= now
+            })
+            .min_by_key(|(_, pending)| pending.deliver_at)
+            .map(|(index, _)| index)?;
+        self.pending
+            .remove(index)
+            .message
+            .downcast::<M>()
+            .ok()
+            .map(|message| *message)
+    }
+
+    /// Drops the messages that are still pending for a connection.
+    pub fn remove_connection(&mut self, handle: u32) {
+        self.pending.retain(|pending| pending.handle != handle);
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    fn roll(&mut self, rate: f32) -> bool {
+        rate > 0.0 && (self.random(u32::MAX) as f64 / u32::MAX as f64) < rate as f64
+    }
+
+    /// Returns a number in the range of [0, max).
+    fn random(&mut self, max: u32) -> u32 {
+        if max == 0 {
+            return 0;
+        }
+        // Xorshift64, we don't need anything fancier (or any new dependencies) here.
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        (self.rng_state % max as u64) as u32
+    }
+}
+
+fn env_var<T: FromStr>(name: &str, compile_time_value: Option<&'static str>) -> Option<T>
+where
+    T::Err: std::fmt::Debug,
+{
+    std::env::var(name)
+        .ok()
+        .or_else(|| compile_time_value.map(str::to_owned))
+        .map(|value| {
+            value
+                .parse::<T>()
+                .unwrap_or_else(|err| panic!("Invalid {} value: {:?}", name, err))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        link_conditioner::{LinkConditioner, LinkConditionerSettings},
+        messages::{Message, ReliableClientMessage, UnreliableServerMessage},
+        net::{MessageId, SessionId},
+        transport::{LoopbackHub, Transport},
+    };
+
+    #[test]
+    fn test_link_conditioner_losses_affect_only_unreliable_messages() {
+        let hub = LoopbackHub::default();
+        let mut server = hub.server();
+        let mut client = hub.client();
+        let client_handle = client.connect();
+        let server_handle = server.connection_handles()[0];
+
+        let mut link_conditioner = LinkConditioner::new(LinkConditionerSettings {
+            loss_rate: 1.0,
+            ..Default::default()
+        });
+        for i in 0..4 {
+            let session_id = SessionId::new(i);
+            server
+                .send_message(
+                    server_handle,
+                    Message {
+                        session_id,
+                        message: UnreliableServerMessage::Handshake(MessageId::new(i)),
+                    },
+                )
+                .unwrap();
+            client
+                .send_message(
+                    client_handle,
+                    Message {
+                        session_id,
+                        message: ReliableClientMessage::UndoLevelEdit,
+                    },
+                )
+                .unwrap();
+        }
+
+        assert!(link_conditioner
+            .recv_message::<Message<UnreliableServerMessage>, _>(&mut client, client_handle)
+            .is_none());
+        for i in 0..4 {
+            let message = link_conditioner
+                .recv_message::<Message<ReliableClientMessage>, _>(&mut server, server_handle)
+                .unwrap();
+            assert_eq!(message.session_id, SessionId::new(i));
+        }
+    }
+
+    #[test]
+    fn test_link_conditioner_duplicates() {
+        let hub = LoopbackHub::default();
+        let mut server = hub.server();
+        let mut client = hub.client();
+        let client_handle = client.connect();
+        let server_handle = server.connection_handles()[0];
+
+        let mut link_conditioner = LinkConditioner::new(LinkConditionerSettings {
+            duplication_rate: 1.0,
+            ..Default::default()
+        });
+        server
+            .send_message(
+                server_handle,
+                Message {
+                    session_id: SessionId::new(0),
+                    message: UnreliableServerMessage::Handshake(MessageId::new(0)),
+                },
+            )
+            .unwrap();
+
+        let mut received = 0;
+        while link_conditioner
+            .recv_message::<Message<UnreliableServerMessage>, _>(&mut client, client_handle)
+            .is_some()
+        {
+            received += 1;
+        }
+        assert_eq!(received, 2);
+    }
+}
@@ -1,10 +1,13 @@
 use crate::{
     framebuffer::FrameNumber,
-    game::commands::{DespawnLevelObject, SpawnLevelObject},
+    game::{
+        commands::{DespawnLevelObject, SpawnLevelObject, UpdateLevelObject},
+        level::{LevelObject, LevelObjectDesc},
+    },
     net::{MessageId, SessionId},
     registry::IncrementId,
 };
-use bevy::math::Vec2;
+use bevy::math::{Vec2, Vec3};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Default, Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -40,6 +43,11 @@ impl IncrementId for ActionNetId {
     }
 }
 
+/// Is issued by the server with `StartGame`, lets clients resume their sessions after
+/// reconnecting.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct SessionToken(pub u64);
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Message<T> {
     pub session_id: SessionId,
@@ -48,8 +56,19 @@ pub struct Message<T> {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum UnreliableClientMessage {
-    Connect(MessageId),
-    PlayerUpdate(PlayerUpdate),
+    /// Is expected to stay the first variant, so that servers of any version could decode it and
+    /// reject incompatible clients.
+    Connect(ConnectRequest),
+    PlayerUpdate(PackedPlayerUpdate),
+}
+
+/// Fields that are needed for protocol version negotiation go first, as they are expected to be
+/// readable by servers of any version.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectRequest {
+    pub protocol_version: u32,
+    pub schema_hash: u64,
+    pub handshake_id: MessageId,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -57,7 +76,45 @@ pub enum ReliableClientMessage {
     /// A kludge message basically, to let our networking stack to initialize properly for webrtc.
     Initialize,
     /// Is sent as a response to server's `UnreliableServerMessage::Handshake`.
-    Handshake(MessageId),
+    Handshake(ClientHandshake),
+    /// Asks the server to persist the current level to its level file.
+    SaveLevel,
+    SpawnLevelObject(SpawnLevelObjectRequest),
+    UpdateLevelObject(UpdateLevelObjectRequest),
+    DespawnLevelObject(DespawnLevelObjectRequest),
+    /// Reverts the latest level edit of the player (if it's still possible).
+    UndoLevelEdit,
+    RedoLevelEdit,
+    /// Asks the server to send `FullState`, if a client can't apply delta updates anymore
+    /// (or has desynced).
+    RequestFullState,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClientHandshake {
+    pub handshake_id: MessageId,
+    /// A token of the previous session, if a client wants to resume it.
+    pub session_token: Option<SessionToken>,
+}
+
+/// Builder mode requests are confirmed (or rejected) by the server with `ConfirmedAction`
+/// messages, which are correlated by `action_id`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SpawnLevelObjectRequest {
+    pub action_id: ActionNetId,
+    pub desc: LevelObjectDesc,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UpdateLevelObjectRequest {
+    pub action_id: ActionNetId,
+    pub object: LevelObject,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DespawnLevelObjectRequest {
+    pub action_id: ActionNetId,
+    pub net_id: EntityNetId,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -66,11 +123,55 @@ pub enum ReliableServerMessage {
     Initialize,
     /// Is sent as a response to client's `ReliableClientMessage::Handshake`.
     StartGame(StartGame),
+    /// Is sent as a response to client's `ReliableClientMessage::RequestFullState`. Starts
+    /// a new session id, so that the messages that were sent before it get ignored.
+    FullState(FullState),
     ConnectedPlayer(ConnectedPlayer),
     DisconnectedPlayer(DisconnectedPlayer),
     SpawnLevelObject(SpawnLevelObject),
+    UpdateLevelObject(UpdateLevelObject),
     DespawnLevelObject(DespawnLevelObject),
-    Disconnect,
+    RaceStarted(RaceStarted),
+    RaceLap(RaceLap),
+    RaceFinished(RaceFinished),
+    Disconnect(DisconnectReason),
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The underlying connection got closed (by a client or the networking stack).
+    ConnectionClosed,
+    /// A client sent acknowledgments or updates that the server can't make sense of.
+    InvalidUpdate,
+    /// A client is lagging or falling behind (or is too far ahead) the server.
+    Lagging,
+    /// A client didn't finish the handshake in time.
+    HandshakeTimeout,
+    /// A client hasn't sent any messages for `CONNECTION_TIMEOUT_MILLIS`.
+    Idle,
+    /// A client kept exceeding the server's rate limits or sending invalid inputs.
+    TooManyViolations,
+    Kicked,
+    ServerShutdown,
+    VersionMismatch(RejectionReason),
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::ConnectionClosed => f.write_str("The connection was closed"),
+            DisconnectReason::InvalidUpdate => f.write_str("The client sent an invalid update"),
+            DisconnectReason::Lagging => f.write_str("The client is lagging too much"),
+            DisconnectReason::HandshakeTimeout => f.write_str("The handshake timed out"),
+            DisconnectReason::Idle => f.write_str("The client was idle for too long"),
+            DisconnectReason::TooManyViolations => {
+                f.write_str("The client sent too many invalid messages")
+            }
+            DisconnectReason::Kicked => f.write_str("Kicked by the server"),
+            DisconnectReason::ServerShutdown => f.write_str("The server is shutting down"),
+            DisconnectReason::VersionMismatch(reason) => reason.fmt(f),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -84,7 +185,63 @@ pub struct PlayerUpdate {
 pub enum UnreliableServerMessage {
     /// Is sent as a response to client's `UnreliableClientMessage::Connect`.
     Handshake(MessageId),
-    DeltaUpdate(DeltaUpdate),
+    /// Is sent as a response to client's `UnreliableClientMessage::Connect` if the client is
+    /// incompatible with the server. Is expected to stay the second variant, so that clients of
+    /// any version could decode it.
+    ConnectionRejected(ConnectionRejected),
+    DeltaUpdate(PackedDeltaUpdate),
+}
+
+/// A `DeltaUpdate` encoded with `codec::encode_delta_update`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PackedDeltaUpdate(pub Vec<u8>);
+
+/// A `PlayerUpdate` encoded with `codec::encode_player_update`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PackedPlayerUpdate(pub Vec<u8>);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectionRejected {
+    pub reason: RejectionReason,
+    pub server_protocol_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum RejectionReason {
+    ServerIsNewer,
+    ServerIsOlder,
+    /// Protocol versions match, but the message types differ (someone forgot to bump the version).
+    SchemaMismatch,
+}
+
+impl RejectionReason {
+    /// Returns `None` if a client is compatible with the server.
+    pub fn check(
+        server_protocol_version: u32,
+        server_schema_hash: u64,
+        connect_request: &ConnectRequest,
+    ) -> Option<Self> {
+        if server_protocol_version > connect_request.protocol_version {
+            Some(RejectionReason::ServerIsNewer)
+        } else if server_protocol_version < connect_request.protocol_version {
+            Some(RejectionReason::ServerIsOlder)
+        } else if server_schema_hash != connect_request.schema_hash {
+            Some(RejectionReason::SchemaMismatch)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            RejectionReason::ServerIsNewer => "The server is newer, please update the client",
+            RejectionReason::ServerIsOlder => "The server is older than the client",
+            RejectionReason::SchemaMismatch => "The server is incompatible with the client",
+        };
+        f.write_str(description)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -93,6 +250,16 @@ pub struct StartGame {
     pub handshake_id: MessageId,
     pub net_id: PlayerNetId,
     pub nickname: String,
+    pub session_token: SessionToken,
+    pub objects: Vec<SpawnLevelObject>,
+    pub players: Vec<ConnectedPlayer>,
+    /// Full game state encoded as a DeltaUpdate.
+    pub game_state: DeltaUpdate,
+}
+
+/// Contains the same state as `StartGame`, but lets a client resync without restarting the game.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FullState {
     pub objects: Vec<SpawnLevelObject>,
     pub players: Vec<ConnectedPlayer>,
     /// Full game state encoded as a DeltaUpdate.
@@ -110,26 +277,80 @@ pub struct DisconnectedPlayer {
     pub net_id: PlayerNetId,
 }
 
+/// Race times are measured in simulation frames (see `SIMULATIONS_PER_SECOND`).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RaceStarted {
+    pub player_net_id: PlayerNetId,
+    pub frame_number: FrameNumber,
+}
+
+/// A lap is a part of the route between two consecutive route sensors crossed by a player.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RaceLap {
+    pub player_net_id: PlayerNetId,
+    pub checkpoint_net_id: EntityNetId,
+    pub frame_number: FrameNumber,
+    pub lap_time: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RaceFinished {
+    pub player_net_id: PlayerNetId,
+    pub frame_number: FrameNumber,
+    pub lap_time: u32,
+    pub finish_time: u32,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct DeltaUpdate {
     pub frame_number: FrameNumber,
     pub acknowledgments: (Option<FrameNumber>, u64),
     pub players: Vec<PlayerState>,
     pub confirmed_actions: Vec<ConfirmedAction>,
+    /// Is sent periodically, not with every update.
+    pub checksum: Option<PositionsChecksum>,
+}
+
+/// Lets clients detect whether their simulation has diverged from the server's one. Contains
+/// coarsely quantized (see `codec::position_checksum`) authoritative player positions for a frame
+/// that is old enough for the server not to rewind it anymore.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PositionsChecksum {
+    pub frame_number: FrameNumber,
+    pub players: Vec<(PlayerNetId, [i32; 3])>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlayerState {
     pub net_id: PlayerNetId,
+    /// Dead players are sent regardless of their relevance, so that clients despawn them. Their
+    /// states don't carry positions or inputs.
+    pub is_spawned: bool,
     /// Contains the initial position, so that applying all inputs renders a player in its actual position on server.
-    pub position: Vec2,
+    pub position: Vec3,
+    /// Linear velocity of a player at the frame of `position`.
+    pub velocity: Vec3,
     pub inputs: Vec<PlayerInput>,
 }
 
+impl PlayerState {
+    pub fn dead(net_id: PlayerNetId) -> Self {
+        Self {
+            net_id,
+            is_spawned: false,
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+            inputs: Vec::new(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlayerInput {
     pub frame_number: FrameNumber,
     pub direction: Vec2,
+    /// Is set only for the frame at which a jump was initiated.
+    pub jump: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -140,3 +361,36 @@ pub struct ConfirmedAction {
     /// If the value is set to `None`, the action was discarded by the server.
     pub confirmed_frame: Option<FrameNumber>,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        messages::{ConnectRequest, RejectionReason},
+        net::MessageId,
+    };
+
+    fn connect_request(protocol_version: u32, schema_hash: u64) -> ConnectRequest {
+        ConnectRequest {
+            protocol_version,
+            schema_hash,
+            handshake_id: MessageId::new(0),
+        }
+    }
+
+    #[test]
+    fn test_rejection_reason() {
+        assert_eq!(RejectionReason::check(2, 42, &connect_request(2, 42)), None);
+        assert_eq!(
+            RejectionReason::check(3, 42, &connect_request(2, 42)),
+            Some(RejectionReason::ServerIsNewer)
+        );
+        assert_eq!(
+            RejectionReason::check(1, 42, &connect_request(2, 0)),
+            Some(RejectionReason::ServerIsOlder)
+        );
+        assert_eq!(
+            RejectionReason::check(2, 42, &connect_request(2, 0)),
+            Some(RejectionReason::SchemaMismatch)
+        );
+    }
+}
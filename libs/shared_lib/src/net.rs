@@ -1,8 +1,8 @@
 use crate::{
     framebuffer::FrameNumber,
     messages::{
-        Message, ReliableClientMessage, ReliableServerMessage, UnreliableClientMessage,
-        UnreliableServerMessage,
+        DisconnectReason, Message, ReliableClientMessage, ReliableServerMessage,
+        UnreliableClientMessage, UnreliableServerMessage,
     },
     wrapped_counter::WrappedCounter,
     TICKS_PER_NETWORK_BROADCAST,
@@ -16,6 +16,9 @@ use chrono::{DateTime, Duration, Utc};
 use std::collections::VecDeque;
 use thiserror::Error;
 
+/// Is expected to be bumped on every incompatible change of the protocol (including changes of
+/// the handshake logic that aren't reflected in the message types).
+pub const PROTOCOL_VERSION: u32 = 2;
 pub const CONNECTION_TIMEOUT_MILLIS: u64 = 2000;
 const RTT_UPDATE_FACTOR: f32 = 0.2;
 const JITTER_DECREASE_THRESHOLD_SECS: u64 = 1;
@@ -23,6 +26,14 @@ const JITTER_DECREASE_THRESHOLD_SECS: u64 = 1;
 pub type MessageId = WrappedCounter<u16>;
 pub type SessionId = WrappedCounter<u16>;
 
+/// A hash of the sources that define the message types, calculated by the build script.
+/// Catches the schema changes that someone forgot to bump `PROTOCOL_VERSION` for.
+pub fn schema_hash() -> u64 {
+    env!("MUDDLE_SCHEMA_HASH")
+        .parse()
+        .expect("Expected the schema hash to be a number")
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ConnectionStatus {
     Uninitialized,
@@ -53,6 +64,11 @@ pub enum AcknowledgeError {
 pub enum AddOutgoingPacketError {
     #[error("adding a new outgoing packet would pop an unacknowledged one")]
     WouldLoseUnacknowledged,
+    #[error("inconsistent packet step (latest: {latest}, new: {new})")]
+    InconsistentStep {
+        latest: FrameNumber,
+        new: FrameNumber,
+    },
 }
 
 // Note: We don't expect clients or server to re-send lost packets. If we detect packet loss,
@@ -63,6 +79,7 @@ pub struct ConnectionState {
     pub last_message_received_at: DateTime<Utc>,
     status: ConnectionStatus,
     status_updated_at: DateTime<Utc>,
+    disconnect_reason: Option<DisconnectReason>,
     newest_acknowledged_incoming_packet: Option<FrameNumber>,
     // Packets that are incoming to us (not to a peer on the other side of a connection).
     // We acknowledge these packets on receiving an unreliable message and send send the acks later.
@@ -84,6 +101,7 @@ impl Default for ConnectionState {
             last_message_received_at: Utc::now(),
             status: ConnectionStatus::Uninitialized,
             status_updated_at: Utc::now(),
+            disconnect_reason: None,
             newest_acknowledged_incoming_packet: None,
             incoming_packets_acks: u64::MAX - 1,
             outgoing_packets_acks: VecDeque::new(),
@@ -149,39 +167,81 @@ impl ConnectionState {
             .map(|ack| ack.frame_number)
     }
 
+    pub fn newest_acknowledged_outgoing_packet(&self) -> Option<FrameNumber> {
+        self.outgoing_packets_acks
+            .iter()
+            .rfind(|ack| ack.acknowledged)
+            .map(|ack| ack.frame_number)
+    }
+
     pub fn set_status(&mut self, status: ConnectionStatus) {
         let session_id = self.session_id;
         let handshake_id = self.handshake_id;
+        // The reason is expected to outlive the `Disconnecting` status, so that it's still
+        // available when we finish the clean-up.
+        let disconnect_reason = if matches!(status, ConnectionStatus::Disconnected) {
+            self.disconnect_reason
+        } else {
+            None
+        };
 
         *self = Self::default();
         self.status = status;
         self.status_updated_at = Utc::now();
         self.session_id = session_id;
         self.handshake_id = handshake_id;
+        self.disconnect_reason = disconnect_reason;
     }
 
-    pub fn add_outgoing_packet(&mut self, frame_number: FrameNumber, sent: DateTime<Utc>) {
-        if self.outgoing_packets_acks.len() == 64 {
-            self.outgoing_packets_acks.pop_front();
-        }
+    /// Switches the status to `Disconnecting`, remembering the reason.
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        self.set_status(ConnectionStatus::Disconnecting);
+        self.disconnect_reason = Some(reason);
+    }
+
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason
+    }
+
+    /// Forgets about sent and received packets, but keeps the connection stats (such as rtt).
+    /// Is expected to be called when frame numbers of a connection jump (see `FullState`).
+    pub fn reset_packets(&mut self) {
+        let Self {
+            newest_acknowledged_incoming_packet,
+            incoming_packets_acks,
+            outgoing_packets_acks,
+            ..
+        } = Self::default();
+        self.newest_acknowledged_incoming_packet = newest_acknowledged_incoming_packet;
+        self.incoming_packets_acks = incoming_packets_acks;
+        self.outgoing_packets_acks = outgoing_packets_acks;
+    }
+
+    pub fn add_outgoing_packet(
+        &mut self,
+        frame_number: FrameNumber,
+        sent: DateTime<Utc>,
+    ) -> Result<(), AddOutgoingPacketError> {
         if let Some(prev_packet) = self.outgoing_packets_acks.back() {
             if prev_packet.frame_number + FrameNumber::new(TICKS_PER_NETWORK_BROADCAST)
                 != frame_number
             {
-                // TODO: don't panic. Clients might be able to DoS?
-                panic!(
-                    "Inconsistent packet step (latest: {}, new: {})",
-                    prev_packet.frame_number.value(),
-                    frame_number.value()
-                );
+                return Err(AddOutgoingPacketError::InconsistentStep {
+                    latest: prev_packet.frame_number,
+                    new: frame_number,
+                });
             }
         }
+        if self.outgoing_packets_acks.len() == 64 {
+            self.outgoing_packets_acks.pop_front();
+        }
         self.outgoing_packets_acks.push_back(Acknowledgment {
             frame_number,
             acknowledged: false,
             acknowledged_at: None,
             sent_at: sent,
         });
+        Ok(())
     }
 
     pub fn acknowledge_incoming(
@@ -221,8 +281,12 @@ impl ConnectionState {
             None => (0, 0),
         };
 
-        // TODO: debug the rare `attempt to shift left with overflow` panic.
-        self.incoming_packets_acks = self.incoming_packets_acks << shift_lhs | 1 << shift_rhs;
+        // A peer can send a frame that is 64 packets ahead, which shifts out all the history.
+        self.incoming_packets_acks = self
+            .incoming_packets_acks
+            .checked_shl(shift_lhs as u32)
+            .unwrap_or(0)
+            | 1 << shift_rhs;
         if newest_acknowledged < frame_number {
             self.newest_acknowledged_incoming_packet = Some(frame_number);
         }
@@ -292,9 +356,9 @@ impl ConnectionState {
             acknowledgment_bit_set <<= 1;
         }
 
-        assert!(frames_to_set > 0);
+        // `frames_to_set` can't be 0, as `skip` is the position of an existing packet.
         let ack = &mut self.outgoing_packets_acks[frames_to_set - 1];
-        assert_eq!(ack.frame_number, frame_number);
+        debug_assert_eq!(ack.frame_number, frame_number);
         ack.acknowledged_at = Some(now);
 
         self.update_stats(frame_number);
@@ -478,7 +542,11 @@ const SERVER_DELTA_UPDATE_MESSAGE_SETTINGS: MessageChannelSettings = MessageChan
 mod tests {
     use crate::{
         framebuffer::FrameNumber,
-        net::{Acknowledgment, ConnectionState, ConnectionStatus, MessageId, SessionId},
+        messages::DisconnectReason,
+        net::{
+            Acknowledgment, AddOutgoingPacketError, ConnectionState, ConnectionStatus, MessageId,
+            SessionId,
+        },
         TICKS_PER_NETWORK_BROADCAST,
     };
     use chrono::Utc;
@@ -518,6 +586,7 @@ mod tests {
             last_message_received_at: Utc::now(),
             status: ConnectionStatus::Uninitialized,
             status_updated_at: Utc::now(),
+            disconnect_reason: None,
             newest_acknowledged_incoming_packet: None,
             incoming_packets_acks: 0,
             outgoing_packets_acks: VecDeque::from(acknowledgments),
@@ -589,6 +658,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_newest_acknowledged_outgoing_packet() {
+        let connection_state = ConnectionState::default();
+        assert_eq!(connection_state.newest_acknowledged_outgoing_packet(), None);
+
+        let connection_state = init_connection_state(Some(vec![true, false, true, false]));
+        assert_eq!(
+            connection_state.newest_acknowledged_outgoing_packet(),
+            Some(FrameNumber::new(2))
+        );
+    }
+
     #[test]
     fn test_incoming_acknowledgment_with_overflow() {
         let mut connection_state = ConnectionState::default();
@@ -671,4 +752,82 @@ mod tests {
             0b1111111111111111000000000000000000000000000000000000000000000001,
         );
     }
+
+    #[test]
+    fn test_incoming_acknowledgment_far_ahead() {
+        let mut connection_state = ConnectionState::default();
+        connection_state
+            .acknowledge_incoming(FrameNumber::new(0))
+            .unwrap();
+        connection_state
+            .acknowledge_incoming(FrameNumber::new(TICKS_PER_NETWORK_BROADCAST * 64))
+            .unwrap();
+        let (frame_number, acks) = connection_state.incoming_acknowledgments();
+        assert_eq!(
+            frame_number,
+            Some(FrameNumber::new(TICKS_PER_NETWORK_BROADCAST * 64))
+        );
+        assert_eq_bitset!(acks, 1);
+    }
+
+    #[test]
+    fn test_add_outgoing_packet_inconsistent_step() {
+        let mut connection_state = ConnectionState::default();
+        connection_state
+            .add_outgoing_packet(FrameNumber::new(0), Utc::now())
+            .unwrap();
+        assert!(matches!(
+            connection_state.add_outgoing_packet(FrameNumber::new(5), Utc::now()),
+            Err(AddOutgoingPacketError::InconsistentStep { .. })
+        ));
+        assert_eq!(connection_state.outgoing_packets_acks.len(), 1);
+        connection_state
+            .add_outgoing_packet(FrameNumber::new(TICKS_PER_NETWORK_BROADCAST), Utc::now())
+            .unwrap();
+        assert_eq!(connection_state.outgoing_packets_acks.len(), 2);
+    }
+
+    #[test]
+    fn test_reset_packets() {
+        let mut connection_state = ConnectionState::default();
+        connection_state.set_initial_rtt_millis(50.0);
+        connection_state
+            .add_outgoing_packet(FrameNumber::new(0), Utc::now())
+            .unwrap();
+        connection_state
+            .acknowledge_incoming(FrameNumber::new(0))
+            .unwrap();
+
+        connection_state.reset_packets();
+        assert_eq!(
+            connection_state.first_unacknowledged_outgoing_packet(),
+            None
+        );
+        assert_eq!(connection_state.incoming_acknowledgments().0, None);
+        assert_eq!(connection_state.rtt_millis(), 50.0);
+        // Frames with a different step are accepted after the reset.
+        connection_state
+            .add_outgoing_packet(FrameNumber::new(5), Utc::now())
+            .unwrap();
+        connection_state
+            .acknowledge_incoming(FrameNumber::new(5))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_disconnect_reason_outlives_disconnecting() {
+        let mut connection_state = ConnectionState::default();
+        connection_state.disconnect(DisconnectReason::Idle);
+        assert_eq!(
+            connection_state.disconnect_reason(),
+            Some(DisconnectReason::Idle)
+        );
+        connection_state.set_status(ConnectionStatus::Disconnected);
+        assert_eq!(
+            connection_state.disconnect_reason(),
+            Some(DisconnectReason::Idle)
+        );
+        connection_state.set_status(ConnectionStatus::Uninitialized);
+        assert_eq!(connection_state.disconnect_reason(), None);
+    }
 }
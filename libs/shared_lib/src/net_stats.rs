@@ -0,0 +1,313 @@
+use crate::{
+    messages::{
+        Message, ReliableClientMessage, ReliableServerMessage, UnreliableClientMessage,
+        UnreliableServerMessage,
+    },
+    transport::{NetworkMessage, Transport, TransportError},
+};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// Rates are sampled once per this window and then smoothed with `RATE_UPDATE_FACTOR`.
+pub const RATE_WINDOW_MILLIS: i64 = 1000;
+const RATE_UPDATE_FACTOR: f32 = 0.2;
+
+/// Names message variants for the traffic statistics.
+pub trait MessageVariant {
+    fn variant_name(&self) -> &'static str;
+}
+
+impl<T: MessageVariant> MessageVariant for Message<T> {
+    fn variant_name(&self) -> &'static str {
+        self.message.variant_name()
+    }
+}
+
+impl MessageVariant for UnreliableClientMessage {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            UnreliableClientMessage::Connect(_) => "UnreliableClientMessage::Connect",
+            UnreliableClientMessage::PlayerUpdate(_) => "UnreliableClientMessage::PlayerUpdate",
+        }
+    }
+}
+
+impl MessageVariant for ReliableClientMessage {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            ReliableClientMessage::Initialize => "ReliableClientMessage::Initialize",
+            ReliableClientMessage::Handshake(_) => "ReliableClientMessage::Handshake",
+            ReliableClientMessage::SaveLevel => "ReliableClientMessage::SaveLevel",
+            ReliableClientMessage::SpawnLevelObject(_) => "ReliableClientMessage::SpawnLevelObject",
+            ReliableClientMessage::UpdateLevelObject(_) => {
+                "ReliableClientMessage::UpdateLevelObject"
+            }
+            ReliableClientMessage::DespawnLevelObject(_) => {
+                "ReliableClientMessage::DespawnLevelObject"
+            }
+            ReliableClientMessage::UndoLevelEdit => "ReliableClientMessage::UndoLevelEdit",
+            ReliableClientMessage::RedoLevelEdit => "ReliableClientMessage::RedoLevelEdit",
+            ReliableClientMessage::RequestFullState => "ReliableClientMessage::RequestFullState",
+        }
+    }
+}
+
+impl MessageVariant for UnreliableServerMessage {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            UnreliableServerMessage::Handshake(_) => "UnreliableServerMessage::Handshake",
+            UnreliableServerMessage::ConnectionRejected(_) => {
+                "UnreliableServerMessage::ConnectionRejected"
+            }
+            UnreliableServerMessage::DeltaUpdate(_) => "UnreliableServerMessage::DeltaUpdate",
+        }
+    }
+}
+
+impl MessageVariant for ReliableServerMessage {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            ReliableServerMessage::Initialize => "ReliableServerMessage::Initialize",
+            ReliableServerMessage::StartGame(_) => "ReliableServerMessage::StartGame",
+            ReliableServerMessage::FullState(_) => "ReliableServerMessage::FullState",
+            ReliableServerMessage::ConnectedPlayer(_) => "ReliableServerMessage::ConnectedPlayer",
+            ReliableServerMessage::DisconnectedPlayer(_) => {
+                "ReliableServerMessage::DisconnectedPlayer"
+            }
+            ReliableServerMessage::SpawnLevelObject(_) => "ReliableServerMessage::SpawnLevelObject",
+            ReliableServerMessage::UpdateLevelObject(_) => {
+                "ReliableServerMessage::UpdateLevelObject"
+            }
+            ReliableServerMessage::DespawnLevelObject(_) => {
+                "ReliableServerMessage::DespawnLevelObject"
+            }
+            ReliableServerMessage::RaceStarted(_) => "ReliableServerMessage::RaceStarted",
+            ReliableServerMessage::RaceLap(_) => "ReliableServerMessage::RaceLap",
+            ReliableServerMessage::RaceFinished(_) => "ReliableServerMessage::RaceFinished",
+            ReliableServerMessage::Disconnect(_) => "ReliableServerMessage::Disconnect",
+        }
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub struct TrafficCounter {
+    pub messages: u64,
+    /// Sizes of serialized messages, transport overhead (headers, resends) isn't counted.
+    pub bytes: u64,
+}
+
+impl TrafficCounter {
+    fn add(&mut self, bytes: u64) {
+        self.messages += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct TrafficStats {
+    pub total: TrafficCounter,
+    pub by_message: HashMap<&'static str, TrafficCounter>,
+    kbps: f32,
+    messages_per_second: f32,
+    window: TrafficCounter,
+    window_started_at: Option<DateTime<Utc>>,
+    has_samples: bool,
+}
+
+impl TrafficStats {
+    pub fn add(&mut self, variant_name: &'static str, bytes: u64) {
+        self.total.add(bytes);
+        self.window.add(bytes);
+        self.by_message.entry(variant_name).or_default().add(bytes);
+    }
+
+    /// Closes the current window if it has lasted for `RATE_WINDOW_MILLIS`, and updates the
+    /// moving averages with its rates.
+    pub fn update(&mut self, now: DateTime<Utc>) {
+        let window_started_at = *self.window_started_at.get_or_insert(now);
+        let elapsed_millis = (now - window_started_at).num_milliseconds();
+        if elapsed_millis < RATE_WINDOW_MILLIS {
+            return;
+        }
+
+        // Bits per millisecond are kilobits per second.
+        let kbps = (self.window.bytes * 8) as f32 / elapsed_millis as f32;
+        let messages_per_second = self.window.messages as f32 * 1000.0 / elapsed_millis as f32;
+        if self.has_samples {
+            self.kbps += (kbps - self.kbps) * RATE_UPDATE_FACTOR;
+            self.messages_per_second +=
+                (messages_per_second - self.messages_per_second) * RATE_UPDATE_FACTOR;
+        } else {
+            self.kbps = kbps;
+            self.messages_per_second = messages_per_second;
+            self.has_samples = true;
+        }
+        self.window = TrafficCounter::default();
+        self.window_started_at = Some(now);
+    }
+
+    pub fn kbps(&self) -> f32 {
+        self.kbps
+    }
+
+    pub fn messages_per_second(&self) -> f32 {
+        self.messages_per_second
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ConnectionTraffic {
+    pub sent: TrafficStats,
+    pub received: TrafficStats,
+}
+
+/// Traffic statistics by connection handle. Messages are expected to be sent via
+/// `NetworkStats::send_message` and registered with `NetworkStats::add_received` once received,
+/// to get counted.
+#[derive(Default)]
+pub struct NetworkStats {
+    connections: HashMap<u32, ConnectionTraffic>,
+}
+
+impl NetworkStats {
+    pub fn send_message<T: Transport, M: NetworkMessage + MessageVariant>(
+        &mut self,
+        net: &mut T,
+        handle: u32,
+        message: M,
+    ) -> Result<(), TransportError> {
+        let variant_name = message.variant_name();
+        let bytes = message_size(&message);
+        net.send_message(handle, message)?;
+        self.connections
+            .entry(handle)
+            .or_default()
+            .sent
+            .add(variant_name, bytes);
+        Ok(())
+    }
+
+    pub fn add_received<M: NetworkMessage + MessageVariant>(&mut self, handle: u32, message: &M) {
+        self.connections
+            .entry(handle)
+            .or_default()
+            .received
+            .add(message.variant_name(), message_size(message));
+    }
+
+    pub fn update(&mut self, now: DateTime<Utc>) {
+        for traffic in self.connections.values_mut() {
+            traffic.sent.update(now);
+            traffic.received.update(now);
+        }
+    }
+
+    pub fn connection(&self, handle: u32) -> Option<&ConnectionTraffic> {
+        self.connections.get(&handle)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &ConnectionTraffic)> {
+        self.connections
+            .iter()
+            .map(|(handle, traffic)| (*handle, traffic))
+    }
+
+    pub fn remove_connection(&mut self, handle: u32) {
+        self.connections.remove(&handle);
+    }
+
+    pub fn clear(&mut self) {
+        self.connections.clear();
+    }
+}
+
+fn message_size<M: NetworkMessage>(message: &M) -> u64 {
+    bincode::serialized_size(message).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        messages::{Message, ReliableClientMessage, ReliableServerMessage},
+        net::SessionId,
+        net_stats::{NetworkStats, TrafficStats},
+        transport::LoopbackHub,
+    };
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_traffic_stats_rates() {
+        let now = Utc::now();
+        let mut stats = TrafficStats::default();
+        stats.update(now);
+
+        for _ in 0..10 {
+            stats.add("Message", 125);
+        }
+        stats.update(now + Duration::milliseconds(500));
+        assert_eq!(stats.kbps(), 0.0);
+
+        stats.update(now + Duration::milliseconds(1000));
+        assert_eq!(stats.kbps(), 10.0);
+        assert_eq!(stats.messages_per_second(), 10.0);
+
+        // An empty window lowers the average, but doesn't reset it.
+        stats.update(now + Duration::milliseconds(2000));
+        assert_eq!(stats.kbps(), 8.0);
+        assert_eq!(stats.messages_per_second(), 8.0);
+
+        assert_eq!(stats.total.messages, 10);
+        assert_eq!(stats.total.bytes, 1250);
+        assert_eq!(stats.by_message["Message"], stats.total);
+    }
+
+    #[test]
+    fn test_network_stats_count_messages() {
+        let hub = LoopbackHub::default();
+        let mut server = hub.server();
+        let mut client = hub.client();
+        let client_handle = client.connect();
+
+        let mut stats = NetworkStats::default();
+        for message in vec![
+            ReliableClientMessage::Initialize,
+            ReliableClientMessage::UndoLevelEdit,
+            ReliableClientMessage::UndoLevelEdit,
+        ] {
+            stats
+                .send_message(
+                    &mut client,
+                    client_handle,
+                    Message {
+                        session_id: SessionId::new(0),
+                        message,
+                    },
+                )
+                .unwrap();
+        }
+        // Messages that fail to be sent aren't counted.
+        assert!(stats
+            .send_message(
+                &mut server,
+                client_handle + 1,
+                Message {
+                    session_id: SessionId::new(0),
+                    message: ReliableServerMessage::Initialize,
+                },
+            )
+            .is_err());
+
+        let traffic = stats.connection(client_handle).unwrap();
+        assert_eq!(traffic.sent.total.messages, 3);
+        assert!(traffic.sent.total.bytes > 0);
+        assert_eq!(
+            traffic.sent.by_message["ReliableClientMessage::UndoLevelEdit"].messages,
+            2
+        );
+        assert_eq!(traffic.received.total.messages, 0);
+        assert!(stats.connection(client_handle + 1).is_none());
+
+        stats.remove_connection(client_handle);
+        assert!(stats.connection(client_handle).is_none());
+    }
+}
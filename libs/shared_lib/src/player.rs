@@ -2,22 +2,33 @@ use crate::{
     framebuffer::{FrameNumber, Framebuffer},
     messages::PlayerNetId,
 };
-use bevy::{log, math::Vec2};
+use bevy::{
+    log,
+    math::{Vec2, Vec3},
+};
 use std::collections::HashMap;
 
 #[derive(Debug, Default)]
 pub struct PlayerUpdates {
     pub direction: HashMap<PlayerNetId, Framebuffer<Option<PlayerDirectionUpdate>>>,
     /// Is supposed to be filled and used only by clients, as it contains authoritative updates.
-    pub position: HashMap<PlayerNetId, Framebuffer<Option<Vec2>>>,
+    pub position: HashMap<PlayerNetId, Framebuffer<Option<PositionUpdate>>>,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct PlayerDirectionUpdate {
     pub direction: Vec2,
+    /// Is set only for the frame at which a jump was initiated.
+    pub jump: bool,
     pub is_processed_client_input: Option<bool>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositionUpdate {
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
 impl PlayerUpdates {
     pub fn get_direction_mut(
         &mut self,
@@ -34,6 +45,7 @@ impl PlayerUpdates {
             let mut buffer = Framebuffer::new(frame_number, default_limit);
             buffer.push(Some(PlayerDirectionUpdate {
                 direction: Vec2::ZERO,
+                jump: false,
                 is_processed_client_input: None,
             }));
             buffer
@@ -45,7 +57,7 @@ impl PlayerUpdates {
         player_net_id: PlayerNetId,
         frame_number: FrameNumber,
         default_limit: u16,
-    ) -> &mut Framebuffer<Option<Vec2>> {
+    ) -> &mut Framebuffer<Option<PositionUpdate>> {
         self.position.entry(player_net_id).or_insert_with(|| {
             log::debug!(
                 "Create a new position buffer (client: {:?}, frame: {})",
@@ -53,7 +65,10 @@ impl PlayerUpdates {
                 frame_number
             );
             let mut buffer = Framebuffer::new(frame_number, default_limit);
-            buffer.push(Some(Vec2::ZERO));
+            buffer.push(Some(PositionUpdate {
+                position: Vec3::ZERO,
+                velocity: Vec3::ZERO,
+            }));
             buffer
         })
     }
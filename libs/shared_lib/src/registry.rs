@@ -16,6 +16,12 @@ impl<K: Copy + Hash + IncrementId + Eq, V: Copy + Hash + Eq> Registry<K, V> {
         net_id
     }
 
+    /// Registers a value with an id that was issued before (and then removed).
+    pub fn insert(&mut self, id: K, value: V) {
+        self.value_by_id.insert(id, value);
+        self.id_by_value.insert(value, id);
+    }
+
     pub fn remove_by_value(&mut self, value: V) -> Option<K> {
         if let Some(id) = self.id_by_value.remove(&value) {
             self.value_by_id.remove(&id);
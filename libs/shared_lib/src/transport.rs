@@ -0,0 +1,614 @@
+use bevy::{log, prelude::*};
+use bevy_networking_turbulence::{NetworkEvent, NetworkResource};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    any::TypeId,
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+pub trait NetworkMessage = Serialize + DeserializeOwned + Debug + Clone + Send + Sync + 'static;
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("connection {0} doesn't exist")]
+    UnknownConnection(u32),
+    #[error("channel buffer of connection {0} is full")]
+    ChannelFull(u32),
+    #[error("failed to send a message: {0}")]
+    Send(String),
+}
+
+/// Abstracts away the network layer, so that the game can be run either on top of real sockets
+/// (`NetworkResource`), or with all the apps being in a single process (`LoopbackTransport`).
+///
+/// Connection events are expected to be emitted as `NetworkEvent`s in both cases.
+pub trait Transport: Send + Sync + 'static {
+    /// Registers the systems that a transport needs (on top of `NetworkingPlugin`, which
+    /// `MuddleSharedPlugin` always adds).
+    fn build(builder: &mut AppBuilder);
+
+    /// Returns connection events that happened since the last call. Transports that emit events on
+    /// their own (i.e. `NetworkResource`, which is driven by `NetworkingPlugin`) return nothing.
+    fn take_events(&mut self) -> Vec<NetworkEvent> {
+        Vec::new()
+    }
+
+    fn connection_handles(&self) -> Vec<u32>;
+
+    fn send_message<M: NetworkMessage>(
+        &mut self,
+        handle: u32,
+        message: M,
+    ) -> Result<(), TransportError>;
+
+    fn recv_message<M: NetworkMessage>(&mut self, handle: u32) -> Option<M>;
+
+    fn disconnect(&mut self, handle: u32);
+
+    /// Opens a connection to a server (used by clients). `LoopbackTransport` ignores the address,
+    /// as it always connects to the server of its hub.
+    fn connect_to_server(&mut self, address: SocketAddr);
+}
+
+impl Transport for NetworkResource {
+    fn build(_builder: &mut AppBuilder) {}
+
+    fn connection_handles(&self) -> Vec<u32> {
+        self.connections.keys().copied().collect()
+    }
+
+    fn send_message<M: NetworkMessage>(
+        &mut self,
+        handle: u32,
+        message: M,
+    ) -> Result<(), TransportError> {
+        match NetworkResource::send_message(self, handle, message) {
+            Ok(None) => Ok(()),
+            Ok(Some(_)) => Err(TransportError::ChannelFull(handle)),
+            Err(err) => Err(TransportError::Send(err.to_string())),
+        }
+    }
+
+    fn recv_message<M: NetworkMessage>(&mut self, handle: u32) -> Option<M> {
+        self.connections.get_mut(&handle)?.channels()?.recv::<M>()
+    }
+
+    fn disconnect(&mut self, handle: u32) {
+        self.connections.remove(&handle);
+    }
+
+    fn connect_to_server(&mut self, address: SocketAddr) {
+        NetworkResource::connect(self, address);
+    }
+}
+
+/// Connects `LoopbackTransport`s that live in the same process. A hub can have one server and
+/// any number of clients.
+#[derive(Clone, Default)]
+pub struct LoopbackHub {
+    state: Arc<Mutex<LoopbackHubState>>,
+}
+
+#[derive(Default)]
+struct LoopbackHubState {
+    endpoints: Vec<LoopbackEndpoint>,
+    server_endpoint: Option<usize>,
+}
+
+#[derive(Default)]
+struct LoopbackEndpoint {
+    next_handle: u32,
+    /// Maps local connection handles to peers' endpoints and handles.
+    connections: HashMap<u32, (usize, u32)>,
+    /// Serialized messages by connection handle and message type.
+    inbox: HashMap<u32, HashMap<TypeId, VecDeque<Vec<u8>>>>,
+    events: Vec<NetworkEvent>,
+}
+
+impl LoopbackEndpoint {
+    fn add_connection(&mut self, peer: (usize, u32)) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.connections.insert(handle, peer);
+        handle
+    }
+}
+
+impl LoopbackHub {
+    pub fn server(&self) -> LoopbackTransport {
+        let mut state = self.state.lock().unwrap();
+        assert!(
+            state.server_endpoint.is_none(),
+            "A loopback hub can't have more than one server"
+        );
+        let endpoint = state.endpoints.len();
+        state.endpoints.push(LoopbackEndpoint::default());
+        state.server_endpoint = Some(endpoint);
+        LoopbackTransport {
+            hub: self.clone(),
+            endpoint,
+        }
+    }
+
+    /// Returns a transport that isn't connected yet (see `LoopbackTransport::connect`).
+    pub fn client(&self) -> LoopbackTransport {
+        let mut state = self.state.lock().unwrap();
+        let endpoint = state.endpoints.len();
+        state.endpoints.push(LoopbackEndpoint::default());
+        LoopbackTransport {
+            hub: self.clone(),
+            endpoint,
+        }
+    }
+}
+
+/// An in-memory transport: messages are delivered (in order and without losses) as soon as they
+/// are sent, so tests can step apps deterministically without binding any ports.
+pub struct LoopbackTransport {
+    hub: LoopbackHub,
+    endpoint: usize,
+}
+
+impl LoopbackTransport {
+    /// Connects to the server of the hub, `Connected` events are emitted on both ends.
+    pub fn connect(&mut self) -> u32 {
+        let mut state = self.hub.state.lock().unwrap();
+        let server_endpoint = state
+            .server_endpoint
+            .expect("Expected a loopback server to be created before connecting");
+        let server_handle = state.endpoints[server_endpoint].next_handle;
+        let client_handle =
+            state.endpoints[self.endpoint].add_connection((server_endpoint, server_handle));
+        state.endpoints[server_endpoint].add_connection((self.endpoint, client_handle));
+
+        state.endpoints[self.endpoint]
+            .events
+            .push(NetworkEvent::Connected(client_handle));
+        state.endpoints[server_endpoint]
+            .events
+            .push(NetworkEvent::Connected(server_handle));
+        client_handle
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn build(builder: &mut AppBuilder) {
+        builder.add_system_to_stage(CoreStage::PreUpdate, emit_transport_events::<Self>.system());
+    }
+
+    fn take_events(&mut self) -> Vec<NetworkEvent> {
+        let mut state = self.hub.state.lock().unwrap();
+        std::mem::take(&mut state.endpoints[self.endpoint].events)
+    }
+
+    fn connection_handles(&self) -> Vec<u32> {
+        let state = self.hub.state.lock().unwrap();
+        let mut handles = state.endpoints[self.endpoint]
+            .connections
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        // Keeping the order stable for the sake of determinism.
+        handles.sort_unstable();
+        handles
+    }
+
+    fn send_message<M: NetworkMessage>(
+        &mut self,
+        handle: u32,
+        message: M,
+    ) -> Result<(), TransportError> {
+        let mut state = self.hub.state.lock().unwrap();
+        let (peer_endpoint, peer_handle) = *state.endpoints[self.endpoint]
+            .connections
+            .get(&handle)
+            .ok_or(TransportError::UnknownConnection(handle))?;
+        // Serializing messages to catch the same errors that real transports would encounter.
+        let bytes =
+            bincode::serialize(&message).map_err(|err| TransportError::Send(err.to_string()))?;
+        state.endpoints[peer_endpoint]
+            .inbox
+            .entry(peer_handle)
+            .or_default()
+            .entry(TypeId::of::<M>())
+            .or_default()
+            .push_back(bytes);
+        Ok(())
+    }
+
+    fn recv_message<M: NetworkMessage>(&mut self, handle: u32) -> Option<M> {
+        let mut state = self.hub.state.lock().unwrap();
+        let bytes = state.endpoints[self.endpoint]
+            .inbox
+            .get_mut(&handle)?
+            .get_mut(&TypeId::of::<M>())?
+            .pop_front()?;
+        match bincode::deserialize(&bytes) {
+            Ok(message) => Some(message),
+            Err(err) => {
+                log::error!("Failed to deserialize a loopback message: {:?}", err);
+                None
+            }
+        }
+    }
+
+    fn disconnect(&mut self, handle: u32) {
+        let mut state = self.hub.state.lock().unwrap();
+        let endpoint = &mut state.endpoints[self.endpoint];
+        endpoint.inbox.remove(&handle);
+        let (peer_endpoint, peer_handle) = match endpoint.connections.remove(&handle) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let peer = &mut state.endpoints[peer_endpoint];
+        if peer.connections.remove(&peer_handle).is_some() {
+            peer.inbox.remove(&peer_handle);
+            peer.events.push(NetworkEvent::Disconnected(peer_handle));
+        }
+    }
+
+    fn connect_to_server(&mut self, _address: SocketAddr) {
+        self.connect();
+    }
+}
+
+/// Connection handles of `CompositeTransport`'s secondary transport start from this number.
+pub const SECONDARY_HANDLE_OFFSET: u32 = 1 << 31;
+
+/// Serves the connections of two transports at once, so that the clients of both can share
+/// a game. Handles of the secondary transport are offset by `SECONDARY_HANDLE_OFFSET`, which lets
+/// the rest of the code keep all connections in the same registries.
+///
+/// Connections whose handles don't fit into their range (which takes billions of connections to
+/// happen) get closed right away.
+///
+/// Both transports are expected to be driven by the composite one (i.e. to report their events via
+/// `Transport::take_events`). That rules out `NetworkResource`, as it's a resource driven by
+/// `NetworkingPlugin`.
+pub struct CompositeTransport<A, B> {
+    pub primary: A,
+    pub secondary: B,
+}
+
+impl<A: Transport, B: Transport> CompositeTransport<A, B> {
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+impl<A: Transport, B: Transport> Transport for CompositeTransport<A, B> {
+    fn build(builder: &mut AppBuilder) {
+        builder.add_system_to_stage(CoreStage::PreUpdate, emit_transport_events::<Self>.system());
+    }
+
+    fn take_events(&mut self) -> Vec<NetworkEvent> {
+        let mut events = Vec::new();
+        for event in self.primary.take_events() {
+            match event {
+                NetworkEvent::Connected(handle) if handle >= SECONDARY_HANDLE_OFFSET => {
+                    log::error!(
+                        "Primary transport's connection handle ({}) is out of range, disconnecting",
+                        handle
+                    );
+                    self.primary.disconnect(handle);
+                }
+                NetworkEvent::Disconnected(handle) | NetworkEvent::Error(handle, _)
+                    if handle >= SECONDARY_HANDLE_OFFSET => {}
+                event => events.push(event),
+            }
+        }
+        for event in self.secondary.take_events() {
+            match event {
+                NetworkEvent::Connected(handle) => match secondary_handle(handle) {
+                    Some(handle) => events.push(NetworkEvent::Connected(handle)),
+                    None => {
+                        log::error!(
+                            "Secondary transport's connection handle ({}) is out of range, disconnecting",
+                            handle
+                        );
+                        self.secondary.disconnect(handle);
+                    }
+                },
+                NetworkEvent::Disconnected(handle) => {
+                    if let Some(handle) = secondary_handle(handle) {
+                        events.push(NetworkEvent::Disconnected(handle));
+                    }
+                }
+                NetworkEvent::Error(handle, err) => {
+                    if let Some(handle) = secondary_handle(handle) {
+                        events.push(NetworkEvent::Error(handle, err));
+                    }
+                }
+                event => {
+                    log::warn!("Skipping a secondary transport's event: {:?}", event);
+                }
+            }
+        }
+        events
+    }
+
+    fn connection_handles(&self) -> Vec<u32> {
+        let mut handles = self
+            .primary
+            .connection_handles()
+            .into_iter()
+            .filter(|handle| *handle < SECONDARY_HANDLE_OFFSET)
+            .collect::<Vec<_>>();
+        handles.extend(
+            self.secondary
+                .connection_handles()
+                .into_iter()
+                .filter_map(secondary_handle),
+        );
+        handles
+    }
+
+    fn send_message<M: NetworkMessage>(
+        &mut self,
+        handle: u32,
+        message: M,
+    ) -> Result<(), TransportError> {
+        if handle < SECONDARY_HANDLE_OFFSET {
+            return self.primary.send_message(handle, message);
+        }
+        self.secondary
+            .send_message(handle - SECONDARY_HANDLE_OFFSET, message)
+            .map_err(|err| match err {
+                TransportError::UnknownConnection(_) => TransportError::UnknownConnection(handle),
+                TransportError::ChannelFull(_) => TransportError::ChannelFull(handle),
+                err => err,
+            })
+    }
+
+    fn recv_message<M: NetworkMessage>(&mut self, handle: u32) -> Option<M> {
+        if handle < SECONDARY_HANDLE_OFFSET {
+            return self.primary.recv_message(handle);
+        }
+        self.secondary
+            .recv_message(handle - SECONDARY_HANDLE_OFFSET)
+    }
+
+    fn disconnect(&mut self, handle: u32) {
+        if handle < SECONDARY_HANDLE_OFFSET {
+            self.primary.disconnect(handle);
+        } else {
+            self.secondary.disconnect(handle - SECONDARY_HANDLE_OFFSET);
+        }
+    }
+
+    fn connect_to_server(&mut self, address: SocketAddr) {
+        self.primary.connect_to_server(address);
+    }
+}
+
+/// Maps a handle of `CompositeTransport`'s secondary transport to the composite one. Returns `None`
+/// for handles that don't fit into the secondary range (i.e. are equal to or greater than
+/// `SECONDARY_HANDLE_OFFSET`).
+fn secondary_handle(handle: u32) -> Option<u32> {
+    handle.checked_add(SECONDARY_HANDLE_OFFSET)
+}
+
+fn emit_transport_events<T: Transport>(
+    mut net: ResMut<T>,
+    mut network_events: EventWriter<NetworkEvent>,
+) {
+    for event in net.take_events() {
+        network_events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        messages::{Message, ReliableClientMessage, ReliableServerMessage},
+        net::SessionId,
+        transport::{CompositeTransport, LoopbackHub, Transport, SECONDARY_HANDLE_OFFSET},
+    };
+    use bevy_networking_turbulence::NetworkEvent;
+
+    #[test]
+    fn test_loopback_delivers_messages() {
+        let hub = LoopbackHub::default();
+        let mut server = hub.server();
+        let mut client = hub.client();
+
+        let client_handle = client.connect();
+        assert!(matches!(
+            client.take_events().as_slice(),
+            [NetworkEvent::Connected(handle)] if *handle == client_handle
+        ));
+        let server_handle = match server.take_events().as_slice() {
+            [NetworkEvent::Connected(handle)] => *handle,
+            events => panic!("Unexpected events: {:?}", events),
+        };
+        assert_eq!(server.connection_handles(), vec![server_handle]);
+
+        for i in 0..2 {
+            client
+                .send_message(
+                    client_handle,
+                    Message {
+                        session_id: SessionId::new(i),
+                        message: ReliableClientMessage::UndoLevelEdit,
+                    },
+                )
+                .unwrap();
+        }
+        // Messages of other types don't get mixed in.
+        assert!(server
+            .recv_message::<Message<ReliableServerMessage>>(server_handle)
+            .is_none());
+        for i in 0..2 {
+            let message = server
+                .recv_message::<Message<ReliableClientMessage>>(server_handle)
+                .unwrap();
+            assert_eq!(message.session_id, SessionId::new(i));
+        }
+        assert!(server
+            .recv_message::<Message<ReliableClientMessage>>(server_handle)
+            .is_none());
+
+        server
+            .send_message(
+                server_handle,
+                Message {
+                    session_id: SessionId::new(0),
+                    message: ReliableServerMessage::Initialize,
+                },
+            )
+            .unwrap();
+        assert!(client
+            .recv_message::<Message<ReliableServerMessage>>(client_handle)
+            .is_some());
+    }
+
+    #[test]
+    fn test_loopback_disconnect() {
+        let hub = LoopbackHub::default();
+        let mut server = hub.server();
+        let mut clients = vec![hub.client(), hub.client()];
+        let client_handles = clients
+            .iter_mut()
+            .map(|client| client.connect())
+            .collect::<Vec<_>>();
+        assert_eq!(server.take_events().len(), 2);
+        assert_eq!(server.connection_handles().len(), 2);
+
+        clients[0].disconnect(client_handles[0]);
+        assert!(clients[0].connection_handles().is_empty());
+        let server_events = server.take_events();
+        assert!(matches!(
+            server_events.as_slice(),
+            [NetworkEvent::Disconnected(_)]
+        ));
+        assert_eq!(server.connection_handles().len(), 1);
+        assert!(clients[1]
+            .send_message(
+                client_handles[1],
+                Message {
+                    session_id: SessionId::new(0),
+                    message: ReliableClientMessage::RedoLevelEdit,
+                },
+            )
+            .is_ok());
+        assert!(clients[0]
+            .send_message(
+                client_handles[0],
+                Message {
+                    session_id: SessionId::new(0),
+                    message: ReliableClientMessage::RedoLevelEdit,
+                },
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_composite_transport() {
+        let (primary_hub, secondary_hub) = (LoopbackHub::default(), LoopbackHub::default());
+        let mut server = CompositeTransport::new(primary_hub.server(), secondary_hub.server());
+        let mut primary_client = primary_hub.client();
+        let mut secondary_client = secondary_hub.client();
+        let primary_client_handle = primary_client.connect();
+        let secondary_client_handle = secondary_client.connect();
+
+        assert!(matches!(
+            server.take_events().as_slice(),
+            [
+                NetworkEvent::Connected(0),
+                NetworkEvent::Connected(SECONDARY_HANDLE_OFFSET)
+            ]
+        ));
+        assert_eq!(
+            server.connection_handles(),
+            vec![0, SECONDARY_HANDLE_OFFSET]
+        );
+
+        for (client, client_handle, server_handle) in vec![
+            (&mut primary_client, primary_client_handle, 0),
+            (
+                &mut secondary_client,
+                secondary_client_handle,
+                SECONDARY_HANDLE_OFFSET,
+            ),
+        ] {
+            client
+                .send_message(
+                    client_handle,
+                    Message {
+                        session_id: SessionId::new(0),
+                        message: ReliableClientMessage::UndoLevelEdit,
+                    },
+                )
+                .unwrap();
+            assert!(server
+                .recv_message::<Message<ReliableClientMessage>>(server_handle)
+                .is_some());
+            server
+                .send_message(
+                    server_handle,
+                    Message {
+                        session_id: SessionId::new(0),
+                        message: ReliableServerMessage::Initialize,
+                    },
+                )
+                .unwrap();
+            assert!(client
+                .recv_message::<Message<ReliableServerMessage>>(client_handle)
+                .is_some());
+        }
+
+        secondary_client.disconnect(secondary_client_handle);
+        assert!(matches!(
+            server.take_events().as_slice(),
+            [NetworkEvent::Disconnected(SECONDARY_HANDLE_OFFSET)]
+        ));
+        assert_eq!(server.connection_handles(), vec![0]);
+        assert!(server
+            .send_message(
+                SECONDARY_HANDLE_OFFSET,
+                Message {
+                    session_id: SessionId::new(0),
+                    message: ReliableServerMessage::Initialize,
+                },
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_composite_transport_out_of_range_handles() {
+        let (primary_hub, secondary_hub) = (LoopbackHub::default(), LoopbackHub::default());
+        let mut server = CompositeTransport::new(primary_hub.server(), secondary_hub.server());
+        {
+            let mut state = secondary_hub.state.lock().unwrap();
+            let server_endpoint = state.server_endpoint.unwrap();
+            state.endpoints[server_endpoint].next_handle = SECONDARY_HANDLE_OFFSET - 1;
+        }
+        let mut clients = vec![secondary_hub.client(), secondary_hub.client()];
+        let client_handles = clients
+            .iter_mut()
+            .map(|client| client.connect())
+            .collect::<Vec<_>>();
+
+        // The handle of the second connection would overflow, so it gets closed.
+        assert!(matches!(
+            server.take_events().as_slice(),
+            [NetworkEvent::Connected(u32::MAX)]
+        ));
+        assert_eq!(server.connection_handles(), vec![u32::MAX]);
+        assert!(matches!(
+            clients[1].take_events().as_slice(),
+            [NetworkEvent::Connected(_), NetworkEvent::Disconnected(handle)] if *handle == client_handles[1]
+        ));
+
+        clients[0].disconnect(client_handles[0]);
+        assert!(matches!(
+            server.take_events().as_slice(),
+            [NetworkEvent::Disconnected(u32::MAX)]
+        ));
+        assert!(server.connection_handles().is_empty());
+    }
+}